@@ -0,0 +1,359 @@
+//! Procedural macros for `weval-guest`; see that crate for usage.
+//!
+//! `#[specialize]` rewrites the interpreter function it is applied
+//! to: it finds the `#[pc]` binding and the `#[dispatch]` loop, which
+//! it strips of those (inert) attributes, and instruments the loop
+//! with context intrinsics. With `id = N`, it also emits the
+//! `weval.func.N` target export and a typed request function.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Error, Expr, FnArg, Ident, ItemFn, Lifetime, LitInt,
+    Pat, Path,
+};
+
+struct Args {
+    krate: Path,
+    id: Option<u32>,
+    num_globals: u32,
+}
+
+/// Instrument an interpreter function for weval. Arguments:
+///
+/// - `id = N`: register the function as weval target `N` and
+///   generate `<name>_weval_request`;
+/// - `num_globals = N`: the number of specialization globals its
+///   requests prepend to their arguments (default 0);
+/// - `crate = path`: the path of the `weval-guest` crate (default
+///   `::weval`).
+#[proc_macro_attribute]
+pub fn specialize(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = Args {
+        krate: parse_quote!(::weval),
+        id: None,
+        num_globals: 0,
+    };
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("id") {
+            args.id = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+        } else if meta.path.is_ident("num_globals") {
+            args.num_globals = meta.value()?.parse::<LitInt>()?.base10_parse()?;
+        } else if meta.path.is_ident("crate") {
+            args.krate = meta.value()?.parse()?;
+        } else {
+            return Err(meta.error("unknown `specialize` argument"));
+        }
+        Ok(())
+    });
+    parse_macro_input!(attr with parser);
+    let func = parse_macro_input!(item as ItemFn);
+    expand(args, func)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Marks the interpreter's PC: a `let` binding or a parameter of a
+/// `#[specialize]` function.
+#[proc_macro_attribute]
+pub fn pc(_attr: TokenStream, _item: TokenStream) -> TokenStream {
+    outside_specialize("pc")
+}
+
+/// Marks the dispatch loop (a `loop` or `while`) of a `#[specialize]`
+/// function.
+#[proc_macro_attribute]
+pub fn dispatch(_attr: TokenStream, _item: TokenStream) -> TokenStream {
+    outside_specialize("dispatch")
+}
+
+fn outside_specialize(name: &str) -> TokenStream {
+    Error::new(
+        Span::call_site(),
+        format!(
+            "`#[{}]` is only valid within a `#[specialize]` function",
+            name
+        ),
+    )
+    .into_compile_error()
+    .into()
+}
+
+/// Remove the attribute named `name` (by the last segment of its
+/// path), returning whether it was present.
+fn take_attr(attrs: &mut Vec<Attribute>, name: &str) -> bool {
+    let len = attrs.len();
+    attrs.retain(|attr| match attr.path().segments.last() {
+        Some(segment) => segment.ident != name,
+        None => true,
+    });
+    attrs.len() != len
+}
+
+fn binding_ident(pat: &Pat) -> Option<&Ident> {
+    match pat {
+        Pat::Ident(pat) => Some(&pat.ident),
+        Pat::Type(pat) => binding_ident(&pat.pat),
+        _ => None,
+    }
+}
+
+fn expand(args: Args, mut func: ItemFn) -> syn::Result<TokenStream2> {
+    let mut instrument = Instrument {
+        krate: args.krate.clone(),
+        pc: None,
+        dispatched: false,
+        errors: vec![],
+    };
+    for input in func.sig.inputs.iter_mut() {
+        if let FnArg::Typed(input) = input {
+            if take_attr(&mut input.attrs, "pc") {
+                instrument.set_pc(&input.pat);
+            }
+        }
+    }
+    instrument.visit_block_mut(&mut func.block);
+    if let Some(error) = instrument.errors.into_iter().reduce(|mut all, error| {
+        all.combine(error);
+        all
+    }) {
+        return Err(error);
+    }
+
+    let registration = match args.id {
+        Some(id) => register(&args.krate, id, args.num_globals, &func)?,
+        None => quote!(),
+    };
+    Ok(quote! {
+        #func
+        #registration
+    })
+}
+
+/// Emit the target export and request function for `func`.
+fn register(krate: &Path, id: u32, num_globals: u32, func: &ItemFn) -> syn::Result<TokenStream2> {
+    let sig = &func.sig;
+    let is_c = match &sig.abi {
+        Some(abi) => match &abi.name {
+            Some(name) => name.value() == "C",
+            None => true,
+        },
+        None => false,
+    };
+    if !is_c {
+        return Err(Error::new_spanned(
+            sig,
+            "a weval target must be an `extern \"C\"` function",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &sig.generics,
+            "a weval target cannot be generic",
+        ));
+    }
+    let mut tys = vec![];
+    let mut names = vec![];
+    for (i, input) in sig.inputs.iter().enumerate() {
+        match input {
+            FnArg::Typed(input) => {
+                tys.push(&input.ty);
+                names.push(match binding_ident(&input.pat) {
+                    Some(ident) => ident.clone(),
+                    None => format_ident!("arg{}", i),
+                });
+            }
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new_spanned(
+                    receiver,
+                    "a weval target cannot take `self`",
+                ))
+            }
+        }
+    }
+
+    let name = &sig.ident;
+    let unsafety = &sig.unsafety;
+    let output = &sig.output;
+    let fn_ty = quote!(#unsafety extern "C" fn(#(#tys),*) #output);
+    let export = format!("weval.func.{}", id);
+    let target = format_ident!("__weval_func_{}", name);
+    let request = format_ident!("{}_weval_request", name);
+    let vis = &func.vis;
+    let specialized = Ident::new("specialized", Span::mixed_site());
+    let generic = Ident::new("generic", Span::mixed_site());
+    let doc = format!(
+        "Request a specialization of `{}` (weval target {}), given one \
+         argument per parameter, to be stored in `*specialized`.\n\n\
+         # Safety\n\n\
+         See `request`.",
+        name, id
+    );
+    Ok(quote! {
+        #[cfg(target_arch = "wasm32")]
+        #[export_name = #export]
+        extern "C" fn #target() -> #krate::Func {
+            let #generic: #fn_ty = #name;
+            ::core::option::Option::Some(unsafe {
+                ::core::mem::transmute::<#fn_ty, unsafe extern "C" fn()>(#generic)
+            })
+        }
+
+        #[doc = #doc]
+        #vis unsafe fn #request(
+            #specialized: *mut ::core::option::Option<#fn_ty>,
+            #(#names: #krate::Arg<'_>),*
+        ) -> *mut #krate::Req {
+            let #generic: #fn_ty = #name;
+            #krate::request(
+                #id,
+                #num_globals,
+                ::core::option::Option::Some(
+                    ::core::mem::transmute::<#fn_ty, unsafe extern "C" fn()>(#generic),
+                ),
+                #specialized as *mut #krate::Func,
+                &[#(#names),*],
+            )
+        }
+    })
+}
+
+/// Finds the PC and instruments the dispatch loop.
+struct Instrument {
+    krate: Path,
+    pc: Option<Ident>,
+    dispatched: bool,
+    errors: Vec<Error>,
+}
+
+impl Instrument {
+    fn set_pc(&mut self, pat: &Pat) {
+        match binding_ident(pat) {
+            _ if self.pc.is_some() => self
+                .errors
+                .push(Error::new_spanned(pat, "more than one `#[weval::pc]`")),
+            Some(ident) => self.pc = Some(ident.clone()),
+            None => self.errors.push(Error::new_spanned(
+                pat,
+                "`#[weval::pc]` must mark a binding of a single variable",
+            )),
+        }
+    }
+
+    /// Rewrite a dispatch loop (given with its attribute removed) so
+    /// that it runs in contexts keyed by the PC.
+    fn instrument(&mut self, expr: &mut Expr) {
+        let pc = match &self.pc {
+            Some(pc) => pc.clone(),
+            None => {
+                self.errors.push(Error::new_spanned(
+                    &*expr,
+                    "`#[weval::dispatch]` needs a `#[weval::pc]` before it",
+                ));
+                return;
+            }
+        };
+        if self.dispatched {
+            self.errors.push(Error::new_spanned(
+                &*expr,
+                "more than one `#[weval::dispatch]`",
+            ));
+            return;
+        }
+        self.dispatched = true;
+
+        let krate = &self.krate;
+        let update = quote!(#krate::update_context(#pc));
+        let (label, body) = match expr {
+            Expr::Loop(expr) => (&expr.label, &mut expr.body),
+            Expr::While(expr) => (&expr.label, &mut expr.body),
+            _ => unreachable!(),
+        };
+        let mut continues = Continues {
+            label: label.as_ref().map(|label| label.name.clone()),
+            depth: 0,
+            update: update.clone(),
+        };
+        continues.visit_block_mut(body);
+        *body = parse_quote!({
+            #body;
+            #update;
+        });
+
+        let result = Ident::new("__weval_result", Span::mixed_site());
+        *expr = parse_quote!({
+            #krate::push_context(#pc);
+            #[allow(unreachable_code, clippy::let_unit_value)]
+            let #result = #expr;
+            #[allow(unreachable_code)]
+            #krate::pop_context();
+            #result
+        });
+    }
+}
+
+impl VisitMut for Instrument {
+    fn visit_local_mut(&mut self, local: &mut syn::Local) {
+        if take_attr(&mut local.attrs, "pc") {
+            self.set_pc(&local.pat);
+        }
+        visit_mut::visit_local_mut(self, local);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let attrs = match expr {
+            Expr::Loop(expr) => Some(&mut expr.attrs),
+            Expr::While(expr) => Some(&mut expr.attrs),
+            _ => None,
+        };
+        if attrs.is_some_and(|attrs| take_attr(attrs, "dispatch")) {
+            self.instrument(expr);
+        } else {
+            visit_mut::visit_expr_mut(self, expr);
+        }
+    }
+
+    fn visit_item_mut(&mut self, _item: &mut syn::Item) {
+        // Nested items are not part of the interpreter function.
+    }
+}
+
+/// Updates the context before each `continue` of the dispatch loop.
+struct Continues {
+    label: Option<Lifetime>,
+    /// Depth of loops nested in the dispatch loop.
+    depth: usize,
+    update: TokenStream2,
+}
+
+impl VisitMut for Continues {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Continue(cont) => {
+                let ours = match &cont.label {
+                    Some(label) => Some(label) == self.label.as_ref(),
+                    None => self.depth == 0,
+                };
+                if ours {
+                    let update = &self.update;
+                    *expr = parse_quote!({
+                        #update;
+                        #cont
+                    });
+                }
+            }
+            Expr::Loop(_) | Expr::While(_) | Expr::ForLoop(_) => {
+                self.depth += 1;
+                visit_mut::visit_expr_mut(self, expr);
+                self.depth -= 1;
+            }
+            // `continue` cannot leave these.
+            Expr::Closure(_) | Expr::Async(_) => {}
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _item: &mut syn::Item) {}
+}
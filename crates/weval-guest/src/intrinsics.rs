@@ -0,0 +1,145 @@
+//! weval's intrinsics: imports from the `weval` module.
+//!
+//! These mirror the `WEVAL_WASM_IMPORT` declarations in
+//! `include/weval.h`, which documents each of them. On `wasm32` they
+//! are imports, which weval interprets while specializing and
+//! replaces in the output; elsewhere (e.g. when testing an
+//! interpreter natively) they are functions behaving as generic code
+//! does after wevaling: no-ops, or passing through their first
+//! argument. The few that are only meaningful in specialized code
+//! (registers and the virtual stack and locals) panic.
+//!
+//! `ABI` lists each intrinsic's import name and signature; the `weval`
+//! crate's `guest_abi` test checks it against the intrinsics weval
+//! recognizes, so that the two cannot drift apart.
+
+/// The wasm type an intrinsic parameter or result is passed as.
+pub trait WasmType {
+    const NAME: &'static str;
+}
+
+macro_rules! wasm_type {
+    ($name:literal: $($ty:ty),*) => {
+        $(impl WasmType for $ty {
+            const NAME: &'static str = $name;
+        })*
+    };
+}
+
+// Guests are 32-bit, so pointers are `i32`s.
+wasm_type!("i32": u32, *const u8, *mut u8, *const u32, *mut u64);
+wasm_type!("i64": u64);
+
+/// An intrinsic's import name and wasm signature.
+#[derive(Clone, Copy, Debug)]
+pub struct Intrinsic {
+    pub name: &'static str,
+    pub params: &'static [&'static str],
+    pub results: &'static [&'static str],
+}
+
+fn only_in_specialized_code(name: &str) -> ! {
+    panic!(
+        "weval intrinsic `{}` is only valid in specialized code",
+        name
+    )
+}
+
+macro_rules! intrinsics {
+    ($(
+        $(#[$attr:meta])*
+        fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)? = $import:literal $fallback:block
+    )*) => {
+        #[cfg(target_arch = "wasm32")]
+        #[link(wasm_import_module = "weval")]
+        extern "C" {
+            $(
+                $(#[$attr])*
+                #[link_name = $import]
+                pub fn $name($($arg: $ty),*) $(-> $ret)?;
+            )*
+        }
+
+        $(
+            $(#[$attr])*
+            #[cfg(not(target_arch = "wasm32"))]
+            #[allow(unused_variables, clippy::missing_safety_doc)]
+            pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? $fallback
+        )*
+
+        /// Each intrinsic's import name and signature.
+        pub const ABI: &[Intrinsic] = &[$(
+            Intrinsic {
+                name: $import,
+                params: &[$(<$ty as WasmType>::NAME),*],
+                results: &[$(<$ret as WasmType>::NAME)?],
+            },
+        )*];
+    };
+}
+
+intrinsics! {
+    /// Enter a new context for the interpreter PC `pc`.
+    fn push_context(pc: u32) = "push.context" {}
+    /// Leave the innermost context.
+    fn pop_context() = "pop.context" {}
+    /// Change the innermost context's PC to `pc`.
+    fn update_context(pc: u32) = "update.context" {}
+    /// Group the current context with others in `bucket`.
+    fn context_bucket(bucket: u32) = "context.bucket" {}
+    fn read_reg(idx: u64) -> u64 = "read.reg" {
+        only_in_specialized_code("read.reg")
+    }
+    fn write_reg(idx: u64, value: u64) = "write.reg" {
+        only_in_specialized_code("write.reg")
+    }
+    fn specialize_value(value: u32, lo: u32, hi: u32) -> u32 = "specialize.value" {
+        value
+    }
+    fn read_specialization_global(index: u32) -> u64 = "read.specialization.global" {
+        only_in_specialized_code("read.specialization.global")
+    }
+    fn push_stack(ptr: *mut u64, value: u64) = "push.stack" {
+        only_in_specialized_code("push.stack")
+    }
+    fn sync_stack() = "sync.stack" {
+        only_in_specialized_code("sync.stack")
+    }
+    fn read_stack(ptr: *mut u64, index: u32) -> u64 = "read.stack" {
+        only_in_specialized_code("read.stack")
+    }
+    fn write_stack(ptr: *mut u64, index: u32, value: u64) = "write.stack" {
+        only_in_specialized_code("write.stack")
+    }
+    fn pop_stack(ptr: *mut u64) -> u64 = "pop.stack" {
+        only_in_specialized_code("pop.stack")
+    }
+    fn read_local(ptr: *mut u64, index: u32) -> u64 = "read.local" {
+        only_in_specialized_code("read.local")
+    }
+    fn write_local(ptr: *mut u64, index: u32, value: u64) = "write.local" {
+        only_in_specialized_code("write.local")
+    }
+    fn stack_declare(base: *mut u8, slot_size: u32, max_depth: u32) = "stack.declare" {}
+    fn region_epoch(ptr: *const u8, len: u32, epoch: u32) = "region.epoch" {}
+    fn peel_loop() = "peel.loop" {}
+    /// `key` must point to a NUL-terminated string.
+    fn env_u32(key: *const u8) -> u32 = "env.u32" {
+        0
+    }
+    fn ic_site(entry: *const u32, key: u32) -> u32 = "ic.site" {
+        0
+    }
+    fn barrier_needed(value: u64, heap_mask: u64, heap_tag: u64) -> u32 = "barrier.needed" {
+        1
+    }
+    fn trace_line(line_number: u32) = "trace.line" {}
+    /// `name` must point to a NUL-terminated string.
+    fn trace_here(name: *const u8) = "trace.here" {}
+    fn abort_specialization(line_number: u32, fatal: u32) = "abort.specialization" {}
+    fn assert_const32(value: u32, line_no: u32) = "assert.const32" {}
+    /// `message` must point to a NUL-terminated string.
+    fn print(message: *const u8, line: u32, val: u32) = "print" {}
+    /// `label` must point to a NUL-terminated string.
+    fn print_value(label: *const u8, value: u32) = "print.value" {}
+}
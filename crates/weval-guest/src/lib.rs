@@ -0,0 +1,127 @@
+//! Guest-side support for interpreters written in Rust and wevaled:
+//! weval's intrinsics, its request ABI, and the `#[specialize]`
+//! attribute, which instruments an interpreter with both.
+//!
+//! Depend on the crate as `weval` so that the attributes read as they
+//! do below (the macros name the crate `::weval` unless told
+//! otherwise with `#[specialize(crate = path)]`):
+//!
+//! ```toml
+//! [dependencies]
+//! weval = { package = "weval-guest", version = "0.1" }
+//! ```
+//!
+//! Annotate the interpreter function, its PC (a `let` binding or a
+//! parameter) and its dispatch loop:
+//!
+//! ```ignore
+//! #[weval::specialize(id = 1)]
+//! extern "C" fn interpret(code: *const u32, len: u32, arg: u32) -> u32 {
+//!     #[weval::pc]
+//!     let mut pc = 0u32;
+//!     let mut acc = arg;
+//!     #[weval::dispatch]
+//!     loop {
+//!         let op = unsafe { *code.add(pc as usize) };
+//!         pc += 1;
+//!         match op {
+//!             0 => break,
+//!             1 => acc += 1,
+//!             // ...
+//!         }
+//!     }
+//!     acc
+//! }
+//! ```
+//!
+//! The dispatch loop is entered with `push_context(pc)`, calls
+//! `update_context(pc)` at the end of each iteration (including on
+//! `continue`), and is left with `pop_context()`, as weval requires of
+//! an interpreter loop. With `id`, the function is also registered as
+//! weval target `id` (the `weval.func.<id>` export), and the attribute
+//! generates `interpret_weval_request`, which requests a
+//! specialization given one `Arg` per parameter:
+//!
+//! ```ignore
+//! static mut SPECIALIZED: Option<extern "C" fn(*const u32, u32, u32) -> u32> = None;
+//! let req = unsafe {
+//!     interpret_weval_request(
+//!         core::ptr::addr_of_mut!(SPECIALIZED),
+//!         weval::Arg::Memory(bytecode_bytes),
+//!         weval::Arg::from(len),
+//!         weval::Arg::Runtime,
+//!     )
+//! };
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod intrinsics;
+pub mod request;
+
+pub use request::{free, is_wevaled, metadata, request, Arg, Field, Func, Req};
+pub use weval_guest_macros::{dispatch, pc, specialize};
+
+/// A value usable as an interpreter PC, i.e. as a context.
+pub trait Pc {
+    fn to_context(&self) -> u32;
+}
+
+impl Pc for u32 {
+    fn to_context(&self) -> u32 {
+        *self
+    }
+}
+
+impl Pc for u8 {
+    fn to_context(&self) -> u32 {
+        u32::from(*self)
+    }
+}
+
+impl Pc for u16 {
+    fn to_context(&self) -> u32 {
+        u32::from(*self)
+    }
+}
+
+impl Pc for usize {
+    fn to_context(&self) -> u32 {
+        *self as u32
+    }
+}
+
+impl Pc for i32 {
+    fn to_context(&self) -> u32 {
+        *self as u32
+    }
+}
+
+impl<T> Pc for *const T {
+    fn to_context(&self) -> u32 {
+        *self as usize as u32
+    }
+}
+
+impl<T> Pc for *mut T {
+    fn to_context(&self) -> u32 {
+        *self as usize as u32
+    }
+}
+
+/// Enter a new context for the interpreter PC `pc`.
+pub fn push_context(pc: impl Pc) {
+    unsafe { intrinsics::push_context(pc.to_context()) }
+}
+
+/// Leave the innermost context.
+pub fn pop_context() {
+    unsafe { intrinsics::pop_context() }
+}
+
+/// Change the innermost context's PC to `pc`.
+pub fn update_context(pc: impl Pc) {
+    unsafe { intrinsics::update_context(pc.to_context()) }
+}
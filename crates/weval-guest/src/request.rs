@@ -0,0 +1,349 @@
+//! Specialization requests, and the globals weval reads and writes.
+//!
+//! This is the Rust counterpart of the request half of
+//! `include/weval.h`: the structures have the same layout, the
+//! argument bytestring the same encoding, and the same exports
+//! (`weval.pending.head`, `weval.is.wevaled`, `weval.lookup.table`
+//! and `weval.metadata.table`) let weval find them in a snapshot.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr::{addr_of, addr_of_mut, null_mut};
+
+/// A function pointer as stored in requests; cast to and from the
+/// actual signature.
+pub type Func = Option<unsafe extern "C" fn()>;
+
+/// A request (`weval_req_t`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct Req {
+    pub next: *mut Req,
+    pub prev: *mut Req,
+    /// A user-provided ID of the weval'd function, for stability of
+    /// collected request bodies across relinkings.
+    pub func_id: u32,
+    /// How many specialization globals are prepended to the arguments.
+    pub num_globals: u32,
+    pub func: Func,
+    pub argbuf: *mut u8,
+    pub arglen: u32,
+    pub specialized: *mut Func,
+}
+
+/// An entry in the lookup table weval fills in for pre-inserted
+/// specializations (`weval_lookup_entry_t`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct LookupEntry {
+    pub func_id: u32,
+    pub argbuf: *const u8,
+    pub arglen: u32,
+    pub specialized: Func,
+}
+
+/// The lookup table (`weval_lookup_t`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct Lookup {
+    pub entries: *mut LookupEntry,
+    pub nentries: u32,
+}
+
+/// An entry in the specialization metadata table
+/// (`weval_metadata_entry_t`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct MetadataEntry {
+    pub func_id: u32,
+    /// Index of the generic function in the input module.
+    pub generic_func: u32,
+    pub specialized: Func,
+    pub arglen: u32,
+    /// FNV-1a hash of the request's argument bytestring.
+    pub arghash: u64,
+}
+
+/// The metadata table (`weval_metadata_t`), filled in when wevaling
+/// with `--emit-metadata`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Metadata {
+    pub entries: *mut MetadataEntry,
+    pub nentries: u32,
+}
+
+/// A field of an `Arg::Struct` (`weval_req_field_t`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Field {
+    pub offset: u32,
+    pub size: u32,
+    pub is_const: u32,
+}
+
+static mut PENDING_HEAD: *mut Req = null_mut();
+static mut IS_WEVALED: bool = false;
+static mut LOOKUP_TABLE: Lookup = Lookup {
+    entries: null_mut(),
+    nentries: 0,
+};
+static mut METADATA_TABLE: Metadata = Metadata {
+    entries: null_mut(),
+    nentries: 0,
+};
+
+#[cfg(target_arch = "wasm32")]
+#[export_name = "weval.pending.head"]
+extern "C" fn pending_head() -> *mut *mut Req {
+    unsafe { addr_of_mut!(PENDING_HEAD) }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[export_name = "weval.is.wevaled"]
+extern "C" fn is_wevaled_flag() -> *mut bool {
+    unsafe { addr_of_mut!(IS_WEVALED) }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[export_name = "weval.lookup.table"]
+extern "C" fn lookup_table() -> *mut Lookup {
+    unsafe { addr_of_mut!(LOOKUP_TABLE) }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[export_name = "weval.metadata.table"]
+extern "C" fn metadata_table() -> *mut Metadata {
+    unsafe { addr_of_mut!(METADATA_TABLE) }
+}
+
+/// Whether this module has been wevaled.
+pub fn is_wevaled() -> bool {
+    unsafe { *addr_of!(IS_WEVALED) }
+}
+
+/// The specialization metadata table; empty unless wevaled with
+/// `--emit-metadata`.
+pub fn metadata() -> &'static [MetadataEntry] {
+    unsafe {
+        let table = &*addr_of!(METADATA_TABLE);
+        if table.entries.is_null() {
+            &[]
+        } else {
+            core::slice::from_raw_parts(table.entries, table.nentries as usize)
+        }
+    }
+}
+
+/// An argument to a specialization request.
+#[derive(Clone, Copy, Debug)]
+pub enum Arg<'a> {
+    I32(u32),
+    I64(u64),
+    F32(f32),
+    F64(f64),
+    /// A pointer to memory with constant contents.
+    Memory(&'a [u8]),
+    /// A pointer to a structure, only the given fields of which are
+    /// constant.
+    Struct {
+        data: &'a [u8],
+        fields: &'a [Field],
+    },
+    /// Not specialized: given at runtime.
+    Runtime,
+}
+
+impl From<u32> for Arg<'_> {
+    fn from(value: u32) -> Self {
+        Arg::I32(value)
+    }
+}
+
+impl From<i32> for Arg<'_> {
+    fn from(value: i32) -> Self {
+        Arg::I32(value as u32)
+    }
+}
+
+impl From<bool> for Arg<'_> {
+    fn from(value: bool) -> Self {
+        Arg::I32(value as u32)
+    }
+}
+
+impl From<u64> for Arg<'_> {
+    fn from(value: u64) -> Self {
+        Arg::I64(value)
+    }
+}
+
+impl From<i64> for Arg<'_> {
+    fn from(value: i64) -> Self {
+        Arg::I64(value as u64)
+    }
+}
+
+impl From<f32> for Arg<'_> {
+    fn from(value: f32) -> Self {
+        Arg::F32(value)
+    }
+}
+
+impl From<f64> for Arg<'_> {
+    fn from(value: f64) -> Self {
+        Arg::F64(value)
+    }
+}
+
+impl<T> From<*const T> for Arg<'_> {
+    fn from(value: *const T) -> Self {
+        Arg::I32(value as usize as u32)
+    }
+}
+
+impl<T> From<*mut T> for Arg<'_> {
+    fn from(value: *mut T) -> Self {
+        Arg::I32(value as usize as u32)
+    }
+}
+
+// Argument type codes (`weval_req_arg_type`).
+const ARG_I32: u32 = 0;
+const ARG_I64: u32 = 1;
+const ARG_F32: u32 = 2;
+const ARG_F64: u32 = 3;
+const ARG_BUFFER: u32 = 4;
+const ARG_STRUCT: u32 = 5;
+const ARG_NONE: u32 = 255;
+
+fn padded(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/// Encode arguments as a request's bytestring: for each, a
+/// `weval_req_arg_t` and any inline data, padded to 8 bytes.
+pub fn encode_args(args: &[Arg]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let header = |buf: &mut Vec<u8>, specialize: bool, ty: u32, value: u64| {
+        buf.extend_from_slice(&(specialize as u32).to_le_bytes());
+        buf.extend_from_slice(&ty.to_le_bytes());
+        buf.extend_from_slice(&value.to_le_bytes());
+    };
+    let lens = |len: usize, padded_len: usize| (len as u64) | ((padded_len as u64) << 32);
+    for arg in args {
+        match *arg {
+            Arg::I32(value) => header(&mut buf, true, ARG_I32, u64::from(value)),
+            Arg::I64(value) => header(&mut buf, true, ARG_I64, value),
+            Arg::F32(value) => header(&mut buf, true, ARG_F32, u64::from(value.to_bits())),
+            Arg::F64(value) => header(&mut buf, true, ARG_F64, value.to_bits()),
+            Arg::Memory(data) => {
+                let padded_len = padded(data.len());
+                header(&mut buf, true, ARG_BUFFER, lens(data.len(), padded_len));
+                buf.extend_from_slice(data);
+                buf.resize(buf.len() + padded_len - data.len(), 0);
+            }
+            Arg::Struct { data, fields } => {
+                let inline_len = 4 + fields.len() * 12 + data.len();
+                let padded_len = padded(inline_len);
+                header(&mut buf, true, ARG_STRUCT, lens(data.len(), padded_len));
+                buf.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+                for field in fields {
+                    buf.extend_from_slice(&field.offset.to_le_bytes());
+                    buf.extend_from_slice(&field.size.to_le_bytes());
+                    buf.extend_from_slice(&field.is_const.to_le_bytes());
+                }
+                buf.extend_from_slice(data);
+                buf.resize(buf.len() + padded_len - inline_len, 0);
+            }
+            Arg::Runtime => header(&mut buf, false, ARG_NONE, 0),
+        }
+    }
+    buf
+}
+
+/// Request a specialization of `generic` (cast to `Func`) on `args`,
+/// one per parameter, to be stored in `*specialized`. Before wevaling,
+/// the request is queued for weval to find in the snapshot; after, it
+/// is looked up among the specializations weval made, and
+/// `*specialized` set if there is one.
+///
+/// # Safety
+///
+/// `specialized` must remain valid, and `generic` must be the
+/// function registered as target `func_id`, for as long as the
+/// request is queued.
+pub unsafe fn request(
+    func_id: u32,
+    num_globals: u32,
+    generic: Func,
+    specialized: *mut Func,
+    args: &[Arg],
+) -> *mut Req {
+    let argbuf = encode_args(args).into_boxed_slice();
+    let arglen = argbuf.len() as u32;
+    let argbuf = Box::into_raw(argbuf) as *mut u8;
+    let req = Box::into_raw(Box::new(Req {
+        next: null_mut(),
+        prev: null_mut(),
+        func_id,
+        num_globals,
+        func: generic,
+        argbuf,
+        arglen,
+        specialized,
+    }));
+    if is_wevaled() {
+        if let Some(entry) = find(&*req) {
+            *specialized = entry.specialized;
+        }
+    } else {
+        let head = addr_of_mut!(PENDING_HEAD);
+        (*req).next = *head;
+        if !(*head).is_null() {
+            (**head).prev = req;
+        }
+        *head = req;
+    }
+    req
+}
+
+/// Dequeue (if queued) and free a request.
+///
+/// # Safety
+///
+/// `req` must have been returned by `request` and not yet freed.
+pub unsafe fn free(req: *mut Req) {
+    let head = addr_of_mut!(PENDING_HEAD);
+    if !(*req).prev.is_null() {
+        (*(*req).prev).next = (*req).next;
+    } else if *head == req {
+        *head = (*req).next;
+    }
+    if !(*req).next.is_null() {
+        (*(*req).next).prev = (*req).prev;
+    }
+    let req = Box::from_raw(req);
+    drop(Box::from_raw(core::ptr::slice_from_raw_parts_mut(
+        req.argbuf,
+        req.arglen as usize,
+    )));
+}
+
+/// Find a request's specialization in the lookup table, which weval
+/// sorts by function ID and then argument bytestring.
+unsafe fn find(req: &Req) -> Option<&'static LookupEntry> {
+    let table = &*addr_of!(LOOKUP_TABLE);
+    if table.entries.is_null() {
+        return None;
+    }
+    let entries = core::slice::from_raw_parts(table.entries, table.nentries as usize);
+    let args = core::slice::from_raw_parts(req.argbuf, req.arglen as usize);
+    entries
+        .binary_search_by(|entry| {
+            let entry_args = core::slice::from_raw_parts(entry.argbuf, entry.arglen as usize);
+            entry.func_id.cmp(&req.func_id).then(entry_args.cmp(args))
+        })
+        .ok()
+        .map(|i| &entries[i])
+}
@@ -0,0 +1,153 @@
+;; A tiny accumulator-machine interpreter, with a weval request to
+;; specialize it on a fixed program. Used by `tests/end_to_end.rs`.
+;;
+;; Bytecode is a sequence of 32-bit words; `pc` indexes words.
+;;
+;;   0 HALT          return acc
+;;   1 LOADI k       acc = k
+;;   2 ARG           acc = (the interpreter's runtime argument)
+;;   3 STORE r       reg[r] = acc   (r is 0 or 1)
+;;   4 LOAD r        acc = reg[r]
+;;   5 ADD r         acc += reg[r]
+;;   6 DEC           acc -= 1
+;;   7 JNZ t         if acc != 0, pc = t
+;;
+;; The handlers use `select` on operands from the bytecode, and a
+;; `nop`; neither should leave anything behind when specialized.
+;;
+;; Any other opcode traps. That arm is followed by dead code, and so
+;; are the loop and dispatch blocks, so that specialization also sees
+;; the frontend's dead and unterminated blocks.
+;;
+;; The program computes 1 + 2 + ... + n for the argument n (n > 0):
+;;
+;;    0: LOADI 0; STORE 0; ARG; STORE 1
+;;    7: LOAD 0; ADD 1; STORE 0; LOAD 1; DEC; STORE 1; JNZ 7
+;;   20: LOAD 0; HALT
+;;
+;; Memory layout:
+;;
+;;    16: head of the pending-request list (`weval.pending.head`)
+;;    32: table index of the specialized interpreter, or 0 (written
+;;        by weval)
+;;    64: the request (a `weval_req_t`)
+;;   128: the request's arguments: the program, as a constant memory
+;;        buffer (whose contents, at 144, are also the program the
+;;        generic interpreter runs), and the runtime argument.
+;;
+;; `wizer.initialize` enqueues the request; `run` calls the
+;; specialized interpreter if there is one, and the generic one
+;; otherwise.
+
+(module
+  (type $interp_t (func (param i32 i32) (result i32)))
+  (import "weval" "push.context" (func $push_context (param i32)))
+  (import "weval" "pop.context" (func $pop_context))
+  (import "weval" "update.context" (func $update_context (param i32)))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $interp)
+
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\80\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\01\00\00\00\04\00\00\00\60\00\00\00\60\00\00\00"
+    "\01\00\00\00\00\00\00\00\03\00\00\00\00\00\00\00"
+    "\02\00\00\00\03\00\00\00\01\00\00\00\04\00\00\00"
+    "\00\00\00\00\05\00\00\00\01\00\00\00\03\00\00\00"
+    "\00\00\00\00\04\00\00\00\01\00\00\00\06\00\00\00"
+    "\03\00\00\00\01\00\00\00\07\00\00\00\07\00\00\00"
+    "\04\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00"
+    "\00\00\00\00\ff\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "wizer.initialize")
+    (i32.store (i32.const 16) (i32.const 64)))
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $interp_t)
+          (i32.const 144) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $interp (i32.const 144) (local.get $n)))))
+
+  (func $interp (type $interp_t) (param $prog i32) (param $arg i32) (result i32)
+    (local $pc i32) (local $acc i32) (local $r0 i32) (local $r1 i32)
+    (local $op i32) (local $imm i32)
+    (call $push_context (i32.const 0))
+    (block $halt
+      (loop $loop
+        (local.set $op
+          (i32.load
+            (i32.add (local.get $prog) (i32.shl (local.get $pc) (i32.const 2)))))
+        (local.set $imm
+          (i32.load offset=4
+            (i32.add (local.get $prog) (i32.shl (local.get $pc) (i32.const 2)))))
+        (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+        (block $bad
+          (block $jnz
+            (block $dec
+              (block $add
+                (block $load
+                  (block $store
+                    (block $argop
+                      (block $loadi
+                        (br_table $halt $loadi $argop $store $load $add $dec $jnz $bad
+                          (local.get $op))
+                        ;; dead
+                        (local.set $op (i32.const 0)))
+                      ;; LOADI
+                      (local.set $acc (local.get $imm))
+                      (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                      (call $update_context (local.get $pc))
+                      (br $loop))
+                    ;; ARG
+                    (local.set $acc (local.get $arg))
+                    (call $update_context (local.get $pc))
+                    (br $loop))
+                  ;; STORE
+                  (if (local.get $imm)
+                    (then (local.set $r1 (local.get $acc)))
+                    (else (local.set $r0 (local.get $acc))))
+                  (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                  (call $update_context (local.get $pc))
+                  (br $loop))
+                ;; LOAD
+                (local.set $acc
+                  (select (local.get $r1) (local.get $r0) (local.get $imm)))
+                (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                (call $update_context (local.get $pc))
+                (br $loop))
+              ;; ADD
+              (local.set $acc
+                (i32.add (local.get $acc)
+                  (select (local.get $r1) (local.get $r0) (local.get $imm))))
+              (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+              (call $update_context (local.get $pc))
+              (br $loop))
+            ;; DEC
+            nop
+            (local.set $acc (i32.sub (local.get $acc) (i32.const 1)))
+            (call $update_context (local.get $pc))
+            (br $loop))
+          ;; JNZ
+          (if (local.get $acc)
+            (then
+              (local.set $pc (local.get $imm))
+              (call $update_context (local.get $pc))
+              (br $loop)))
+          (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+          (call $update_context (local.get $pc))
+          (br $loop))
+        ;; bad opcode
+        unreachable
+        ;; dead
+        (local.set $acc (i32.const 0))
+        (br $loop)))
+    (call $pop_context)
+    (local.get $acc)))
@@ -14,6 +14,8 @@ typedef struct weval_req_t weval_req_t;
 typedef struct weval_req_arg_t weval_req_arg_t;
 typedef struct weval_lookup_entry_t weval_lookup_entry_t;
 typedef struct weval_lookup_t weval_lookup_t;
+typedef struct weval_metadata_entry_t weval_metadata_entry_t;
+typedef struct weval_metadata_t weval_metadata_t;
 
 struct weval_req_t {
   weval_req_t* next;
@@ -35,6 +37,7 @@ typedef enum {
   weval_req_arg_f32 = 2,
   weval_req_arg_f64 = 3,
   weval_req_arg_buffer = 4,
+  weval_req_arg_struct = 5,
   weval_req_arg_none = 255,
 } weval_req_arg_type;
 
@@ -56,9 +59,27 @@ struct weval_req_arg_t {
       /* Size of buffer in data stream; next arg follows inline data. */
       uint32_t padded_len;
     } buffer;
+    struct {
+      /* A pointer to a structure of the given length, only some of
+       * whose fields are constant. Inline data follows: a `uint32_t`
+       * field count, that many `weval_req_field_t`s, then the
+       * structure's contents. */
+      uint32_t len;
+      /* Size of all inline data in the data stream. */
+      uint32_t padded_len;
+    } structure;
   } u;
 };
 
+/* A field of a `weval_req_arg_struct` argument. Loads within a
+ * constant field fold to the contents given in the request; loads
+ * elsewhere in the structure are left to runtime. */
+typedef struct weval_req_field_t {
+  uint32_t offset;
+  uint32_t size;
+  uint32_t is_const;
+} weval_req_field_t;
+
 /* Lookup table created by weval for pre-inserted wevaled function bodies */
 struct weval_lookup_t {
   weval_lookup_entry_t* entries;
@@ -72,9 +93,30 @@ struct weval_lookup_entry_t {
   weval_func_t specialized;
 };
 
+/* Table of the specializations in a module wevaled with
+ * `--emit-metadata`. The module exports `weval_metadata()` returning
+ * its address, for the embedder; the guest sees it in
+ * `weval_metadata_table`. Entries are sorted by `func_id`, then by
+ * argument bytestring. */
+struct weval_metadata_t {
+  weval_metadata_entry_t* entries;
+  uint32_t nentries;
+};
+
+struct weval_metadata_entry_t {
+  uint32_t func_id;
+  /* Index of the generic function in the input module: */
+  uint32_t generic_func;
+  weval_func_t specialized;
+  uint32_t arglen;
+  /* FNV-1a hash of the request's argument bytestring: */
+  uint64_t arghash;
+};
+
 extern weval_req_t* weval_req_pending_head;
 extern bool weval_is_wevaled;
 extern weval_lookup_t weval_lookup_table;
+extern weval_metadata_t weval_metadata_table;
 
 #define WEVAL_DEFINE_GLOBALS()                                          \
   weval_req_t* weval_req_pending_head;                                  \
@@ -93,6 +135,13 @@ extern weval_lookup_t weval_lookup_table;
   __attribute__((export_name("weval.lookup.table"))) weval_lookup_t*    \
   __weval_lookup_table() {                                              \
     return &weval_lookup_table;                                         \
+  }                                                                     \
+                                                                        \
+  weval_metadata_t weval_metadata_table = {.entries = NULL,             \
+                                           .nentries = 0};              \
+  __attribute__((export_name("weval.metadata.table"))) weval_metadata_t* \
+  __weval_metadata_table() {                                            \
+    return &weval_metadata_table;                                       \
   }
 
 #define WEVAL_DEFINE_TARGET(index, func)             \
@@ -190,6 +239,9 @@ extern "C" {
 #define WEVAL_WASM_IMPORT(name) \
   __attribute__((__import_module__("weval"), __import_name__(name)))
 
+/* Rust guests get these from the `weval-guest` crate
+ * (`crates/weval-guest`), whose declarations must match. */
+
 /* Core intrinsics for interpreter loops: contexts, registers, value
  * specialization */
     
@@ -246,15 +298,91 @@ uint64_t weval_read_local(uint64_t* ptr, uint32_t index)
 void weval_write_local(uint64_t* ptr, uint32_t index, uint64_t value)
     WEVAL_WASM_IMPORT("write.local");
 
+/* Operand-stack renaming: declare that `max_depth` slots of
+ * `slot_size` bytes at `base` are the interpreter's operand stack,
+ * accessed with ordinary loads and stores. Pointers computed from
+ * `base` by adding constants (e.g. a stack pointer kept in a local)
+ * are tracked as offsets into it, so that a load from a slot sees the
+ * value last stored there, across opcode handlers, even when `base`
+ * is only known at runtime. Stores still happen. Call once, at entry
+ * to the interpreter; the stack must not be accessed other than
+ * through pointers derived from `base`. */
+void weval_stack_declare(void* base, uint32_t slot_size, uint32_t max_depth)
+    WEVAL_WASM_IMPORT("stack.declare");
+
+/* Versioned constness: declare that the memory region [ptr, ptr+len)
+ * is at version `epoch`. Code specialized while reading the region
+ * records the epoch in the weval manifest; when the guest modifies
+ * the region (e.g., patching bytecode in place) it should bump the
+ * epoch, and specializations made at older epochs are marked stale. */
+
+void weval_region_epoch(const void* ptr, uint32_t len, uint32_t epoch)
+    WEVAL_WASM_IMPORT("region.epoch");
+
+/* Loop peeling: called at the top of a loop body, asks weval (when
+ * run with `--peel-loops`) to specialize the loop's first iteration
+ * separately, so that state it establishes (e.g., a lazily
+ * initialized cache) is constant in the steady-state loop. */
+
+void weval_peel_loop() WEVAL_WASM_IMPORT("peel.loop");
+
+/* Returns the environment constant named by `key` (a NUL-terminated
+ * string in constant memory), as given to weval for this request or
+ * for the whole run (`--env-for`, `--env`). Specialized code sees the
+ * value as a constant; where no value is given, and in generic code,
+ * this returns 0 at runtime. */
+uint32_t weval_env_u32(const char* key) WEVAL_WASM_IMPORT("env.u32");
+
+/* Inline-cache sites: `entry` points to an inline-cache entry, two
+ * words { guard, target } filled in by the interpreter's IC miss path
+ * (a guard of 0 marks an empty entry). Returns `target` if `key`
+ * equals `guard`, and 0 (a miss) otherwise. Specialized code reads
+ * the entry from the snapshot and checks `key` against the guard it
+ * held then, so that on a hit `target` (e.g. a method's table index)
+ * is a constant and calls through it can be made direct; the guest
+ * handles 0 with its generic lookup. In generic code, and where the
+ * entry is empty or not at a constant address, this always misses. */
+uint32_t weval_ic_site(const uint32_t* entry, uint32_t key)
+    WEVAL_WASM_IMPORT("ic.site");
+
+/* Write-barrier elision: returns whether a GC write barrier is needed
+ * for storing `value`, which is a heap reference if
+ * `(value & heap_mask) == heap_tag`; guard the barrier call with it:
+ *
+ *   if (weval_barrier_needed(v, MASK, TAG)) write_barrier(obj, v);
+ *
+ * This always returns 1 at runtime. With `--elide-barriers`,
+ * specialized code folds it to 0 where `value` is a constant that is
+ * not a heap reference (`immediates`), or is any constant
+ * (`constants`): the latter assumes that objects in the snapshot,
+ * the only ones a constant can refer to, never need a barrier (e.g.
+ * a generational GC that treats them as tenured). */
+uint32_t weval_barrier_needed(uint64_t value, uint64_t heap_mask,
+                              uint64_t heap_tag)
+    WEVAL_WASM_IMPORT("barrier.needed");
+
 /* Debugging and stats intrinsics */
     
 void weval_trace_line(uint32_t line_number) WEVAL_WASM_IMPORT("trace.line");
+/* Labels the current specialized point (e.g., with the name of an
+ * opcode handler) in weval's logs and reports. `name` must point to a
+ * NUL-terminated string in constant memory. */
+void weval_trace_here(const char* name) WEVAL_WASM_IMPORT("trace.here");
 void weval_abort_specialization(uint32_t line_number, uint32_t fatal)
     WEVAL_WASM_IMPORT("abort.specialization");
+/* Fails specialization, reporting `line_no` and the source location
+ * (from DWARF, if present), if `value` is not a compile-time constant
+ * here in every context. */
 void weval_assert_const32(uint32_t value, uint32_t line_no)
     WEVAL_WASM_IMPORT("assert.const32");
 void weval_print(const char* message, uint32_t line, uint32_t val)
     WEVAL_WASM_IMPORT("print");
+/* Reports the abstract value of `value` at this point, in every
+ * context in which it is reached, after specialization; the call is
+ * removed from the output. `label` must point to a NUL-terminated
+ * string in constant memory. */
+void weval_print_value(const char* label, uint32_t value)
+    WEVAL_WASM_IMPORT("print.value");
 void weval_context_bucket(uint32_t bucket) WEVAL_WASM_IMPORT("context.bucket");
 
 #undef WEVAL_WASM_IMPORT
@@ -351,6 +479,21 @@ struct SpecializeMemory : ArgSpec<T> {
   SpecializeMemory(const SpecializeMemory& other) = default;
 };
 
+/* A pointer to a structure whose constant fields are given by
+ * `fields`; e.g. an interpreter context whose code pointer is
+ * constant but whose stack pointer is not. */
+template <typename T>
+struct SpecializeStruct : ArgSpec<T> {
+  T ptr;
+  uint32_t len;
+  const weval_req_field_t* fields;
+  uint32_t num_fields;
+  SpecializeStruct(T ptr_, uint32_t len_, const weval_req_field_t* fields_,
+                   uint32_t num_fields_)
+      : ptr(ptr_), len(len_), fields(fields_), num_fields(num_fields_) {}
+  SpecializeStruct(const SpecializeStruct& other) = default;
+};
+
 namespace impl {
 template <typename Ret, typename... Args>
 using FuncPtr = Ret (*)(Args...);
@@ -489,6 +632,40 @@ struct StoreArgs<SpecializeMemory<T>, Rest...> {
   }
 };
 
+template <typename T, typename... Rest>
+struct StoreArgs<SpecializeStruct<T>, Rest...> {
+  bool operator()(ArgWriter& args, SpecializeStruct<T> arg0, Rest... rest) {
+    weval_req_arg_t arg;
+    arg.specialize = 1;
+    arg.ty = weval_req_arg_struct;
+    arg.u.raw = 0;
+    arg.u.structure.len = arg0.len;
+    uint32_t inline_len =
+        sizeof(uint32_t) + arg0.num_fields * sizeof(weval_req_field_t) + arg0.len;
+    arg.u.structure.padded_len = (inline_len + 7) & ~7;  // Align to 8-byte boundary.
+    if (!args.write(arg) || !args.write(arg0.num_fields)) {
+      return false;
+    }
+    for (uint32_t i = 0; i < arg0.num_fields; i++) {
+      if (!args.write(arg0.fields[i])) {
+        return false;
+      }
+    }
+    const uint8_t* src = reinterpret_cast<const uint8_t*>(arg0.ptr);
+    uint32_t rest_len = arg.u.structure.padded_len -
+                        sizeof(uint32_t) -
+                        arg0.num_fields * sizeof(weval_req_field_t);
+    uint8_t* dst = args.alloc(rest_len);
+    if (!dst) {
+      return false;
+    }
+    memcpy(dst, src, arg0.len);
+    // Ensure deterministic (zeroed) padding bytes.
+    memset(dst + arg0.len, 0, rest_len - arg0.len);
+    return StoreArgs<Rest...>()(args, rest...);
+  }
+};
+
 template <typename T, typename... Rest>
 struct StoreArgs<RuntimeArg<T>, Rest...> {
   bool operator()(ArgWriter& args, RuntimeArg<T> arg0, Rest... rest) {
@@ -12,12 +12,14 @@
        unreachable)
  (func (export "write.reg") (param i64 i64))
  (func (export "trace.line") (param i32))
+ (func (export "trace.here") (param i32))
  (func (export "abort.specialization") (param i32 i32))
  (func (export "assert.const32") (param i32 i32))
  (func (export "assert.const.memory") (param i32 i32))
  (func (export "specialize.value") (param i32 i32 i32) (result i32)
  local.get 0)
  (func (export "print") (param i32 i32 i32))
+ (func (export "print.value") (param i32 i32))
  (func (export "read.specialization.global") (param i32) (result i64) unreachable)
  (func (export "push.stack") (param i32 i64))
  (func (export "sync.stack"))
@@ -29,6 +31,15 @@
  (func (export "read.local") (param i32 i32) (result i64)
        unreachable)
  (func (export "write.local") (param i32 i32 i64))
+ (func (export "stack.declare") (param i32 i32 i32))
+ (func (export "region.epoch") (param i32 i32 i32))
+ (func (export "peel.loop"))
+ (func (export "env.u32") (param i32) (result i32)
+       i32.const 0)
+ (func (export "ic.site") (param i32 i32) (result i32)
+       i32.const 0)
+ (func (export "barrier.needed") (param i64 i64 i64) (result i32)
+       i32.const 1)
  (func (export "read.global.0") (result i64)
        global.get $g0)
  (func (export "write.global.0") (param i64)
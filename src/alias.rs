@@ -0,0 +1,193 @@
+//! May-alias queries over memory accesses.
+//!
+//! A memory access is described by a `MemLoc`: a base SSA value, a
+//! static offset, and a size. Passes that reason about memory
+//! (store-to-load forwarding, and anything else that needs to know
+//! whether a store may clobber some location) should ask this module
+//! rather than making their own aliasing assumptions.
+//!
+//! The precision of the answers is selectable:
+//!
+//! - `None`: every store may alias every access to the same memory.
+//! - `BaseOffset`: accesses off the same SSA base alias only if their
+//!   byte ranges overlap; accesses off different bases may alias.
+//! - `RegionTagged`: additionally, each base is tagged with the
+//!   region it points into, if known: an absolute static address
+//!   (from a constant) or the shadow stack (derived from the stack
+//!   pointer global). Static addresses are compared exactly, and the
+//!   shadow stack is assumed never to overlap static data.
+
+use crate::collections::HashMap;
+use waffle::{FunctionBody, Global, Memory, MemoryArg, Operator, Value, ValueDef};
+
+/// How precise alias queries are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AliasPrecision {
+    None,
+    #[default]
+    BaseOffset,
+    RegionTagged,
+}
+
+impl std::str::FromStr for AliasPrecision {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "none" => Ok(AliasPrecision::None),
+            "base-offset" => Ok(AliasPrecision::BaseOffset),
+            "region" => Ok(AliasPrecision::RegionTagged),
+            _ => anyhow::bail!("Unknown alias precision: {}", s),
+        }
+    }
+}
+
+/// A memory location accessed by a load or store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MemLoc {
+    pub memory: Memory,
+    pub base: Value,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl MemLoc {
+    pub fn new(func: &FunctionBody, memarg: MemoryArg, addr: Value, size: u32) -> MemLoc {
+        MemLoc {
+            memory: memarg.memory,
+            base: func.resolve_alias(addr),
+            offset: memarg.offset,
+            size,
+        }
+    }
+
+    fn range(&self) -> (u64, u64) {
+        let start = self.offset as u64;
+        (start, start + self.size as u64)
+    }
+}
+
+fn overlap((a_start, a_end): (u64, u64), (b_start, b_end): (u64, u64)) -> bool {
+    a_start < b_end && b_start < a_end
+}
+
+/// The region a base address points into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Region {
+    /// An absolute address in static memory.
+    Static(u32),
+    /// Somewhere in the shadow stack.
+    Stack,
+    Unknown,
+}
+
+/// Bound on the length of add/sub chains we follow to find a region.
+const MAX_REGION_DEPTH: usize = 16;
+
+pub struct AliasAnalysis {
+    precision: AliasPrecision,
+    regions: HashMap<Value, Region>,
+}
+
+impl AliasAnalysis {
+    pub fn new(
+        func: &FunctionBody,
+        precision: AliasPrecision,
+        stack_pointer: Option<Global>,
+    ) -> AliasAnalysis {
+        let mut regions = HashMap::default();
+        if precision == AliasPrecision::RegionTagged {
+            for (value, _) in func.values.entries() {
+                let region = region_of(func, stack_pointer, value, 0);
+                if region != Region::Unknown {
+                    regions.insert(value, region);
+                }
+            }
+        }
+        AliasAnalysis { precision, regions }
+    }
+
+    pub fn precision(&self) -> AliasPrecision {
+        self.precision
+    }
+
+    fn region(&self, base: Value) -> Region {
+        self.regions.get(&base).copied().unwrap_or(Region::Unknown)
+    }
+
+    /// Whether the two accesses definitely touch exactly the same
+    /// bytes.
+    pub fn must_alias(&self, a: &MemLoc, b: &MemLoc) -> bool {
+        a == b
+    }
+
+    /// Whether the two accesses may touch any common byte.
+    pub fn may_alias(&self, a: &MemLoc, b: &MemLoc) -> bool {
+        if a.memory != b.memory {
+            return false;
+        }
+        match self.precision {
+            AliasPrecision::None => true,
+            AliasPrecision::BaseOffset => a.base != b.base || overlap(a.range(), b.range()),
+            AliasPrecision::RegionTagged => {
+                if a.base == b.base {
+                    return overlap(a.range(), b.range());
+                }
+                match (self.region(a.base), self.region(b.base)) {
+                    (Region::Static(x), Region::Static(y)) => {
+                        let (a_start, a_end) = a.range();
+                        let (b_start, b_end) = b.range();
+                        overlap(
+                            (a_start + x as u64, a_end + x as u64),
+                            (b_start + y as u64, b_end + y as u64),
+                        )
+                    }
+                    (Region::Static(_), Region::Stack) | (Region::Stack, Region::Static(_)) => {
+                        false
+                    }
+                    _ => true,
+                }
+            }
+        }
+    }
+}
+
+fn region_of(
+    func: &FunctionBody,
+    stack_pointer: Option<Global>,
+    value: Value,
+    depth: usize,
+) -> Region {
+    if depth > MAX_REGION_DEPTH {
+        return Region::Unknown;
+    }
+    match &func.values[value] {
+        &ValueDef::Alias(orig) => region_of(func, stack_pointer, orig, depth + 1),
+        &ValueDef::Operator(Operator::I32Const { value }, _, _) => Region::Static(value),
+        &ValueDef::Operator(Operator::GlobalGet { global_index }, _, _)
+            if Some(global_index) == stack_pointer =>
+        {
+            Region::Stack
+        }
+        &ValueDef::Operator(op @ (Operator::I32Add | Operator::I32Sub), args, _) => {
+            let args = &func.arg_pool[args];
+            let konst = |v: Value| match &func.values[func.resolve_alias(v)] {
+                &ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(value),
+                _ => None,
+            };
+            let (base, k) = match (op, konst(args[0]), konst(args[1])) {
+                (Operator::I32Add, Some(k), None) => (args[1], k),
+                (_, None, Some(k)) => (args[0], k),
+                _ => return Region::Unknown,
+            };
+            match region_of(func, stack_pointer, base, depth + 1) {
+                Region::Static(addr) if matches!(op, Operator::I32Add) => {
+                    Region::Static(addr.wrapping_add(k))
+                }
+                Region::Static(addr) => Region::Static(addr.wrapping_sub(k)),
+                Region::Stack => Region::Stack,
+                Region::Unknown => Region::Unknown,
+            }
+        }
+        _ => Region::Unknown,
+    }
+}
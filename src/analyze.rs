@@ -0,0 +1,227 @@
+//! Analysis-only mode: report where specialization loses precision.
+//!
+//! `weval analyze` runs the abstract interpreter over every directive
+//! without emitting any code, and reports the points at which it
+//! could not prove a value known at specialization time: the first
+//! branch per context whose condition is only known at runtime, and
+//! every load from a known address whose contents could not be
+//! proven constant. These are the places where an interpreter author
+//! may want to add intrinsics (e.g. `weval_specialize_value` or
+//! `weval_read_reg`).
+
+use crate::directive::DirectiveId;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use waffle::{Module, SourceLoc};
+
+/// The kind of a precision-loss point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PrecisionLossKind {
+    /// A conditional branch with a runtime condition.
+    Branch,
+    /// A `br_table` with a runtime selector.
+    Select,
+    /// A load from a known address whose contents are not provably
+    /// constant.
+    Load,
+}
+
+impl std::fmt::Display for PrecisionLossKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrecisionLossKind::Branch => write!(f, "branch on runtime condition"),
+            PrecisionLossKind::Select => write!(f, "br_table on runtime selector"),
+            PrecisionLossKind::Load => write!(f, "load from unproven memory"),
+        }
+    }
+}
+
+/// Why a load from a known address was not folded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LoadLossReason {
+    /// The address is a plain constant, not a pointer into memory
+    /// known to be constant (e.g., via `weval_assume_const_memory` or
+    /// a constant-memory argument).
+    NotConstTagged,
+    /// The address is in a struct argument, in a field not marked
+    /// constant.
+    MutableField,
+    /// The access is outside the constant buffer or the memory
+    /// image.
+    OutOfBounds,
+    /// The evaluator does not fold loads of this type or width from
+    /// this kind of address.
+    UnsupportedLoad,
+    /// The address is in a shared memory, which other threads may
+    /// write.
+    SharedMemory,
+}
+
+impl std::fmt::Display for LoadLossReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadLossReason::NotConstTagged => write!(f, "address not tagged as constant memory"),
+            LoadLossReason::MutableField => write!(f, "field not marked constant"),
+            LoadLossReason::OutOfBounds => write!(f, "read out of bounds"),
+            LoadLossReason::UnsupportedLoad => write!(f, "load type not folded"),
+            LoadLossReason::SharedMemory => write!(f, "address in shared memory"),
+        }
+    }
+}
+
+/// A load that was not folded, though its address was known.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UnfoldedLoad {
+    /// The block in the generic function.
+    pub block: usize,
+    /// The load in the generic function.
+    pub value: usize,
+    /// Source location (`file:line:col`), if debug info is present.
+    pub loc: Option<String>,
+    pub reason: LoadLossReason,
+    /// The number of contexts in which the load was not folded for
+    /// this reason.
+    pub contexts: usize,
+}
+
+/// Unfolded loads for one directive, for `--explain-loads`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoadReport {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// The directive's argument bytestring.
+    pub args: Vec<u8>,
+    /// Index of the generic function.
+    pub func: usize,
+    /// Name of the generic function.
+    pub func_name: String,
+    pub loads: Vec<UnfoldedLoad>,
+}
+
+/// Produces a human-readable report of unfolded loads.
+pub fn load_report(reports: &[LoadReport]) -> String {
+    let mut s = String::new();
+    for report in reports {
+        writeln!(
+            &mut s,
+            "Directive {} (site {}, {} arg bytes) on function {} ({}): {} unfolded loads",
+            report.id,
+            report.user_id,
+            report.args.len(),
+            report.func,
+            report.func_name,
+            report.loads.len()
+        )
+        .unwrap();
+        for load in &report.loads {
+            writeln!(
+                &mut s,
+                "  load v{} at block{} ({}): {}, in {} context(s)",
+                load.value,
+                load.block,
+                load.loc.as_deref().unwrap_or("unknown location"),
+                load.reason,
+                load.contexts
+            )
+            .unwrap();
+        }
+    }
+    s
+}
+
+/// One point at which precision was lost.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PrecisionLoss {
+    pub kind: PrecisionLossKind,
+    /// The context stack in which precision was lost, outermost
+    /// first.
+    pub context: Vec<String>,
+    /// The block in the generic function.
+    pub block: usize,
+    /// The value in the generic function: the branch condition or the
+    /// load.
+    pub value: usize,
+    /// Source location (`file:line:col`), if debug info is present.
+    pub loc: Option<String>,
+    /// The label most recently given by `weval.trace.here` in the
+    /// context, if any.
+    pub label: Option<String>,
+    /// The imported globals (`<module>.<name>`) with no assumed value
+    /// that the value derives from, if any.
+    pub blocked_on: Vec<String>,
+}
+
+/// Analysis results for one directive.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirectiveAnalysis {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// The directive's argument bytestring.
+    pub args: Vec<u8>,
+    /// Index of the generic function.
+    pub func: usize,
+    /// Name of the generic function.
+    pub func_name: String,
+    /// Whether the evaluator completed (i.e., did not hit a limit).
+    pub completed: bool,
+    pub losses: Vec<PrecisionLoss>,
+}
+
+/// Describes a source location in the module's debug info, if any.
+pub fn source_loc_desc(module: &Module, loc: SourceLoc) -> Option<String> {
+    if loc.is_invalid() {
+        return None;
+    }
+    let data = &module.debug.source_locs[loc];
+    let file = &module.debug.source_files[data.file];
+    Some(format!("{}:{}:{}", file, data.line, data.col))
+}
+
+/// Produces a human-readable report.
+pub fn report(analyses: &[DirectiveAnalysis]) -> String {
+    let mut s = String::new();
+    for analysis in analyses {
+        writeln!(
+            &mut s,
+            "Directive {} (site {}, {} arg bytes) on function {} ({}){}: {} precision losses",
+            analysis.id,
+            analysis.user_id,
+            analysis.args.len(),
+            analysis.func,
+            analysis.func_name,
+            if analysis.completed {
+                ""
+            } else {
+                " [incomplete]"
+            },
+            analysis.losses.len()
+        )
+        .unwrap();
+        for loss in &analysis.losses {
+            writeln!(
+                &mut s,
+                "  {} at block{} v{} ({}) in context [{}]{}{}",
+                loss.kind,
+                loss.block,
+                loss.value,
+                loss.loc.as_deref().unwrap_or("unknown location"),
+                loss.context.join(", "),
+                match &loss.label {
+                    Some(label) => format!(" at \"{}\"", label),
+                    None => String::new(),
+                },
+                match &loss.blocked_on[..] {
+                    [] => String::new(),
+                    [global] => format!(", blocked on imported global {}", global),
+                    globals => format!(", blocked on imported globals {}", globals.join(", ")),
+                }
+            )
+            .unwrap();
+        }
+    }
+    s
+}
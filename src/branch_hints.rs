@@ -0,0 +1,243 @@
+//! Branch hints: the `metadata.code.branch_hint` custom section.
+//!
+//! Toolchains (e.g. Binaryen, or LLVM for `__builtin_expect`) may
+//! record, for an `if` or `br_if`, whether it is likely taken; engines
+//! use this to lay out the likely path. The section keys each hint by
+//! the byte offset of the instruction in its function body, which
+//! every rewrite of the body invalidates, so in between we key hints
+//! by the branch's ordinal among the body's `if`s and `br_if`s:
+//!
+//! - On input, each hinted function's branches are numbered in
+//!   bytecode order, and matched to the conditional branches of the
+//!   frontend's IR (see `hinted_targets`).
+//! - Each conditional branch the evaluator residualizes takes the
+//!   hint of the generic branch it came from, in every context.
+//! - The branches of a compiled specialized function are matched back
+//!   to its IR blocks (see `compiled_hints`).
+//! - The final filter pass turns ordinals into offsets in the output
+//!   bodies, and emits the section.
+
+use crate::collections::HashMap;
+use std::collections::BTreeMap;
+use waffle::{
+    entity::EntityRef, pool::ListRef, Block, FunctionBody, Operator, Terminator, Type, ValueDef,
+};
+use wasmparser::{BinaryReader, Parser, Payload, TypeRef};
+
+/// Name of the custom section.
+pub const SECTION_NAME: &str = "metadata.code.branch_hint";
+
+/// The branch hints of one function body.
+#[derive(Clone, Debug, Default)]
+pub struct FuncHints {
+    /// Number of `if`s and `br_if`s in the body.
+    pub branches: usize,
+    /// Whether each hinted branch is likely taken, by its ordinal
+    /// among the body's `if`s and `br_if`s.
+    pub hints: BTreeMap<usize, bool>,
+}
+
+pub fn is_branch(op: &wasmparser::Operator) -> bool {
+    matches!(
+        op,
+        wasmparser::Operator::If { .. } | wasmparser::Operator::BrIf { .. }
+    )
+}
+
+/// Read the branch hints of a module, keyed by function index.
+pub fn read(module: &[u8]) -> anyhow::Result<BTreeMap<u32, FuncHints>> {
+    // The section precedes the code section, so first collect the
+    // hinted offsets per function.
+    let parser = Parser::new(0);
+    let mut offsets: BTreeMap<u32, BTreeMap<usize, bool>> = BTreeMap::new();
+    for payload in parser.clone().parse_all(module) {
+        match payload? {
+            Payload::CustomSection(reader) if reader.name() == SECTION_NAME => {
+                let mut reader = BinaryReader::new(reader.data());
+                for _ in 0..reader.read_var_u32()? {
+                    let func = reader.read_var_u32()?;
+                    let func_offsets = offsets.entry(func).or_default();
+                    for _ in 0..reader.read_var_u32()? {
+                        let offset = reader.read_var_u32()? as usize;
+                        let size = reader.read_var_u32()?;
+                        if size != 1 {
+                            anyhow::bail!("Branch hint in function {} has size {}", func, size);
+                        }
+                        func_offsets.insert(offset, reader.read_u8()? != 0);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if offsets.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    // Then number the branches of each hinted function.
+    let mut result = BTreeMap::new();
+    let mut func = 0;
+    for payload in parser.parse_all(module) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    if let TypeRef::Func(_) = import?.ty {
+                        func += 1;
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(code) => {
+                let this_func = func;
+                func += 1;
+                let func_offsets = match offsets.get(&this_func) {
+                    Some(func_offsets) => func_offsets,
+                    None => continue,
+                };
+                let start = code.range().start;
+                let mut hints = FuncHints::default();
+                for entry in code.get_operators_reader()?.into_iter_with_offsets() {
+                    let (op, offset) = entry?;
+                    if is_branch(&op) {
+                        if let Some(&likely) = func_offsets.get(&(offset - start)) {
+                            hints.hints.insert(hints.branches, likely);
+                        }
+                        hints.branches += 1;
+                    }
+                }
+                result.insert(this_func, hints);
+            }
+            _ => {}
+        }
+    }
+    log::info!("Read branch hints for {} functions", result.len());
+    Ok(result)
+}
+
+/// The newer target of a conditional branch, which identifies it; see
+/// `hinted_targets`.
+pub fn branch_key(term: &Terminator) -> Option<Block> {
+    match term {
+        Terminator::CondBr {
+            if_true, if_false, ..
+        } => Some(std::cmp::max(if_true.block, if_false.block)),
+        _ => None,
+    }
+}
+
+/// Map the hints of a function onto the conditional branches of its
+/// body as translated by the frontend, keyed by `branch_key`.
+///
+/// The frontend translates each `if` and `br_if` in reachable code to
+/// a conditional branch, at least one of whose targets (the arms, or
+/// the fallthrough) it creates at that point, so ordering the branches
+/// by their newer target recovers the bytecode order. Unlike the
+/// branch's own block, the key survives splitting blocks.
+pub fn hinted_targets(body: &FunctionBody, hints: &FuncHints) -> HashMap<Block, bool> {
+    let mut keys = body
+        .blocks
+        .values()
+        .filter_map(|def| branch_key(&def.terminator))
+        .collect::<Vec<_>>();
+    if keys.len() != hints.branches {
+        // E.g., branches in unreachable code, which the frontend
+        // skips.
+        log::info!(
+            "{} conditional branches for {} `if`s and `br_if`s; ignoring branch hints",
+            keys.len(),
+            hints.branches
+        );
+        return HashMap::default();
+    }
+    keys.sort();
+    hints
+        .hints
+        .iter()
+        .map(|(&ordinal, &likely)| (keys[ordinal], likely))
+        .collect()
+}
+
+/// For each `if` or `br_if` in a compiled body, in order, the value of
+/// the `i32.const` immediately preceding it, if any.
+fn compiled_branches(func: &wasm_encoder::Function) -> anyhow::Result<Vec<Option<u32>>> {
+    use wasm_encoder::Encode;
+    let mut bytes = vec![];
+    func.encode(&mut bytes);
+    let mut reader = BinaryReader::new(&bytes[..]);
+    let _size = reader.read_var_u32()?;
+    for _ in 0..reader.read_var_u32()? {
+        reader.read_var_u32()?;
+        reader.read::<wasmparser::ValType>()?;
+    }
+    let mut branches = vec![];
+    let mut last_const = None;
+    while !reader.eof() {
+        let op = reader.read_operator()?;
+        if is_branch(&op) {
+            branches.push(last_const);
+        }
+        last_const = match op {
+            wasmparser::Operator::I32Const { value } => Some(value as u32),
+            _ => None,
+        };
+    }
+    Ok(branches)
+}
+
+/// Map hints on the conditional branches of a function body, keyed by
+/// block, onto the branches of its compiled form.
+///
+/// The backend's structuring depends only on the CFG, so we compile a
+/// copy in which each conditional branch's condition is a constant
+/// naming its block, and read the blocks back off the branches of the
+/// copy. Branches whose block cannot be read back get no hint.
+pub fn compiled_hints(
+    body: &FunctionBody,
+    compiled: &wasm_encoder::Function,
+    hinted: &HashMap<Block, bool>,
+) -> anyhow::Result<Option<FuncHints>> {
+    if hinted.is_empty() {
+        return Ok(None);
+    }
+    let mut marked = body.clone();
+    let i32_ty = marked.single_type_list(Type::I32);
+    for (block, def) in body.blocks.entries() {
+        if branch_key(&def.terminator).is_none() {
+            continue;
+        }
+        let marker = marked.add_value(ValueDef::Operator(
+            Operator::I32Const {
+                value: block.index() as u32,
+            },
+            ListRef::default(),
+            i32_ty,
+        ));
+        marked.append_to_block(block, marker);
+        if let Terminator::CondBr { cond, .. } = &mut marked.blocks[block].terminator {
+            *cond = marker;
+        }
+    }
+    let markers = compiled_branches(&marked.compile()?)?;
+    let branches = compiled_branches(compiled)?.len();
+    if markers.len() != branches {
+        log::debug!(
+            "marked body has {} branches, compiled body {}; dropping branch hints",
+            markers.len(),
+            branches
+        );
+        return Ok(None);
+    }
+
+    let hints = markers
+        .iter()
+        .enumerate()
+        .filter_map(|(ordinal, marker)| {
+            let block = Block::new((*marker)? as usize);
+            let likely = *hinted.get(&block)?;
+            Some((ordinal, likely))
+        })
+        .collect::<BTreeMap<_, _>>();
+    if hints.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(FuncHints { branches, hints }))
+}
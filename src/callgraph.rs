@@ -0,0 +1,193 @@
+//! Module-level call-graph queries.
+
+use std::collections::{BTreeMap, BTreeSet};
+use waffle::{ExportKind, Func, FunctionBody, Module, Operator, ValueDef};
+
+/// Visit every function referenced directly from a function body:
+/// as a `call` or `return_call` target, or via `ref.func`.
+pub fn visit_func_refs<F: FnMut(Func)>(body: &FunctionBody, mut f: F) {
+    for def in body.values.values() {
+        if let ValueDef::Operator(op, _, _) = def {
+            match op {
+                Operator::Call { function_index } | Operator::ReturnCall { function_index } => {
+                    f(*function_index)
+                }
+                Operator::RefFunc { func_index } => f(*func_index),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Find any remaining reference to `func` in the module, other than
+/// from its own body, and describe it. Function bodies that are
+/// already compiled cannot be scanned, so their references must be
+/// given in `compiled_refs`.
+pub fn find_reference(
+    module: &Module,
+    func: Func,
+    compiled_refs: &[(Func, Func)],
+) -> anyhow::Result<Option<String>> {
+    if module.start_func == Some(func) {
+        return Ok(Some("start function".to_owned()));
+    }
+    for export in &module.exports {
+        if let ExportKind::Func(f) = export.kind {
+            if f == func {
+                return Ok(Some(format!("export \"{}\"", export.name)));
+            }
+        }
+    }
+    for (table, data) in module.tables.entries() {
+        if let Some(elems) = &data.func_elements {
+            if elems.contains(&func) {
+                return Ok(Some(format!("table {}", table)));
+            }
+        }
+    }
+    for &(from, to) in compiled_refs {
+        if to == func && from != func {
+            return Ok(Some(format!("call from {}", from)));
+        }
+    }
+    for (from, decl) in module.funcs.entries() {
+        if from == func {
+            continue;
+        }
+        let mut decl = decl.clone();
+        decl.parse(module)?;
+        let body = match decl.body() {
+            Some(body) => body,
+            None => continue,
+        };
+        let mut found = false;
+        visit_func_refs(body, |f| found |= f == func);
+        if found {
+            return Ok(Some(format!("call from {}", from)));
+        }
+    }
+    Ok(None)
+}
+
+/// Find any effect of running `root` (and everything it transitively
+/// calls) that is not visible in a memory snapshot taken afterward:
+/// writes to globals or tables, or calls we cannot resolve. Calls to
+/// imports are allowed. Returns a description of the first such
+/// effect found.
+pub fn find_unrecordable_effect(module: &Module, root: Func) -> anyhow::Result<Option<String>> {
+    let mut visited = BTreeSet::new();
+    let mut queue = vec![root];
+    while let Some(func) = queue.pop() {
+        if !visited.insert(func) {
+            continue;
+        }
+        let mut decl = module.funcs[func].clone();
+        decl.parse(module)?;
+        let body = match decl.body() {
+            Some(body) => body,
+            None => continue,
+        };
+        for def in body.values.values() {
+            if let ValueDef::Operator(op, _, _) = def {
+                match op {
+                    Operator::GlobalSet { global_index } => {
+                        return Ok(Some(format!("{} writes {}", func, global_index)));
+                    }
+                    Operator::TableSet { .. } | Operator::TableGrow { .. } => {
+                        return Ok(Some(format!("{} modifies a table", func)));
+                    }
+                    Operator::CallIndirect { .. } | Operator::ReturnCallIndirect { .. } => {
+                        return Ok(Some(format!("{} makes an indirect call", func)));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        visit_func_refs(body, |f| queue.push(f));
+    }
+    Ok(None)
+}
+
+/// Order the given functions callees-first: each function comes after
+/// every function it calls directly, except within a cycle of mutual
+/// calls, whose members come together in index order. Returns each
+/// function's position in that order. Calls to functions not in
+/// `bodies` are ignored.
+pub fn callee_first_order<'a>(
+    bodies: impl Iterator<Item = (Func, &'a FunctionBody)>,
+) -> BTreeMap<Func, usize> {
+    let bodies = bodies.collect::<BTreeMap<_, _>>();
+    let edges = bodies
+        .iter()
+        .map(|(&func, body)| {
+            let mut callees = BTreeSet::new();
+            visit_func_refs(body, |callee| {
+                if callee != func && bodies.contains_key(&callee) {
+                    callees.insert(callee);
+                }
+            });
+            (func, callees)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    // Tarjan's algorithm, which finds strongly connected components
+    // in reverse topological order: callees first.
+    struct Tarjan<'a> {
+        edges: &'a BTreeMap<Func, BTreeSet<Func>>,
+        index: BTreeMap<Func, usize>,
+        lowlink: BTreeMap<Func, usize>,
+        stack: Vec<Func>,
+        on_stack: BTreeSet<Func>,
+        order: BTreeMap<Func, usize>,
+    }
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, func: Func) {
+            let index = self.index.len();
+            self.index.insert(func, index);
+            self.lowlink.insert(func, index);
+            self.stack.push(func);
+            self.on_stack.insert(func);
+            for &callee in &self.edges[&func] {
+                if !self.index.contains_key(&callee) {
+                    self.visit(callee);
+                    let low = std::cmp::min(self.lowlink[&func], self.lowlink[&callee]);
+                    self.lowlink.insert(func, low);
+                } else if self.on_stack.contains(&callee) {
+                    let low = std::cmp::min(self.lowlink[&func], self.index[&callee]);
+                    self.lowlink.insert(func, low);
+                }
+            }
+            if self.lowlink[&func] == self.index[&func] {
+                let mut component = vec![];
+                loop {
+                    let member = self.stack.pop().unwrap();
+                    self.on_stack.remove(&member);
+                    component.push(member);
+                    if member == func {
+                        break;
+                    }
+                }
+                component.sort();
+                for member in component {
+                    let position = self.order.len();
+                    self.order.insert(member, position);
+                }
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        edges: &edges,
+        index: BTreeMap::new(),
+        lowlink: BTreeMap::new(),
+        stack: vec![],
+        on_stack: BTreeSet::new(),
+        order: BTreeMap::new(),
+    };
+    for &func in edges.keys() {
+        if !tarjan.index.contains_key(&func) {
+            tarjan.visit(func);
+        }
+    }
+    tarjan.order
+}
@@ -0,0 +1,28 @@
+//! Hash map and set types used throughout the crate.
+//!
+//! All hashing goes through these aliases so that the hasher is
+//! chosen in one place. The default is FxHash: it is fast on the
+//! small keys (entities, contexts) that we mostly hash, and unlike
+//! std's randomly seeded hasher it is deterministic, so that a run is
+//! reproducible even where a map's iteration order leaks into the
+//! evaluator's work order. With the `ahash` feature, aHash (with its
+//! fixed default keys, so still deterministic) is used instead, which
+//! is faster on larger keys such as operators and states.
+//!
+//! Iteration order still depends on the hasher, so output must not:
+//! where a map's order matters, use `IndexMap` or `IndexSet`, which
+//! iterate in insertion order.
+
+use std::hash::BuildHasherDefault;
+
+#[cfg(not(feature = "ahash"))]
+pub type Hasher = fxhash::FxHasher;
+#[cfg(feature = "ahash")]
+pub type Hasher = ahash::AHasher;
+
+pub type BuildHasher = BuildHasherDefault<Hasher>;
+
+pub type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasher>;
+pub type HashSet<T> = std::collections::HashSet<T, BuildHasher>;
+pub type IndexMap<K, V> = indexmap::IndexMap<K, V, BuildHasher>;
+pub type IndexSet<T> = indexmap::IndexSet<T, BuildHasher>;
@@ -0,0 +1,304 @@
+//! Per-function constant pooling.
+//!
+//! Each specialized context emits its own constants, so the same
+//! `i32.const K` may appear in thousands of blocks. This pass defines
+//! each constant that appears more than once, and whose encoding is
+//! larger than a `local.get`, once in the entry block (which
+//! dominates every use), and makes the other definitions aliases of
+//! it.
+//!
+//! Across functions, optionally, each constant still defined in many
+//! specialized functions becomes an immutable global, read with
+//! `global.get` (see `ConstGlobals::globalize`).
+
+use crate::collections::{HashMap, HashSet, IndexMap};
+use waffle::{
+    pool::ListRef, FunctionBody, Global, GlobalData, Module, Operator, Type, Value, ValueDef,
+};
+
+/// Size in bytes of the signed LEB128 encoding of `value`.
+fn sleb_size(mut value: i64) -> usize {
+    let mut size = 1;
+    loop {
+        let byte = value & 0x7f;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            return size;
+        }
+        size += 1;
+    }
+}
+
+/// Size in bytes of the constant's encoding, including the opcode, if
+/// it is a constant.
+fn const_size(op: &Operator) -> Option<usize> {
+    match op {
+        Operator::I32Const { value } => Some(1 + sleb_size(*value as i32 as i64)),
+        Operator::I64Const { value } => Some(1 + sleb_size(*value as i64)),
+        Operator::F32Const { .. } => Some(5),
+        Operator::F64Const { .. } => Some(9),
+        Operator::V128Const { .. } => Some(18),
+        _ => None,
+    }
+}
+
+/// Size of a `local.get` of a local with a one-byte index.
+const LOCAL_GET_SIZE: usize = 2;
+
+/// Size in bytes of the unsigned LEB128 encoding of `value`.
+fn uleb_size(mut value: u64) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+/// The type and bits of a constant that can initialize a global,
+/// with a rank for ordering.
+fn global_init(op: &Operator) -> Option<(u8, Type, u64)> {
+    match *op {
+        Operator::I32Const { value } => Some((0, Type::I32, u64::from(value))),
+        Operator::I64Const { value } => Some((1, Type::I64, value)),
+        Operator::F32Const { value } => Some((2, Type::F32, u64::from(value))),
+        Operator::F64Const { value } => Some((3, Type::F64, value)),
+        _ => None,
+    }
+}
+
+pub fn run(func: &mut FunctionBody) {
+    // Find all definitions of each large-enough constant, in order
+    // of first definition.
+    let mut defs: IndexMap<Operator, Vec<Value>> = IndexMap::default();
+    for (_, block) in func.blocks.entries() {
+        for &inst in &block.insts {
+            if let ValueDef::Operator(op, _, _) = &func.values[inst] {
+                if const_size(op).map_or(false, |size| size > LOCAL_GET_SIZE) {
+                    defs.entry(*op).or_default().push(inst);
+                }
+            }
+        }
+    }
+
+    let mut hoisted = vec![];
+    let mut removed = HashSet::default();
+    for (_, insts) in defs {
+        if insts.len() < 2 {
+            continue;
+        }
+        let (&first, rest) = insts.split_first().unwrap();
+        hoisted.push(first);
+        removed.insert(first);
+        for &inst in rest {
+            func.values[inst] = ValueDef::Alias(first);
+            removed.insert(inst);
+        }
+    }
+    if hoisted.is_empty() {
+        return;
+    }
+    hoisted.sort();
+    let pooled = removed.len() - hoisted.len();
+
+    // Remove the hoisted definitions and the aliased duplicates from
+    // their blocks, and define the former at the top of the entry.
+    for block in func.blocks.iter().collect::<Vec<_>>() {
+        func.blocks[block]
+            .insts
+            .retain(|inst| !removed.contains(inst));
+    }
+    let entry = func.entry;
+    let mut insts = hoisted;
+    insts.append(&mut func.blocks[entry].insts);
+    func.blocks[entry].insts = insts;
+
+    waffle::passes::resolve_aliases::run(func);
+    log::debug!("const_pool: pooled {} constants", pooled);
+}
+
+/// Constants moved into immutable globals across specialized
+/// functions, which are compiled a batch at a time (see `globalize`).
+#[derive(Default)]
+pub struct ConstGlobals {
+    /// The number of bodies defining each constant, over all batches
+    /// so far.
+    counts: HashMap<Operator, usize>,
+    /// The global holding each constant defined in enough of them.
+    globals: HashMap<Operator, Global>,
+}
+
+impl ConstGlobals {
+    /// Replace each constant defined in at least `min_defs` of the
+    /// bodies seen so far (in this batch or an earlier one), and whose
+    /// encoding is larger than a `global.get` of a new global, with a
+    /// read of an immutable global holding it. Bodies of earlier
+    /// batches are already compiled, so keep their definitions.
+    pub fn globalize<'a>(
+        &mut self,
+        module: &mut Module,
+        bodies: impl Iterator<Item = &'a mut FunctionBody>,
+        min_defs: usize,
+    ) {
+        let mut bodies = bodies.collect::<Vec<_>>();
+
+        // Count the bodies defining each constant (after pooling,
+        // each defines it at most once).
+        let mut reached = vec![];
+        for body in &bodies {
+            let mut seen = HashSet::default();
+            for (_, block) in body.blocks.entries() {
+                for &inst in &block.insts {
+                    if let ValueDef::Operator(op, _, _) = &body.values[inst] {
+                        if global_init(op).is_some() && seen.insert(*op) {
+                            let count = self.counts.entry(*op).or_default();
+                            *count += 1;
+                            if *count == min_defs {
+                                reached.push(*op);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        reached.sort_by_key(|op| {
+            let (rank, _, bits) = global_init(op).unwrap();
+            (rank, bits)
+        });
+
+        let mut added = 0;
+        for op in reached {
+            let global_get_size = 1 + uleb_size(module.globals.len() as u64);
+            if const_size(&op).unwrap() <= global_get_size {
+                continue;
+            }
+            let (_, ty, bits) = global_init(&op).unwrap();
+            let global = module.globals.push(GlobalData {
+                ty,
+                value: Some(bits),
+                mutable: false,
+            });
+            self.globals.insert(op, global);
+            added += 1;
+        }
+        if self.globals.is_empty() {
+            return;
+        }
+
+        let mut replaced = 0;
+        for body in &mut bodies {
+            for block in body.blocks.iter().collect::<Vec<_>>() {
+                for i in 0..body.blocks[block].insts.len() {
+                    let inst = body.blocks[block].insts[i];
+                    if let ValueDef::Operator(op, _, tys) = body.values[inst] {
+                        if let Some(&global_index) = self.globals.get(&op) {
+                            body.values[inst] = ValueDef::Operator(
+                                Operator::GlobalGet { global_index },
+                                ListRef::default(),
+                                tys,
+                            );
+                            replaced += 1;
+                        }
+                    }
+                }
+            }
+        }
+        log::info!(
+            "const_pool: materialized {} more constants as globals ({} in all), replacing {} definitions",
+            added,
+            self.globals.len(),
+            replaced
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+    use waffle::{Block, FrontendOptions, Func};
+
+    #[test]
+    fn leb_sizes() {
+        assert_eq!(sleb_size(0), 1);
+        assert_eq!(sleb_size(63), 1);
+        assert_eq!(sleb_size(64), 2);
+        assert_eq!(sleb_size(-64), 1);
+        assert_eq!(sleb_size(-65), 2);
+        assert_eq!(uleb_size(127), 1);
+        assert_eq!(uleb_size(128), 2);
+    }
+
+    #[test]
+    fn repeated_constants_pool_in_entry() {
+        let bytes = wat::parse_str(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32)
+                (if (local.get 0)
+                  (then
+                    (i32.store (i32.const 1) (i32.const 1000)))
+                  (else
+                    (i32.store (i32.const 1) (i32.const 1000))))))
+            "#,
+        )
+        .unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        let mut func = module.clone_and_expand_body(Func::new(0)).unwrap();
+        run(&mut func);
+
+        let defs_of = |value: u32| {
+            func.blocks
+                .entries()
+                .flat_map(|(block, def)| def.insts.iter().map(move |&inst| (block, inst)))
+                .filter(|&(_, inst)| {
+                    matches!(
+                        func.values[inst],
+                        ValueDef::Operator(Operator::I32Const { value: v }, ..) if v == value
+                    )
+                })
+                .map(|(block, _)| block)
+                .collect::<Vec<Block>>()
+        };
+        // The large constant is defined once, in the entry; the small
+        // one is no bigger than a `local.get`, so stays put.
+        assert_eq!(defs_of(1000), vec![func.entry]);
+        assert_eq!(defs_of(1).len(), 2);
+        assert!(!defs_of(1).contains(&func.entry));
+    }
+
+    #[test]
+    fn globals_count_over_batches() {
+        let bytes = wat::parse_str(
+            r#"
+            (module
+              (func (result i64) (i64.const 123456789))
+              (func (result i64) (i64.const 123456789))
+              (func (result i64) (i64.const 123456789)))
+            "#,
+        )
+        .unwrap();
+        let mut module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        let mut bodies = (0..3)
+            .map(|i| module.clone_and_expand_body(Func::new(i)).unwrap())
+            .collect::<Vec<_>>();
+        let reads_global = |body: &FunctionBody| {
+            body.values
+                .values()
+                .any(|def| matches!(def, ValueDef::Operator(Operator::GlobalGet { .. }, ..)))
+        };
+
+        // One body per batch: the constant reaches two definitions in
+        // the second batch, and from then on is read from a global.
+        let mut globals = ConstGlobals::default();
+        for body in &mut bodies {
+            globals.globalize(&mut module, std::iter::once(body), 2);
+        }
+        assert_eq!(
+            bodies.iter().map(reads_global).collect::<Vec<_>>(),
+            vec![false, true, true]
+        );
+        assert_eq!(module.globals.len(), 1);
+    }
+}
@@ -2,7 +2,7 @@
 //! of one base, to minimize live value / register pressure. Also push
 //! these offsets into loads/stores where possible.
 
-use fxhash::{FxHashMap, FxHashSet};
+use crate::collections::{HashMap, HashSet};
 use std::collections::{BTreeMap, VecDeque};
 use waffle::{
     cfg::CFGInfo, entity::PerEntity, pool::ListRef, Block, FunctionBody, MemoryArg, Operator,
@@ -62,7 +62,7 @@ fn is_load_or_store(op: &Operator) -> bool {
     }
 }
 
-fn update_load_or_store_memarg<F: Fn(&mut MemoryArg)>(op: &mut Operator, f: F) {
+fn update_load_or_store_memarg<F: FnOnce(&mut MemoryArg)>(op: &mut Operator, f: F) {
     match op {
         Operator::I32Load { memory }
         | Operator::I32Load8S { memory }
@@ -98,8 +98,8 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
     let mut values: PerEntity<Value, AbsValue> = PerEntity::default();
 
     let mut workqueue: VecDeque<Block> = VecDeque::new();
-    let mut workqueue_set: FxHashSet<Block> = FxHashSet::default();
-    let mut visited: FxHashSet<Block> = FxHashSet::default();
+    let mut workqueue_set: HashSet<Block> = HashSet::default();
+    let mut visited: HashSet<Block> = HashSet::default();
 
     workqueue.push_back(func.entry);
     workqueue_set.insert(func.entry);
@@ -232,7 +232,7 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
     }
 
     // Find the set of all values used as addresses to loads/stores.
-    let mut used_as_addr = FxHashSet::default();
+    let mut used_as_addr = HashSet::default();
     for (_, def) in func.values.entries() {
         if let ValueDef::Operator(op, args, _) = def {
             if is_load_or_store(op) {
@@ -295,7 +295,7 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
     // instruction.
     for (block, block_def) in func.blocks.entries_mut() {
         log::trace!("rewriting in block {}", block);
-        let mut computed_offsets: FxHashMap<AbsValue, Value> = FxHashMap::default();
+        let mut computed_offsets: HashMap<AbsValue, Value> = HashMap::default();
         let mut new_insts = vec![];
 
         for (_, param) in &block_def.params {
@@ -320,20 +320,31 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
                         log::trace!("inst {} is a load/store with addr that is offset from base {}; pushing offset into instruction", inst, base);
                         // Update the offset embedded in the Operator
                         // and use the `base` value instead as the
-                        // address arg.
+                        // address arg. The memarg offset does not wrap
+                        // (an access past 4GiB traps), so leave the
+                        // access alone if the new offset would not fit.
                         let mut op = op.clone();
                         let mut args = args.iter().cloned().collect::<Vec<_>>();
                         let common_base = *offset_base.get(&base).unwrap();
                         let offset = *min_offset_from.get(&base).unwrap();
                         assert!(offset <= 0);
-                        let addend = (-offset) as u32;
+                        let delta = i64::from(this_offset as i32) - i64::from(offset);
+                        let mut pushed = false;
                         update_load_or_store_memarg(&mut op, |memory| {
-                            memory.offset =
-                                memory.offset.wrapping_add(addend).wrapping_add(this_offset)
+                            let new_offset = u64::try_from(delta)
+                                .ok()
+                                .and_then(|delta| u64::from(memory.offset).checked_add(delta))
+                                .and_then(|offset| offset.try_into().ok());
+                            if let Some(new_offset) = new_offset {
+                                memory.offset = new_offset;
+                                pushed = true;
+                            }
                         });
-                        args[0] = common_base;
-                        let args = func.arg_pool.from_iter(args.into_iter());
-                        func.values[inst] = ValueDef::Operator(op, args, tys);
+                        if pushed {
+                            args[0] = common_base;
+                            let args = func.arg_pool.from_iter(args.into_iter());
+                            func.values[inst] = ValueDef::Operator(op, args, tys);
+                        }
                     }
                 }
             }
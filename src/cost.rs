@@ -0,0 +1,127 @@
+//! Static cost estimates of function bodies.
+//!
+//! A rough model, for an embedder's tiering policy to weigh a
+//! specialization against its generic function: each instruction
+//! and terminator in a block reachable from the entry is charged by
+//! category (e.g. a division costs more than an add, a call more
+//! than a load). Loops are not accounted for, so this is the cost of
+//! executing every reachable block once, not a prediction of running
+//! time; it is comparable only between bodies estimated the same way.
+
+use waffle::{FunctionBody, Operator, Terminator, ValueDef};
+
+/// Constants, which engines materialize in place of their uses.
+const CONST: u64 = 0;
+/// Integer arithmetic, comparisons, conversions, `select`, globals.
+const SIMPLE: u64 = 1;
+/// Multiplications and other floating-point arithmetic.
+const ARITH: u64 = 3;
+/// Divisions, remainders, and square roots.
+const DIVIDE: u64 = 20;
+/// Loads and stores.
+const MEMORY: u64 = 3;
+/// Bulk memory operations.
+const BULK_MEMORY: u64 = 20;
+/// Direct calls.
+const CALL: u64 = 10;
+/// Indirect calls.
+const CALL_INDIRECT: u64 = 15;
+/// Multi-way branches.
+const BR_TABLE: u64 = 4;
+
+fn is_memory_access(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Load { .. }
+            | Operator::I64Load { .. }
+            | Operator::F32Load { .. }
+            | Operator::F64Load { .. }
+            | Operator::I32Load8S { .. }
+            | Operator::I32Load8U { .. }
+            | Operator::I32Load16S { .. }
+            | Operator::I32Load16U { .. }
+            | Operator::I64Load8S { .. }
+            | Operator::I64Load8U { .. }
+            | Operator::I64Load16S { .. }
+            | Operator::I64Load16U { .. }
+            | Operator::I64Load32S { .. }
+            | Operator::I64Load32U { .. }
+            | Operator::V128Load { .. }
+            | Operator::I32Store { .. }
+            | Operator::I64Store { .. }
+            | Operator::F32Store { .. }
+            | Operator::F64Store { .. }
+            | Operator::I32Store8 { .. }
+            | Operator::I32Store16 { .. }
+            | Operator::I64Store8 { .. }
+            | Operator::I64Store16 { .. }
+            | Operator::I64Store32 { .. }
+            | Operator::V128Store { .. }
+    )
+}
+
+fn op_cost(op: &Operator) -> u64 {
+    match op {
+        Operator::I32Const { .. }
+        | Operator::I64Const { .. }
+        | Operator::F32Const { .. }
+        | Operator::F64Const { .. }
+        | Operator::V128Const { .. } => CONST,
+        Operator::I32DivS
+        | Operator::I32DivU
+        | Operator::I32RemS
+        | Operator::I32RemU
+        | Operator::I64DivS
+        | Operator::I64DivU
+        | Operator::I64RemS
+        | Operator::I64RemU
+        | Operator::F32Div
+        | Operator::F64Div
+        | Operator::F32Sqrt
+        | Operator::F64Sqrt => DIVIDE,
+        Operator::I32Mul
+        | Operator::I64Mul
+        | Operator::F32Add
+        | Operator::F32Sub
+        | Operator::F32Mul
+        | Operator::F64Add
+        | Operator::F64Sub
+        | Operator::F64Mul => ARITH,
+        Operator::MemoryCopy { .. } | Operator::MemoryFill { .. } => BULK_MEMORY,
+        Operator::Call { .. } => CALL,
+        Operator::CallIndirect { .. } | Operator::CallRef { .. } => CALL_INDIRECT,
+        op if is_memory_access(op) => MEMORY,
+        _ => SIMPLE,
+    }
+}
+
+fn terminator_cost(term: &Terminator) -> u64 {
+    match term {
+        Terminator::Select { .. } => BR_TABLE,
+        Terminator::ReturnCall { .. } => CALL,
+        Terminator::ReturnCallIndirect { .. } => CALL_INDIRECT,
+        Terminator::None | Terminator::Unreachable => 0,
+        _ => SIMPLE,
+    }
+}
+
+/// The estimated cost of executing each block of `body` reachable
+/// from its entry once.
+pub fn estimate(body: &FunctionBody) -> u64 {
+    let (_, _, reachable) = crate::stats::count_reachable_blocks_and_insts(body);
+    reachable
+        .iter()
+        .map(|&block| {
+            let def = &body.blocks[block];
+            let insts = def
+                .insts
+                .iter()
+                .map(|&inst| match &body.values[inst] {
+                    ValueDef::Operator(op, _, _) => op_cost(op),
+                    _ => 0,
+                })
+                .sum::<u64>();
+            insts + terminator_cost(&def.terminator)
+        })
+        .sum()
+}
@@ -0,0 +1,81 @@
+//! A map from (context, generic block) to specialized block.
+//!
+//! The evaluator looks this up for every edge it evaluates, so rather
+//! than hashing the pair, it is stored densely: a row per context,
+//! indexed by generic block. Rows are allocated on a context's first
+//! insert, so contexts that never reach a block cost nothing.
+
+use crate::state::Context;
+use waffle::{entity::EntityRef, Block};
+
+#[derive(Clone, Debug, Default)]
+pub struct CtxBlockMap {
+    /// Per context, per generic block, the specialized block, or
+    /// `Block::invalid()` if none.
+    rows: Vec<Vec<Block>>,
+    /// Number of entries.
+    len: usize,
+}
+
+impl CtxBlockMap {
+    pub fn get(&self, key: &(Context, Block)) -> Option<&Block> {
+        let (ctx, block) = *key;
+        self.rows
+            .get(ctx.index())?
+            .get(block.index())
+            .filter(|block| block.is_valid())
+    }
+
+    pub fn contains_key(&self, key: &(Context, Block)) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: (Context, Block), value: Block) -> Option<Block> {
+        let (ctx, block) = key;
+        if self.rows.len() <= ctx.index() {
+            self.rows.resize_with(ctx.index() + 1, Vec::new);
+        }
+        let row = &mut self.rows[ctx.index()];
+        if row.len() <= block.index() {
+            row.resize(block.index() + 1, Block::invalid());
+        }
+        let old = std::mem::replace(&mut row[block.index()], value);
+        if old.is_valid() {
+            Some(old)
+        } else {
+            self.len += 1;
+            None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Entries in order of context, then generic block.
+    pub fn iter(&self) -> impl Iterator<Item = ((Context, Block), Block)> + '_ {
+        self.rows.iter().enumerate().flat_map(|(ctx, row)| {
+            row.iter()
+                .enumerate()
+                .filter(|(_, block)| block.is_valid())
+                .map(move |(orig_block, &block)| {
+                    ((Context::new(ctx), Block::new(orig_block)), block)
+                })
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = Block> + '_ {
+        self.iter().map(|(_, block)| block)
+    }
+
+    /// The specialized blocks of one context, in order of generic
+    /// block.
+    pub fn row(&self, ctx: Context) -> impl Iterator<Item = Block> + '_ {
+        self.rows
+            .get(ctx.index())
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|block| block.is_valid())
+    }
+}
@@ -0,0 +1,68 @@
+//! Preservation of the input module's custom sections.
+//!
+//! Neither waffle nor the filter pass carries custom sections through
+//! to the output, so toolchain metadata, source maps, component type
+//! annotations and the like would otherwise be lost. Instead they are
+//! read from the input module (before snapshotting) and appended to
+//! the output byte-for-byte, in their original order.
+//!
+//! The exceptions are the sections weval itself transforms: the
+//! `name` section and a side module's `dylink.0` section, which the
+//! filter pass rewrites for the output's index spaces; the branch-hint
+//! section, which it re-encodes at the output's offsets; and weval's
+//! own `weval.*` sections, which are either consumed (directives) or
+//! produced anew.
+//!
+//! Sections whose contents refer to code offsets, such as DWARF
+//! `.debug_*` sections or a `sourceMappingURL`, describe the input's
+//! code; they are kept as they are, but are only accurate for
+//! functions weval left untouched.
+
+use wasm_encoder::{Encode, Section};
+use wasmparser::{Parser, Payload};
+
+/// A custom section of the input module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomSection {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Whether weval rewrites or regenerates the custom section `name`,
+/// rather than preserving it.
+pub fn is_transformed(name: &str) -> bool {
+    name == "name"
+        || name == "dylink.0"
+        || name == crate::branch_hints::SECTION_NAME
+        || name.starts_with("weval.")
+}
+
+/// Read the custom sections of a module that weval does not
+/// transform, in order.
+pub fn read(module: &[u8]) -> anyhow::Result<Vec<CustomSection>> {
+    let mut sections = vec![];
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::CustomSection(reader) = payload? {
+            if !is_transformed(reader.name()) {
+                sections.push(CustomSection {
+                    name: reader.name().to_owned(),
+                    data: reader.data().to_vec(),
+                });
+            }
+        }
+    }
+    Ok(sections)
+}
+
+/// Append the sections to an encoded module. Custom sections may
+/// appear anywhere, so this is valid after any section.
+pub fn append(bytes: &mut Vec<u8>, sections: &[CustomSection]) {
+    for section in sections {
+        let section = wasm_encoder::CustomSection {
+            name: section.name.as_str().into(),
+            data: section.data[..].into(),
+        };
+        bytes.push(section.id());
+        section.encode(bytes);
+    }
+}
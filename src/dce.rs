@@ -1,6 +1,6 @@
 //! Dead-code elimination pass.
 
-use fxhash::FxHashSet;
+use crate::collections::HashSet;
 use waffle::{cfg::CFGInfo, Block, FunctionBody, Operator, Terminator, Value, ValueDef};
 
 fn op_can_be_removed(op: &Operator) -> bool {
@@ -33,8 +33,8 @@ fn op_can_be_removed(op: &Operator) -> bool {
 /// instruction that itself is used (or for a branch arg, for which
 /// any target's corresponding blockparam is used). Returns `true` if
 /// any changes occurred to the used-value set.
-fn scan_block(func: &FunctionBody, block: Block, used: &mut FxHashSet<Value>) -> bool {
-    let mark_used = |used: &mut FxHashSet<Value>, mut arg: Value| -> bool {
+fn scan_block(func: &FunctionBody, block: Block, used: &mut HashSet<Value>) -> bool {
+    let mark_used = |used: &mut HashSet<Value>, mut arg: Value| -> bool {
         let mut changed = false;
         changed |= used.insert(arg);
         while let ValueDef::Alias(orig) = &func.values[arg] {
@@ -88,6 +88,9 @@ fn scan_block(func: &FunctionBody, block: Block, used: &mut FxHashSet<Value>) ->
                 }
             }
             ValueDef::Trace(_, args) => {
+                // Trace markers have no uses of their own but are
+                // consumed by downstream tooling: always keep them.
+                changed |= used.insert(inst);
                 for &arg in &func.arg_pool[*args] {
                     log::trace!(" -> marking trace arg {} used", arg);
                     changed |= mark_used(used, arg);
@@ -128,7 +131,7 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
     }
 
     // Now compute value uses.
-    let mut used = FxHashSet::default();
+    let mut used = HashSet::default();
     for &(_, param) in &func.blocks[func.entry].params {
         used.insert(param);
     }
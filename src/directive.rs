@@ -4,8 +4,10 @@ use crate::image::Image;
 use crate::intrinsics::find_global_data_by_exported_func;
 use crate::value::{AbstractValue, MemoryBufferIndex, WasmVal};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
 use std::sync::Arc;
-use waffle::{Func, Memory, Module};
+use waffle::{Func, Global, Memory, Module, Type};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Directive {
@@ -14,6 +16,10 @@ pub struct Directive {
     /// Evaluate the given function.
     #[serde(skip)]
     pub func: Func,
+    /// The module defining `func`: 0 for the main module, otherwise
+    /// one plus the index of a linked side module.
+    #[serde(skip)]
+    pub module: usize,
     /// Evaluate with the given arguments, encoded as a bytestring.
     pub args: Vec<u8>,
     /// The number of globals prepended to the `args` list.
@@ -22,6 +28,52 @@ pub struct Directive {
     /// given address in memory, if nonzero.
     #[serde(skip)]
     pub func_index_out_addr: u32,
+    /// Directives with higher priority are specialized first, and
+    /// are the last to be skipped under a size budget. Set from the
+    /// command line per weval site; defaults to zero.
+    #[serde(skip)]
+    pub priority: i32,
+    /// Specialize relative to the directive with this ID, which
+    /// targets the same function with a subset of these constants
+    /// (see `PartialEvalOptions::delta_bases`).
+    #[serde(skip)]
+    pub base: Option<DirectiveId>,
+}
+
+impl Directive {
+    /// A stable ID for this directive, derived from its content
+    /// rather than its position among the directives, for use in
+    /// reports and logs and to select directives with `--only-id`.
+    pub fn id(&self) -> DirectiveId {
+        let mut bytes = vec![];
+        bytes.extend(u32::to_le_bytes(self.module as u32));
+        bytes.extend(u32::to_le_bytes(self.func.index() as u32));
+        bytes.extend(u32::to_le_bytes(self.func_index_out_addr));
+        bytes.extend(&self.args[..]);
+        DirectiveId(crate::meta::hash_bytes(&bytes[..]))
+    }
+}
+
+/// A content-derived directive ID: the hash of the module and
+/// function, the argument bytestring and the out-address. Displayed and parsed as
+/// 16 hex digits.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DirectiveId(pub u64);
+
+impl std::fmt::Display for DirectiveId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl std::str::FromStr for DirectiveId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<DirectiveId> {
+        Ok(DirectiveId(u64::from_str_radix(
+            s.trim_start_matches("0x"),
+            16,
+        )?))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -32,17 +84,60 @@ pub struct DirectiveArgs {
     pub const_memory: Vec<Option<MemoryBuffer>>,
 }
 
+/// Facts about a directive's entry state supplied by an embedder, in
+/// addition to the constants encoded in its `args` (see
+/// `PartialEvalOptions::entry_facts`). An embedder that knows more
+/// than the guest could say, e.g. that a param points to a frozen
+/// object graph, can seed the analysis with it directly.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntryFacts {
+    /// Abstract values of params, by index (not counting
+    /// specialization globals), replacing those in `args`.
+    pub params: BTreeMap<usize, AbstractValue>,
+    /// Constant memory behind params, by index: each such param is a
+    /// pointer to the start of its buffer.
+    pub memory: BTreeMap<usize, MemoryBuffer>,
+    /// Abstract values of Wasm globals on entry.
+    pub globals: BTreeMap<Global, AbstractValue>,
+}
+
 /// A "symbolic pointer" backing buffer: if we are specializing a
 /// function assuming a given argument which is a pointer has fixed
 /// *contents* (but not necessarily a constant pointer value), this
 /// allows us to give the backing data directly.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MemoryBuffer {
     /// The bytes in memory at this pointer.
     data: Arc<Vec<u8>>,
+    /// For a structure with some mutable fields, the (offset, size)
+    /// of each constant field; loads elsewhere are left to runtime.
+    /// `None` if the whole buffer is constant.
+    const_fields: Option<Arc<Vec<(u32, u32)>>>,
 }
 
 impl MemoryBuffer {
+    /// A buffer with the given contents. If `const_fields` is given,
+    /// only those (offset, size) ranges are constant.
+    pub fn new(data: Vec<u8>, const_fields: Option<Vec<(u32, u32)>>) -> MemoryBuffer {
+        MemoryBuffer {
+            data: Arc::new(data),
+            const_fields: const_fields.map(Arc::new),
+        }
+    }
+
+    /// Whether the `size` bytes at `offset` lie within one constant
+    /// field (or the buffer is wholly constant).
+    pub fn is_const(&self, offset: u32, size: u32) -> bool {
+        match &self.const_fields {
+            None => true,
+            Some(fields) => fields.iter().any(|&(field_offset, field_size)| {
+                offset >= field_offset
+                    && u64::from(offset) + u64::from(size)
+                        <= u64::from(field_offset) + u64::from(field_size)
+            }),
+        }
+    }
+
     pub fn read_size(&self, offset: u32, size: u32) -> anyhow::Result<u64> {
         let offset = usize::try_from(offset).unwrap();
         let size = usize::try_from(size).unwrap();
@@ -103,11 +198,84 @@ pub fn collect(module: &Module, im: &mut Image) -> anyhow::Result<Vec<Directive>
     Ok(directives)
 }
 
+/// A source of directives. The driver collects from every provider
+/// before specializing, so embedders can add their own discovery
+/// mechanisms alongside the built-in ones.
+pub trait DirectiveProvider {
+    /// A short description, for logs.
+    fn name(&self) -> String;
+
+    /// Live requests: directives with a resolved `func`, and usually
+    /// an address to which to write the specialized function's table
+    /// index. May update the image (e.g., to dequeue requests).
+    fn requests(&mut self, _module: &Module, _im: &mut Image) -> anyhow::Result<Vec<Directive>> {
+        Ok(vec![])
+    }
+
+    /// Pre-collected requests, identified only by weval site (user
+    /// ID) and arguments; the function is resolved from the site,
+    /// and results go in the lookup table.
+    fn corpus(&mut self, _module: &Module) -> anyhow::Result<Vec<Directive>> {
+        Ok(vec![])
+    }
+}
+
+/// Requests on the guest's pending list in the snapshot.
+pub struct PendingRequests;
+
+impl DirectiveProvider for PendingRequests {
+    fn name(&self) -> String {
+        "pending requests".to_owned()
+    }
+
+    fn requests(&mut self, module: &Module, im: &mut Image) -> anyhow::Result<Vec<Directive>> {
+        collect(module, im)
+    }
+}
+
+/// A bincode-serialized corpus file, as written by `weval collect`.
+pub struct CorpusFile(pub PathBuf);
+
+impl DirectiveProvider for CorpusFile {
+    fn name(&self) -> String {
+        format!("corpus {}", self.0.display())
+    }
+
+    fn corpus(&mut self, _module: &Module) -> anyhow::Result<Vec<Directive>> {
+        let bytes = std::fs::read(&self.0)?;
+        Ok(bincode::deserialize(&bytes[..])?)
+    }
+}
+
+/// Collects live requests and corpus directives from all providers,
+/// in order.
+pub fn collect_from(
+    providers: &mut [Box<dyn DirectiveProvider>],
+    module: &Module,
+    im: &mut Image,
+) -> anyhow::Result<(Vec<Directive>, Vec<Directive>)> {
+    let mut requests = vec![];
+    let mut corpus = vec![];
+    for provider in providers.iter_mut() {
+        let r = provider.requests(module, im)?;
+        let c = provider.corpus(module)?;
+        log::info!(
+            "{}: {} requests, {} corpus directives",
+            provider.name(),
+            r.len(),
+            c.len()
+        );
+        requests.extend(r);
+        corpus.extend(c);
+    }
+    Ok((requests, corpus))
+}
+
 fn decode_weval_req(im: &Image, heap: Memory, head: u32) -> anyhow::Result<Directive> {
     let user_id = im.read_u32(heap, head + 8)?;
     let num_globals = im.read_u32(heap, head + 12)?;
     let func_table_index = im.read_u32(heap, head + 16)?;
-    let func = im.func_ptr(func_table_index)?;
+    let (module, func) = im.resolve_func_ptr(func_table_index)?;
     let arg_ptr = im.read_u32(heap, head + 20)?;
     let arg_len = im.read_u32(heap, head + 24)?;
     let func_index_out_addr = im.read_u32(heap, head + 28)?;
@@ -119,8 +287,11 @@ fn decode_weval_req(im: &Image, heap: Memory, head: u32) -> anyhow::Result<Direc
         user_id,
         num_globals,
         func,
+        module,
         args,
         func_index_out_addr,
+        priority: 0,
+        base: None,
     })
 }
 
@@ -154,6 +325,9 @@ impl DirectiveArgs {
 
         let mut i = 0;
         while arg_ptr < bytes.len() {
+            if arg_ptr + 16 > bytes.len() {
+                anyhow::bail!("Argument {} truncated", i);
+            }
             let is_specialized = read_u32(arg_ptr);
             let ty = read_u32(arg_ptr + 4);
             let (value, mem, arg_len) = if is_specialized != 0 {
@@ -181,11 +355,46 @@ impl DirectiveArgs {
                     4 => {
                         let len = read_u32(arg_ptr + 8);
                         let padded_len = read_u32(arg_ptr + 12);
+                        if arg_ptr + 16 + usize::try_from(len).unwrap() > bytes.len() {
+                            anyhow::bail!("Argument {} memory buffer truncated", i);
+                        }
                         let data = MemoryBuffer {
                             data: Arc::new(
                                 bytes[arg_ptr + 16..(arg_ptr + 16 + usize::try_from(len).unwrap())]
                                     .to_vec(),
                             ),
+                            const_fields: None,
+                        };
+                        (
+                            AbstractValue::ConcreteMemory(MemoryBufferIndex(i), 0),
+                            Some(data),
+                            16 + padded_len,
+                        )
+                    }
+                    5 => {
+                        // A structure: a field count, then (offset,
+                        // size, flags) per field, then the contents.
+                        let len = usize::try_from(read_u32(arg_ptr + 8)).unwrap();
+                        let padded_len = read_u32(arg_ptr + 12);
+                        let base = arg_ptr + 16;
+                        if base + 4 > bytes.len() {
+                            anyhow::bail!("Argument {} structure truncated", i);
+                        }
+                        let num_fields = usize::try_from(read_u32(base)).unwrap();
+                        let data_start = base + 4 + 12 * num_fields;
+                        if data_start + len > bytes.len()
+                            || data_start + len > base + usize::try_from(padded_len).unwrap()
+                        {
+                            anyhow::bail!("Argument {} structure truncated", i);
+                        }
+                        let const_fields = (0..num_fields)
+                            .map(|field| base + 4 + 12 * field)
+                            .filter(|&field| read_u32(field + 8) & 1 != 0)
+                            .map(|field| (read_u32(field), read_u32(field + 4)))
+                            .collect::<Vec<_>>();
+                        let data = MemoryBuffer {
+                            data: Arc::new(bytes[data_start..data_start + len].to_vec()),
+                            const_fields: Some(Arc::new(const_fields)),
                         };
                         (
                             AbstractValue::ConcreteMemory(MemoryBufferIndex(i), 0),
@@ -209,4 +418,620 @@ impl DirectiveArgs {
             const_memory,
         })
     }
+
+    /// Apply an embedder's entry facts over the decoded arguments.
+    /// Params named in `facts` must exist; their types are checked
+    /// by `coerce_to_params` as for decoded arguments.
+    pub fn apply_entry_facts(
+        &mut self,
+        num_globals: usize,
+        facts: &EntryFacts,
+    ) -> anyhow::Result<()> {
+        for (&param, abs) in &facts.params {
+            let i = num_globals + param;
+            if i >= self.const_params.len() {
+                anyhow::bail!("Entry fact for nonexistent param {}", param);
+            }
+            self.const_params[i] = abs.clone();
+            self.const_memory[i] = None;
+        }
+        for (&param, buf) in &facts.memory {
+            let i = num_globals + param;
+            if i >= self.const_params.len() {
+                anyhow::bail!("Entry memory for nonexistent param {}", param);
+            }
+            self.const_params[i] =
+                AbstractValue::ConcreteMemory(MemoryBufferIndex(u32::try_from(i).unwrap()), 0);
+            self.const_memory[i] = Some(buf.clone());
+        }
+        Ok(())
+    }
+
+    /// Whether every constant of `base` is also a constant, with the
+    /// same value, here.
+    pub fn extends(&self, base: &DirectiveArgs) -> bool {
+        let is_const =
+            |abs: &AbstractValue| !matches!(abs, AbstractValue::Runtime(_) | AbstractValue::Top);
+        self.const_params.len() == base.const_params.len()
+            && self
+                .const_params
+                .iter()
+                .zip(base.const_params.iter())
+                .all(|(this, base)| !is_const(base) || this == base)
+            && self
+                .const_memory
+                .iter()
+                .zip(base.const_memory.iter())
+                .all(|(this, base)| base.is_none() || this == base)
+    }
+
+    /// Check the constant params (after the `num_globals`
+    /// specialization globals) against the function's param types,
+    /// converting where no information is lost: an `i32` for an `i64`
+    /// param is zero-extended, an `i64` for an `i32` param is
+    /// truncated if it fits in 32 bits, and `f32` and `f64` convert
+    /// if the value is exactly representable. Any other mismatch,
+    /// or a count mismatch, is an error naming the param.
+    pub fn coerce_to_params(&mut self, num_globals: usize, params: &[Type]) -> anyhow::Result<()> {
+        if self.const_params.len() != num_globals + params.len() {
+            anyhow::bail!(
+                "{} arguments ({} specialization globals) for {} params",
+                self.const_params.len(),
+                num_globals,
+                params.len()
+            );
+        }
+        for (i, (abs, &ty)) in self.const_params[num_globals..]
+            .iter_mut()
+            .zip(params.iter())
+            .enumerate()
+        {
+            let converted = match (&*abs, ty) {
+                (AbstractValue::Concrete(WasmVal::I32(_)), Type::I32)
+                | (AbstractValue::Concrete(WasmVal::I64(_)), Type::I64)
+                | (AbstractValue::Concrete(WasmVal::F32(_)), Type::F32)
+                | (AbstractValue::Concrete(WasmVal::F64(_)), Type::F64)
+                | (AbstractValue::ConcreteMemory(..), Type::I32) => continue,
+                (AbstractValue::Concrete(WasmVal::I32(k)), Type::I64) => {
+                    Some(WasmVal::I64(u64::from(*k)))
+                }
+                (AbstractValue::Concrete(WasmVal::I64(k)), Type::I32) => {
+                    u32::try_from(*k).ok().map(WasmVal::I32)
+                }
+                (AbstractValue::Concrete(WasmVal::F32(bits)), Type::F64) => {
+                    Some(WasmVal::F64(f64::from(f32::from_bits(*bits)).to_bits()))
+                }
+                (AbstractValue::Concrete(WasmVal::F64(bits)), Type::F32) => {
+                    let x = f64::from_bits(*bits);
+                    let y = x as f32;
+                    (f64::from(y) == x || x.is_nan()).then(|| WasmVal::F32(y.to_bits()))
+                }
+                (AbstractValue::Concrete(_) | AbstractValue::ConcreteMemory(..), _) => None,
+                _ => continue,
+            };
+            match converted {
+                Some(val) => {
+                    log::debug!("param {}: converting {:?} to {:?}", i, abs, val);
+                    *abs = AbstractValue::Concrete(val);
+                }
+                None => anyhow::bail!(
+                    "Argument for param {} is {:?}, which does not convert to the param type {:?}",
+                    i,
+                    abs,
+                    ty
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How much work to put into a specialization, trading compile time
+/// and output size for speed of the result.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Emit only a wrapper that calls the generic function with
+    /// constant params bound, without copying or evaluating its body.
+    Wrap,
+    /// Emit the generic function as-is, with constant params bound.
+    O0,
+    /// Constant-fold, but do not specialize on context (intrinsics
+    /// that push or update contexts or specialize on a value are
+    /// ignored).
+    O1,
+    /// Constant-fold and specialize on contexts (PCs and specialized
+    /// values).
+    O2,
+    /// Additionally optimize memory accesses in the result
+    /// (shadow-stack removal, constant offsets, store-to-load
+    /// forwarding), cancel redundant extend/wrap chains, fold masks
+    /// and comparisons on values of known width, and convert small
+    /// branch diamonds to `select`s.
+    #[default]
+    O3,
+}
+
+impl std::str::FromStr for OptLevel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "wrap" => Ok(OptLevel::Wrap),
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            _ => anyhow::bail!("Unknown opt level: {}", s),
+        }
+    }
+}
+
+/// A `<user_id>=<level>` pair, as given on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct OptLevelArg {
+    pub user_id: u32,
+    pub level: OptLevel,
+}
+
+impl std::str::FromStr for OptLevelArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, level) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <user_id>=<level>, got: {}", s))?;
+        Ok(OptLevelArg {
+            user_id: user_id.parse()?,
+            level: level.parse()?,
+        })
+    }
+}
+
+/// What to do with a generic function after specializing it.
+///
+/// Ordered from most to least conservative: when several weval sites
+/// target the same generic function with different policies, the
+/// most conservative one wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GenericFuncPolicy {
+    /// Keep the generic function as-is.
+    #[default]
+    Keep,
+    /// Keep the generic function, but list it in the `weval.cold`
+    /// custom section as a hint to the engine.
+    Cold,
+    /// Remove the generic function's body, if no references to it
+    /// remain in the module; otherwise fall back to `Cold`.
+    Remove,
+}
+
+impl std::str::FromStr for GenericFuncPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "keep" => Ok(GenericFuncPolicy::Keep),
+            "cold" => Ok(GenericFuncPolicy::Cold),
+            "remove" => Ok(GenericFuncPolicy::Remove),
+            _ => anyhow::bail!("Unknown generic function policy: {}", s),
+        }
+    }
+}
+
+/// What to do when a load from constant memory (a constant-memory
+/// argument or the image) reads out of bounds. Such a read almost
+/// always means that the directive's constants, or its assumptions
+/// about constant memory, are wrong.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutOfBoundsReads {
+    /// Leave the load to runtime.
+    #[default]
+    Degrade,
+    /// Leave the load to runtime, and warn with the address, size,
+    /// load and context of each.
+    Warn,
+    /// Warn as with `Warn`, and do not specialize the directive.
+    Fail,
+}
+
+impl std::str::FromStr for OutOfBoundsReads {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "degrade" => Ok(OutOfBoundsReads::Degrade),
+            "warn" => Ok(OutOfBoundsReads::Warn),
+            "fail" => Ok(OutOfBoundsReads::Fail),
+            _ => anyhow::bail!("Unknown out-of-bounds read mode: {}", s),
+        }
+    }
+}
+
+/// Which `weval.barrier.needed` checks to fold away in specialized
+/// code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BarrierElision {
+    /// Keep all barriers.
+    #[default]
+    None,
+    /// Elide barriers for constants that are not heap references
+    /// (e.g. small integers).
+    Immediates,
+    /// Elide barriers for all constants, including references to
+    /// objects in the snapshot. Sound only if the guest's GC never
+    /// needs a barrier for storing such references.
+    Constants,
+}
+
+impl std::str::FromStr for BarrierElision {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "none" => Ok(BarrierElision::None),
+            "immediates" => Ok(BarrierElision::Immediates),
+            "constants" => Ok(BarrierElision::Constants),
+            _ => anyhow::bail!("Unknown barrier elision mode: {}", s),
+        }
+    }
+}
+
+/// What to do about calls to weval intrinsics whose arguments the
+/// evaluator cannot act on (e.g. `weval.update.context` with a
+/// runtime PC, or `weval.env.u32` with an unknown key), which are
+/// otherwise left as runtime calls or dropped with at most a log
+/// message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StrictIntrinsics {
+    /// Degrade silently.
+    #[default]
+    Off,
+    /// Warn with the intrinsic, its location and context, and what
+    /// was done instead.
+    Warn,
+    /// Fail, as with a failed `weval.assert.const32`.
+    Error,
+}
+
+impl std::str::FromStr for StrictIntrinsics {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "off" => Ok(StrictIntrinsics::Off),
+            "warn" => Ok(StrictIntrinsics::Warn),
+            "error" => Ok(StrictIntrinsics::Error),
+            _ => anyhow::bail!("Unknown strict-intrinsics mode: {}", s),
+        }
+    }
+}
+
+/// A `<user_id>=<priority>` pair, as given on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct PriorityArg {
+    pub user_id: u32,
+    pub priority: i32,
+}
+
+impl std::str::FromStr for PriorityArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, priority) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <user_id>=<priority>, got: {}", s))?;
+        Ok(PriorityArg {
+            user_id: user_id.parse()?,
+            priority: priority.parse()?,
+        })
+    }
+}
+
+/// An `<id>=<base-id>` pair of directive IDs, as given on the command
+/// line.
+#[derive(Clone, Copy, Debug)]
+pub struct DeltaArg {
+    pub id: DirectiveId,
+    pub base: DirectiveId,
+}
+
+impl std::str::FromStr for DeltaArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (id, base) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <id>=<base-id>, got: {}", s))?;
+        Ok(DeltaArg {
+            id: id.parse()?,
+            base: base.parse()?,
+        })
+    }
+}
+
+/// A `<user_id>=<global>` pair, as given on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct ContextGlobalArg {
+    pub user_id: u32,
+    pub global: Global,
+}
+
+impl std::str::FromStr for ContextGlobalArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, global) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <user_id>=<global>, got: {}", s))?;
+        Ok(ContextGlobalArg {
+            user_id: user_id.parse()?,
+            global: Global::from(global.parse::<u32>()?),
+        })
+    }
+}
+
+/// An environment constant for `weval.env.u32`, as `<key>=<value>`
+/// on the command line.
+#[derive(Clone, Debug)]
+pub struct EnvArg {
+    pub key: String,
+    pub value: u32,
+}
+
+impl std::str::FromStr for EnvArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <key>=<value>, got: {}", s))?;
+        Ok(EnvArg {
+            key: key.to_owned(),
+            value: crate::image::parse_u32(value)?,
+        })
+    }
+}
+
+/// An environment constant for one weval site, as
+/// `<user_id>=<key>=<value>` on the command line.
+#[derive(Clone, Debug)]
+pub struct SiteEnvArg {
+    pub user_id: u32,
+    pub env: EnvArg,
+}
+
+impl std::str::FromStr for SiteEnvArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, env) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <user_id>=<key>=<value>, got: {}", s))?;
+        Ok(SiteEnvArg {
+            user_id: user_id.parse()?,
+            env: env.parse()?,
+        })
+    }
+}
+
+/// An assumed value for an imported global, which the embedder
+/// provides at instantiation, as `<module>.<name>=<value>` on the
+/// command line. Float values are given as their bits.
+#[derive(Clone, Debug)]
+pub struct ImportedGlobalArg {
+    pub module: String,
+    pub name: String,
+    pub value: u64,
+}
+
+impl std::str::FromStr for ImportedGlobalArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (import, value) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <module>.<name>=<value>, got: {}", s))?;
+        let ImportName { module, name } = import.parse()?;
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16)?,
+            None => match value.parse::<u64>() {
+                Ok(value) => value,
+                Err(_) => value.parse::<i64>()? as u64,
+            },
+        };
+        Ok(ImportedGlobalArg {
+            module,
+            name,
+            value,
+        })
+    }
+}
+
+/// The name of an import, as `<module>.<name>` on the command line.
+#[derive(Clone, Debug)]
+pub struct ImportName {
+    pub module: String,
+    pub name: String,
+}
+
+impl std::str::FromStr for ImportName {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (module, name) = s
+            .split_once('.')
+            .ok_or_else(|| anyhow::anyhow!("Expected <module>.<name>, got: {}", s))?;
+        Ok(ImportName {
+            module: module.to_owned(),
+            name: name.to_owned(),
+        })
+    }
+}
+
+/// A `<user_id>=<policy>` pair, as given on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct GenericFuncPolicyArg {
+    pub user_id: u32,
+    pub policy: GenericFuncPolicy,
+}
+
+impl std::str::FromStr for GenericFuncPolicyArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, policy) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <user_id>=<policy>, got: {}", s))?;
+        Ok(GenericFuncPolicyArg {
+            user_id: user_id.parse()?,
+            policy: policy.parse()?,
+        })
+    }
+}
+
+/// A class of operators whose folding can be turned off for a weval
+/// site (see `PartialEvalOptions::no_fold`): a workaround for a
+/// suspected mis-fold, and a way to bisect one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FoldClass {
+    /// Integer arithmetic, bitwise operators, and conversions.
+    Int,
+    /// Integer comparisons.
+    Compare,
+    /// Operators on `f32` and `f64` values.
+    Float,
+    /// Operators on `v128` values.
+    Simd,
+    /// Loads: from constant memory, and forwarded from stores.
+    Load,
+    /// `select`.
+    Select,
+    /// Devirtualization of indirect calls, and calls evaluated by
+    /// their callee's summary.
+    Call,
+}
+
+impl FoldClass {
+    pub const ALL: [FoldClass; 7] = [
+        FoldClass::Int,
+        FoldClass::Compare,
+        FoldClass::Float,
+        FoldClass::Simd,
+        FoldClass::Load,
+        FoldClass::Select,
+        FoldClass::Call,
+    ];
+}
+
+impl std::str::FromStr for FoldClass {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "int" => Ok(FoldClass::Int),
+            "compare" => Ok(FoldClass::Compare),
+            "float" => Ok(FoldClass::Float),
+            "simd" => Ok(FoldClass::Simd),
+            "load" => Ok(FoldClass::Load),
+            "select" => Ok(FoldClass::Select),
+            "call" => Ok(FoldClass::Call),
+            _ => anyhow::bail!("Unknown operator class: {}", s),
+        }
+    }
+}
+
+/// A `<user_id>=<class>[,<class>...]` pair, as given on the command
+/// line, where a class is one of `int`, `compare`, `float`, `simd`,
+/// `load`, `select`, `call`, or `all`.
+#[derive(Clone, Debug)]
+pub struct NoFoldArg {
+    pub user_id: u32,
+    pub classes: BTreeSet<FoldClass>,
+}
+
+impl std::str::FromStr for NoFoldArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, classes) = s.split_once('=').ok_or_else(|| {
+            anyhow::anyhow!("Expected <user_id>=<class>[,<class>...], got: {}", s)
+        })?;
+        let mut parsed = BTreeSet::new();
+        for class in classes.split(',') {
+            match class.trim() {
+                "all" => parsed.extend(FoldClass::ALL),
+                class => {
+                    parsed.insert(class.parse()?);
+                }
+            }
+        }
+        Ok(NoFoldArg {
+            user_id: user_id.parse()?,
+            classes: parsed,
+        })
+    }
+}
+
+/// A `<user_id>=<ms>` pair, as given on the command line: the time
+/// allowed to each specialization for the weval site.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeoutArg {
+    pub user_id: u32,
+    pub timeout: std::time::Duration,
+}
+
+impl std::str::FromStr for TimeoutArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, ms) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <user_id>=<ms>, got: {}", s))?;
+        Ok(TimeoutArg {
+            user_id: user_id.parse()?,
+            timeout: std::time::Duration::from_millis(ms.parse()?),
+        })
+    }
+}
+
+/// A `<user_id>=<lo>..<hi>` pair, as given on the command line:
+/// specialize the weval site's bytecode PCs in `[lo, hi)` only.
+#[derive(Clone, Copy, Debug)]
+pub struct PcRangeArg {
+    pub user_id: u32,
+    pub lo: u32,
+    pub hi: u32,
+}
+
+impl std::str::FromStr for PcRangeArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (user_id, range) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <user_id>=<lo>..<hi>, got: {}", s))?;
+        let (lo, hi) = range
+            .split_once("..")
+            .ok_or_else(|| anyhow::anyhow!("Expected <lo>..<hi>, got: {}", range))?;
+        let (lo, hi) = (
+            crate::image::parse_u32(lo.trim())?,
+            crate::image::parse_u32(hi.trim())?,
+        );
+        if lo >= hi {
+            anyhow::bail!("Empty PC range: {}", range);
+        }
+        Ok(PcRangeArg {
+            user_id: user_id.parse()?,
+            lo,
+            hi,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+
+    #[test]
+    fn id_depends_on_module() {
+        let main = Directive {
+            user_id: 1,
+            func: Func::new(3),
+            module: 0,
+            args: vec![1, 2, 3, 4],
+            num_globals: 0,
+            func_index_out_addr: 0x100,
+            priority: 0,
+            base: None,
+        };
+        let side = Directive {
+            module: 1,
+            ..main.clone()
+        };
+        assert_ne!(main.id(), side.id());
+        assert_eq!(main.id(), main.clone().id());
+    }
 }
@@ -0,0 +1,198 @@
+//! Directives embedded in the module: the `weval.directives` custom
+//! section.
+//!
+//! A guest can ship requests in its module, alongside (or instead of)
+//! requests enqueued at runtime or collected into a corpus. Like
+//! corpus directives, these name a weval site (user ID) rather than a
+//! function, and their results go in the site's lookup table. The
+//! section is a vector of directives, each encoded as
+//!
+//! - the user ID (`u32`, LEB128),
+//! - the number of globals prepended to the arguments (`u32`, LEB128),
+//! - the argument bytestring (a LEB128 length, then the bytes), as
+//!   decoded by `DirectiveArgs::decode`.
+//!
+//! As the section is awkward to produce by hand, there is also a text
+//! form, one directive per line:
+//!
+//! ```text
+//! # user-id num-globals args-in-hex
+//! 1 0 01000000000000002a00000000000000
+//! ```
+//!
+//! with blank lines and `#` comments ignored. `weval directives
+//! encode` and `decode` convert between the two, and can also emit
+//! the section as a WAT custom-section annotation that `wasm-tools`
+//! accepts.
+
+use crate::directive::{Directive, DirectiveProvider};
+use waffle::entity::EntityRef;
+use wasm_encoder::Encode;
+use wasmparser::{BinaryReader, Parser, Payload};
+
+/// Name of the custom section.
+pub const SECTION_NAME: &str = "weval.directives";
+
+/// Encode directives as the contents of the section.
+pub fn encode(directives: &[Directive]) -> Vec<u8> {
+    let mut bytes = vec![];
+    directives.len().encode(&mut bytes);
+    for directive in directives {
+        directive.user_id.encode(&mut bytes);
+        directive.num_globals.encode(&mut bytes);
+        directive.args[..].encode(&mut bytes);
+    }
+    bytes
+}
+
+/// Decode the contents of the section.
+pub fn decode(bytes: &[u8]) -> anyhow::Result<Vec<Directive>> {
+    let mut reader = BinaryReader::new(bytes);
+    let mut directives = vec![];
+    for _ in 0..reader.read_var_u32()? {
+        let user_id = reader.read_var_u32()?;
+        let num_globals = reader.read_var_u32()?;
+        let len = reader.read_var_u32()? as usize;
+        let args = reader.read_bytes(len)?.to_vec();
+        directives.push(corpus_directive(user_id, num_globals, args));
+    }
+    if !reader.eof() {
+        anyhow::bail!(
+            "Trailing bytes at offset {} of {} section",
+            reader.original_position(),
+            SECTION_NAME
+        );
+    }
+    Ok(directives)
+}
+
+fn corpus_directive(user_id: u32, num_globals: u32, args: Vec<u8>) -> Directive {
+    Directive {
+        user_id,
+        func: waffle::Func::invalid(),
+        module: 0,
+        args,
+        num_globals,
+        func_index_out_addr: 0,
+        priority: 0,
+        base: None,
+    }
+}
+
+/// Read the section's contents from a module, if present.
+pub fn read(module: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::CustomSection(reader) = payload? {
+            if reader.name() == SECTION_NAME {
+                return Ok(Some(reader.data().to_vec()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Append the section to a module. The module must not already have
+/// one.
+pub fn append(module: &[u8], contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use wasm_encoder::Section;
+    if read(module)?.is_some() {
+        anyhow::bail!("Module already has a {} section", SECTION_NAME);
+    }
+    let section = wasm_encoder::CustomSection {
+        name: SECTION_NAME.into(),
+        data: contents.into(),
+    };
+    let mut bytes = module.to_vec();
+    bytes.push(section.id());
+    section.encode(&mut bytes);
+    Ok(bytes)
+}
+
+/// Render directives in the text form.
+pub fn to_text(directives: &[Directive]) -> String {
+    let mut text = "# user-id num-globals args-in-hex\n".to_owned();
+    for directive in directives {
+        text += &format!(
+            "{} {} {}\n",
+            directive.user_id,
+            directive.num_globals,
+            hex(&directive.args[..])
+        );
+    }
+    text
+}
+
+/// Parse directives from the text form.
+pub fn from_text(text: &str) -> anyhow::Result<Vec<Directive>> {
+    let mut directives = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (user_id, num_globals, args) = match &fields[..] {
+            &[user_id, num_globals] => (user_id, num_globals, ""),
+            &[user_id, num_globals, args] => (user_id, num_globals, args),
+            _ => anyhow::bail!(
+                "Line {}: expected <user-id> <num-globals> <args-in-hex>, got: {}",
+                i + 1,
+                line
+            ),
+        };
+        directives.push(corpus_directive(
+            user_id.parse()?,
+            num_globals.parse()?,
+            unhex(args).map_err(|e| anyhow::anyhow!("Line {}: {}", i + 1, e))?,
+        ));
+    }
+    Ok(directives)
+}
+
+/// Render the section's contents as a WAT custom-section annotation,
+/// to paste into a text-format module.
+pub fn to_wat(contents: &[u8]) -> String {
+    let mut wat = format!("(@custom \"{}\" \"", SECTION_NAME);
+    for byte in contents {
+        wat += &format!("\\{:02x}", byte);
+    }
+    wat += "\")\n";
+    wat
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        anyhow::bail!("Invalid hex string: {}", s);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}
+
+/// Directives from a module's `weval.directives` section.
+pub struct SectionDirectives(pub Vec<Directive>);
+
+impl SectionDirectives {
+    /// Read the section from a module, if present.
+    pub fn from_module(module: &[u8]) -> anyhow::Result<Option<SectionDirectives>> {
+        Ok(match read(module)? {
+            Some(contents) => Some(SectionDirectives(decode(&contents[..])?)),
+            None => None,
+        })
+    }
+}
+
+impl DirectiveProvider for SectionDirectives {
+    fn name(&self) -> String {
+        format!("{} section", SECTION_NAME)
+    }
+
+    fn corpus(&mut self, _module: &waffle::Module) -> anyhow::Result<Vec<Directive>> {
+        Ok(std::mem::take(&mut self.0))
+    }
+}
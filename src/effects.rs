@@ -0,0 +1,273 @@
+//! May-write summaries of callees.
+//!
+//! The evaluator tracks the values of globals across a specialized
+//! body, and store-to-load forwarding tracks the contents of memory
+//! locations within a block. A call may write either, so without
+//! knowing the callee, both must be forgotten at every call. For each
+//! function called from a generic function, and everything those call
+//! in turn, we compute a cheap, flow-insensitive summary of what a
+//! call to it may write: some memory, certain globals, or anything
+//! (for indirect calls, and calls to imports not known to be benign).
+
+use crate::collections::{HashMap, HashSet};
+use rayon::prelude::*;
+use std::collections::BTreeSet;
+use waffle::{Func, FuncDecl, FunctionBody, Global, Module, Operator, ValueDef};
+
+/// What a call to a function may write.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MayWrite {
+    /// Memory (any of it).
+    pub memory: bool,
+    /// These globals.
+    pub globals: BTreeSet<Global>,
+    /// Anything at all.
+    pub unknown: bool,
+}
+
+impl MayWrite {
+    fn unknown() -> MayWrite {
+        MayWrite {
+            unknown: true,
+            ..MayWrite::default()
+        }
+    }
+
+    /// Add the writes of `other`; returns whether anything changed.
+    fn union(&mut self, other: &MayWrite) -> bool {
+        let before = (self.memory, self.globals.len(), self.unknown);
+        self.memory |= other.memory;
+        self.globals.extend(other.globals.iter().copied());
+        self.unknown |= other.unknown;
+        before != (self.memory, self.globals.len(), self.unknown)
+    }
+}
+
+/// May-write summaries, by function. Functions without one may write
+/// anything.
+#[derive(Clone, Debug, Default)]
+pub struct Effects {
+    funcs: HashMap<Func, MayWrite>,
+}
+
+fn is_memory_read(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Load { .. }
+            | Operator::I64Load { .. }
+            | Operator::F32Load { .. }
+            | Operator::F64Load { .. }
+            | Operator::I32Load8S { .. }
+            | Operator::I32Load8U { .. }
+            | Operator::I32Load16S { .. }
+            | Operator::I32Load16U { .. }
+            | Operator::I64Load8S { .. }
+            | Operator::I64Load8U { .. }
+            | Operator::I64Load16S { .. }
+            | Operator::I64Load16U { .. }
+            | Operator::I64Load32S { .. }
+            | Operator::I64Load32U { .. }
+            | Operator::V128Load { .. }
+            | Operator::MemorySize { .. }
+    )
+}
+
+/// The writes of one function's own body, and its direct callees.
+fn local_effects(
+    module: &Module,
+    func: Func,
+    benign_imports: &HashSet<Func>,
+) -> anyhow::Result<(MayWrite, Vec<Func>)> {
+    let mut decl = module.funcs[func].clone();
+    if let FuncDecl::Import(..) = decl {
+        let is_intrinsic = module.imports.iter().any(|import| {
+            import.module == "weval"
+                && matches!(import.kind, waffle::ImportKind::Func(f) if f == func)
+        });
+        let effects = if benign_imports.contains(&func) {
+            MayWrite::default()
+        } else if is_intrinsic {
+            // Intrinsics that survive evaluation may spill to memory,
+            // but never write globals.
+            MayWrite {
+                memory: true,
+                ..MayWrite::default()
+            }
+        } else {
+            MayWrite::unknown()
+        };
+        return Ok((effects, vec![]));
+    }
+    decl.parse(module)?;
+    let body: &FunctionBody = match decl.body() {
+        Some(body) => body,
+        None => return Ok((MayWrite::unknown(), vec![])),
+    };
+
+    let mut effects = MayWrite::default();
+    let mut callees = vec![];
+    for def in body.values.values() {
+        let op = match def {
+            ValueDef::Operator(op, _, _) => op,
+            _ => continue,
+        };
+        match op {
+            Operator::Call { function_index } | Operator::ReturnCall { function_index } => {
+                callees.push(*function_index);
+            }
+            Operator::GlobalSet { global_index } => {
+                effects.globals.insert(*global_index);
+            }
+            op if op.is_call() => effects.unknown = true,
+            Operator::GlobalGet { .. } => {}
+            op if op.is_pure() || is_memory_read(op) => {}
+            // Stores, bulk memory, and anything else with side
+            // effects.
+            _ => effects.memory = true,
+        }
+    }
+    Ok((effects, callees))
+}
+
+impl Effects {
+    /// Summarize every function called directly from the given bodies,
+    /// and transitively from those.
+    pub fn compute<'a>(
+        module: &Module,
+        bodies: impl Iterator<Item = &'a FunctionBody>,
+        benign_imports: &HashSet<Func>,
+    ) -> anyhow::Result<Effects> {
+        let mut frontier = BTreeSet::new();
+        for body in bodies {
+            crate::callgraph::visit_func_refs(body, |f| {
+                frontier.insert(f);
+            });
+        }
+
+        // Scan the call graph breadth-first, a level at a time.
+        let mut local: HashMap<Func, (MayWrite, Vec<Func>)> = HashMap::default();
+        while !frontier.is_empty() {
+            let level = frontier
+                .into_par_iter()
+                .map(|func| Ok((func, local_effects(module, func, benign_imports)?)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            frontier = BTreeSet::new();
+            for (func, (effects, callees)) in level {
+                for &callee in &callees {
+                    if !local.contains_key(&callee) {
+                        frontier.insert(callee);
+                    }
+                }
+                local.insert(func, (effects, callees));
+            }
+            frontier.retain(|func| !local.contains_key(func));
+        }
+
+        // Propagate from callees to callers until nothing changes
+        // (recursion makes this a fixpoint).
+        let mut funcs = local
+            .iter()
+            .map(|(&func, (effects, _))| (func, effects.clone()))
+            .collect::<HashMap<_, _>>();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (&func, (_, callees)) in &local {
+                for callee in callees {
+                    if *callee == func {
+                        continue;
+                    }
+                    let callee_effects = funcs[callee].clone();
+                    changed |= funcs.get_mut(&func).unwrap().union(&callee_effects);
+                }
+            }
+        }
+
+        log::info!(
+            "Computed may-write summaries of {} functions ({} writing no memory)",
+            funcs.len(),
+            funcs
+                .values()
+                .filter(|effects| !effects.memory && !effects.unknown)
+                .count()
+        );
+        Ok(Effects { funcs })
+    }
+
+    /// What a call to `callee` may write, if known.
+    pub fn of_call(&self, callee: Func) -> Option<&MayWrite> {
+        self.funcs.get(&callee).filter(|effects| !effects.unknown)
+    }
+
+    /// Whether a call to `callee` may write memory.
+    pub fn may_write_memory(&self, callee: Func) -> bool {
+        self.of_call(callee).map_or(true, |effects| effects.memory)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+    use waffle::FrontendOptions;
+
+    #[test]
+    fn writes_propagate_to_callers() {
+        let bytes = wat::parse_str(
+            r#"
+            (module
+              (memory 1)
+              (global (mut i32) (i32.const 0))
+              (table 1 funcref)
+              (type $t (func))
+              (func $caller
+                (call $pure)
+                (call $sets_global)
+                (call $stores)
+                (call $indirect)
+                (call $recursive (i32.const 3)))
+              (func $pure
+                (drop (i32.add (i32.const 1) (i32.load (i32.const 0)))))
+              (func $sets_global
+                (global.set 0 (i32.const 1)))
+              (func $stores
+                (call $sets_global)
+                (i32.store (i32.const 0) (i32.const 1)))
+              (func $indirect
+                (call_indirect (type $t) (i32.const 0)))
+              (func $recursive (param i32)
+                (if (local.get 0)
+                  (then
+                    (call $recursive (i32.sub (local.get 0) (i32.const 1)))))))
+            "#,
+        )
+        .unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        let caller = module.clone_and_expand_body(Func::new(0)).unwrap();
+        let effects =
+            Effects::compute(&module, std::iter::once(&caller), &HashSet::default()).unwrap();
+
+        let global = Global::new(0);
+        let [pure, sets_global, stores, indirect, recursive] = [1, 2, 3, 4, 5].map(Func::new);
+        assert_eq!(effects.of_call(pure), Some(&MayWrite::default()));
+        assert_eq!(
+            effects.of_call(sets_global),
+            Some(&MayWrite {
+                globals: [global].into_iter().collect(),
+                ..MayWrite::default()
+            })
+        );
+        assert_eq!(
+            effects.of_call(stores),
+            Some(&MayWrite {
+                memory: true,
+                globals: [global].into_iter().collect(),
+                ..MayWrite::default()
+            })
+        );
+        assert_eq!(effects.of_call(indirect), None);
+        assert!(effects.may_write_memory(indirect));
+        assert_eq!(effects.of_call(recursive), Some(&MayWrite::default()));
+        assert!(!effects.may_write_memory(recursive));
+    }
+}
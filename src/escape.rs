@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use crate::collections::HashSet;
 /// Shadow-stack escape analysis optimization.
 ///
 /// Determines whether pointers derived from global 0 (the shadow
@@ -19,7 +19,7 @@ enum EscapeAnalysisResult {
 }
 
 fn shadow_stack_escapes(func: &FunctionBody, cfg: &CFGInfo) -> EscapeAnalysisResult {
-    let mut tainted = HashSet::new();
+    let mut tainted = HashSet::default();
     for (block_rpo, &block) in cfg.rpo.entries() {
         for &inst in &func.blocks[block].insts {
             match &func.values[inst] {
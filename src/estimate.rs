@@ -0,0 +1,243 @@
+//! Estimation mode: predict the size of specializations cheaply.
+//!
+//! `weval analyze --estimate` runs the evaluator over each directive,
+//! creating contexts and folding as when specializing, but stops
+//! short of finishing and optimizing the body or emitting code. It
+//! reports, per directive, the contexts and blocks created and a
+//! predicted size of the code, so that of many candidate directives
+//! the ones worth specializing in full can be picked out quickly.
+//!
+//! The predicted size is the number of instructions evaluated, times
+//! the bytes per instruction of the generic function as compiled by
+//! the same backend. As the cleanup passes run on real
+//! specializations are skipped, it tends to overestimate.
+//!
+//! Two options bound the time taken: sampling evaluates only a few
+//! directives per weval site, evenly spaced, and extrapolates the
+//! site's totals from them; a block limit stops evaluating a
+//! directive once it has created that many blocks, so that its
+//! estimate is a lower bound.
+
+use crate::directive::{Directive, DirectiveId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use waffle::{entity::EntityRef, FunctionBody};
+
+/// Options for estimation mode.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EstimateOptions {
+    /// Evaluate at most this many directives per weval site.
+    pub sample: Option<usize>,
+    /// Stop evaluating a directive once it has this many blocks.
+    pub max_blocks: Option<usize>,
+}
+
+/// How evaluation of a directive ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    /// Evaluation completed.
+    Complete,
+    /// Evaluation was stopped at the block limit; the estimate is a
+    /// lower bound.
+    CutOff,
+    /// Evaluation hit one of the evaluator's own limits, so the
+    /// directive would not be specialized.
+    Failed,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Complete => Ok(()),
+            Outcome::CutOff => write!(f, " [cut off]"),
+            Outcome::Failed => write!(f, " [would fail]"),
+        }
+    }
+}
+
+/// The estimate for one directive.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Estimate {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// Length of the directive's argument bytestring.
+    pub args_len: usize,
+    /// Index of the generic function.
+    pub func: usize,
+    /// Name of the generic function.
+    pub func_name: String,
+    pub contexts: usize,
+    pub blocks: usize,
+    pub insts: usize,
+    /// Predicted size of the compiled body, in bytes.
+    pub bytes: usize,
+    pub outcome: Outcome,
+}
+
+impl Estimate {
+    /// Estimate a directive's specialization from its (possibly
+    /// unfinished) body.
+    pub fn new(
+        directive: &Directive,
+        func_name: &str,
+        contexts: usize,
+        body: &FunctionBody,
+        bytes_per_inst: f64,
+        outcome: Outcome,
+    ) -> Estimate {
+        let insts = count_insts(body);
+        Estimate {
+            id: directive.id(),
+            user_id: directive.user_id,
+            args_len: directive.args.len(),
+            func: directive.func.index(),
+            func_name: func_name.to_owned(),
+            contexts,
+            blocks: body.blocks.len(),
+            insts,
+            bytes: (insts as f64 * bytes_per_inst).round() as usize,
+            outcome,
+        }
+    }
+}
+
+/// Instructions in a body, counting terminators.
+fn count_insts(body: &FunctionBody) -> usize {
+    body.blocks
+        .values()
+        .map(|block| block.insts.len() + 1)
+        .sum()
+}
+
+/// Bytes per instruction of a body as compiled, to scale instruction
+/// counts by.
+pub fn bytes_per_inst(body: &FunctionBody) -> anyhow::Result<f64> {
+    Ok(body.compile()?.byte_len() as f64 / count_insts(body).max(1) as f64)
+}
+
+/// Totals for one weval site.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SiteEstimate {
+    pub user_id: u32,
+    /// Number of directives for the site.
+    pub directives: usize,
+    /// Number of those evaluated.
+    pub sampled: usize,
+    /// Totals over all the site's directives, extrapolated from those
+    /// evaluated.
+    pub contexts: usize,
+    pub blocks: usize,
+    pub bytes: usize,
+    /// Number of evaluated directives cut off or failed.
+    pub incomplete: usize,
+}
+
+/// Choose which directives to evaluate: at most `per_site` per weval
+/// site, evenly spaced in the given order.
+pub fn sample(directives: &[Directive], per_site: usize) -> BTreeSet<DirectiveId> {
+    let mut by_site: BTreeMap<u32, Vec<DirectiveId>> = BTreeMap::new();
+    for directive in directives {
+        by_site
+            .entry(directive.user_id)
+            .or_default()
+            .push(directive.id());
+    }
+    let mut chosen = BTreeSet::new();
+    for ids in by_site.values() {
+        let n = ids.len();
+        let k = per_site.min(n);
+        chosen.extend((0..k).map(|i| ids[i * n / k]));
+    }
+    chosen
+}
+
+/// Total the estimates per site, given the number of directives each
+/// site had before sampling.
+pub fn sites(estimates: &[Estimate], directives: &BTreeMap<u32, usize>) -> Vec<SiteEstimate> {
+    directives
+        .iter()
+        .map(|(&user_id, &total)| {
+            let evaluated = estimates
+                .iter()
+                .filter(|estimate| estimate.user_id == user_id)
+                .collect::<Vec<_>>();
+            let sampled = evaluated.len();
+            let scale = |sum: usize| {
+                if sampled == 0 {
+                    0
+                } else {
+                    (sum as f64 * total as f64 / sampled as f64).round() as usize
+                }
+            };
+            SiteEstimate {
+                user_id,
+                directives: total,
+                sampled,
+                contexts: scale(evaluated.iter().map(|e| e.contexts).sum()),
+                blocks: scale(evaluated.iter().map(|e| e.blocks).sum()),
+                bytes: scale(evaluated.iter().map(|e| e.bytes).sum()),
+                incomplete: evaluated
+                    .iter()
+                    .filter(|e| e.outcome != Outcome::Complete)
+                    .count(),
+            }
+        })
+        .collect()
+}
+
+/// Produces a human-readable report: one line per directive, then
+/// totals per site and overall.
+pub fn report(estimates: &[Estimate], sites: &[SiteEstimate]) -> String {
+    let mut s = String::new();
+    for estimate in estimates {
+        writeln!(
+            &mut s,
+            "Directive {} (site {}, {} arg bytes) on function {} ({}): {} contexts, {} blocks, {} insts, ~{} bytes{}",
+            estimate.id,
+            estimate.user_id,
+            estimate.args_len,
+            estimate.func,
+            estimate.func_name,
+            estimate.contexts,
+            estimate.blocks,
+            estimate.insts,
+            estimate.bytes,
+            estimate.outcome
+        )
+        .unwrap();
+    }
+    for site in sites {
+        writeln!(
+            &mut s,
+            "Site {}: {} directive(s), {} evaluated{}: ~{} contexts, ~{} blocks, ~{} bytes{}",
+            site.user_id,
+            site.directives,
+            site.sampled,
+            if site.sampled < site.directives {
+                " (extrapolated)"
+            } else {
+                ""
+            },
+            site.contexts,
+            site.blocks,
+            site.bytes,
+            if site.incomplete > 0 {
+                format!(" ({} cut off or failing)", site.incomplete)
+            } else {
+                String::new()
+            }
+        )
+        .unwrap();
+    }
+    writeln!(
+        &mut s,
+        "Total: ~{} bytes over {} directive(s)",
+        sites.iter().map(|site| site.bytes).sum::<usize>(),
+        sites.iter().map(|site| site.directives).sum::<usize>()
+    )
+    .unwrap();
+    s
+}
@@ -1,21 +1,44 @@
 //! Partial evaluation.
 
-use crate::directive::{Directive, DirectiveArgs};
+use crate::alias::AliasPrecision;
+use crate::analyze::{
+    DirectiveAnalysis, LoadLossReason, LoadReport, PrecisionLoss, PrecisionLossKind, UnfoldedLoad,
+};
+use crate::branch_hints::FuncHints;
+use crate::collections::{HashMap, HashSet};
+use crate::ctx_block_map::CtxBlockMap;
+use crate::directive::{
+    BarrierElision, Directive, DirectiveArgs, DirectiveId, EntryFacts, FoldClass,
+    GenericFuncPolicy, OptLevel, OutOfBoundsReads, StrictIntrinsics,
+};
+use crate::effects::Effects;
+use crate::estimate::{Estimate, EstimateOptions, Outcome, SiteEstimate};
+use crate::filter::FuncIndexReloc;
+use crate::fold_log::{Fold, FoldLog};
 use crate::image::Image;
-use crate::intrinsics::{find_global_data_by_exported_func, Intrinsics};
+use crate::intrinsics::{
+    find_global_data_by_exported_func, find_untargeted_intrinsic_uses, Intrinsics,
+    UntargetedIntrinsicUse,
+};
 use crate::liveness::Liveness;
+use crate::manifest::{Manifest, ManifestEntry, RegionEpoch};
+use crate::passive::PassiveOp;
+use crate::size_report::SizeReport;
 use crate::state::*;
 use crate::stats::SpecializationStats;
-use crate::value::{AbstractValue, WasmVal};
-use fxhash::FxHashMap as HashMap;
-use fxhash::FxHashSet as HashSet;
+use crate::summary::FuncSummary;
+use crate::telemetry::DirectiveMetrics;
+use crate::value::{AbstractValue, Tag, WasmVal};
 use rayon::prelude::*;
-use std::collections::{hash_map::Entry as HashEntry, BTreeSet, VecDeque};
-use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{hash_map::Entry as HashEntry, BTreeMap, BTreeSet, BinaryHeap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use waffle::{
     cfg::CFGInfo, entity::EntityRef, entity::PerEntity, pool::ListRef, Block, BlockDef,
-    BlockTarget, FuncDecl, FunctionBody, Memory, MemoryArg, Module, Operator, Signature, SourceLoc,
-    Table, Terminator, Type, Value, ValueDef,
+    BlockTarget, Func, FuncDecl, FunctionBody, Memory, MemoryArg, Module, Operator, Signature,
+    SourceLoc, Table, Terminator, Type, Value, ValueDef,
 };
 
 struct Evaluator<'a> {
@@ -41,7 +64,7 @@ struct Evaluator<'a> {
     /// New function body.
     func: FunctionBody,
     /// Map of (ctx, block_in_generic) to specialized block_in_func.
-    block_map: HashMap<(Context, Block), Block>,
+    block_map: CtxBlockMap,
     /// Reverse map from specialized block to its original ctx/block.
     block_rev_map: PerEntity<Block, (Context, Block)>,
     /// Map of (ctx, value_in_generic) to specialized value_in_func.
@@ -49,36 +72,712 @@ struct Evaluator<'a> {
     /// Dependency map from a given value to any blocks (in
     /// specialized function) that must be re-evaluated if it changes.
     value_dep_blocks: HashMap<(Context, Value), BTreeSet<Block>>,
+    /// Reverse of `value_dep_blocks`: the values each specialized
+    /// block depended on when last evaluated. This only lets us skip
+    /// blocks that no longer use a changed value; a block that does
+    /// is still re-evaluated from its start, as there is no resuming
+    /// evaluation mid-block at the changed instruction.
+    block_deps: PerEntity<Block, Vec<(Context, Value)>>,
     /// Map of (ctx, block, idx) to blockparams for specialization-register values.
     reg_map: HashMap<(Context, Block, RegSlot), Value>,
-    /// Queue of blocks to (re)compute. List of (block_in_generic,
-    /// ctx, block_in_func).
-    queue: VecDeque<(Block, Context, Block)>,
+    /// Queue of blocks to (re)compute, as (ctx, RPO position of
+    /// block_in_generic, block_in_generic, block_in_func). Blocks are
+    /// taken in reverse postorder within each context, and contexts in
+    /// creation order, so that a block is usually evaluated after its
+    /// forward-edge predecessors have settled.
+    queue: BinaryHeap<Reverse<(Context, usize, Block, Block)>>,
     /// Set to deduplicate `queue`.
     queue_set: HashSet<(Block, Context)>,
+    /// If retiring drained contexts, the number of queued blocks in
+    /// the region of each context with any: the context and those
+    /// nested in it.
+    queued_in_region: Option<HashMap<Context, usize>>,
+    /// Contexts retired since their region last drained.
+    retired: HashSet<Context>,
     /// Stats accumulated during specialization.
     stats: SpecializationStats,
+    /// Branches (keyed by context and generic block) whose condition
+    /// is currently known only at runtime, with the condition value.
+    branch_losses: BTreeMap<(Context, Block), (PrecisionLossKind, Value)>,
+    /// Loads (keyed by context and generic value) from a known
+    /// address whose result is currently known only at runtime, with
+    /// the reason.
+    load_losses: BTreeMap<(Context, Value), (Block, LoadLossReason)>,
+    /// Why the load just evaluated was not folded, if it was not, as
+    /// noted by `unfolded_load` for `note_load_precision`.
+    pending_load_loss: Option<LoadLossReason>,
+    /// The address and size of the load just evaluated, if it read
+    /// out of bounds, as noted by `out_of_bounds_load`.
+    pending_oob_read: Option<(String, u32)>,
+    /// Out-of-bounds constant-memory reads, by context and load, with
+    /// a description of each. As with `load_losses`, entries are
+    /// removed if the load is re-evaluated and folds.
+    oob_reads: BTreeMap<(Context, Value), String>,
+    oob_reads_mode: OutOfBoundsReads,
+    /// Epochs of memory regions declared via `weval.region.epoch`
+    /// that this specialization assumes, keyed by (address, length).
+    region_epochs: BTreeMap<(u32, u32), u32>,
+    /// The operand stack declared via `weval.stack.declare`, if any.
+    declared_stack: Option<OperandStack>,
+    /// Whether the operand stack was declared twice, differently; if
+    /// so, pointers into it can't be told apart and none are tracked.
+    stack_conflict: bool,
+    /// Classes of operators not to fold in this specialization (see
+    /// `PartialEvalOptions::no_fold`).
+    no_fold: BTreeSet<FoldClass>,
+    /// Folds in the latest evaluation of each generic value in each
+    /// context, as (operator, inputs, result), if logging them.
+    folds: Option<BTreeMap<(Context, Value), (String, Vec<String>, String)>>,
+    /// Number of operators folded in the latest evaluation of each
+    /// generic block in each context.
+    block_folds: HashMap<(Context, Block), usize>,
+    /// Optimization level for this specialization.
+    opt_level: OptLevel,
+    /// PC range to specialize, if restricted (see
+    /// `PartialEvalOptions::pc_ranges`).
+    pc_range: Option<(u32, u32)>,
+    barrier_elision: BarrierElision,
+    /// Blocks in the generic function from which every path reaches
+    /// `unreachable`, if pruning such paths.
+    doomed: &'a HashSet<Block>,
+    /// The block that pruned edges target, once created.
+    trap_block: Option<Block>,
+    /// (context, generic block) pairs pruned, for reporting.
+    pruned: BTreeSet<(Context, Block)>,
+    /// Labels given by `weval.trace.here`, per context (the most
+    /// recent one seen).
+    labels: BTreeMap<Context, String>,
+    /// Metering to preserve, if any.
+    metering: Option<Metering>,
+    /// Loops whose first iteration is peeled: body blocks, keyed by
+    /// header.
+    peeled_loops: &'a HashMap<Block, HashSet<Block>>,
+    /// Automatically detected dispatch loops, by header.
+    auto_loops: &'a HashMap<Block, AutoLoop>,
+    /// Input branch hints, keyed by `branch_hints::branch_key`.
+    branch_hints: &'a HashMap<Block, bool>,
+    /// Hints carried over to residual conditional branches, by
+    /// specialized block.
+    hinted_branches: HashMap<Block, bool>,
+    /// Summaries of small callees.
+    summaries: &'a HashMap<Func, FuncSummary>,
+    /// What calls to each function may write.
+    effects: &'a Effects,
+    /// Remaining number of blocks that may be created by hoisting a
+    /// constant blockparam into the target context.
+    hoist_budget: usize,
+    /// Globals whose constant values are keyed into the context.
+    context_globals: &'a [waffle::Global],
+    /// Environment constants for `weval.env.u32`, for every site.
+    env: &'a BTreeMap<String, u32>,
+    /// Environment constants for `weval.env.u32` for this site.
+    site_env: Option<&'a BTreeMap<String, u32>>,
+    /// Failed `weval.assert.const32` checks, by context and call,
+    /// with a description of each. A check may fail while its block's
+    /// state is still incomplete, so entries are removed if the block
+    /// is re-evaluated with a constant; any left at the end fail the
+    /// specialization.
+    assert_failures: BTreeMap<(Context, Value), String>,
+    /// Calls to intrinsics whose arguments could not be acted on, by
+    /// context and call, with a description of each, if flagging them.
+    /// As with `assert_failures`, the last evaluation of each call
+    /// wins.
+    unfolded_intrinsics: BTreeMap<(Context, Value), String>,
+    /// What the intrinsic just evaluated could not do, if anything,
+    /// for `note_unfolded_intrinsic`.
+    pending_intrinsic_problem: Option<String>,
+    strict_intrinsics: StrictIntrinsics,
+    /// Values reported by `weval.print.value`, by context and call.
+    /// As with `assert_failures`, the last evaluation of each call
+    /// wins.
+    printed_values: BTreeMap<(Context, Value), PrintedValue>,
+    /// Imported globals, with any assumed values.
+    imported_globals: &'a ImportedGlobals,
+    /// Runtime values (by context and generic value) that derive from
+    /// imported globals with no assumed value, with those globals.
+    blocked_on: HashMap<(Context, Value), BTreeSet<waffle::Global>>,
+    /// The imported globals the instruction just evaluated is blocked
+    /// on, for `def_value`.
+    pending_blocked_on: BTreeSet<waffle::Global>,
+    /// What the base specialization found, if this is a delta
+    /// directive.
+    base: Option<&'a BaseFacts>,
+    /// In estimation mode, the number of blocks at which to stop
+    /// evaluating, if any.
+    block_limit: Option<usize>,
+    /// Whether evaluation stopped at `block_limit`.
+    cut_off: bool,
+    /// Cut blocks of the generic body's max-SSA form: those at which
+    /// the generic function can be resumed.
+    cut_blocks: &'a std::collections::HashSet<Block>,
+    /// When to stop evaluating and resume the generic function at the
+    /// blocks not yet evaluated, if ever.
+    deadline: Option<Instant>,
+    /// The generic function, made resumable at those blocks, once
+    /// evaluation has been cut short.
+    resume: Option<crate::split::Resumable>,
+}
+
+/// What a specialization found, for specializing delta directives
+/// relative to it: its context tree, and the entry state of each
+/// (context, generic block) it reached.
+struct BaseFacts {
+    contexts: Contexts,
+    entries: HashMap<(Context, Block), ProgPointState>,
+}
+
+/// Options controlling partial evaluation.
+#[derive(Clone, Debug, Default)]
+pub struct PartialEvalOptions {
+    /// Output IR for generic and specialized functions to files in
+    /// this directory, if given.
+    pub output_ir: Option<std::path::PathBuf>,
+    /// Dump the final entry state of every specialized block to this
+    /// file, if given (see `BlockStatesDump`).
+    pub output_block_states: Option<std::path::PathBuf>,
+    /// What to do with each generic function after specialization,
+    /// keyed by the user ID of the weval site. Sites not listed use
+    /// the default (keep).
+    pub generic_func_policies: BTreeMap<u32, GenericFuncPolicy>,
+    /// Only run the abstract interpreter and report precision losses
+    /// per directive; do not emit any specialized functions.
+    pub analyze: bool,
+    /// Only run the abstract interpreter and estimate the size of each
+    /// directive's specialization (see `estimate`); do not emit any
+    /// specialized functions.
+    pub estimate: Option<EstimateOptions>,
+    /// Precision of alias queries in memory optimizations on
+    /// specialized functions.
+    pub alias_precision: AliasPrecision,
+    /// Offset of this module's table within the shared table, for a
+    /// linked side module; added to the table indices of specialized
+    /// functions written to memory.
+    pub table_base: u32,
+    /// Maximum number of blocks per specialization that may be
+    /// created by specializing a block on a constant blockparam
+    /// (hoisting it into the context) when its predecessors disagree
+    /// on the constant. Zero disables this.
+    pub max_hoisted_blocks: usize,
+    /// Drop the values of globals from the block states of each
+    /// context whose region has drained from the queue.
+    pub retire_drained_contexts: bool,
+    /// Priority of each weval site's directives, keyed by user ID.
+    /// Sites not listed have priority zero.
+    pub priorities: BTreeMap<u32, i32>,
+    /// Maximum total size, in bytes, of specialized function bodies
+    /// added to the module. Directives are admitted in priority
+    /// order; once one does not fit, it and all lower-priority ones
+    /// are skipped.
+    pub max_added_bytes: Option<usize>,
+    /// Instead of skipping a directive over `max_added_bytes`, add a
+    /// wrapper for it (see `OptLevel::Wrap`) if that fits.
+    pub wrap_over_budget: bool,
+    /// Optimization level for directives whose site is not listed in
+    /// `opt_levels`.
+    pub opt_level: OptLevel,
+    /// Optimization level per weval site, keyed by user ID.
+    pub opt_levels: BTreeMap<u32, OptLevel>,
+    /// Do not specialize paths that always reach `unreachable` (e.g.,
+    /// an interpreter's invalid-opcode handler reached with a
+    /// constant opcode); branch to a trap instead, and warn, as this
+    /// usually indicates bad bytecode or a bad directive. Side
+    /// effects on such paths (e.g., printing an error) are dropped.
+    pub prune_unreachable: bool,
+    /// Charge folded-away instructions to a metering global, if
+    /// given.
+    pub metering: Option<Metering>,
+    /// Specialize the first iteration of loops annotated with
+    /// `weval.peel.loop` separately from the rest, so that state
+    /// established in the first iteration may be constant in the
+    /// steady-state loop.
+    pub peel_loops: bool,
+    /// After specialization, also run waffle's cleanup passes (alias
+    /// resolution, GVN, constant propagation, redundant-blockparam
+    /// removal) over every other function in the module. This lifts
+    /// and recompiles functions that would otherwise be copied
+    /// through unchanged.
+    pub optimize_all_funcs: bool,
+    /// Globals to key into the context per weval site, keyed by user
+    /// ID. Where such a global holds a known integer, blocks are
+    /// specialized on its value as with a PC, so that it survives
+    /// loop backedges (e.g., an interpreter's frame pointer held in
+    /// a global). As with PCs, each global should take only a few
+    /// values over a specialization.
+    pub context_globals: BTreeMap<u32, Vec<waffle::Global>>,
+    /// Report, per directive, every load from a known address that
+    /// was not folded, and why.
+    pub explain_loads: bool,
+    /// Environment constants for `weval.env.u32`, for every site.
+    pub env: BTreeMap<String, u32>,
+    /// Environment constants for `weval.env.u32` per weval site,
+    /// keyed by user ID; these take precedence over `env`.
+    pub site_env: BTreeMap<u32, BTreeMap<String, u32>>,
+    /// Attribute the compiled size of each specialized function to
+    /// the generic blocks and contexts it came from.
+    pub size_report: bool,
+    /// Where a `call_indirect`'s table index is one of a few
+    /// constants chosen at runtime, emit direct calls to each
+    /// candidate guarded by an index check, falling back to the
+    /// indirect call.
+    pub guarded_devirt: bool,
+    /// At each specialized join point, check at runtime that
+    /// blockparams assumed constant have their assumed values, and
+    /// trap (via `weval_const_assertion_failed`, with the specialized
+    /// block's index) otherwise.
+    pub debug_assert_consts: bool,
+    /// Append a table of the specializations in the module to memory,
+    /// and export a function `weval_metadata` returning its address.
+    pub emit_metadata: bool,
+    /// In functions without context intrinsics, detect interpreter
+    /// dispatch loops (a loop that branches on a value loaded from a
+    /// header blockparam, the PC) and specialize them per PC as if
+    /// annotated. Requires -O2 or higher.
+    pub auto_loop_headers: bool,
+    /// Process only the directives with these IDs (see
+    /// `Directive::id`), if nonempty.
+    pub only_ids: Vec<DirectiveId>,
+    /// Assumed values (as bits) of imported globals, keyed by import
+    /// module and name.
+    pub imported_globals: BTreeMap<(String, String), u64>,
+    /// User IDs of weval sites whose generic function's table entries
+    /// are rewritten to point at its specialization.
+    pub rewrite_elems: BTreeSet<u32>,
+    /// Imported functions, by module and name, known not to write
+    /// guest memory.
+    pub benign_imports: BTreeSet<(String, String)>,
+    /// Move constants defined in at least this many specialized
+    /// functions into immutable globals, if given.
+    pub const_globals_min_defs: Option<usize>,
+    /// Split each specialized function into one function per this
+    /// many bytecode PCs, calling each other with tail calls (see
+    /// `split`), if given.
+    pub split_pc_range: Option<u32>,
+    /// Base directive of each delta directive, by ID. A delta
+    /// directive is specialized after its base, which must target the
+    /// same function with a subset of its constants, starting from the
+    /// base's context tree and taking the base's facts wherever its
+    /// own are no more precise.
+    pub delta_bases: BTreeMap<DirectiveId, DirectiveId>,
+    /// Classes of operators never to fold, per weval site, keyed by
+    /// user ID. Such operators are kept as runtime computations in
+    /// the specialized code, though what they depend on may still be
+    /// constant.
+    pub no_fold: BTreeMap<u32, BTreeSet<FoldClass>>,
+    /// Record every fold performed in each specialization (see
+    /// `fold_log`).
+    pub fold_log: bool,
+    /// Collect per-directive metrics (see `telemetry`).
+    pub metrics: bool,
+    /// Whether to flag calls to intrinsics whose arguments can't be
+    /// acted on.
+    pub strict_intrinsics: StrictIntrinsics,
+    /// Macro-op fusion patterns to apply to specialized bodies at
+    /// `-O3` (see `fusion`); none by default.
+    pub fusion_patterns: Vec<crate::fusion::Pattern>,
+    /// Time to allow each specialization, if limited. One that runs
+    /// out keeps the blocks it has specialized and resumes the
+    /// generic function at the rest (see `split::Resumable`); it
+    /// fails only if that isn't possible.
+    pub timeout: Option<Duration>,
+    /// Per-site overrides of `timeout`, keyed by user ID.
+    pub timeouts: BTreeMap<u32, Duration>,
+    /// Entry facts supplied by the embedder per directive, by ID,
+    /// applied over the directive's decoded arguments.
+    pub entry_facts: BTreeMap<DirectiveId, EntryFacts>,
+    /// Bytecode PC range `[lo, hi)` to specialize, per weval site,
+    /// keyed by user ID. At an `update_context` with a PC outside the
+    /// range, the interpreter loop branches back to the generic
+    /// dispatch path (the loop in the enclosing context) rather than
+    /// into a new PC context; sites not listed specialize all PCs.
+    pub pc_ranges: BTreeMap<u32, (u32, u32)>,
+    /// Which `weval.barrier.needed` checks on constant values to fold
+    /// to "not needed", eliding the guarded write barriers.
+    pub barrier_elision: BarrierElision,
+    /// What to do about loads from constant memory that read out of
+    /// bounds.
+    pub oob_reads: OutOfBoundsReads,
+    /// Embedder passes to run, in order, over each specialized body
+    /// after weval's own cleanup passes and before it is added to
+    /// the module.
+    pub post_passes: Vec<PostPass>,
+}
+
+impl PartialEvalOptions {
+    /// The optimization level for directives of the given site.
+    pub fn opt_level_for(&self, user_id: u32) -> OptLevel {
+        self.opt_levels
+            .get(&user_id)
+            .copied()
+            .unwrap_or(self.opt_level)
+    }
+
+    pub fn timeout_for(&self, user_id: u32) -> Option<Duration> {
+        self.timeouts.get(&user_id).copied().or(self.timeout)
+    }
+}
+
+/// A metering (fuel) global maintained by the guest's instrumentation.
+///
+/// Metering tools (e.g. `wasm-meter`) charge each basic block for the
+/// instructions it executes by decrementing a global. Specialized code
+/// executes fewer instructions than the generic code it came from, so
+/// a module metered after specialization would be charged less for
+/// the same work. With this option, each specialized block charges
+/// the global `cost` units for each instruction of its generic block
+/// that was folded away (to a constant, an alias of another value, or
+/// nothing), keeping the total charge equal to that of the generic
+/// code.
+#[derive(Clone, Copy, Debug)]
+pub struct Metering {
+    /// The global to decrement; must be a mutable `i32` or `i64`.
+    pub global: waffle::Global,
+    /// Units charged per folded instruction.
+    pub cost: u64,
+}
+
+/// What a post-pass (see `PostPass`) may know about the
+/// specialization whose body it transforms.
+#[derive(Clone, Debug)]
+pub struct SpecializationInfo {
+    /// The directive's ID.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// The generic function.
+    pub generic: Func,
+    /// The optimization level the body was specialized at.
+    pub opt_level: OptLevel,
+    /// The generic value each specialized value was evaluated from,
+    /// for values the evaluator created. Values since removed by
+    /// cleanup passes may remain in the map.
+    pub value_origins: HashMap<Value, Value>,
+    /// The generic block each specialized block was evaluated from.
+    pub block_origins: HashMap<Block, Block>,
+}
+
+/// A transformation over a specialized function body, registered by
+/// an embedder in `PartialEvalOptions::post_passes`. Specializations
+/// run in parallel, so the pass must be `Send + Sync`.
+#[derive(Clone)]
+pub struct PostPass {
+    /// A name, for logs.
+    pub name: String,
+    pass: Arc<dyn Fn(&mut FunctionBody, &SpecializationInfo) + Send + Sync>,
+}
+
+impl PostPass {
+    pub fn new(
+        name: impl Into<String>,
+        pass: impl Fn(&mut FunctionBody, &SpecializationInfo) + Send + Sync + 'static,
+    ) -> PostPass {
+        PostPass {
+            name: name.into(),
+            pass: Arc::new(pass),
+        }
+    }
+
+    pub fn run(&self, body: &mut FunctionBody, info: &SpecializationInfo) {
+        (self.pass)(body, info)
+    }
+}
+
+impl std::fmt::Debug for PostPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PostPass({})", self.name)
+    }
 }
 
 pub struct PartialEvalResult<'a> {
     pub module: Module<'a>,
     pub global_base: usize,
     pub stats: Vec<SpecializationStats>,
+    /// Generic functions to be marked cold in the output.
+    pub cold_funcs: Vec<Func>,
+    /// Per-directive precision-loss reports, in analysis mode.
+    pub analyses: Vec<DirectiveAnalysis>,
+    /// Per-directive size estimates, in estimation mode.
+    pub estimates: Vec<Estimate>,
+    /// Size estimates per weval site, in estimation mode.
+    pub site_estimates: Vec<SiteEstimate>,
+    /// Functions that call intrinsics but are targeted by no
+    /// directive.
+    pub untargeted_intrinsic_uses: Vec<UntargetedIntrinsicUse>,
+    /// Manifest of all specializations added to the module.
+    pub manifest: Manifest,
+    /// Directives skipped because they would exceed the size
+    /// budget.
+    pub skipped: Vec<Directive>,
+    /// Directives added only as wrappers (see `OptLevel::Wrap`)
+    /// because they would exceed the size budget.
+    pub wrapped: Vec<Directive>,
+    /// Directives added only partially specialized because they ran
+    /// out of time (see `PartialEvalOptions::timeout`).
+    pub partial: Vec<Directive>,
+    /// Total size, in bytes, of specialized function bodies added.
+    pub added_bytes: usize,
+    /// Writes of specialized functions' table indices into memory.
+    pub relocs: Vec<FuncIndexReloc>,
+    /// Values reported by `weval.print.value`.
+    pub printed_values: Vec<PrintedValue>,
+    /// Unfolded loads per directive, if explaining loads.
+    pub load_reports: Vec<LoadReport>,
+    /// Size attribution per added specialized function, if requested.
+    pub size_reports: Vec<SizeReport>,
+    /// Folds performed per added specialized function, if requested.
+    pub fold_logs: Vec<FoldLog>,
+    /// Metrics of each directive evaluated, if requested.
+    pub directive_metrics: Vec<DirectiveMetrics>,
+    /// Branch hints for the output, by function: those of added
+    /// specialized functions, and those of the input for functions
+    /// copied through unchanged.
+    pub branch_hints: BTreeMap<Func, FuncHints>,
+}
+
+/// The abstract value of a `weval.print.value` argument in one
+/// context, for printf-style debugging of a specialization.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PrintedValue {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// The label given at the call.
+    pub label: String,
+    /// The call's source location, from debug info, if any.
+    pub loc: Option<String>,
+    /// Description of each element of the context stack, from root to
+    /// leaf.
+    pub context_stack: Vec<String>,
+    /// The abstract value.
+    pub value: String,
+}
+
+/// A generic function prepared for specialization, with its
+/// analyses. Computed once per function and shared by every directive
+/// that targets it.
+struct GenericFunc {
+    /// The body, split at intrinsic calls and in max-SSA form.
+    body: FunctionBody,
+    /// Hash of the body as read from the input, for the manifest.
+    fingerprint: u64,
+    /// Estimated cost of the body as read from the input (see
+    /// `cost`), for the manifest.
+    cost: u64,
+    cfg: CFGInfo,
+    /// Stats for all specializations of this function.
+    stats: Mutex<SpecializationStats>,
+    /// Loops whose first iteration is peeled: body blocks, keyed by
+    /// header. Empty unless peeling loops.
+    peeled_loops: HashMap<Block, HashSet<Block>>,
+    auto_loops: HashMap<Block, AutoLoop>,
+    /// Blocks from which every path reaches `unreachable`. Empty
+    /// unless pruning such paths.
+    doomed: HashSet<Block>,
+    /// The cut blocks of `body`'s max-SSA form.
+    cut_blocks: std::collections::HashSet<Block>,
+    /// Input branch hints, keyed by `branch_hints::branch_key`.
+    branch_hints: HashMap<Block, bool>,
+    /// Compiled bytes per instruction of the body as read from the
+    /// input, in estimation mode.
+    bytes_per_inst: f64,
+}
+
+impl GenericFunc {
+    fn new(
+        module: &Module,
+        func: Func,
+        intrinsics: &Intrinsics,
+        opts: &PartialEvalOptions,
+        hints: Option<&FuncHints>,
+    ) -> anyhow::Result<GenericFunc> {
+        let mut body = module.clone_and_expand_body(func)?;
+        let fingerprint = crate::meta::hash_bytes(format!("{}", body.display("", None)).as_bytes());
+        let cost = crate::cost::estimate(&body);
+        let branch_hints = hints
+            .map(|hints| crate::branch_hints::hinted_targets(&body, hints))
+            .unwrap_or_default();
+
+        if let Some(path) = &opts.output_ir {
+            let mut generic_ir_file = path.clone();
+            generic_ir_file.push(&format!("generic_{}.txt", func));
+            std::fs::write(
+                &generic_ir_file,
+                format!("{}", body.display_verbose("", Some(module))),
+            )?;
+        }
+
+        let stats = Mutex::new(SpecializationStats::new(func, &body));
+        let bytes_per_inst = if opts.estimate.is_some() {
+            crate::estimate::bytes_per_inst(&body)?
+        } else {
+            0.0
+        };
+
+        split_blocks_at_intrinsic_calls(&mut body, intrinsics);
+
+        body.recompute_edges();
+        let cfg = CFGInfo::new(&body);
+        let peeled_loops = if opts.peel_loops {
+            find_peeled_loops(&body, &cfg, intrinsics)
+        } else {
+            HashMap::default()
+        };
+        let auto_loops = if opts.auto_loop_headers {
+            find_auto_loops(&body, &cfg, intrinsics)
+        } else {
+            HashMap::default()
+        };
+        let cut_blocks = find_cut_blocks(&body, &cfg, intrinsics, &peeled_loops, &auto_loops);
+
+        body.convert_to_max_ssa(Some(cut_blocks.clone()));
+
+        let doomed = if opts.prune_unreachable {
+            find_doomed_blocks(&body)
+        } else {
+            HashSet::default()
+        };
+
+        Ok(GenericFunc {
+            body,
+            fingerprint,
+            cost,
+            cfg,
+            stats,
+            peeled_loops,
+            auto_loops,
+            doomed,
+            cut_blocks,
+            branch_hints,
+            bytes_per_inst,
+        })
+    }
+}
+
+/// The result of specializing one function.
+struct SpecializedFunc {
+    body: FunctionBody,
+    sig: Signature,
+    name: String,
+    stats: SpecializationStats,
+    block_states: Option<Vec<BlockEntryState>>,
+    region_epochs: Vec<RegionEpoch>,
+    /// Origin (generic block and context stack) of each block, if
+    /// attributing sizes.
+    origins: Option<BTreeMap<Block, (usize, Vec<String>)>>,
+    /// Folds performed, if logging them.
+    folds: Option<Vec<Fold>>,
+    /// Input branch hints carried over to conditional branches, by
+    /// block.
+    hinted_branches: HashMap<Block, bool>,
+    /// Innermost PC of each block's context, if splitting by PC
+    /// range.
+    block_pcs: Option<BTreeMap<Block, PC>>,
+    /// What the specialization found, if a delta directive is based
+    /// on it.
+    facts: Option<BaseFacts>,
+    /// The generic function, resumable where the specialization was
+    /// cut short, if it was.
+    resume: Option<crate::split::Resumable>,
+}
+
+/// The final block-entry states of one specialized function.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpecializationBlockStates {
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// The directive's argument bytestring.
+    pub args: Vec<u8>,
+    /// Index of the generic function.
+    pub generic_func: usize,
+    /// Index of the specialized function in the output module.
+    pub specialized_func: usize,
+    /// Entry state of every specialized block.
+    pub blocks: Vec<BlockEntryState>,
+}
+
+/// A dump of the block states of every added specialization, written
+/// as each is added so that they are not all held until the end. It
+/// decodes as a bincode `Vec<SpecializationBlockStates>`, in priority
+/// order; the length is filled in once all are written.
+struct BlockStatesDump {
+    file: std::io::BufWriter<std::fs::File>,
+    len: u64,
+}
+
+impl BlockStatesDump {
+    fn create(path: &std::path::Path) -> anyhow::Result<Self> {
+        use std::io::Write;
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        file.write_all(&0u64.to_le_bytes())?;
+        Ok(BlockStatesDump { file, len: 0 })
+    }
+
+    fn push(&mut self, states: &SpecializationBlockStates) -> anyhow::Result<()> {
+        bincode::serialize_into(&mut self.file, states)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        use std::io::{Seek, Write};
+        let mut file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(std::io::SeekFrom::Start(0))?;
+        file.write_all(&self.len.to_le_bytes())?;
+        Ok(())
+    }
 }
 
 /// Partially evaluates according to the given directives. Returns
-/// clone of original module, with tracing added.
+/// clone of original module, with tracing added. `progress`, if given,
+/// is called once per directive as its specialization completes.
 pub fn partially_evaluate<'a>(
     mut module: Module<'a>,
     im: &mut Image,
     directives: &[Directive],
     corpus: &[Directive],
-    mut progress: Option<indicatif::ProgressBar>,
-    output_ir: Option<std::path::PathBuf>,
+    progress: Option<&(dyn Fn() + Sync)>,
+    opts: &PartialEvalOptions,
+    branch_hints: &BTreeMap<u32, FuncHints>,
 ) -> anyhow::Result<PartialEvalResult<'a>> {
     let intrinsics = Intrinsics::find(&module);
     log::trace!("intrinsics: {:?}", intrinsics);
 
+    crate::preflight::check(&module, im, &intrinsics, directives, corpus)?;
+
+    let const_assert_trap = if opts.debug_assert_consts && !opts.analyze && opts.estimate.is_none()
+    {
+        Some(add_const_assert_trap(&mut module))
+    } else {
+        None
+    };
+
+    if let Some(metering) = opts.metering {
+        let global = module
+            .globals
+            .get(metering.global)
+            .ok_or_else(|| anyhow::anyhow!("Metering global {} not found", metering.global))?;
+        if !global.mutable || !matches!(global.ty, Type::I32 | Type::I64) {
+            anyhow::bail!(
+                "Metering global {} must be a mutable i32 or i64",
+                metering.global
+            );
+        }
+    }
+
+    for &global in opts.context_globals.values().flatten() {
+        let ty = module
+            .globals
+            .get(global)
+            .ok_or_else(|| anyhow::anyhow!("Context global {} not found", global))?
+            .ty;
+        if !matches!(ty, Type::I32 | Type::I64) {
+            anyhow::bail!("Context global {} must be an i32 or i64", global);
+        }
+    }
+
+    let imported_globals = ImportedGlobals::new(&module, opts)?;
+    let benign_imports = benign_import_funcs(&module, opts);
+
     // Sort directives by out-address, and remove duplicates.
     let mut directives = directives.to_vec();
     directives.sort_by_key(|d| d.func_index_out_addr);
@@ -109,130 +808,588 @@ pub fn partially_evaluate<'a>(
         d
     }));
 
-    // Expand function bodies of any function named in a directive.
-    let mut funcs = HashMap::default();
-    for directive in &directives {
-        if !funcs.contains_key(&directive.func) {
-            let mut f = module.clone_and_expand_body(directive.func)?;
-
-            if let Some(path) = &output_ir {
-                let mut generic_ir_file = path.clone();
-                generic_ir_file.push(&format!("generic_{}.txt", directive.func));
-                std::fs::write(
-                    &generic_ir_file,
-                    format!("{}", f.display_verbose("", Some(&module))),
-                )
-                .unwrap();
-            }
-
-            let stats = Mutex::new(SpecializationStats::new(directive.func, &f));
-
-            split_blocks_at_intrinsic_calls(&mut f, &intrinsics);
-
-            f.recompute_edges();
-            let cfg = CFGInfo::new(&f);
-            let cut_blocks = find_cut_blocks(&f, &cfg, &intrinsics);
+    // Order by priority, highest first (stably, so that equal
+    // priorities keep their order).
+    for directive in &mut directives {
+        directive.priority = opts
+            .priorities
+            .get(&directive.user_id)
+            .copied()
+            .unwrap_or(0);
+    }
+    directives.sort_by_key(|d| std::cmp::Reverse(d.priority));
 
-            f.convert_to_max_ssa(Some(cut_blocks));
+    if !opts.only_ids.is_empty() {
+        directives.retain(|d| opts.only_ids.contains(&d.id()));
+        log::info!("{} directive(s) selected by ID", directives.len());
+    }
 
-            funcs.insert(directive.func, (f, cfg, stats));
+    // In estimation mode, count each site's directives, then keep
+    // only a sample of them if asked.
+    let mut site_directives = BTreeMap::new();
+    if let Some(estimate) = &opts.estimate {
+        for directive in &directives {
+            *site_directives.entry(directive.user_id).or_insert(0) += 1;
+        }
+        if let Some(per_site) = estimate.sample {
+            let sampled = crate::estimate::sample(&directives[..], per_site);
+            directives.retain(|d| sampled.contains(&d.id()));
+            log::info!("{} directive(s) sampled for estimation", directives.len());
         }
     }
 
-    if let Some(p) = progress.as_mut() {
-        p.set_length(directives.len() as u64);
+    // Name each delta directive's base, if it's one we can specialize
+    // relative to.
+    let bases = directives
+        .iter()
+        .map(|directive| {
+            let base_id = *opts.delta_bases.get(&directive.id())?;
+            let base = directives.iter().find(|base| base.id() == base_id);
+            match check_delta_base(directive, base, opts) {
+                Ok(()) => Some(base_id),
+                Err(e) => {
+                    log::warn!(
+                        "Specializing directive {} without base {}: {}",
+                        directive.id(),
+                        base_id,
+                        e
+                    );
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    for (directive, base) in directives.iter_mut().zip(bases.into_iter()) {
+        directive.base = base;
     }
+    let base_ids = directives
+        .iter()
+        .filter_map(|directive| directive.base)
+        .collect::<BTreeSet<_>>();
+
+    // Find intrinsic calls that will never take effect because no
+    // directive targets their function.
+    let targeted = directives.iter().map(|d| d.func).collect::<BTreeSet<_>>();
+    let untargeted_intrinsic_uses = find_untargeted_intrinsic_uses(&module, &targeted)?;
+
+    // Expand and analyze the body of each function named in a
+    // directive, once.
+    let funcs = directives
+        .iter()
+        .map(|directive| directive.func)
+        .collect::<BTreeSet<_>>()
+        .into_par_iter()
+        .map(|func| {
+            let hints = branch_hints.get(&(func.index() as u32));
+            Ok((
+                func,
+                GenericFunc::new(&module, func, &intrinsics, opts, hints)?,
+            ))
+        })
+        .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+
+    // Within a priority, take the directives for callees before those
+    // for their callers (and otherwise keep their order), so that the
+    // order of processing and of admission under a size budget
+    // follows the call graph rather than the order in which the
+    // directives happened to be collected.
+    let call_order = crate::callgraph::callee_first_order(
+        funcs.iter().map(|(&func, generic)| (func, &generic.body)),
+    );
+    directives.sort_by_key(|d| (std::cmp::Reverse(d.priority), call_order[&d.func]));
+
+    let summaries =
+        crate::summary::summarize_callees(&module, funcs.values().map(|generic| &generic.body));
+    let effects = Effects::compute(
+        &module,
+        funcs.values().map(|generic| &generic.body),
+        &benign_imports,
+    )?;
 
     let global_base = module.globals.len();
 
-    let progress_ref = progress.as_ref();
-    let bodies = directives
-        .par_iter()
-        .flat_map(|directive| {
-            let (generic, cfg, stats) = funcs.get(&directive.func).unwrap();
-            let result =
-                match partially_evaluate_func(&module, generic, cfg, im, &intrinsics, directive) {
-                    Ok(result) => result,
-                    Err(e) => return Some(Err(e)),
-                };
+    let analyses = Mutex::new(vec![]);
+    let printed_values = Mutex::new(vec![]);
+    let load_reports = Mutex::new(vec![]);
+    let size_reports = Mutex::new(vec![]);
+    let fold_logs = Mutex::new(vec![]);
+    let estimates = Mutex::new(vec![]);
+    let directive_metrics = Mutex::new(vec![]);
+    let partial = Mutex::new(vec![]);
+    // Specialization takes the module as an argument rather than
+    // borrowing it, as specialized functions are added to it between
+    // batches (below).
+    let specialize_from = |module: &Module,
+                           directive: &Directive,
+                           base: Option<&BaseFacts>|
+     -> Option<anyhow::Result<SpecializedFunc>> {
+        let generic = funcs.get(&directive.func).unwrap();
+        let mut losses = if opts.analyze { Some(vec![]) } else { None };
+        let start = Instant::now();
+        let result = match partially_evaluate_func(
+            module,
+            generic,
+            im,
+            &intrinsics,
+            directive,
+            opts,
+            losses.as_mut(),
+            &printed_values,
+            &load_reports,
+            &estimates,
+            &summaries,
+            const_assert_trap,
+            &imported_globals,
+            &effects,
+            base,
+            base_ids.contains(&directive.id()),
+        ) {
+            Ok(result) => result,
+            Err(e) => return Some(Err(e)),
+        };
+        if opts.metrics {
+            directive_metrics
+                .lock()
+                .unwrap()
+                .push(DirectiveMetrics::new(
+                    directive,
+                    module.funcs[directive.func].name(),
+                    start.elapsed(),
+                    result.as_ref().map(|spec| &spec.stats),
+                    base.is_some(),
+                ));
+        }
 
-            if let Some(p) = progress_ref {
-                p.inc(1);
-            }
-            if let Some((body, sig, name, spec_stats)) = result {
-                stats.lock().unwrap().add_specialization(&spec_stats);
-                let ir = if output_ir.is_some() {
-                    use std::fmt::Write;
-                    let cfg = CFGInfo::new(&body);
-                    let liveness = Liveness::new(&body, &cfg);
-                    let mut s = String::new();
-                    writeln!(&mut s, "# Liveness:").unwrap();
-                    for (block, _) in body.blocks.entries() {
-                        let mut live = liveness.block_start[block]
-                            .iter()
-                            .cloned()
-                            .collect::<Vec<_>>();
-                        live.sort();
-                        writeln!(&mut s, "# {}: {:?}", block, live).unwrap();
-                    }
-                    writeln!(&mut s, "").unwrap();
-                    writeln!(&mut s, "{}", body.display_verbose("", Some(&module))).unwrap();
-                    s
-                } else {
-                    String::new()
-                };
-                let decl = {
-                    let body = match body.compile() {
-                        Ok(body) => body,
-                        Err(e) => return Some(Err(e)),
-                    };
-                    FuncDecl::Compiled(sig, name, body)
-                };
-                Some(Ok((directive, decl, ir)))
-            } else {
+        if let Some(progress) = progress {
+            progress();
+        }
+        if let Some(losses) = losses {
+            analyses.lock().unwrap().push(DirectiveAnalysis {
+                id: directive.id(),
+                user_id: directive.user_id,
+                args: directive.args.clone(),
+                func: directive.func.index(),
+                func_name: module.funcs[directive.func].name().to_owned(),
+                completed: result.is_some(),
+                losses,
+            });
+            return None;
+        }
+        if opts.estimate.is_some() {
+            return None;
+        }
+        match result {
+            Some(spec) => {
+                generic
+                    .stats
+                    .lock()
+                    .unwrap()
+                    .add_specialization(&spec.stats);
+                Some(Ok(spec))
+            }
+            None => {
                 log::warn!("Failed to weval for directive {:?}", directive);
                 None
             }
-        })
-        .collect::<anyhow::Result<Vec<_>>>()?;
+        }
+    };
+
+    // Specialize the bases of delta directives first, keeping what
+    // they found; each is then taken in its turn below.
+    let mut base_facts = HashMap::default();
+    let mut prespecialized = HashMap::default();
+    let base_results = directives
+        .par_iter()
+        .filter(|directive| base_ids.contains(&directive.id()))
+        .map(|directive| (directive.id(), specialize_from(&module, directive, None)))
+        .collect::<Vec<_>>();
+    for (id, mut result) in base_results {
+        if let Some(Ok(spec)) = &mut result {
+            if let Some(facts) = spec.facts.take() {
+                base_facts.insert(id, facts);
+            }
+        }
+        prespecialized.insert(id, result);
+    }
+    let prespecialized = Mutex::new(prespecialized);
+    let specialize =
+        |module: &Module, directive: &Directive| -> Option<anyhow::Result<SpecializedFunc>> {
+            if let Some(result) = prespecialized.lock().unwrap().remove(&directive.id()) {
+                return result;
+            }
+            let base = directive.base.and_then(|base| base_facts.get(&base));
+            specialize_from(module, directive, base)
+        };
+
+    // Compile a specialized function and note its size.
+    let finish =
+        |module: &Module, directive: &Directive, spec: SpecializedFunc| -> anyhow::Result<_> {
+            let SpecializedFunc {
+                body,
+                sig,
+                name,
+                block_states,
+                region_epochs,
+                origins,
+                folds,
+                hinted_branches,
+                block_pcs,
+                resume,
+                ..
+            } = spec;
+            let ir = if opts.output_ir.is_some() {
+                use std::fmt::Write;
+                let cfg = CFGInfo::new(&body);
+                let liveness = Liveness::new(&body, &cfg);
+                let mut s = String::new();
+                writeln!(&mut s, "# Liveness:").unwrap();
+                for (block, _) in body.blocks.entries() {
+                    let mut live = liveness.block_start[block]
+                        .iter()
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    live.sort();
+                    writeln!(&mut s, "# {}: {:?}", block, live).unwrap();
+                }
+                writeln!(&mut s, "").unwrap();
+                writeln!(&mut s, "{}", body.display_verbose("", Some(module))).unwrap();
+                s
+            } else {
+                String::new()
+            };
+            let mut cost = crate::cost::estimate(&body);
+            if opts.opt_level_for(directive.user_id) == OptLevel::Wrap {
+                // The wrapper runs the generic function.
+                cost += funcs[&directive.func].cost;
+            }
+            let mut callees = vec![];
+            crate::callgraph::visit_func_refs(&body, |f| callees.push(f));
+            let split = match (resume, opts.split_pc_range, &block_pcs) {
+                (Some(resume), _, _) => {
+                    // Stitch the specialization to the generic
+                    // function it resumes, as a function of one part.
+                    crate::callgraph::visit_func_refs(resume.body(), |f| callees.push(f));
+                    partial.lock().unwrap().push(directive.clone());
+                    let params = module.signatures[sig].params.clone();
+                    Some(resume.stitch(body.clone(), params))
+                }
+                (None, Some(range), Some(block_pcs)) => {
+                    crate::split::split(module, sig, &body, block_pcs, range)
+                }
+                _ => None,
+            };
+            let (decl, size, hints) = match &split {
+                Some(split) => {
+                    // The parts are compiled with the output module,
+                    // once their calls to each other are resolved;
+                    // branch hints aren't carried over to them.
+                    let size = split.compiled_size()?;
+                    let trampoline = split.trampoline.body.clone();
+                    (FuncDecl::Body(sig, name, trampoline), size, None)
+                }
+                None => {
+                    let compiled = body.compile()?;
+                    let hints =
+                        crate::branch_hints::compiled_hints(&body, &compiled, &hinted_branches)?;
+                    let size = compiled.byte_len();
+                    (FuncDecl::Compiled(sig, name, compiled), size, hints)
+                }
+            };
+            if let Some(origins) = origins {
+                size_reports.lock().unwrap().push(SizeReport::new(
+                    directive.id(),
+                    directive.user_id,
+                    directive.args.clone(),
+                    module.funcs[directive.func].name().to_owned(),
+                    &body,
+                    &origins,
+                    size,
+                ));
+            }
+            if let Some(folds) = folds {
+                fold_logs.lock().unwrap().push(FoldLog {
+                    id: directive.id(),
+                    user_id: directive.user_id,
+                    func_name: module.funcs[directive.func].name().to_owned(),
+                    folds,
+                });
+            }
+            Ok((
+                decl,
+                size,
+                cost,
+                ir,
+                block_states,
+                callees,
+                region_epochs,
+                hints,
+                split,
+            ))
+        };
+
+    // What the manifest needs of each generic function: its
+    // fingerprint and estimated cost.
+    let generic_info = funcs
+        .iter()
+        .map(|(&func, generic)| (func, (generic.fingerprint, generic.cost)))
+        .collect::<BTreeMap<_, _>>();
 
     // Compute memory updates and the pre-weval lookup table.
-    let mut mem_updates = HashMap::default();
+    let mut mem_updates = BTreeMap::new();
+    let mut relocs = vec![];
     let mut lookup_table = vec![];
-    for (directive, decl, ir) in bodies {
-        // Add function to module.
-        let func = module.funcs.push(decl);
-        // Append to table.
-        let func_table = &mut module.tables[Table::from(0)];
-        let table_idx = {
-            let func_table_elts = func_table.func_elements.as_mut().unwrap();
-            let table_idx = func_table_elts.len();
-            func_table_elts.push(func);
-            table_idx
-        } as u32;
-        func_table.initial = std::cmp::max(func_table.initial, table_idx + 1);
-        if func_table.max.is_some() && table_idx >= func_table.max.unwrap() {
-            func_table.max = Some(table_idx + 1);
-        }
-        log::info!("New func index {} -> table index {}", func, table_idx);
-
-        if let Some(path) = &output_ir {
-            let mut specialized_ir_file = path.clone();
-            specialized_ir_file.push(&format!("specialized_{}_to_{}.txt", directive.func, func));
-            std::fs::write(&specialized_ir_file, ir).unwrap();
-        }
-
-        // Update memory image if this request is a live one with an
-        // output function index, otherwise add to pre-weval lookup
-        // table if it came from corpus.
-        if directive.func_index_out_addr != 0 {
-            log::info!(" -> writing to 0x{:x}", directive.func_index_out_addr);
-            mem_updates.insert(directive.func_index_out_addr, table_idx);
-        } else {
-            log::info!(" -> adding to lookup table");
-            lookup_table.push((directive.user_id, &directive.args[..], table_idx));
+    let mut compiled_refs = vec![];
+    let mut manifest = Manifest::default();
+    let mut skipped = vec![];
+    let mut wrapped = vec![];
+    let mut elem_rewrites: BTreeMap<Func, BTreeSet<Func>> = BTreeMap::new();
+    let mut over_budget = false;
+    let mut added_bytes = 0;
+    let mut out_branch_hints = BTreeMap::new();
+
+    // Specialize and compile the directives a batch at a time, in
+    // priority order, adding each batch to the module before starting
+    // the next, so that only one batch's specializations are held at
+    // once rather than every directive's.
+    let batch_size = rayon::current_num_threads() * 4;
+    let mut const_globals = crate::const_pool::ConstGlobals::default();
+    let mut block_states_dump = match &opts.output_block_states {
+        Some(path) => Some(BlockStatesDump::create(path)?),
+        None => None,
+    };
+    for batch in directives.chunks(batch_size.max(1)) {
+        let finished = match opts.const_globals_min_defs {
+            None => batch
+                .par_iter()
+                .flat_map(|directive| {
+                    let spec = specialize(&module, directive)?;
+                    Some(spec.and_then(|spec| Ok((directive, finish(&module, directive, spec)?))))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            Some(min_defs) => {
+                // Move constants defined across many specializations
+                // (so far) into globals before compiling the batch.
+                let mut specialized = batch
+                    .par_iter()
+                    .flat_map(|directive| {
+                        Some(specialize(&module, directive)?.map(|spec| (directive, spec)))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                const_globals.globalize(
+                    &mut module,
+                    specialized.iter_mut().map(|(_, spec)| &mut spec.body),
+                    min_defs,
+                );
+                specialized
+                    .into_par_iter()
+                    .map(|(directive, spec)| Ok((directive, finish(&module, directive, spec)?)))
+                    .collect::<anyhow::Result<Vec<_>>>()?
+            }
+        };
+
+        for (
+            directive,
+            (
+                mut decl,
+                mut size,
+                mut cost,
+                mut ir,
+                mut blocks,
+                mut callees,
+                mut region_epochs,
+                mut hints,
+                mut split,
+            ),
+        ) in finished
+        {
+            // Admit in priority order (the order of `finished`) until the
+            // budget is exhausted; past that, admit wrappers instead, if
+            // requested and while they fit.
+            if let Some(max) = opts.max_added_bytes {
+                over_budget |= added_bytes + size > max;
+                if over_budget {
+                    let wrapper = if opts.wrap_over_budget {
+                        let body = wrapper_body(&module, directive)?;
+                        let compiled = body.compile()?;
+                        Some((body, compiled))
+                            .filter(|(_, compiled)| added_bytes + compiled.byte_len() <= max)
+                    } else {
+                        None
+                    };
+                    match wrapper {
+                        Some((body, compiled)) => {
+                            log::info!(
+                                "Wrapping directive {} (site {}, priority {}): {} bytes would exceed budget; wrapper is {} bytes",
+                                directive.id(),
+                                directive.user_id,
+                                directive.priority,
+                                size,
+                                compiled.byte_len()
+                            );
+                            if opts.output_ir.is_some() {
+                                ir = format!("{}", body.display_verbose("", Some(&module)));
+                            }
+                            let sig = module.funcs[directive.func].sig();
+                            let name = format!("{} (wrapper)", module.funcs[directive.func].name());
+                            size = compiled.byte_len();
+                            // The wrapper runs the generic function.
+                            cost = crate::cost::estimate(&body) + generic_info[&directive.func].1;
+                            decl = FuncDecl::Compiled(sig, name, compiled);
+                            blocks = None;
+                            callees = vec![directive.func];
+                            region_epochs = vec![];
+                            hints = None;
+                            split = None;
+                            wrapped.push(directive.clone());
+                        }
+                        None => {
+                            log::info!(
+                                "Skipping directive {} (site {}, priority {}): {} bytes would exceed budget ({} of {} used)",
+                                directive.id(),
+                                directive.user_id,
+                                directive.priority,
+                                size,
+                                added_bytes,
+                                max
+                            );
+                            skipped.push(directive.clone());
+                            continue;
+                        }
+                    }
+                }
+            }
+            added_bytes += size;
+
+            // Add function to module.
+            let func = match split {
+                Some(split) => split.add_to_module(&mut module, decl.sig(), decl.name()),
+                None => module.funcs.push(decl),
+            };
+            if opts.rewrite_elems.contains(&directive.user_id) {
+                elem_rewrites
+                    .entry(directive.func)
+                    .or_default()
+                    .insert(func);
+            }
+            if let Some(hints) = hints {
+                out_branch_hints.insert(func, hints);
+            }
+            compiled_refs.extend(callees.into_iter().map(|callee| (func, callee)));
+            // Append to table.
+            let func_table = &mut module.tables[Table::from(0)];
+            let table_idx = {
+                let func_table_elts = func_table.func_elements.as_mut().unwrap();
+                let table_idx = func_table_elts.len();
+                func_table_elts.push(func);
+                table_idx
+            } as u32;
+            func_table.initial = std::cmp::max(func_table.initial, table_idx + 1);
+            if func_table.max.is_some() && table_idx >= func_table.max.unwrap() {
+                func_table.max = Some(table_idx + 1);
+            }
+            log::info!("New func index {} -> table index {}", func, table_idx);
+
+            if let (Some(dump), Some(blocks)) = (&mut block_states_dump, blocks) {
+                dump.push(&SpecializationBlockStates {
+                    user_id: directive.user_id,
+                    args: directive.args.clone(),
+                    generic_func: directive.func.index(),
+                    specialized_func: func.index(),
+                    blocks,
+                })?;
+            }
+
+            if let Some(path) = &opts.output_ir {
+                let mut specialized_ir_file = path.clone();
+                specialized_ir_file
+                    .push(&format!("specialized_{}_to_{}.txt", directive.func, func));
+                std::fs::write(&specialized_ir_file, ir).unwrap();
+            }
+
+            // Update memory image if this request is a live one with an
+            // output function index, otherwise add to pre-weval lookup
+            // table if it came from corpus.
+            let table_idx = opts.table_base + table_idx;
+            let (generic_fingerprint, generic_cost) = generic_info[&directive.func];
+            manifest.entries.push(ManifestEntry {
+                id: directive.id(),
+                user_id: directive.user_id,
+                args: directive.args.clone(),
+                generic_func: directive.func.index(),
+                generic_fingerprint,
+                specialized_func: func.index(),
+                table_index: table_idx,
+                cost,
+                generic_cost,
+                region_epochs,
+                stale: false,
+            });
+            if directive.func_index_out_addr != 0 {
+                log::info!(" -> writing to 0x{:x}", directive.func_index_out_addr);
+                mem_updates.insert(directive.func_index_out_addr, table_idx);
+                relocs.push(FuncIndexReloc {
+                    addr: directive.func_index_out_addr,
+                    table_index: table_idx,
+                    func: func.index() as u32,
+                });
+            } else {
+                log::info!(" -> adding to lookup table");
+                lookup_table.push((directive.user_id, &directive.args[..], table_idx));
+            }
         }
     }
+    if let Some(dump) = block_states_dump {
+        dump.finish()?;
+    }
+
+    // Done with the generic bodies and their analyses; free them
+    // before building the output.
+    let mut stats = funcs
+        .into_iter()
+        .map(|(_, generic)| generic.stats.into_inner().unwrap())
+        .collect::<Vec<_>>();
+    stats.sort_by_key(|stats| stats.generic);
+
+    let mut analyses = analyses.into_inner().unwrap();
+    let mut printed_values = printed_values.into_inner().unwrap();
+    printed_values.sort();
+    let mut load_reports = load_reports.into_inner().unwrap();
+    load_reports.sort_by(|a, b| (a.user_id, &a.args).cmp(&(b.user_id, &b.args)));
+    let mut size_reports = size_reports.into_inner().unwrap();
+    size_reports.sort_by(|a, b| (a.user_id, &a.args).cmp(&(b.user_id, &b.args)));
+    let mut fold_logs = fold_logs.into_inner().unwrap();
+    fold_logs.sort_by_key(|log| (log.user_id, log.id));
+    let mut directive_metrics = directive_metrics.into_inner().unwrap();
+    let mut partial = partial.into_inner().unwrap();
+    directive_metrics.sort_by_key(|metrics| (metrics.user_id, metrics.id));
+    let mut estimates = estimates.into_inner().unwrap();
+    estimates.sort_by_key(|estimate| (estimate.user_id, estimate.id));
+    let site_estimates = crate::estimate::sites(&estimates[..], &site_directives);
+    if opts.analyze || opts.estimate.is_some() {
+        analyses.sort_by(|a, b| (a.user_id, &a.args).cmp(&(b.user_id, &b.args)));
+        return Ok(PartialEvalResult {
+            module,
+            global_base,
+            stats: vec![],
+            cold_funcs: vec![],
+            analyses,
+            estimates,
+            site_estimates,
+            untargeted_intrinsic_uses,
+            manifest: Manifest::default(),
+            skipped: vec![],
+            wrapped: vec![],
+            partial: vec![],
+            added_bytes: 0,
+            relocs: vec![],
+            printed_values,
+            load_reports,
+            size_reports: vec![],
+            fold_logs: vec![],
+            directive_metrics,
+            branch_hints: BTreeMap::new(),
+        });
+    }
 
     // Update memory.
     let heap = im.main_heap()?;
@@ -294,35 +1451,505 @@ pub fn partially_evaluate<'a>(
         );
     }
 
-    let mut stats = funcs
-        .drain()
-        .map(|(_, (_, _, stats))| stats.into_inner().unwrap())
-        .collect::<Vec<_>>();
-    stats.sort_by_key(|stats| stats.generic);
+    manifest.sort();
+    if opts.emit_metadata {
+        emit_metadata(&mut module, im, heap, &manifest)?;
+    }
+
+    // Point table entries for generic functions at their
+    // specializations, where requested. This happens before applying
+    // the generic function policy, so that a function referenced only
+    // from the table may then be removed.
+    for (generic, specialized) in elem_rewrites {
+        if specialized.len() > 1 {
+            log::warn!(
+                "Not rewriting table entries for generic function {}: {} specializations to choose from",
+                generic,
+                specialized.len()
+            );
+            continue;
+        }
+        let specialized = specialized.into_iter().next().unwrap();
+        let mut count = 0;
+        for table in module.tables.values_mut() {
+            for elem in table.func_elements.iter_mut().flatten() {
+                if *elem == generic {
+                    *elem = specialized;
+                    count += 1;
+                }
+            }
+        }
+        if count == 0 {
+            log::warn!(
+                "Not rewriting table entries for generic function {}: it is in no table",
+                generic
+            );
+        } else {
+            log::info!(
+                "Rewrote {} table entries for generic function {} to {}",
+                count,
+                generic,
+                specialized
+            );
+        }
+    }
+
+    // Apply the requested policy to each generic function. The most
+    // conservative policy of all sites targeting a function wins. A
+    // skipped or wrapped directive still needs its generic function.
+    let mut generic_policies: BTreeMap<Func, GenericFuncPolicy> = BTreeMap::new();
+    for directive in &directives {
+        let policy = if skipped.contains(directive) || wrapped.contains(directive) {
+            GenericFuncPolicy::Keep
+        } else {
+            opts.generic_func_policies
+                .get(&directive.user_id)
+                .copied()
+                .unwrap_or_default()
+        };
+        generic_policies
+            .entry(directive.func)
+            .and_modify(|p| *p = std::cmp::min(*p, policy))
+            .or_insert(policy);
+    }
+    let mut cold_funcs = vec![];
+    for (func, policy) in generic_policies {
+        match policy {
+            GenericFuncPolicy::Keep => {}
+            GenericFuncPolicy::Cold => cold_funcs.push(func),
+            GenericFuncPolicy::Remove => {
+                match crate::callgraph::find_reference(&module, func, &compiled_refs[..])? {
+                    Some(reference) => {
+                        log::warn!(
+                            "Cannot remove generic function {}: still referenced by {}; marking cold instead",
+                            func,
+                            reference
+                        );
+                        cold_funcs.push(func);
+                    }
+                    None => {
+                        log::info!("Removing body of generic function {}", func);
+                        let sig = module.funcs[func].sig();
+                        let name = module.funcs[func].name().to_owned();
+                        let mut body = FunctionBody::new(&module, sig);
+                        body.blocks[body.entry].terminator = Terminator::Unreachable;
+                        module.funcs[func] = FuncDecl::Body(sig, name, body);
+                    }
+                }
+            }
+        }
+    }
+
+    if opts.optimize_all_funcs {
+        optimize_all_funcs(&mut module)?;
+    }
+
+    // Functions copied through unchanged keep their hints as-is.
+    for (&func, hints) in branch_hints {
+        let func = Func::new(func as usize);
+        if matches!(module.funcs.get(func), Some(FuncDecl::Lazy(..))) {
+            out_branch_hints.insert(func, hints.clone());
+        }
+    }
+
+    // Don't attribute sizes of functions that weren't added, or that
+    // were replaced by wrappers.
+    size_reports.retain(|report| {
+        !skipped
+            .iter()
+            .chain(wrapped.iter())
+            .any(|d| d.user_id == report.user_id && d.args == report.args)
+    });
+    fold_logs.retain(|log| {
+        !skipped
+            .iter()
+            .chain(wrapped.iter())
+            .any(|d| d.id() == log.id)
+    });
+    partial.retain(|d| !skipped.contains(d) && !wrapped.contains(d));
+    partial.sort_by_key(|d| (d.user_id, d.id()));
 
     Ok(PartialEvalResult {
         module,
         global_base,
         stats,
+        cold_funcs,
+        analyses,
+        estimates,
+        site_estimates,
+        untargeted_intrinsic_uses,
+        manifest,
+        skipped,
+        wrapped,
+        partial,
+        added_bytes,
+        relocs,
+        printed_values,
+        load_reports,
+        size_reports,
+        fold_logs,
+        directive_metrics,
+        branch_hints: out_branch_hints,
     })
 }
 
+/// Add the function called when a check inserted by
+/// `debug_assert_consts` fails: it traps, and its name and argument
+/// (the failing block) identify the failure in a backtrace.
+fn add_const_assert_trap(module: &mut Module) -> Func {
+    let sig = module
+        .signatures
+        .entries()
+        .find(|(_, sig)| sig.params == [Type::I32] && sig.returns.is_empty())
+        .map(|(sig, _)| sig)
+        .unwrap_or_else(|| {
+            module.signatures.push(waffle::SignatureData {
+                params: vec![Type::I32],
+                returns: vec![],
+            })
+        });
+    let mut body = FunctionBody::new(module, sig);
+    body.blocks[body.entry].terminator = Terminator::Unreachable;
+    module.funcs.push(FuncDecl::Body(
+        sig,
+        "weval_const_assertion_failed".to_owned(),
+        body,
+    ))
+}
+
+/// Append a `weval_metadata_t` (see `weval.h`) describing every
+/// specialization to memory, point the guest's
+/// `weval_metadata_table` at it if exported, and export a function
+/// `weval_metadata` returning its address, so that both the guest
+/// and the embedder may find which specializations exist.
+fn emit_metadata(
+    module: &mut Module,
+    im: &mut Image,
+    heap: Memory,
+    manifest: &Manifest,
+) -> anyhow::Result<()> {
+    let base = u32::try_from(im.memories[&heap].len())?;
+    let mut bytes = vec![];
+    // The header: `entries` and `nentries`, followed directly by the
+    // entries, 8-aligned.
+    let nentries = u32::try_from(manifest.entries.len())?;
+    bytes.extend(u32::to_le_bytes(base + 8));
+    bytes.extend(u32::to_le_bytes(nentries));
+    for entry in &manifest.entries {
+        bytes.extend(u32::to_le_bytes(entry.user_id));
+        bytes.extend(u32::to_le_bytes(u32::try_from(entry.generic_func)?));
+        bytes.extend(u32::to_le_bytes(entry.table_index));
+        bytes.extend(u32::to_le_bytes(u32::try_from(entry.args.len())?));
+        bytes.extend(u64::to_le_bytes(crate::meta::hash_bytes(&entry.args[..])));
+    }
+    im.append_data(heap, bytes);
+
+    if let Some(table) = find_global_data_by_exported_func(module, "weval.metadata.table") {
+        im.write_u32(heap, table, base + 8)?;
+        im.write_u32(heap, table + 4, nentries)?;
+    }
+
+    let sig = module
+        .signatures
+        .entries()
+        .find(|(_, sig)| sig.params.is_empty() && sig.returns == [Type::I32])
+        .map(|(sig, _)| sig)
+        .unwrap_or_else(|| {
+            module.signatures.push(waffle::SignatureData {
+                params: vec![],
+                returns: vec![Type::I32],
+            })
+        });
+    let mut body = FunctionBody::new(module, sig);
+    let tys = body.single_type_list(Type::I32);
+    let addr = body.add_value(ValueDef::Operator(
+        Operator::I32Const { value: base },
+        ListRef::default(),
+        tys,
+    ));
+    body.append_to_block(body.entry, addr);
+    body.blocks[body.entry].terminator = Terminator::Return { values: vec![addr] };
+    let func = module
+        .funcs
+        .push(FuncDecl::Body(sig, "weval_metadata".to_owned(), body));
+    module.exports.push(waffle::Export {
+        name: "weval_metadata".to_owned(),
+        kind: waffle::ExportKind::Func(func),
+    });
+    log::info!(
+        "created weval metadata table at {:#x} with {} entries",
+        base,
+        nentries
+    );
+    Ok(())
+}
+
+/// Build a wrapper for a directive: a function with the generic
+/// function's signature that calls it with the directive's constant
+/// params bound in place of the passed-in values. As at `-O0`,
+/// specialization globals are not bound.
+fn wrapper_body(module: &Module, directive: &Directive) -> anyhow::Result<FunctionBody> {
+    let sig = module.funcs[directive.func].sig();
+    let sig_data = &module.signatures[sig];
+    let num_globals = directive.num_globals as usize;
+    let mut directive_args = DirectiveArgs::decode(&directive.args[..])?;
+    directive_args.coerce_to_params(num_globals, &sig_data.params[..])?;
+
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    let mut args = body.blocks[entry]
+        .params
+        .iter()
+        .map(|&(_, param)| param)
+        .collect::<Vec<_>>();
+    for (i, abs) in directive_args.const_params[num_globals..]
+        .iter()
+        .enumerate()
+    {
+        let op = match abs {
+            &AbstractValue::Concrete(value) => const_operator(sig_data.params[i], value),
+            _ => None,
+        };
+        if let Some(op) = op {
+            let tys = body.single_type_list(sig_data.params[i]);
+            let k = body.add_value(ValueDef::Operator(op, ListRef::default(), tys));
+            body.append_to_block(entry, k);
+            args[i] = k;
+        }
+    }
+
+    let args = body.arg_pool.from_iter(args.into_iter());
+    let tys = body.type_pool.from_iter(sig_data.returns.iter().cloned());
+    let call = body.add_value(ValueDef::Operator(
+        Operator::Call {
+            function_index: directive.func,
+        },
+        args,
+        tys,
+    ));
+    body.append_to_block(entry, call);
+    let values = if sig_data.returns.len() == 1 {
+        vec![call]
+    } else {
+        sig_data
+            .returns
+            .iter()
+            .enumerate()
+            .map(|(i, &ty)| {
+                let value = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+                body.append_to_block(entry, value);
+                value
+            })
+            .collect()
+    };
+    body.blocks[entry].terminator = Terminator::Return { values };
+    Ok(body)
+}
+
+/// Check that `directive` can be specialized relative to `base`.
+fn check_delta_base(
+    directive: &Directive,
+    base: Option<&Directive>,
+    opts: &PartialEvalOptions,
+) -> anyhow::Result<()> {
+    let base = base.ok_or_else(|| anyhow::anyhow!("base is not among the directives"))?;
+    if base.func != directive.func || base.num_globals != directive.num_globals {
+        anyhow::bail!("base specializes a different function");
+    }
+    if opts.delta_bases.contains_key(&base.id()) {
+        anyhow::bail!("base is itself a delta directive");
+    }
+    let args = DirectiveArgs::decode(&directive.args[..])?;
+    if !args.extends(&DirectiveArgs::decode(&base.args[..])?) {
+        anyhow::bail!("base has constants that this directive lacks");
+    }
+    Ok(())
+}
+
+/// The imported functions named in `PartialEvalOptions::benign_imports`.
+fn benign_import_funcs(module: &Module, opts: &PartialEvalOptions) -> HashSet<Func> {
+    let mut funcs = HashSet::default();
+    for import in &module.imports {
+        if let waffle::ImportKind::Func(func) = import.kind {
+            let key = (import.module.clone(), import.name.clone());
+            if opts.benign_imports.contains(&key) {
+                funcs.insert(func);
+            }
+        }
+    }
+    if funcs.len() < opts.benign_imports.len() {
+        // As with imported globals, the same options apply to any
+        // side modules.
+        log::info!(
+            "{} of {} benign imports found in this module",
+            funcs.len(),
+            opts.benign_imports.len()
+        );
+    }
+    funcs
+}
+
+/// The imported globals of a module, whose values the embedder
+/// provides at instantiation.
+struct ImportedGlobals {
+    /// `<module>.<name>` of each, for diagnostics.
+    names: BTreeMap<waffle::Global, String>,
+    /// Values assumed via `PartialEvalOptions::imported_globals`.
+    assumed: BTreeMap<waffle::Global, AbstractValue>,
+}
+
+impl ImportedGlobals {
+    fn new(module: &Module, opts: &PartialEvalOptions) -> anyhow::Result<ImportedGlobals> {
+        let mut names = BTreeMap::new();
+        let mut assumed = BTreeMap::new();
+        for import in &module.imports {
+            let global = match import.kind {
+                waffle::ImportKind::Global(global) => global,
+                _ => continue,
+            };
+            let name = format!("{}.{}", import.module, import.name);
+            let key = (import.module.clone(), import.name.clone());
+            if let Some(&bits) = opts.imported_globals.get(&key) {
+                let data = &module.globals[global];
+                if data.mutable {
+                    anyhow::bail!("Cannot assume a value for mutable imported global {}", name);
+                }
+                let value = WasmVal::from_bits(data.ty, bits).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Cannot assume a value for imported global {} of type {:?}",
+                        name,
+                        data.ty
+                    )
+                })?;
+                let value = match value {
+                    // As with initialized globals, an i32 may be a
+                    // pointer into static memory.
+                    WasmVal::I32(addr) => AbstractValue::StaticMemory(addr),
+                    value => AbstractValue::Concrete(value),
+                };
+                log::info!("assuming imported global {} = {:?}", name, value);
+                assumed.insert(global, value);
+            }
+            names.insert(global, name);
+        }
+        // The same options apply to the main module and any side
+        // modules, so a name need only be imported by one of them.
+        for (module_name, name) in opts.imported_globals.keys() {
+            if !names
+                .values()
+                .any(|n| *n == format!("{}.{}", module_name, name))
+            {
+                log::info!(
+                    "no imported global {}.{} in this module; ignoring its value",
+                    module_name,
+                    name
+                );
+            }
+        }
+        Ok(ImportedGlobals { names, assumed })
+    }
+
+    /// The imported global read by `op`, if it is a read of one whose
+    /// value is unknown: neither assumed nor tracked in `globals`.
+    fn blocking(
+        &self,
+        op: &Operator,
+        globals: &BTreeMap<waffle::Global, AbstractValue>,
+    ) -> Option<waffle::Global> {
+        match op {
+            Operator::GlobalGet { global_index }
+                if self.names.contains_key(global_index) && !globals.contains_key(global_index) =>
+            {
+                Some(*global_index)
+            }
+            _ => None,
+        }
+    }
+}
+
 fn partially_evaluate_func(
     module: &Module,
-    generic: &FunctionBody,
-    cfg: &CFGInfo,
+    generic_func: &GenericFunc,
     image: &Image,
     intrinsics: &Intrinsics,
     directive: &Directive,
-) -> anyhow::Result<Option<(FunctionBody, Signature, String, SpecializationStats)>> {
-    let directive_args = DirectiveArgs::decode(&directive.args[..])?;
+    opts: &PartialEvalOptions,
+    precision_losses: Option<&mut Vec<PrecisionLoss>>,
+    printed_values: &Mutex<Vec<PrintedValue>>,
+    load_reports: &Mutex<Vec<LoadReport>>,
+    estimates: &Mutex<Vec<Estimate>>,
+    summaries: &HashMap<Func, FuncSummary>,
+    const_assert_trap: Option<Func>,
+    imported_globals: &ImportedGlobals,
+    effects: &Effects,
+    base: Option<&BaseFacts>,
+    keep_facts: bool,
+) -> anyhow::Result<Option<SpecializedFunc>> {
+    let generic = &generic_func.body;
+    let mut directive_args = DirectiveArgs::decode(&directive.args[..])?;
     let orig_name = module.funcs[directive.func].name();
     let sig = module.funcs[directive.func].sig();
+    let entry_facts = opts.entry_facts.get(&directive.id());
+    if let Some(facts) = entry_facts {
+        directive_args.apply_entry_facts(directive.num_globals as usize, facts)?;
+    }
+    directive_args.coerce_to_params(
+        directive.num_globals as usize,
+        &module.signatures[sig].params[..],
+    )?;
 
-    log::info!("Specializing: {:?}", directive);
+    log::info!("Specializing {}: {:?}", directive.id(), directive);
     log::info!("Args: {:?}", directive_args);
     log::debug!("body:\n{}", generic.display("| ", Some(module)));
 
+    let opt_level = opts.opt_level_for(directive.user_id);
+    log::info!("Opt level: {:?}", opt_level);
+
+    if opt_level == OptLevel::Wrap {
+        if let Some(losses) = precision_losses {
+            losses.clear();
+            return Ok(None);
+        }
+        let body = wrapper_body(module, directive)?;
+        if opts.estimate.is_some() {
+            estimates.lock().unwrap().push(Estimate::new(
+                directive,
+                orig_name,
+                0,
+                &body,
+                generic_func.bytes_per_inst,
+                Outcome::Complete,
+            ));
+            return Ok(None);
+        }
+        let mut stats = SpecializationStats::default();
+        accumulate_stats_from_func(&mut stats, &body);
+        let origins = if opts.size_report {
+            Some(
+                body.blocks
+                    .iter()
+                    .map(|block| (block, (generic.entry.index(), vec!["wrapper".to_owned()])))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        return Ok(Some(SpecializedFunc {
+            body,
+            sig,
+            name: format!("{} (wrapper)", orig_name),
+            stats,
+            block_states: None,
+            region_epochs: vec![],
+            origins,
+            folds: None,
+            hinted_branches: HashMap::default(),
+            block_pcs: None,
+            facts: None,
+            resume: None,
+        }));
+    }
+
     // Build the evaluator.
     let func = FunctionBody::new(module, sig);
     let mut evaluator = Evaluator {
@@ -332,26 +1959,167 @@ fn partially_evaluate_func(
         directive_args,
         intrinsics,
         image,
-        cfg,
+        cfg: &generic_func.cfg,
         state: FunctionState::new(),
         func,
-        block_map: HashMap::default(),
+        block_map: CtxBlockMap::default(),
         block_rev_map: PerEntity::default(),
         value_map: HashMap::default(),
         value_dep_blocks: HashMap::default(),
+        block_deps: PerEntity::default(),
         reg_map: HashMap::default(),
-        queue: VecDeque::new(),
+        queue: BinaryHeap::new(),
         queue_set: HashSet::default(),
+        // The dumps of block states and base facts read the globals
+        // in every entry state, so keep them all for those.
+        queued_in_region: (opts.retire_drained_contexts
+            && opts.output_block_states.is_none()
+            && !keep_facts)
+            .then(HashMap::default),
+        retired: HashSet::default(),
         stats: SpecializationStats::default(),
+        branch_losses: BTreeMap::new(),
+        load_losses: BTreeMap::new(),
+        pending_load_loss: None,
+        pending_oob_read: None,
+        oob_reads: BTreeMap::new(),
+        oob_reads_mode: opts.oob_reads,
+        region_epochs: BTreeMap::new(),
+        declared_stack: None,
+        stack_conflict: false,
+        no_fold: opts
+            .no_fold
+            .get(&directive.user_id)
+            .cloned()
+            .unwrap_or_default(),
+        folds: opts.fold_log.then(BTreeMap::new),
+        block_folds: HashMap::default(),
+        opt_level,
+        pc_range: opts.pc_ranges.get(&directive.user_id).copied(),
+        barrier_elision: opts.barrier_elision,
+        doomed: &generic_func.doomed,
+        trap_block: None,
+        pruned: BTreeSet::new(),
+        labels: BTreeMap::new(),
+        assert_failures: BTreeMap::new(),
+        unfolded_intrinsics: BTreeMap::new(),
+        pending_intrinsic_problem: None,
+        strict_intrinsics: opts.strict_intrinsics,
+        printed_values: BTreeMap::new(),
+        metering: opts.metering,
+        peeled_loops: &generic_func.peeled_loops,
+        auto_loops: &generic_func.auto_loops,
+        branch_hints: &generic_func.branch_hints,
+        hinted_branches: HashMap::default(),
+        summaries,
+        effects,
+        hoist_budget: if opt_level >= OptLevel::O2 {
+            opts.max_hoisted_blocks
+        } else {
+            0
+        },
+        context_globals: opts
+            .context_globals
+            .get(&directive.user_id)
+            .map_or(&[], |globals| &globals[..]),
+        env: &opts.env,
+        site_env: opts.site_env.get(&directive.user_id),
+        imported_globals,
+        blocked_on: HashMap::default(),
+        pending_blocked_on: BTreeSet::new(),
+        base,
+        block_limit: opts.estimate.and_then(|estimate| estimate.max_blocks),
+        cut_off: false,
+        cut_blocks: &generic_func.cut_blocks,
+        deadline: opts
+            .timeout_for(directive.user_id)
+            .map(|timeout| Instant::now() + timeout),
+        resume: None,
     };
-    let (ctx, entry_state) = evaluator.state.init(image);
+
+    if opt_level == OptLevel::O0 {
+        if let Some(losses) = precision_losses {
+            losses.clear();
+            return Ok(None);
+        }
+        // Emit the generic body as-is, with constant params bound.
+        evaluator.func = generic.clone();
+        let pre_entry = evaluator.create_pre_entry(generic.entry);
+        evaluator.func.entry = pre_entry;
+        evaluator.func.recompute_edges();
+        if opts.estimate.is_some() {
+            estimates.lock().unwrap().push(Estimate::new(
+                directive,
+                orig_name,
+                1,
+                &evaluator.func,
+                generic_func.bytes_per_inst,
+                Outcome::Complete,
+            ));
+            return Ok(None);
+        }
+        accumulate_stats_from_func(&mut evaluator.stats, &evaluator.func);
+        let origins = if opts.size_report {
+            Some(
+                generic
+                    .blocks
+                    .iter()
+                    .map(|block| (block, (block.index(), vec!["root".to_owned()])))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let hinted_branches = generic
+            .blocks
+            .entries()
+            .filter_map(|(block, def)| {
+                let key = crate::branch_hints::branch_key(&def.terminator)?;
+                Some((block, *generic_func.branch_hints.get(&key)?))
+            })
+            .collect();
+        return Ok(Some(SpecializedFunc {
+            body: evaluator.func,
+            sig,
+            name: format!("{} (specialized)", orig_name),
+            stats: evaluator.stats,
+            block_states: None,
+            region_epochs: vec![],
+            origins,
+            folds: None,
+            hinted_branches,
+            block_pcs: None,
+            facts: None,
+            resume: None,
+        }));
+    }
+
+    if let Some(base) = base {
+        // Start from the base's context tree, so that the contexts it
+        // reached keep their IDs (and so their facts); the new
+        // constants only add contexts to it.
+        log::info!("Specializing relative to base {}", directive.base.unwrap());
+        evaluator.state.contexts = base.contexts.clone();
+    }
+    let (ctx, mut entry_state) = evaluator.state.init(image);
+    entry_state.globals.extend(
+        imported_globals
+            .assumed
+            .iter()
+            .map(|(&global, value)| (global, value.clone())),
+    );
+    if let Some(facts) = entry_facts {
+        entry_state.globals.extend(
+            facts
+                .globals
+                .iter()
+                .map(|(&global, value)| (global, value.clone())),
+        );
+    }
     log::trace!("after init_args, state is {:?}", evaluator.state);
 
     let specialized_entry = evaluator.create_block(evaluator.generic.entry, ctx, entry_state);
-    evaluator
-        .queue
-        .push_back((evaluator.generic.entry, ctx, specialized_entry));
-    evaluator.queue_set.insert((evaluator.generic.entry, ctx));
+    evaluator.enqueue(evaluator.generic.entry, ctx, specialized_entry);
     evaluator.state.set_args(
         evaluator.generic,
         evaluator.directive.num_globals as usize,
@@ -364,35 +2132,154 @@ fn partially_evaluate_func(
     evaluator.func.entry = pre_entry;
 
     let success = evaluator.evaluate()?;
+    printed_values
+        .lock()
+        .unwrap()
+        .extend(std::mem::take(&mut evaluator.printed_values).into_values());
+    if opts.explain_loads {
+        load_reports.lock().unwrap().push(LoadReport {
+            id: directive.id(),
+            user_id: directive.user_id,
+            args: directive.args.clone(),
+            func: directive.func.index(),
+            func_name: orig_name.to_owned(),
+            loads: evaluator.unfolded_loads(),
+        });
+    }
+    if opts.estimate.is_some() {
+        // Estimation only: count what was evaluated.
+        let outcome = if evaluator.cut_off {
+            Outcome::CutOff
+        } else if success {
+            Outcome::Complete
+        } else {
+            Outcome::Failed
+        };
+        estimates.lock().unwrap().push(Estimate::new(
+            directive,
+            orig_name,
+            evaluator.state.contexts.len(),
+            &evaluator.func,
+            generic_func.bytes_per_inst,
+            outcome,
+        ));
+        if precision_losses.is_none() {
+            return Ok(None);
+        }
+    }
+    if let Some(losses) = precision_losses {
+        // Analysis only: don't bother finishing the function body.
+        *losses = evaluator.precision_losses();
+        return Ok(None);
+    }
     if !success {
         return Ok(None);
     }
+    if let Some(trap) = const_assert_trap {
+        evaluator.insert_const_assertions(trap);
+    }
+
+    let block_states = if opts.output_block_states.is_some() {
+        Some(evaluator.block_entry_states())
+    } else {
+        None
+    };
+    let origins = if opts.size_report {
+        Some(evaluator.block_origins())
+    } else {
+        None
+    };
+    let folds = evaluator.fold_log();
+    let hinted_branches = std::mem::take(&mut evaluator.hinted_branches);
+    let resume = evaluator.resume.take();
+    let block_pcs = if opts.split_pc_range.is_some() && resume.is_none() {
+        Some(evaluator.block_pcs())
+    } else {
+        None
+    };
+    let facts = if keep_facts {
+        Some(evaluator.base_facts())
+    } else {
+        None
+    };
+    let info = if opts.post_passes.is_empty() {
+        None
+    } else {
+        Some(evaluator.specialization_info())
+    };
+
+    // Drop the evaluator's state, the bulk of the memory used per
+    // directive, before optimizing the result.
+    let (mut func, mut stats, region_epochs) = evaluator.into_output();
+    if opts.guarded_devirt {
+        crate::guarded_devirt::run(&mut func, module, image);
+    }
 
     let name = format!("{} (specialized)", orig_name);
-    let cfg = CFGInfo::new(&evaluator.func);
-    crate::escape::remove_shadow_stack_if_non_escaping(&mut evaluator.func, &cfg);
-    evaluator.func.optimize(&waffle::OptOptions {
-        gvn: false,
-        cprop: false,
-        redundant_blockparams: true,
-    });
-    crate::constant_offsets::run(&mut evaluator.func, &cfg);
-    waffle::passes::resolve_aliases::run(&mut evaluator.func);
-    evaluator.func.optimize(&waffle::OptOptions {
+    let cfg = CFGInfo::new(&func);
+    if opt_level >= OptLevel::O3 {
+        // The generic function, if resumed, expects the shadow-stack
+        // frame in memory.
+        if resume.is_none() {
+            crate::escape::remove_shadow_stack_if_non_escaping(&mut func, &cfg);
+        }
+        func.optimize(&waffle::OptOptions {
+            gvn: false,
+            cprop: false,
+            redundant_blockparams: true,
+        });
+        crate::constant_offsets::run(&mut func, &cfg);
+        let aa = crate::alias::AliasAnalysis::new(&func, opts.alias_precision, image.stack_pointer);
+        crate::store_forward::run(&mut func, &aa, effects);
+        crate::extend_wrap::run(&mut func);
+        crate::known_bits::run(&mut func);
+        crate::fusion::run(&mut func, &opts.fusion_patterns);
+    }
+    waffle::passes::resolve_aliases::run(&mut func);
+    func.optimize(&waffle::OptOptions {
         gvn: false,
         cprop: false,
         redundant_blockparams: true,
     });
-    crate::dce::run(&mut evaluator.func, &cfg);
+    let cfg = if opt_level >= OptLevel::O3 {
+        let converted = crate::select_diamond::run(&mut func, &hinted_branches);
+        stats.select_diamonds += converted;
+        if converted > 0 {
+            CFGInfo::new(&func)
+        } else {
+            cfg
+        }
+    } else {
+        cfg
+    };
+    crate::dce::run(&mut func, &cfg);
+    crate::const_pool::run(&mut func);
+    crate::schedule::run(&mut func);
+    if let Some(info) = &info {
+        for pass in &opts.post_passes {
+            log::debug!("Running post-pass {}", pass.name);
+            pass.run(&mut func, info);
+        }
+    }
 
-    accumulate_stats_from_func(&mut evaluator.stats, &evaluator.func);
+    accumulate_stats_from_func(&mut stats, &func);
 
     log::info!("Specialization of {:?} done", directive);
-    log::debug!(
-        "Adding func:\n{}",
-        evaluator.func.display_verbose("| ", Some(module))
-    );
-    Ok(Some((evaluator.func, sig, name, evaluator.stats)))
+    log::debug!("Adding func:\n{}", func.display_verbose("| ", Some(module)));
+    Ok(Some(SpecializedFunc {
+        body: func,
+        sig,
+        name,
+        stats,
+        block_states,
+        region_epochs,
+        origins,
+        folds,
+        hinted_branches,
+        block_pcs,
+        facts,
+        resume,
+    }))
 }
 
 // Split at every `weval_specialize_value()` call and
@@ -438,10 +2325,265 @@ fn split_blocks_at_intrinsic_calls(func: &mut FunctionBody, intrinsics: &Intrins
     log::trace!("After splitting:\n{}\n", func.display_verbose("| ", None));
 }
 
+/// Find blocks from which every path reaches an `unreachable`
+/// terminator.
+fn find_doomed_blocks(func: &FunctionBody) -> HashSet<Block> {
+    let mut doomed = HashSet::default();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (block, blockdata) in func.blocks.entries() {
+            if doomed.contains(&block) {
+                continue;
+            }
+            let is_doomed = match &blockdata.terminator {
+                Terminator::Unreachable => true,
+                term => {
+                    let mut any = false;
+                    let mut all = true;
+                    term.visit_targets(|target| {
+                        any = true;
+                        all &= doomed.contains(&target.block);
+                    });
+                    any && all
+                }
+            };
+            if is_doomed {
+                doomed.insert(block);
+                changed = true;
+            }
+        }
+    }
+    doomed
+}
+
+/// Run waffle's cleanup passes over every function with a body that
+/// did not come from specialization (specialized functions were
+/// optimized already, and are compiled).
+fn optimize_all_funcs(module: &mut Module) -> anyhow::Result<()> {
+    let funcs = module
+        .funcs
+        .entries()
+        .filter(|(_, decl)| matches!(decl, FuncDecl::Lazy(..) | FuncDecl::Body(..)))
+        .map(|(func, _)| func)
+        .collect::<Vec<_>>();
+    let bodies = funcs
+        .par_iter()
+        .map(|&func| -> anyhow::Result<(Func, FunctionBody)> {
+            let mut body = module.clone_and_expand_body(func)?;
+            waffle::passes::resolve_aliases::run(&mut body);
+            body.optimize(&waffle::OptOptions {
+                gvn: true,
+                cprop: true,
+                redundant_blockparams: true,
+            });
+            Ok((func, body))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    log::info!("Optimized {} functions", bodies.len());
+    for (func, body) in bodies {
+        let sig = module.funcs[func].sig();
+        let name = module.funcs[func].name().to_owned();
+        module.funcs[func] = FuncDecl::Body(sig, name, body);
+    }
+    Ok(())
+}
+
+/// Find the loops annotated with `weval.peel.loop`: for each call,
+/// the innermost loop containing it. Returns each loop's body, keyed
+/// by its header.
+fn find_peeled_loops(
+    func: &FunctionBody,
+    cfg: &CFGInfo,
+    intrinsics: &Intrinsics,
+) -> HashMap<Block, HashSet<Block>> {
+    let mut loops = HashMap::default();
+    if intrinsics.peel_loop.is_none() {
+        return loops;
+    }
+    for (block, blockdata) in func.blocks.entries() {
+        let annotated = blockdata.insts.iter().any(|&inst| {
+            matches!(
+                &func.values[inst],
+                ValueDef::Operator(Operator::Call { function_index }, ..)
+                    if Some(*function_index) == intrinsics.peel_loop
+            )
+        });
+        if !annotated {
+            continue;
+        }
+        let mut header = block;
+        let body = loop {
+            if let Some(body) = natural_loop(cfg, header) {
+                if body.contains(&block) {
+                    break Some(body);
+                }
+            }
+            let parent = cfg.domtree[header];
+            if parent.is_invalid() || parent == header {
+                break None;
+            }
+            header = parent;
+        };
+        match body {
+            Some(body) => {
+                log::trace!("peeling loop at {}: body {:?}", header, body);
+                loops.insert(header, body);
+            }
+            None => log::warn!(
+                "weval.peel.loop in block {} is not in a loop; ignoring",
+                block
+            ),
+        }
+    }
+    loops
+}
+
+/// The natural loop with the given header: the header, and all
+/// blocks that reach a backedge to it without passing through it.
+/// `None` if the block is not a loop header.
+fn natural_loop(cfg: &CFGInfo, header: Block) -> Option<HashSet<Block>> {
+    let mut stack = cfg.preds[header]
+        .iter()
+        .copied()
+        .filter(|&pred| cfg.dominates(header, pred))
+        .collect::<Vec<_>>();
+    if stack.is_empty() {
+        return None;
+    }
+    let mut body = HashSet::default();
+    body.insert(header);
+    while let Some(block) = stack.pop() {
+        if body.insert(block) {
+            stack.extend(cfg.preds[block].iter().copied());
+        }
+    }
+    Some(body)
+}
+
+/// A loop detected as an interpreter's dispatch loop, to be
+/// specialized per PC as if annotated with context intrinsics.
+struct AutoLoop {
+    /// Index of the header's blockparam holding the PC.
+    pc_param: usize,
+    /// The natural loop, including the header.
+    body: HashSet<Block>,
+}
+
+/// Trace a value back through non-load operators with at most one
+/// non-constant operand, returning the first value defined otherwise.
+fn trace_through_consts(func: &FunctionBody, mut value: Value) -> Value {
+    for _ in 0..8 {
+        value = func.resolve_alias(value);
+        let (op, args) = match &func.values[value] {
+            ValueDef::Operator(op, args, _) => (op, &func.arg_pool[*args]),
+            _ => break,
+        };
+        if is_load(op) {
+            break;
+        }
+        let is_const = |v: Value| {
+            matches!(
+                &func.values[func.resolve_alias(v)],
+                ValueDef::Operator(Operator::I32Const { .. } | Operator::I64Const { .. }, _, _)
+            )
+        };
+        let mut non_const = args.iter().copied().filter(|&v| !is_const(v));
+        value = match (non_const.next(), non_const.next()) {
+            (Some(v), None) if !op.is_call() => v,
+            _ => break,
+        };
+    }
+    value
+}
+
+/// Find loops that look like interpreter dispatch loops, in a
+/// function with no context intrinsics: natural loops containing a
+/// `br_table` or `select` on a value loaded (perhaps through
+/// arithmetic with constants) from an address computed from a
+/// blockparam of the header plus constants. That blockparam is taken
+/// as the PC.
+fn find_auto_loops(
+    func: &FunctionBody,
+    cfg: &CFGInfo,
+    intrinsics: &Intrinsics,
+) -> HashMap<Block, AutoLoop> {
+    let mut loops = HashMap::default();
+    let annotated = func.blocks.values().any(|blockdata| {
+        blockdata.insts.iter().any(|&inst| {
+            matches!(
+                &func.values[inst],
+                ValueDef::Operator(Operator::Call { function_index }, ..)
+                    if Some(*function_index) == intrinsics.push_context
+                        || Some(*function_index) == intrinsics.update_context
+                        || Some(*function_index) == intrinsics.pop_context
+            )
+        })
+    });
+    if annotated {
+        return loops;
+    }
+
+    // The header blockparam, if any, from which the value selected on
+    // is loaded.
+    let pc_param = |header: Block, selector: Value| -> Option<usize> {
+        let load = trace_through_consts(func, selector);
+        let addr = match &func.values[load] {
+            ValueDef::Operator(op, args, _) if is_load(op) => func.arg_pool[*args][0],
+            _ => return None,
+        };
+        match &func.values[trace_through_consts(func, addr)] {
+            &ValueDef::BlockParam(block, index, Type::I32) if block == header => {
+                Some(index as usize)
+            }
+            _ => None,
+        }
+    };
+
+    for header in func.blocks.iter() {
+        let body = match natural_loop(cfg, header) {
+            Some(body) => body,
+            None => continue,
+        };
+        let mut blocks = body.iter().copied().collect::<Vec<_>>();
+        blocks.sort();
+        let found = blocks.into_iter().find_map(|block| {
+            let term_selector = match &func.blocks[block].terminator {
+                Terminator::Select { value, .. } => pc_param(header, *value),
+                _ => None,
+            };
+            term_selector.or_else(|| {
+                func.blocks[block]
+                    .insts
+                    .iter()
+                    .find_map(|&inst| match &func.values[inst] {
+                        ValueDef::Operator(
+                            Operator::Select | Operator::TypedSelect { .. },
+                            args,
+                            _,
+                        ) => pc_param(header, func.arg_pool[*args][2]),
+                        _ => None,
+                    })
+            })
+        });
+        if let Some(pc_param) = found {
+            log::info!(
+                "auto-detected dispatch loop at {} with PC in blockparam {}",
+                header,
+                pc_param
+            );
+            loops.insert(header, AutoLoop { pc_param, body });
+        }
+    }
+    loops
+}
+
 fn find_cut_blocks(
     func: &FunctionBody,
     cfg: &CFGInfo,
     intrinsics: &Intrinsics,
+    peeled_loops: &HashMap<Block, HashSet<Block>>,
+    auto_loops: &HashMap<Block, AutoLoop>,
 ) -> std::collections::HashSet<Block> {
     let mut blocks = std::collections::HashSet::default();
 
@@ -461,6 +2603,21 @@ fn find_cut_blocks(
             }
         }
     }
+    // Edges into, around, and out of a peeled or automatically
+    // detected loop also change context.
+    let loop_bodies = peeled_loops
+        .iter()
+        .chain(auto_loops.iter().map(|(header, l)| (header, &l.body)));
+    for (&header, body) in loop_bodies {
+        change_ctx_blocks.extend(cfg.preds[header].iter().copied());
+        for &block in body {
+            func.blocks[block].terminator.visit_targets(|target| {
+                if !body.contains(&target.block) {
+                    change_ctx_blocks.insert(block);
+                }
+            });
+        }
+    }
 
     // For each block, we'll find a "highest same-context ancestor" in
     // the domtree.
@@ -529,42 +2686,62 @@ fn accumulate_stats_from_func(stats: &mut SpecializationStats, func: &FunctionBo
     }
 }
 
-fn const_operator(ty: Type, value: WasmVal) -> Option<Operator> {
-    match (ty, value) {
-        (Type::I32, WasmVal::I32(k)) => Some(Operator::I32Const { value: k }),
-        (Type::I64, WasmVal::I64(k)) => Some(Operator::I64Const { value: k }),
-        (Type::F32, WasmVal::F32(k)) => Some(Operator::F32Const { value: k }),
-        (Type::F64, WasmVal::F64(k)) => Some(Operator::F64Const { value: k }),
-        _ => None,
+fn is_load(op: &Operator) -> bool {
+    match op {
+        Operator::I32Load { .. }
+        | Operator::I64Load { .. }
+        | Operator::F32Load { .. }
+        | Operator::F64Load { .. }
+        | Operator::I32Load8S { .. }
+        | Operator::I32Load8U { .. }
+        | Operator::I32Load16S { .. }
+        | Operator::I32Load16U { .. }
+        | Operator::I64Load8S { .. }
+        | Operator::I64Load8U { .. }
+        | Operator::I64Load16S { .. }
+        | Operator::I64Load16U { .. }
+        | Operator::I64Load32S { .. }
+        | Operator::I64Load32U { .. }
+        | Operator::V128Load { .. } => true,
+        _ => false,
     }
 }
 
-fn store_operator(ty: Type) -> Option<Operator> {
-    let memory = MemoryArg {
-        memory: Memory::new(0),
-        align: 0,
-        offset: 0,
-    };
-    match ty {
-        Type::I32 => Some(Operator::I32Store { memory }),
-        Type::I64 => Some(Operator::I64Store { memory }),
-        Type::F32 => Some(Operator::F32Store { memory }),
-        Type::F64 => Some(Operator::F64Store { memory }),
-        _ => None,
+fn is_int_compare(op: &Operator) -> bool {
+    match op {
+        Operator::I32Eqz
+        | Operator::I32Eq
+        | Operator::I32Ne
+        | Operator::I32LtS
+        | Operator::I32LtU
+        | Operator::I32GtS
+        | Operator::I32GtU
+        | Operator::I32LeS
+        | Operator::I32LeU
+        | Operator::I32GeS
+        | Operator::I32GeU
+        | Operator::I64Eqz
+        | Operator::I64Eq
+        | Operator::I64Ne
+        | Operator::I64LtS
+        | Operator::I64LtU
+        | Operator::I64GtS
+        | Operator::I64GtU
+        | Operator::I64LeS
+        | Operator::I64LeU
+        | Operator::I64GeS
+        | Operator::I64GeU => true,
+        _ => false,
     }
 }
 
-fn load_operator(ty: Type) -> Option<Operator> {
-    let memory = MemoryArg {
-        memory: Memory::new(0),
-        align: 0,
-        offset: 0,
-    };
-    match ty {
-        Type::I32 => Some(Operator::I32Load { memory }),
-        Type::I64 => Some(Operator::I64Load { memory }),
-        Type::F32 => Some(Operator::F32Load { memory }),
-        Type::F64 => Some(Operator::F64Load { memory }),
+fn const_operator(ty: Type, value: WasmVal) -> Option<Operator> {
+    match (ty, value) {
+        (Type::I32, WasmVal::I32(k)) => Some(Operator::I32Const { value: k }),
+        (Type::I64, WasmVal::I64(k)) => Some(Operator::I64Const { value: k }),
+        (Type::F32, WasmVal::F32(k)) => Some(Operator::F32Const { value: k }),
+        (Type::F64, WasmVal::F64(k)) => Some(Operator::F64Const { value: k }),
+        (Type::V128, WasmVal::V128(k)) => Some(Operator::V128Const { value: k }),
         _ => None,
     }
 }
@@ -575,7 +2752,6 @@ enum EvalResult {
     Elide,
     Alias(AbstractValue, Value),
     Normal(AbstractValue),
-    NewBlock(Block, AbstractValue, Value),
 }
 impl EvalResult {
     fn is_handled(&self) -> bool {
@@ -584,27 +2760,290 @@ impl EvalResult {
             _ => true,
         }
     }
-}
-
-const MAX_BLOCKS: usize = 100_000;
-const MAX_VALUES: usize = 1_000_000;
+}
+
+const MAX_BLOCKS: usize = 100_000;
+const MAX_VALUES: usize = 1_000_000;
+/// Largest `memory.init` folded into stores.
+const MAX_FOLDED_INIT_BYTES: u32 = 64;
+
+impl<'a> Evaluator<'a> {
+    fn evaluate(&mut self) -> anyhow::Result<bool> {
+        while let Some(Reverse((ctx, rpo, orig_block, new_block))) = self.queue.pop() {
+            if self.too_large() {
+                return Ok(false);
+            }
+            if let Some(limit) = self.block_limit {
+                if self.block_map.len() >= limit {
+                    log::info!(" -> estimate cut off at {} blocks", self.block_map.len());
+                    self.cut_off = true;
+                    return Ok(false);
+                }
+            }
+            if self
+                .deadline
+                .map_or(false, |deadline| Instant::now() >= deadline)
+            {
+                self.queue.push(Reverse((ctx, rpo, orig_block, new_block)));
+                if !self.resume_pending()? {
+                    return Ok(false);
+                }
+                break;
+            }
+            self.queue_set.remove(&(orig_block, ctx));
+            self.stats.block_evaluations += 1;
+            self.evaluate_block(orig_block, ctx, new_block)?;
+            self.note_evaluated(ctx);
+        }
+        log::debug!(
+            "evaluated {} blocks ({} evaluations)",
+            self.block_map.len(),
+            self.stats.block_evaluations
+        );
+        if !self.assert_failures.is_empty() {
+            anyhow::bail!(
+                "Specialization {} of site {}: {} weval_assert_const32() check(s) failed:\n{}",
+                self.directive.id(),
+                self.directive.user_id,
+                self.assert_failures.len(),
+                self.assert_failures
+                    .values()
+                    .map(|failure| format!("  - {}", failure))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+        if !self.unfolded_intrinsics.is_empty() {
+            let problems = self
+                .unfolded_intrinsics
+                .values()
+                .map(|problem| format!("  - {}", problem))
+                .collect::<Vec<_>>()
+                .join("\n");
+            match self.strict_intrinsics {
+                StrictIntrinsics::Off => {}
+                StrictIntrinsics::Warn => log::warn!(
+                    "Specialization {} of site {}: {} intrinsic call(s) not acted on:\n{}",
+                    self.directive.id(),
+                    self.directive.user_id,
+                    self.unfolded_intrinsics.len(),
+                    problems
+                ),
+                StrictIntrinsics::Error => anyhow::bail!(
+                    "Specialization {} of site {}: {} intrinsic call(s) not acted on:\n{}",
+                    self.directive.id(),
+                    self.directive.user_id,
+                    self.unfolded_intrinsics.len(),
+                    problems
+                ),
+            }
+        }
+        if !self.oob_reads.is_empty() && self.oob_reads_mode != OutOfBoundsReads::Degrade {
+            for read in self.oob_reads.values() {
+                log::warn!(
+                    "Specialization {} of site {}: out-of-bounds constant read: {}",
+                    self.directive.id(),
+                    self.directive.user_id,
+                    read
+                );
+            }
+            if self.oob_reads_mode == OutOfBoundsReads::Fail {
+                log::warn!(
+                    "Not specializing {}: {} out-of-bounds constant read(s)",
+                    self.directive.id(),
+                    self.oob_reads.len()
+                );
+                return Ok(false);
+            }
+        }
+        self.finalize()?;
+        Ok(true)
+    }
+
+    fn too_large(&self) -> bool {
+        if self.func.blocks.len() > MAX_BLOCKS || self.func.values.len() > MAX_VALUES {
+            log::info!(
+                " -> too many blocks or values: {} blocks {} values",
+                self.func.blocks.len(),
+                self.func.values.len()
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Out of time: end each block still queued by resuming the
+    /// generic function there instead, keeping the blocks already
+    /// evaluated. Returns whether that was possible.
+    ///
+    /// Only at cut blocks of the generic body is nothing but the
+    /// block's params live, so queued blocks elsewhere are evaluated
+    /// first; they stay in the contexts they are in (a context change
+    /// always leads to a cut block), so this ends. Values held only
+    /// in specialized code must be handed over too: the virtualized
+    /// stack and locals are spilled to memory, as at a sync point, but
+    /// specialization registers have no generic counterpart, so a
+    /// queued block holding any can't be resumed at.
+    fn resume_pending(&mut self) -> anyhow::Result<bool> {
+        log::info!(
+            "Specialization {} of site {} timed out with {} blocks queued",
+            self.directive.id(),
+            self.directive.user_id,
+            self.queue.len()
+        );
+        let mut pending = BTreeMap::new();
+        while let Some(Reverse((ctx, _, orig_block, new_block))) = self.queue.pop() {
+            self.queue_set.remove(&(orig_block, ctx));
+            if self.cut_blocks.contains(&orig_block) {
+                pending.insert(new_block, (ctx, orig_block));
+                continue;
+            }
+            if self.too_large() {
+                return Ok(false);
+            }
+            self.stats.block_evaluations += 1;
+            self.evaluate_block(orig_block, ctx, new_block)?;
+        }
+        if let Some(&block) = pending
+            .keys()
+            .find(|&&block| !self.state.block_entry[block].regs.is_empty())
+        {
+            log::info!(
+                " -> can't resume at block {}: specialization registers are live",
+                block
+            );
+            return Ok(false);
+        }
+
+        let entries = pending
+            .values()
+            .map(|&(_, orig_block)| orig_block)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut resume = match crate::split::Resumable::new(self.generic, &entries[..]) {
+            Some(resume) => resume,
+            None => return Ok(false),
+        };
+        for (&new_block, &(ctx, orig_block)) in &pending {
+            let mut state = self.enter_block(orig_block, ctx, new_block)?;
+            let spills = state
+                .flow
+                .stack
+                .drain(..)
+                .chain(std::mem::take(&mut state.flow.locals).into_values());
+            for (addr, data) in spills {
+                let args = self
+                    .func
+                    .arg_pool
+                    .double(addr.value().unwrap(), data.value().unwrap());
+                let store = self.func.add_value(ValueDef::Operator(
+                    Operator::I64Store {
+                        memory: MemoryArg {
+                            align: 1,
+                            offset: 0,
+                            memory: self.image.main_heap().unwrap(),
+                        },
+                    },
+                    args,
+                    ListRef::default(),
+                ));
+                self.func.blocks[new_block].insts.push(store);
+            }
+            self.state.block_exit[new_block] = ProgPointState::default();
+            let params = self.func.blocks[new_block]
+                .params
+                .iter()
+                .map(|&(_, param)| param)
+                .collect::<Vec<_>>();
+            resume.resume(&mut self.func, new_block, orig_block, &params[..]);
+        }
+        log::info!(
+            " -> resuming the generic function at {} blocks ({} generic blocks)",
+            pending.len(),
+            resume.entries()
+        );
+        self.stats.resumed_blocks = pending.len();
+        self.resume = Some(resume);
+        Ok(true)
+    }
+
+    /// Queue a specialized block for (re)evaluation, if not already
+    /// queued.
+    fn enqueue(&mut self, orig_block: Block, ctx: Context, new_block: Block) {
+        if self.queue_set.insert((orig_block, ctx)) {
+            let rpo = self.cfg.rpo_pos[orig_block].map_or(usize::MAX, |pos| pos.index());
+            self.queue.push(Reverse((ctx, rpo, orig_block, new_block)));
+            self.stats.max_queue_len = std::cmp::max(self.stats.max_queue_len, self.queue.len());
+            Self::note_queued(
+                &mut self.queued_in_region,
+                &mut self.retired,
+                &mut self.stats,
+                &self.state.contexts,
+                ctx,
+            );
+        }
+    }
+
+    /// Count a block just queued in `ctx` towards the region of the
+    /// context and of each enclosing one, if retiring drained
+    /// contexts. A retired context entered again is live again.
+    fn note_queued(
+        queued_in_region: &mut Option<HashMap<Context, usize>>,
+        retired: &mut HashSet<Context>,
+        stats: &mut SpecializationStats,
+        contexts: &Contexts,
+        mut ctx: Context,
+    ) {
+        let queued = match queued_in_region {
+            Some(queued) => queued,
+            None => return,
+        };
+        if retired.remove(&ctx) {
+            stats.reentered_contexts += 1;
+        }
+        while ctx.is_valid() {
+            *queued.entry(ctx).or_default() += 1;
+            ctx = contexts.parent(ctx);
+        }
+    }
+
+    /// Uncount a block just evaluated in `ctx`, and retire each
+    /// context whose region has drained.
+    fn note_evaluated(&mut self, mut ctx: Context) {
+        let queued = match &mut self.queued_in_region {
+            Some(queued) => queued,
+            None => return,
+        };
+        let mut drained = vec![];
+        while ctx.is_valid() {
+            let count = queued.get_mut(&ctx).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                queued.remove(&ctx);
+                drained.push(ctx);
+            }
+            ctx = self.state.contexts.parent(ctx);
+        }
+        for ctx in drained {
+            self.retire(ctx);
+        }
+    }
 
-impl<'a> Evaluator<'a> {
-    fn evaluate(&mut self) -> anyhow::Result<bool> {
-        while let Some((orig_block, ctx, new_block)) = self.queue.pop_back() {
-            if self.func.blocks.len() > MAX_BLOCKS || self.func.values.len() > MAX_VALUES {
-                log::info!(
-                    " -> too many blocks or values: {} blocks {} values",
-                    self.func.blocks.len(),
-                    self.func.values.len()
-                );
-                return Ok(false);
-            }
-            self.queue_set.remove(&(orig_block, ctx));
-            self.evaluate_block(orig_block, ctx, new_block)?;
+    /// Drop the values of globals from the entry states of `ctx`'s
+    /// blocks (exit states never keep them). This only loses
+    /// precision: should the context be entered again, a meet into an
+    /// entry state without globals makes each unknown, and a block
+    /// evaluated from one reads each as unknown.
+    fn retire(&mut self, ctx: Context) {
+        if !self.retired.insert(ctx) {
+            return;
         }
-        self.finalize()?;
-        Ok(true)
+        log::trace!("retiring drained context {}", ctx);
+        for block in self.block_map.row(ctx) {
+            self.state.block_entry[block].globals = BTreeMap::new();
+        }
+        self.stats.retired_contexts += 1;
     }
 
     fn evaluate_block(
@@ -613,10 +3052,60 @@ impl<'a> Evaluator<'a> {
         ctx: Context,
         new_block: Block,
     ) -> anyhow::Result<()> {
+        let mut state = self.enter_block(orig_block, ctx, new_block)?;
+
+        // Do the actual constant-prop, carrying the state across the
+        // block and updating flow-sensitive state, and updating SSA
+        // vals as well.
+        let new_block = self
+            .evaluate_block_body(orig_block, &mut state, new_block)
+            .map_err(|e| {
+                e.context(anyhow::anyhow!(
+                    "Evaluating block body {} in func:\n{}",
+                    orig_block,
+                    self.generic.display("| ", None)
+                ))
+            })?;
+
+        // Store the exit state at this point for later use. Only what
+        // `add_blockparam_reg_args` and `insert_stack_syncs` read back
+        // is kept, not a copy of every global's value per block.
+        self.state.block_exit[new_block] = ProgPointState {
+            regs: state.flow.regs.clone(),
+            globals: BTreeMap::new(),
+            stack: state.flow.stack.clone(),
+            locals: state.flow.locals.clone(),
+            operand_stack: state.flow.operand_stack,
+            stack_slots: state.flow.stack_slots.clone(),
+        };
+
+        self.evaluate_term(orig_block, &mut state, new_block);
+
+        Ok(())
+    }
+
+    /// Start (re)building a specialized block: clear its body and
+    /// dependencies, and return its entry state.
+    fn enter_block(
+        &mut self,
+        orig_block: Block,
+        ctx: Context,
+        new_block: Block,
+    ) -> anyhow::Result<PointState> {
         // Clear the block body each time we rebuild it -- we may be
         // recomputing a specialization with an existing output.
         self.func.blocks[new_block].insts.clear();
 
+        // Likewise its dependencies: the block re-registers the
+        // values it still uses as it is evaluated, so that it is not
+        // re-evaluated when a value it no longer uses (e.g. on a
+        // branch since folded away) changes.
+        for key in std::mem::take(&mut self.block_deps[new_block]) {
+            if let Some(blocks) = self.value_dep_blocks.get_mut(&key) {
+                blocks.remove(&new_block);
+            }
+        }
+
         log::trace!(
             "evaluate_block: orig {} ctx {} new {}",
             orig_block,
@@ -655,25 +3144,7 @@ impl<'a> Evaluator<'a> {
             }
         )?;
 
-        // Do the actual constant-prop, carrying the state across the
-        // block and updating flow-sensitive state, and updating SSA
-        // vals as well.
-        let new_block = self
-            .evaluate_block_body(orig_block, &mut state, new_block)
-            .map_err(|e| {
-                e.context(anyhow::anyhow!(
-                    "Evaluating block body {} in func:\n{}",
-                    orig_block,
-                    self.generic.display("| ", None)
-                ))
-            })?;
-
-        // Store the exit state at this point for later use.
-        self.state.block_exit[new_block] = state.flow.clone();
-
-        self.evaluate_term(orig_block, &mut state, new_block);
-
-        Ok(())
+        Ok(state)
     }
 
     /// For a given value in the generic function, accessed in the
@@ -693,11 +3164,14 @@ impl<'a> Evaluator<'a> {
             context
         );
         if let Some(&val) = self.value_map.get(&(context, orig_val)) {
-            if self.cfg.def_block[orig_val] != orig_block {
-                self.value_dep_blocks
+            if self.cfg.def_block[orig_val] != orig_block
+                && self
+                    .value_dep_blocks
                     .entry((context, orig_val))
                     .or_default()
-                    .insert(new_block);
+                    .insert(new_block)
+            {
+                self.block_deps[new_block].push((context, orig_val));
             }
             let abs = &self.state.values[val];
             log::trace!(" -> found abstract  value {:?} at context {}", abs, context);
@@ -710,6 +3184,10 @@ impl<'a> Evaluator<'a> {
         );
     }
 
+    /// Define a generic value in `context`, meeting `abs`, and the
+    /// imported globals it is `blocked_on`, into what earlier
+    /// definitions (of other evaluations, or other edges into a
+    /// blockparam) gave, each as its tag declares.
     fn def_value(
         &mut self,
         block: Block,
@@ -717,6 +3195,7 @@ impl<'a> Evaluator<'a> {
         orig_val: Value,
         val: Value,
         abs: AbstractValue,
+        blocked_on: BTreeSet<waffle::Global>,
     ) -> bool {
         log::debug!(
             "defining val {} in block {} context {} with specialized val {} abs {:?}",
@@ -729,7 +3208,7 @@ impl<'a> Evaluator<'a> {
         self.value_map.insert((context, orig_val), val);
         let val_abs = &mut self.state.values[val];
         let updated = AbstractValue::meet(val_abs, &abs);
-        let changed = updated != *val_abs;
+        let mut changed = updated != *val_abs;
         log::debug!(
             " -> meet: cur {:?} input {:?} result {:?} (changed: {})",
             val_abs,
@@ -739,12 +3218,29 @@ impl<'a> Evaluator<'a> {
         );
         *val_abs = updated;
 
+        // Only runtime values are blocked on anything.
+        if let AbstractValue::Runtime(_) = self.state.values[val] {
+            if !blocked_on.is_empty() {
+                let globals = self.blocked_on.entry((context, orig_val)).or_default();
+                changed |= Tag::BlockedOn.meet().meet_sets(globals, &blocked_on);
+            }
+        }
+
         if changed {
             if let Some(deps) = self.value_dep_blocks.get(&(context, orig_val)) {
                 for &new_block in deps {
+                    // (Inlined `enqueue`, as `deps` borrows `self`.)
                     let (ctx, block) = self.block_rev_map[new_block];
                     if self.queue_set.insert((block, ctx)) {
-                        self.queue.push_back((block, ctx, new_block));
+                        let rpo = self.cfg.rpo_pos[block].map_or(usize::MAX, |pos| pos.index());
+                        self.queue.push(Reverse((ctx, rpo, block, new_block)));
+                        Self::note_queued(
+                            &mut self.queued_in_region,
+                            &mut self.retired,
+                            &mut self.stats,
+                            &self.state.contexts,
+                            ctx,
+                        );
                     }
                 }
             }
@@ -755,9 +3251,7 @@ impl<'a> Evaluator<'a> {
 
     fn enqueue_block_if_existing(&mut self, orig_block: Block, context: Context) {
         if let Some(block) = self.block_map.get(&(context, orig_block)).copied() {
-            if self.queue_set.insert((orig_block, context)) {
-                self.queue.push_back((orig_block, context, block));
-            }
+            self.enqueue(orig_block, context, block);
         }
     }
 
@@ -765,15 +3259,20 @@ impl<'a> Evaluator<'a> {
         &mut self,
         orig_block: Block,
         state: &mut PointState,
-        mut new_block: Block,
+        new_block: Block,
     ) -> anyhow::Result<Block> {
         // Reused below for each instruction.
         let mut arg_abs_values = vec![];
+        // Number of generic instructions folded away, for metering
+        // and stats.
+        let mut folded = 0u64;
+        let entry_ctx = state.context;
 
         log::trace!("evaluate_block_body: {}: state {:?}", orig_block, state);
 
         for &inst in &self.generic.blocks[orig_block].insts {
             let input_ctx = state.context;
+            self.pending_blocked_on.clear();
             log::trace!(
                 "inst {} in context {} -> {:?}",
                 inst,
@@ -808,6 +3307,7 @@ impl<'a> Evaluator<'a> {
                         let arg = self.generic.resolve_alias(arg);
                         log::trace!(" -> resolves to arg {}", arg);
                         let (val, abs) = self.use_value(state.context, orig_block, new_block, arg);
+                        let abs = self.stack_base_abs(state, val, abs);
                         arg_abs_values.push(abs);
                         self.func.arg_pool[arg_values][i] = val;
                     }
@@ -833,6 +3333,41 @@ impl<'a> Evaluator<'a> {
                         .func
                         .type_pool
                         .from_iter(self.generic.type_pool[*tys].iter().cloned());
+                    let is_const = matches!(
+                        op,
+                        Operator::I32Const { .. }
+                            | Operator::I64Const { .. }
+                            | Operator::F32Const { .. }
+                            | Operator::F64Const { .. }
+                            | Operator::V128Const { .. }
+                    );
+                    if !is_const
+                        && matches!(
+                            result,
+                            EvalResult::Alias(..)
+                                | EvalResult::Elide
+                                | EvalResult::Normal(
+                                    AbstractValue::Concrete(_) | AbstractValue::StaticMemory(_)
+                                )
+                        )
+                    {
+                        folded += 1;
+                        if let Some(folds) = &mut self.folds {
+                            let result = match &result {
+                                EvalResult::Alias(av, _) => format!("alias {:?}", av),
+                                EvalResult::Elide => "elided".to_owned(),
+                                EvalResult::Normal(av) => format!("{:?}", av),
+                                _ => unreachable!(),
+                            };
+                            let inputs = arg_abs_values.iter().map(|abs| format!("{:?}", abs));
+                            folds.insert(
+                                (input_ctx, inst),
+                                (format!("{:?}", op), inputs.collect(), result),
+                            );
+                        }
+                    } else if let Some(folds) = &mut self.folds {
+                        folds.remove(&(input_ctx, inst));
+                    }
                     match result {
                         EvalResult::Unhandled => unreachable!(),
                         EvalResult::Alias(av, val) => Some((ValueDef::Alias(val), av)),
@@ -874,10 +3409,6 @@ impl<'a> Evaluator<'a> {
                             ),
                             av,
                         )),
-                        EvalResult::NewBlock(block, av, value) => {
-                            new_block = block;
-                            Some((ValueDef::Alias(value), av))
-                        }
                     }
                 }
                 ValueDef::Trace(id, args) => {
@@ -903,24 +3434,103 @@ impl<'a> Evaluator<'a> {
                 self.func.append_to_block(new_block, result_value);
                 self.func.source_locs[result_value] = self.generic.source_locs[inst];
 
-                self.def_value(orig_block, input_ctx, inst, result_value, result_abs);
+                let blocked_on = std::mem::take(&mut self.pending_blocked_on);
+                self.def_value(
+                    orig_block,
+                    input_ctx,
+                    inst,
+                    result_value,
+                    result_abs,
+                    blocked_on,
+                );
+            }
+        }
+
+        self.block_folds
+            .insert((entry_ctx, orig_block), folded as usize);
+        if let Some(metering) = self.metering {
+            if folded > 0 {
+                self.charge_metering(new_block, metering, folded);
+                state
+                    .flow
+                    .globals
+                    .insert(metering.global, AbstractValue::Runtime(None));
             }
         }
 
         Ok(new_block)
     }
 
+    /// Appends `global.set G (global.get G - cost * folded)` to the
+    /// block.
+    fn charge_metering(&mut self, block: Block, metering: Metering, folded: u64) {
+        let ty = self.module.globals[metering.global].ty;
+        let charge = metering.cost.saturating_mul(folded);
+        let (k_op, sub_op) = match ty {
+            Type::I32 => (
+                Operator::I32Const {
+                    value: charge as u32,
+                },
+                Operator::I32Sub,
+            ),
+            Type::I64 => (Operator::I64Const { value: charge }, Operator::I64Sub),
+            _ => unreachable!(),
+        };
+        let tys = self.func.single_type_list(ty);
+        let get = self.func.add_value(ValueDef::Operator(
+            Operator::GlobalGet {
+                global_index: metering.global,
+            },
+            ListRef::default(),
+            tys,
+        ));
+        let k = self
+            .func
+            .add_value(ValueDef::Operator(k_op, ListRef::default(), tys));
+        let args = self.func.arg_pool.double(get, k);
+        let sub = self.func.add_value(ValueDef::Operator(sub_op, args, tys));
+        let args = self.func.arg_pool.single(sub);
+        let set = self.func.add_value(ValueDef::Operator(
+            Operator::GlobalSet {
+                global_index: metering.global,
+            },
+            args,
+            ListRef::default(),
+        ));
+        for value in [get, k, sub, set] {
+            self.func.blocks[block].insts.push(value);
+        }
+    }
+
     fn meet_into_block_entry(
         &mut self,
-        _block: Block,
-        _context: Context,
+        block: Block,
+        context: Context,
         new_block: Block,
         state: &ProgPointState,
     ) -> bool {
         let mut state = state.clone();
         state.update_across_edge();
 
-        self.state.block_entry[new_block].meet_with(&state)
+        match self.base_entry(context, block) {
+            None => self.state.block_entry[new_block].meet_with(&state),
+            Some(base_state) => {
+                // Refining may undo some of what the meet loses, so
+                // compare the result as a whole.
+                let entry = &mut self.state.block_entry[new_block];
+                let before = entry.clone();
+                entry.meet_with(&state);
+                entry.refine_with(base_state);
+                *entry != before
+            }
+        }
+    }
+
+    /// The base specialization's entry state for `orig_block` in
+    /// `context`, if this is a delta directive and the base reached
+    /// it.
+    fn base_entry(&self, context: Context, orig_block: Block) -> Option<&'a ProgPointState> {
+        self.base?.entries.get(&(context, orig_block))
     }
 
     fn context_desc(&self, ctx: Context) -> String {
@@ -928,9 +3538,326 @@ impl<'a> Evaluator<'a> {
             ContextElem::Root => "root".to_owned(),
             ContextElem::Loop(pc) => format!("PC {:?}", pc),
             ContextElem::Specialized(index, val) => format!("Specialization of {}: {}", index, val),
+            ContextElem::Peeled(header) => format!("First iteration of loop at {}", header),
+            ContextElem::Global(global, val) => format!("Global {}: {}", global, val),
+            ContextElem::AutoLoop(header, pc) => format!("Loop at {}: PC {:?}", header, pc),
+        }
+    }
+
+    fn context_stack_desc(&self, mut ctx: Context) -> Vec<String> {
+        let mut descs = vec![];
+        while ctx.is_valid() {
+            descs.push(self.context_desc(ctx));
+            ctx = self.state.contexts.parent(ctx);
+        }
+        descs.reverse();
+        descs
+    }
+
+    /// Track whether a load from a known address produced a value
+    /// only known at runtime.
+    fn note_load_precision(
+        &mut self,
+        orig_block: Block,
+        orig_inst: Value,
+        addr: &AbstractValue,
+        result: &AbstractValue,
+        state: &PointState,
+    ) {
+        let key = (state.context, orig_inst);
+        let known_addr = match addr {
+            AbstractValue::Top | AbstractValue::Runtime(_) => false,
+            _ => true,
+        };
+        let reason = self.pending_load_loss.take();
+        let oob_read = self.pending_oob_read.take();
+        if known_addr && matches!(result, AbstractValue::Runtime(_)) {
+            let reason = reason.unwrap_or(match addr {
+                AbstractValue::Concrete(_) => LoadLossReason::NotConstTagged,
+                _ => LoadLossReason::UnsupportedLoad,
+            });
+            self.load_losses.insert(key, (orig_block, reason));
+            match oob_read {
+                Some((addr, size)) if reason == LoadLossReason::OutOfBounds => {
+                    let loc = crate::analyze::source_loc_desc(
+                        self.module,
+                        self.generic.source_locs[orig_inst],
+                    )
+                    .map(|loc| format!(" ({})", loc))
+                    .unwrap_or_default();
+                    self.oob_reads.insert(
+                        key,
+                        format!(
+                            "{}-byte load {}{} at {} in block {}, context [{}]",
+                            size,
+                            orig_inst,
+                            loc,
+                            addr,
+                            orig_block,
+                            self.context_stack_desc(state.context).join(", ")
+                        ),
+                    );
+                }
+                _ => {
+                    self.oob_reads.remove(&key);
+                }
+            }
+        } else {
+            self.load_losses.remove(&key);
+            self.oob_reads.remove(&key);
+        }
+    }
+
+    /// Collect precision-loss points: the first runtime branch in
+    /// each context, and every unproven load.
+    fn precision_losses(&self) -> Vec<PrecisionLoss> {
+        let ids = self.state.contexts.canonical_ids();
+        let mut losses = vec![];
+        let mut last_ctx = None;
+        for (&(ctx, block), &(kind, value)) in &self.branch_losses {
+            if last_ctx == Some(ctx) {
+                continue;
+            }
+            last_ctx = Some(ctx);
+            losses.push((ids[ctx], self.precision_loss(kind, ctx, block, value)));
+        }
+        for (&(ctx, value), &(block, _)) in &self.load_losses {
+            losses.push((
+                ids[ctx],
+                self.precision_loss(PrecisionLossKind::Load, ctx, block, value),
+            ));
+        }
+        // Report in canonical context order, so that reports from
+        // different runs are comparable.
+        losses.sort_by_key(|(id, _)| *id);
+        losses.into_iter().map(|(_, loss)| loss).collect()
+    }
+
+    /// Summarize unfolded loads, aggregated over contexts, for
+    /// `--explain-loads`.
+    fn unfolded_loads(&self) -> Vec<UnfoldedLoad> {
+        let mut counts: BTreeMap<(Value, LoadLossReason), (Block, usize)> = BTreeMap::new();
+        for (&(_, value), &(block, reason)) in &self.load_losses {
+            counts.entry((value, reason)).or_insert((block, 0)).1 += 1;
+        }
+        counts
+            .into_iter()
+            .map(|((value, reason), (block, contexts))| UnfoldedLoad {
+                block: block.index(),
+                value: value.index(),
+                loc: crate::analyze::source_loc_desc(self.module, self.generic.source_locs[value]),
+                reason,
+                contexts,
+            })
+            .collect()
+    }
+
+    /// A load result that is known only at runtime, noting why.
+    fn unfolded_load(&mut self, orig_inst: Value, reason: LoadLossReason) -> AbstractValue {
+        self.pending_load_loss = Some(reason);
+        AbstractValue::Runtime(Some(orig_inst))
+    }
+
+    /// The result of a load of `size` bytes at `addr` that read out
+    /// of bounds of its constant buffer or the image.
+    fn out_of_bounds_load(&mut self, orig_inst: Value, addr: String, size: u32) -> AbstractValue {
+        self.pending_oob_read = Some((addr, size));
+        self.unfolded_load(orig_inst, LoadLossReason::OutOfBounds)
+    }
+
+    /// Note that the intrinsic `name` being evaluated could not act on
+    /// its arguments, and what was done instead.
+    fn intrinsic_problem(&mut self, name: &str, problem: String) {
+        self.pending_intrinsic_problem = Some(format!("{}: {}", name, problem));
+    }
+
+    /// Record or clear, for strict mode, the problem (if any) noted by
+    /// `intrinsic_problem` while evaluating `orig_inst`.
+    fn note_unfolded_intrinsic(
+        &mut self,
+        orig_block: Block,
+        orig_inst: Value,
+        loc: SourceLoc,
+        ctx: Context,
+    ) {
+        let problem = self.pending_intrinsic_problem.take();
+        if self.strict_intrinsics == StrictIntrinsics::Off {
+            return;
+        }
+        let key = (ctx, orig_inst);
+        match problem {
+            Some(problem) => {
+                let loc = crate::analyze::source_loc_desc(self.module, loc)
+                    .map(|loc| format!(" ({})", loc))
+                    .unwrap_or_default();
+                let desc = format!(
+                    "{}{} in block {}, context [{}]",
+                    problem,
+                    loc,
+                    orig_block,
+                    self.context_stack_desc(ctx).join(", ")
+                );
+                self.unfolded_intrinsics.insert(key, desc);
+            }
+            None => {
+                self.unfolded_intrinsics.remove(&key);
+            }
+        }
+    }
+
+    fn precision_loss(
+        &self,
+        kind: PrecisionLossKind,
+        ctx: Context,
+        block: Block,
+        value: Value,
+    ) -> PrecisionLoss {
+        PrecisionLoss {
+            kind,
+            context: self.context_stack_desc(ctx),
+            block: block.index(),
+            value: value.index(),
+            loc: crate::analyze::source_loc_desc(self.module, self.generic.source_locs[value]),
+            label: self.labels.get(&ctx).cloned(),
+            blocked_on: self
+                .blocked_on_globals(ctx, value)
+                .map(|globals| {
+                    globals
+                        .iter()
+                        .map(|global| self.imported_globals.names[global].clone())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The generic block and context stack of every specialized
+    /// block.
+    fn block_origins(&self) -> BTreeMap<Block, (usize, Vec<String>)> {
+        self.block_map
+            .iter()
+            .map(|((ctx, orig_block), block)| {
+                (block, (orig_block.index(), self.context_stack_desc(ctx)))
+            })
+            .collect()
+    }
+
+    /// What post-passes may know about this specialization.
+    fn specialization_info(&self) -> SpecializationInfo {
+        SpecializationInfo {
+            id: self.directive.id(),
+            user_id: self.directive.user_id,
+            generic: self.directive.func,
+            opt_level: self.opt_level,
+            value_origins: self
+                .value_map
+                .iter()
+                .map(|(&(_, generic), &specialized)| (specialized, generic))
+                .collect(),
+            block_origins: self
+                .block_map
+                .iter()
+                .map(|((_, generic), specialized)| (specialized, generic))
+                .collect(),
+        }
+    }
+
+    /// The logged folds, if logging them, in context and generic
+    /// value order.
+    fn fold_log(&self) -> Option<Vec<Fold>> {
+        let folds = self.folds.as_ref()?;
+        Some(
+            folds
+                .iter()
+                .map(|(&(ctx, value), (op, inputs, result))| Fold {
+                    context: self.context_stack_desc(ctx),
+                    generic_value: value.index(),
+                    op: op.clone(),
+                    inputs: inputs.clone(),
+                    result: result.clone(),
+                })
+                .collect(),
+        )
+    }
+
+    /// The innermost PC of the context of every specialized block
+    /// that has one.
+    fn block_pcs(&self) -> BTreeMap<Block, PC> {
+        self.block_map
+            .iter()
+            .filter_map(|((ctx, _), block)| {
+                let pc = self.state.contexts.path(ctx).into_iter().rev().find_map(
+                    |elem| match elem {
+                        ContextElem::Loop(pc) | ContextElem::AutoLoop(_, pc) => Some(pc),
+                        _ => None,
+                    },
+                )?;
+                Some((block, pc))
+            })
+            .collect()
+    }
+
+    /// What this specialization found, for delta directives based on
+    /// it.
+    fn base_facts(&self) -> BaseFacts {
+        BaseFacts {
+            contexts: self.state.contexts.clone(),
+            entries: self
+                .block_map
+                .iter()
+                .map(|(key, block)| (key, self.state.block_entry[block].clone()))
+                .collect(),
         }
     }
 
+    fn block_entry_states(&self) -> Vec<BlockEntryState> {
+        let ids = self.state.contexts.canonical_ids();
+        let mut states = self
+            .block_map
+            .iter()
+            .map(|((ctx, orig_block), block)| {
+                let entry = &self.state.block_entry[block];
+                BlockEntryState {
+                    context: ids[ctx],
+                    context_stack: self.context_stack_desc(ctx),
+                    label: self.labels.get(&ctx).cloned(),
+                    orig_block: orig_block.index(),
+                    specialized_block: block.index(),
+                    params: self.func.blocks[block].params
+                        [..self.generic.blocks[orig_block].params.len()]
+                        .iter()
+                        .map(|&(_, param)| self.state.values[param].clone())
+                        .collect(),
+                    globals: entry
+                        .globals
+                        .iter()
+                        .map(|(global, abs)| (global.index(), abs.clone()))
+                        .collect(),
+                    regs: entry
+                        .regs
+                        .iter()
+                        .filter_map(|(slot, value)| match slot {
+                            RegSlot::Register(idx) => Some((*idx, value.abs().clone())),
+                            _ => None,
+                        })
+                        .collect(),
+                    stack: entry
+                        .stack
+                        .iter()
+                        .map(|(_, data)| data.abs().clone())
+                        .collect(),
+                    locals: entry
+                        .locals
+                        .iter()
+                        .map(|(idx, (_, data))| (*idx, data.abs().clone()))
+                        .collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        states.sort_by_key(|state| (state.context, state.orig_block));
+        states
+    }
+
     fn create_block(
         &mut self,
         orig_block: Block,
@@ -962,6 +3889,9 @@ impl<'a> Evaluator<'a> {
         self.block_map.insert((context, orig_block), block);
         self.block_rev_map[block] = (context, orig_block);
         self.state.block_entry[block] = state;
+        if let Some(base_state) = self.base_entry(context, orig_block) {
+            self.state.block_entry[block].refine_with(base_state);
+        }
         block
     }
 
@@ -982,41 +3912,246 @@ impl<'a> Evaluator<'a> {
 
         log::trace!(" -> new context {}", target_context);
 
-        log::trace!(
-            "target_block: from orig {} ctx {} to {} ctx {}",
-            orig_block,
-            state.context,
-            target,
-            target_context
-        );
+        log::trace!(
+            "target_block: from orig {} ctx {} to {} ctx {}",
+            orig_block,
+            state.context,
+            target,
+            target_context
+        );
+
+        match self.block_map.get(&(target_context, target)).copied() {
+            None => {
+                let block = self.create_block(target, target_context, state.flow.clone());
+                log::trace!(" -> created block {}", block);
+                self.block_map.insert((target_context, target), block);
+                self.enqueue(target, target_context, block);
+                block
+            }
+            Some(target_specialized) => {
+                log::trace!(" -> already existing block {}", target_specialized);
+                let changed = self.meet_into_block_entry(
+                    target,
+                    target_context,
+                    target_specialized,
+                    &state.flow,
+                );
+                if changed {
+                    log::trace!("   -> changed");
+                    self.enqueue(target, target_context, target_specialized);
+                }
+                target_specialized
+            }
+        }
+    }
+
+    /// If this edge passes a constant for some blockparam of `target`
+    /// that differs from what the block's other in-context
+    /// predecessors pass, target a child context specialized on the
+    /// constant instead, so that every copy of the block sees a
+    /// single constant (as with PC-based contexts, but for an
+    /// arbitrary blockparam, e.g. an interpreter's current opcode).
+    fn hoist_const_blockparam(
+        &mut self,
+        target_ctx: Context,
+        target: Block,
+        abs_args: &[AbstractValue],
+    ) -> Context {
+        // Re-specializing a copy already specialized on a param
+        // replaces that specialization rather than nesting.
+        let (base_ctx, specialized_param) = match self.state.contexts.leaf_element(target_ctx) {
+            ContextElem::Specialized(param, _) => {
+                (self.state.contexts.parent(target_ctx), Some(param))
+            }
+            _ => (target_ctx, None),
+        };
+        let existing = match self.block_map.get(&(target_ctx, target)) {
+            Some(&block) => block,
+            None => return target_ctx,
+        };
+        for (i, abs) in abs_args.iter().enumerate() {
+            let k = match abs {
+                &AbstractValue::Concrete(WasmVal::I32(k)) => k,
+                _ => continue,
+            };
+            let param = self.generic.blocks[target].params[i].1;
+            if specialized_param.is_some() && specialized_param != Some(param) {
+                continue;
+            }
+            let entry = &self.state.values[self.func.blocks[existing].params[i].1];
+            if *entry == AbstractValue::Top || entry == abs {
+                continue;
+            }
+            let ctx = self
+                .state
+                .contexts
+                .create(Some(base_ctx), ContextElem::Specialized(param, k));
+            if ctx == target_ctx {
+                continue;
+            }
+            if !self.block_map.contains_key(&(ctx, target)) {
+                if self.hoist_budget == 0 {
+                    log::trace!("hoist of {} = {} into {}: out of budget", param, k, target);
+                    return target_ctx;
+                }
+                self.hoist_budget -= 1;
+            }
+            log::trace!(
+                "hoisting constant blockparam {} = {} of {} into context {}",
+                param,
+                k,
+                target,
+                ctx
+            );
+            return ctx;
+        }
+        target_ctx
+    }
+
+    /// Pop keyed-global elements off the top of the context, to be
+    /// re-keyed from the state at the edge by `key_globals`.
+    fn unkey_globals(&self, mut ctx: Context) -> Context {
+        while let ContextElem::Global(..) = self.state.contexts.leaf_element(ctx) {
+            ctx = self.state.contexts.parent(ctx);
+        }
+        ctx
+    }
+
+    /// Key each of the directive's context globals that holds a known
+    /// integer at this edge into the target context, so that copies
+    /// of a block reached with different values stay separate rather
+    /// than merging the global to a runtime value at the block's
+    /// entry (e.g., at a loop header, by way of a backedge).
+    fn key_globals(&mut self, mut ctx: Context, state: &PointState) -> Context {
+        if self.opt_level < OptLevel::O2 {
+            return ctx;
+        }
+        let context_globals = self.context_globals;
+        for &global in context_globals {
+            let val = match state.flow.globals.get(&global) {
+                Some(&AbstractValue::Concrete(WasmVal::I32(k))) => u64::from(k),
+                Some(&AbstractValue::Concrete(WasmVal::I64(k))) => k,
+                _ => continue,
+            };
+            // Already keyed on this value further down the stack
+            // (e.g., outside a nested PC context): nothing to split.
+            let path = self.state.contexts.path(ctx);
+            let keyed = path.iter().rev().find_map(|elem| match elem {
+                &ContextElem::Global(g, k) if g == global => Some(k),
+                _ => None,
+            });
+            if keyed == Some(val) {
+                continue;
+            }
+            ctx = self
+                .state
+                .contexts
+                .create(Some(ctx), ContextElem::Global(global, val));
+        }
+        ctx
+    }
+
+    /// Adjust an edge's target context for first-iteration peeling:
+    /// entering a peeled loop from outside targets a child context for
+    /// its first iteration, and leaving that iteration, by the
+    /// backedge or by exiting the loop, returns to the enclosing
+    /// context. (Only a peeled context at the top of the stack is
+    /// left; contexts pushed within the first iteration keep it.)
+    fn peel_context(&mut self, mut ctx: Context, from: Block, target: Block) -> Context {
+        let peeled_loops = self.peeled_loops;
+        if peeled_loops.is_empty() || self.opt_level < OptLevel::O2 {
+            return ctx;
+        }
+        while let ContextElem::Peeled(header) = self.state.contexts.leaf_element(ctx) {
+            if target == header {
+                return self.state.contexts.parent(ctx);
+            }
+            if peeled_loops[&header].contains(&target) {
+                return ctx;
+            }
+            ctx = self.state.contexts.parent(ctx);
+        }
+        match peeled_loops.get(&target) {
+            Some(body) if !body.contains(&from) => self
+                .state
+                .contexts
+                .create(Some(ctx), ContextElem::Peeled(target)),
+            _ => ctx,
+        }
+    }
+
+    /// Compute the context across an edge for automatically detected
+    /// dispatch loops, as `weval.push.context` /
+    /// `weval.update.context` / `weval.pop.context` would at their
+    /// entry, backedges and exits: leave loops that do not contain
+    /// the target, and key a loop's header on its PC, if known.
+    fn auto_loop_context(
+        &mut self,
+        mut ctx: Context,
+        target: Block,
+        abs_args: &[AbstractValue],
+    ) -> Context {
+        let auto_loops = self.auto_loops;
+        if auto_loops.is_empty() || self.opt_level < OptLevel::O2 {
+            return ctx;
+        }
+        while let ContextElem::AutoLoop(header, _) = self.state.contexts.leaf_element(ctx) {
+            if header != target && auto_loops[&header].body.contains(&target) {
+                break;
+            }
+            ctx = self.state.contexts.parent(ctx);
+        }
+        if let Some(auto_loop) = auto_loops.get(&target) {
+            // With a runtime PC, the loop is left unspecialized in
+            // the enclosing context.
+            // Likewise for a PC outside the specialized range.
+            if let Some(pc) = abs_args[auto_loop.pc_param]
+                .as_const_u32_or_mem_offset()
+                .filter(|&pc| self.pc_in_range(pc))
+            {
+                ctx = self
+                    .state
+                    .contexts
+                    .create(Some(ctx), ContextElem::AutoLoop(target, pc));
+            }
+        }
+        ctx
+    }
+
+    /// Whether to specialize on the given PC, given the directive's
+    /// PC range, if any.
+    fn pc_in_range(&self, pc: u32) -> bool {
+        match self.pc_range {
+            Some((lo, hi)) => (lo..hi).contains(&pc),
+            None => true,
+        }
+    }
 
-        match self.block_map.entry((target_context, target)) {
-            HashEntry::Vacant(_) => {
-                let block = self.create_block(target, target_context, state.flow.clone());
-                log::trace!(" -> created block {}", block);
-                self.block_map.insert((target_context, target), block);
-                self.queue_set.insert((target, target_context));
-                self.queue.push_back((target, target_context, block));
+    /// Redirect an edge to a generic block that always traps to a
+    /// shared trap block, rather than specializing the path.
+    fn prune_edge(&mut self, state: &PointState, target: Block) -> BlockTarget {
+        if self.pruned.insert((state.context, target)) {
+            log::warn!(
+                "Specialization {} of site {}: path to block {} in context [{}] always traps; pruned (bad bytecode or directive?)",
+                self.directive.id(),
+                self.directive.user_id,
+                target,
+                self.context_stack_desc(state.context).join(", ")
+            );
+        }
+        let trap_block = match self.trap_block {
+            Some(block) => block,
+            None => {
+                let block = self.func.add_block();
+                self.func.blocks[block].terminator = Terminator::Unreachable;
+                self.func.blocks[block].desc = "Pruned path to trap".to_owned();
+                self.trap_block = Some(block);
                 block
             }
-            HashEntry::Occupied(o) => {
-                let target_specialized = *o.get();
-                log::trace!(" -> already existing block {}", target_specialized);
-                let changed = self.meet_into_block_entry(
-                    target,
-                    target_context,
-                    target_specialized,
-                    &state.flow,
-                );
-                if changed {
-                    log::trace!("   -> changed");
-                    if self.queue_set.insert((target, target_context)) {
-                        self.queue
-                            .push_back((target, target_context, target_specialized));
-                    }
-                }
-                target_specialized
-            }
+        };
+        BlockTarget {
+            block: trap_block,
+            args: vec![],
         }
     }
 
@@ -1028,9 +4163,16 @@ impl<'a> Evaluator<'a> {
         target_ctx: Context,
         target: &BlockTarget,
     ) -> BlockTarget {
+        if self.doomed.contains(&target.block) {
+            return self.prune_edge(state, target.block);
+        }
+        let target_ctx = self.unkey_globals(target_ctx);
+        let target_ctx = self.peel_context(target_ctx, orig_block, target.block);
+
         let n_args = self.generic.blocks[orig_block].params.len();
         let mut args = Vec::with_capacity(n_args);
         let mut abs_args = Vec::with_capacity(n_args);
+        let mut orig_args = Vec::with_capacity(n_args);
         log::trace!(
             "evaluate target: block {} context {} to {:?}",
             orig_block,
@@ -1038,12 +4180,10 @@ impl<'a> Evaluator<'a> {
             target
         );
 
-        let target_block =
-            self.target_block(state, orig_block, new_block, target.block, target_ctx);
-
         for &arg in &target.args {
             let arg = self.generic.resolve_alias(arg);
             let (val, abs) = self.use_value(state.context, orig_block, new_block, arg);
+            let abs = self.stack_base_abs(state, val, abs);
             log::trace!(
                 "blockparam: block {} context {}: arg {} has val {} abs {:?}",
                 orig_block,
@@ -1054,16 +4194,24 @@ impl<'a> Evaluator<'a> {
             );
             args.push(val);
             abs_args.push(abs);
+            orig_args.push(arg);
         }
 
+        let target_ctx = self.auto_loop_context(target_ctx, target.block, &abs_args);
+        let target_ctx = self.key_globals(target_ctx, state);
+        let target_ctx = self.hoist_const_blockparam(target_ctx, target.block, &abs_args);
+        let target_block =
+            self.target_block(state, orig_block, new_block, target.block, target_ctx);
+
         // Parallel-move semantics: read all uses above, then write
         // all defs below.
         let mut changed = false;
-        for (blockparam, abs) in self.generic.blocks[target.block]
+        for (i, (blockparam, abs)) in self.generic.blocks[target.block]
             .params
             .iter()
             .map(|(_, val)| *val)
             .zip(abs_args.iter())
+            .enumerate()
         {
             let &val = self.value_map.get(&(target_ctx, blockparam)).unwrap();
 
@@ -1089,7 +4237,17 @@ impl<'a> Evaluator<'a> {
             log::debug!(
                 "blockparam: updating with new def: block {} context {} param {} val {} abstract {:?}",
                 target.block, target_ctx, blockparam, val, abs);
-            changed |= self.def_value(orig_block, target_ctx, blockparam, val, abs);
+            // `def_value` meets this edge's arg into the param's
+            // value, so a constant passed identically on every
+            // incoming edge survives the join, while a runtime param
+            // is blocked on every global the arg on any edge is. If
+            // either changes, re-evaluate the target so that values
+            // computed from the param see it.
+            let blocked_on = self
+                .blocked_on_globals(state.context, orig_args[i])
+                .cloned()
+                .unwrap_or_default();
+            changed |= self.def_value(orig_block, target_ctx, blockparam, val, abs, blocked_on);
         }
 
         // If blockparam inputs changed, re-enqueue target for evaluation.
@@ -1115,13 +4273,28 @@ impl<'a> Evaluator<'a> {
         let new_context = state.pending_context.unwrap_or(state.context);
 
         let new_term = match &self.generic.blocks[orig_block].terminator {
-            &Terminator::None => Terminator::None,
+            &Terminator::None => {
+                // A block without a terminator (e.g., a synthetic
+                // block from the frontend that was never filled in)
+                // can never fall through; it must be dead in the
+                // generic function. Seal it rather than emit a block
+                // that fails validation.
+                log::warn!(
+                    "Specialization {} of site {}: reached block {} with no terminator in context [{}]; sealing with unreachable",
+                    self.directive.id(),
+                    self.directive.user_id,
+                    orig_block,
+                    self.context_stack_desc(state.context).join(", ")
+                );
+                Terminator::Unreachable
+            }
             &Terminator::CondBr {
                 cond,
                 ref if_true,
                 ref if_false,
             } => {
                 assert!(!state.pending_specialize.is_some());
+                let orig_cond = cond;
                 let (cond, abs_cond) = self.use_value(state.context, orig_block, new_block, cond);
                 // Update pending context with new stack if necessary.
                 match abs_cond.as_const_truthy() {
@@ -1143,23 +4316,35 @@ impl<'a> Evaluator<'a> {
                             if_false,
                         ),
                     },
-                    None => Terminator::CondBr {
-                        cond,
-                        if_true: self.evaluate_block_target(
-                            orig_block,
-                            new_block,
-                            state,
-                            new_context,
-                            if_true,
-                        ),
-                        if_false: self.evaluate_block_target(
-                            orig_block,
-                            new_block,
-                            state,
-                            new_context,
-                            if_false,
-                        ),
-                    },
+                    None => {
+                        self.branch_losses.insert(
+                            (state.context, orig_block),
+                            (PrecisionLossKind::Branch, orig_cond),
+                        );
+                        if let Some(&likely) = self
+                            .branch_hints
+                            .get(&std::cmp::max(if_true.block, if_false.block))
+                        {
+                            self.hinted_branches.insert(new_block, likely);
+                        }
+                        Terminator::CondBr {
+                            cond,
+                            if_true: self.evaluate_block_target(
+                                orig_block,
+                                new_block,
+                                state,
+                                new_context,
+                                if_true,
+                            ),
+                            if_false: self.evaluate_block_target(
+                                orig_block,
+                                new_block,
+                                state,
+                                new_context,
+                                if_false,
+                            ),
+                        }
+                    }
                 }
             }
             &Terminator::Br { ref target } => {
@@ -1208,6 +4393,7 @@ impl<'a> Evaluator<'a> {
                 ref default,
             } => {
                 assert!(!state.pending_specialize.is_some());
+                let orig_value = value;
                 let (value, abs_value) =
                     self.use_value(state.context, orig_block, new_block, value);
                 if let Some(selector) = abs_value.as_const_u32() {
@@ -1227,6 +4413,10 @@ impl<'a> Evaluator<'a> {
                         ),
                     }
                 } else {
+                    self.branch_losses.insert(
+                        (state.context, orig_block),
+                        (PrecisionLossKind::Select, orig_value),
+                    );
                     let targets = targets
                         .iter()
                         .map(|target| {
@@ -1263,75 +4453,645 @@ impl<'a> Evaluator<'a> {
                     .collect::<Vec<_>>();
                 Terminator::Return { values }
             }
-            &Terminator::Unreachable => Terminator::Unreachable,
-        };
-        // Note: we don't use `set_terminator`, because it adds edges;
-        // we add edges once, in a separate pass at the end.
-        self.func.blocks[new_block].terminator = new_term;
+            &Terminator::Unreachable => Terminator::Unreachable,
+        };
+        // Note: we don't use `set_terminator`, because it adds edges;
+        // we add edges once, in a separate pass at the end.
+        self.func.blocks[new_block].terminator = new_term;
+    }
+
+    fn abstract_eval(
+        &mut self,
+        orig_block: Block,
+        new_block: Block,
+        orig_inst: Value,
+        op: Operator,
+        loc: SourceLoc,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+        orig_values: &[Value],
+        tys: &[Type],
+        state: &mut PointState,
+    ) -> anyhow::Result<EvalResult> {
+        log::debug!(
+            "abstract eval of {} {}: op {:?} abs {:?} state {:?}",
+            orig_block,
+            orig_inst,
+            op,
+            abs,
+            state
+        );
+
+        debug_assert_eq!(abs.len(), values.len());
+
+        let intrinsic_result = self.abstract_eval_intrinsic(
+            orig_block,
+            new_block,
+            orig_inst,
+            op,
+            loc,
+            abs,
+            values,
+            orig_values,
+            state,
+        );
+        self.note_unfolded_intrinsic(orig_block, orig_inst, loc, state.context);
+        if intrinsic_result.is_handled() {
+            log::debug!(" -> intrinsic: {:?}", intrinsic_result);
+            return Ok(intrinsic_result);
+        }
+
+        let reg_result =
+            self.abstract_eval_regs(orig_inst, new_block, op, abs, values, tys, state)?;
+        if reg_result.is_handled() {
+            log::debug!(" -> specialization regs: {:?}", reg_result);
+            return Ok(reg_result);
+        }
+
+        if op == Operator::Nop {
+            return Ok(EvalResult::Elide);
+        }
+
+        if let Some(result) = self.abstract_eval_passive(new_block, orig_inst, op, abs, state) {
+            log::debug!(" -> passive segment op: {:?}", result);
+            return Ok(result);
+        }
+
+        if op.is_call() {
+            self.clobber_globals(op, abs, state);
+        }
+
+        let stack_result = self.abstract_eval_operand_stack(op, abs, values, state);
+
+        if let Some(class) = self.suppressed_fold_class(op, orig_values, tys) {
+            log::debug!(" -> not folded ({:?})", class);
+            return Ok(EvalResult::Normal(AbstractValue::Runtime(Some(orig_inst))));
+        }
+
+        if let Some(result) = stack_result {
+            log::debug!(" -> operand stack: {:?}", result);
+            return Ok(result);
+        }
+
+        if let Operator::CallIndirect {
+            sig_index,
+            table_index,
+        } = op
+        {
+            if let Some(result) = self.abstract_eval_call_indirect(
+                new_block,
+                orig_inst,
+                sig_index,
+                table_index,
+                loc,
+                abs,
+                values,
+                tys,
+            ) {
+                log::debug!(" -> devirtualized: {:?}", result);
+                return Ok(result);
+            }
+        }
+
+        if let Operator::CallRef { sig_index } = op {
+            if let Some(result) =
+                self.abstract_eval_call_ref(new_block, orig_inst, sig_index, loc, values, tys)
+            {
+                log::debug!(" -> devirtualized: {:?}", result);
+                return Ok(result);
+            }
+        }
+
+        if let Operator::Call { function_index } = op {
+            if let Some(result) = self.abstract_eval_summarized_call(
+                orig_inst,
+                function_index,
+                abs,
+                values,
+                orig_values,
+                state,
+            )? {
+                log::debug!(" -> summarized call: {:?}", result);
+                return Ok(result);
+            }
+        }
+
+        if let Some(result) =
+            self.abstract_eval_partial(new_block, orig_inst, op, loc, abs, values, tys)
+        {
+            log::debug!(" -> strength-reduced: {:?}", result);
+            return Ok(result);
+        }
+
+        let ret = if op.is_call() {
+            log::debug!(" -> call");
+            AbstractValue::Runtime(Some(orig_inst))
+        } else {
+            match abs.len() {
+                0 => self.abstract_eval_nullary(orig_inst, op, state),
+                1 => {
+                    let ret =
+                        self.abstract_eval_unary(orig_inst, op, &abs[0], orig_values[0], state)?;
+                    if is_load(&op) {
+                        self.note_load_precision(orig_block, orig_inst, &abs[0], &ret, state);
+                    }
+                    ret
+                }
+                2 => self.abstract_eval_binary(orig_inst, op, &abs[0], &abs[1]),
+                3 => self.abstract_eval_ternary(orig_inst, op, &abs[0], &abs[1], &abs[2]),
+                _ => AbstractValue::Runtime(Some(orig_inst)),
+            }
+        };
+
+        self.note_blocked_on(&op, orig_values, &ret, state);
+
+        log::debug!(" -> result: {:?}", ret);
+        Ok(EvalResult::Normal(ret))
+    }
+
+    /// The class of `op` if folding it is suppressed for this
+    /// specialization. Operators with effects other than loads and
+    /// calls (e.g. stores, which update the tracked memory state) are
+    /// never suppressed, nor are constants.
+    fn suppressed_fold_class(
+        &self,
+        op: Operator,
+        orig_values: &[Value],
+        tys: &[Type],
+    ) -> Option<FoldClass> {
+        if self.no_fold.is_empty() {
+            return None;
+        }
+        let class = if is_load(&op) {
+            FoldClass::Load
+        } else if op.is_call() {
+            FoldClass::Call
+        } else if matches!(op, Operator::Select | Operator::TypedSelect { .. }) {
+            FoldClass::Select
+        } else if !op.is_pure() || orig_values.is_empty() {
+            return None;
+        } else {
+            let arg_tys = orig_values
+                .iter()
+                .filter_map(|&arg| self.generic.values[arg].ty(&self.generic.type_pool));
+            let mut all_tys = tys.iter().copied().chain(arg_tys);
+            if all_tys.clone().any(|ty| ty == Type::V128) {
+                FoldClass::Simd
+            } else if all_tys.any(|ty| matches!(ty, Type::F32 | Type::F64)) {
+                FoldClass::Float
+            } else if is_int_compare(&op) {
+                FoldClass::Compare
+            } else {
+                FoldClass::Int
+            }
+        };
+        self.no_fold.contains(&class).then_some(class)
+    }
+
+    /// Track which runtime values derive from imported globals with
+    /// no assumed value, for diagnostics: a read of one, or anything
+    /// computed from such values in the same context. `def_value`
+    /// records them.
+    fn note_blocked_on(
+        &mut self,
+        op: &Operator,
+        orig_values: &[Value],
+        ret: &AbstractValue,
+        state: &PointState,
+    ) {
+        if let AbstractValue::Runtime(_) = ret {
+            let ctx = state.context;
+            let mut blocked_on = BTreeSet::new();
+            blocked_on.extend(self.imported_globals.blocking(op, &state.flow.globals));
+            for &arg in orig_values {
+                if let Some(globals) = self.blocked_on_globals(ctx, arg) {
+                    blocked_on.extend(globals.iter().copied());
+                }
+            }
+            self.pending_blocked_on = blocked_on;
+        }
+    }
+
+    /// The imported globals a generic value, used in `ctx`, is
+    /// blocked on, if any. The value may have been defined in an
+    /// enclosing context, so look there if it was not defined in
+    /// `ctx` itself.
+    fn blocked_on_globals(
+        &self,
+        mut ctx: Context,
+        value: Value,
+    ) -> Option<&BTreeSet<waffle::Global>> {
+        while ctx.is_valid() {
+            if let Some(globals) = self.blocked_on.get(&(ctx, value)) {
+                return Some(globals);
+            }
+            if self.value_map.contains_key(&(ctx, value)) {
+                return None;
+            }
+            ctx = self.state.contexts.parent(ctx);
+        }
+        None
+    }
+
+    /// Evaluate a call to a `memory.init` or `data.drop` helper (see
+    /// `crate::passive`). Neither writes globals. A `memory.init` of a
+    /// few bytes, at constant addresses in bounds of both the segment
+    /// and the memory's image, from a segment never dropped, becomes
+    /// stores of the segment's bytes; otherwise the call is kept.
+    fn abstract_eval_passive(
+        &mut self,
+        new_block: Block,
+        orig_inst: Value,
+        op: Operator,
+        abs: &[AbstractValue],
+        state: &mut PointState,
+    ) -> Option<EvalResult> {
+        let function_index = match op {
+            Operator::Call { function_index } => function_index,
+            _ => return None,
+        };
+        let (segment, memory) = match *self.image.passive_ops.get(&function_index)? {
+            PassiveOp::Drop { .. } => {
+                return Some(EvalResult::Normal(AbstractValue::Runtime(Some(orig_inst))));
+            }
+            PassiveOp::Init { segment, memory } => (segment, memory),
+        };
+        state.flow.stack_slots.clear();
+        let kept = EvalResult::Normal(AbstractValue::Runtime(Some(orig_inst)));
+        let (dst, src, len) = match (
+            abs[0].as_const_u32(),
+            abs[1].as_const_u32(),
+            abs[2].as_const_u32(),
+        ) {
+            (Some(dst), Some(src), Some(len)) if len <= MAX_FOLDED_INIT_BYTES => (dst, src, len),
+            _ => return Some(kept),
+        };
+        if !self.image.can_read(memory, dst, len) {
+            return Some(kept);
+        }
+        let bytes = match self.image.passive_segment_range(segment, src, len) {
+            Some(bytes) => bytes.to_vec(),
+            None => return Some(kept),
+        };
+        log::trace!(
+            "memory.init at {}: {} bytes of segment {} to {:#x}; folding into stores",
+            orig_inst,
+            len,
+            segment,
+            dst
+        );
+
+        let i32_ty = self.func.single_type_list(Type::I32);
+        let i64_ty = self.func.single_type_list(Type::I64);
+        let store = |func: &mut FunctionBody,
+                     addr: u32,
+                     data: Operator,
+                     ty: ListRef<Type>,
+                     op: Operator| {
+            let addr = func.add_value(ValueDef::Operator(
+                Operator::I32Const { value: addr },
+                ListRef::default(),
+                i32_ty,
+            ));
+            func.blocks[new_block].insts.push(addr);
+            let data = func.add_value(ValueDef::Operator(data, ListRef::default(), ty));
+            func.blocks[new_block].insts.push(data);
+            let args = func.arg_pool.double(addr, data);
+            let store = func.add_value(ValueDef::Operator(op, args, ListRef::default()));
+            func.blocks[new_block].insts.push(store);
+        };
+        let memarg = MemoryArg {
+            align: 0,
+            offset: 0,
+            memory,
+        };
+        let mut chunks = bytes.chunks_exact(8);
+        let mut addr = dst;
+        for chunk in &mut chunks {
+            let value = u64::from_le_bytes(chunk.try_into().unwrap());
+            store(
+                &mut self.func,
+                addr,
+                Operator::I64Const { value },
+                i64_ty,
+                Operator::I64Store { memory: memarg },
+            );
+            addr += 8;
+        }
+        for &byte in chunks.remainder() {
+            store(
+                &mut self.func,
+                addr,
+                Operator::I32Const {
+                    value: u32::from(byte),
+                },
+                i32_ty,
+                Operator::I32Store8 { memory: memarg },
+            );
+            addr += 1;
+        }
+        Some(EvalResult::Elide)
+    }
+
+    /// Forget the values of globals that a call may write: those in
+    /// the callee's may-write summary, or every mutable global if the
+    /// callee or its summary is unknown.
+    fn clobber_globals(&self, op: Operator, abs: &[AbstractValue], state: &mut PointState) {
+        let callee = match op {
+            Operator::Call { function_index } | Operator::ReturnCall { function_index } => {
+                Some(function_index)
+            }
+            Operator::CallIndirect { table_index, .. } => abs
+                .last()
+                .and_then(|idx| idx.as_const_u32())
+                .and_then(|idx| {
+                    self.image
+                        .tables
+                        .get(&table_index)?
+                        .get(idx as usize)
+                        .copied()
+                }),
+            _ => None,
+        };
+        match callee.and_then(|callee| self.effects.of_call(callee)) {
+            Some(may_write) => {
+                for global in &may_write.globals {
+                    if let Some(value) = state.flow.globals.get_mut(global) {
+                        *value = AbstractValue::Runtime(None);
+                    }
+                }
+            }
+            None => {
+                for (&global, value) in state.flow.globals.iter_mut() {
+                    if self.module.globals[global].mutable {
+                        *value = AbstractValue::Runtime(None);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Devirtualize a `call_indirect` whose table index is known at
+    /// specialization time (e.g., a function pointer read from an
+    /// immutable global or from constant memory) into a direct call.
+    fn abstract_eval_call_indirect(
+        &mut self,
+        new_block: Block,
+        orig_inst: Value,
+        sig_index: Signature,
+        table_index: Table,
+        loc: SourceLoc,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+        tys: &[Type],
+    ) -> Option<EvalResult> {
+        // The table must hold the same function at runtime.
+        if !self.image.is_table_immutable(table_index) {
+            return None;
+        }
+        let idx = abs.last()?.as_const_u32()?;
+        let callee = *self.image.tables.get(&table_index)?.get(idx as usize)?;
+        // An empty slot traps; leave that to runtime.
+        if !callee.is_valid() {
+            return None;
+        }
+        if self.module.funcs[callee].sig() != sig_index {
+            log::debug!(
+                "call_indirect at {}: table index {} is {} with mismatching signature",
+                orig_inst,
+                idx,
+                callee
+            );
+            return None;
+        }
+        log::trace!(
+            "call_indirect at {}: table index {} is {}; devirtualizing",
+            orig_inst,
+            idx,
+            callee
+        );
+
+        let args = &self.func.arg_pool[values];
+        let args = args[..args.len() - 1].to_vec();
+        let args = self.func.arg_pool.from_iter(args.into_iter());
+        let tys = self.func.type_pool.from_iter(tys.iter().cloned());
+        let call = self.func.add_value(ValueDef::Operator(
+            Operator::Call {
+                function_index: callee,
+            },
+            args,
+            tys,
+        ));
+        self.func.source_locs[call] = loc;
+        self.func.blocks[new_block].insts.push(call);
+        Some(EvalResult::Alias(
+            AbstractValue::Runtime(Some(orig_inst)),
+            call,
+        ))
+    }
+
+    /// The referent of a reference value in the specialized body, if
+    /// it is a `ref.func` or `ref.null` (e.g. a `select` between two
+    /// `ref.func`s, on a known condition, earlier in the block).
+    fn known_ref(&self, value: Value) -> Option<crate::fold::KnownRef> {
+        match &self.func.values[self.func.resolve_alias(value)] {
+            ValueDef::Operator(Operator::RefFunc { func_index }, _, _) => {
+                Some(crate::fold::KnownRef::Func(*func_index))
+            }
+            ValueDef::Operator(Operator::RefNull { .. }, _, _) => Some(crate::fold::KnownRef::Null),
+            _ => None,
+        }
     }
 
-    fn abstract_eval(
+    /// Devirtualize a `call_ref` on a known function reference into a
+    /// direct call.
+    fn abstract_eval_call_ref(
         &mut self,
-        orig_block: Block,
         new_block: Block,
         orig_inst: Value,
-        op: Operator,
+        sig_index: Signature,
         loc: SourceLoc,
-        abs: &[AbstractValue],
         values: ListRef<Value>,
-        orig_values: &[Value],
         tys: &[Type],
-        state: &mut PointState,
-    ) -> anyhow::Result<EvalResult> {
-        log::debug!(
-            "abstract eval of {} {}: op {:?} abs {:?} state {:?}",
-            orig_block,
+    ) -> Option<EvalResult> {
+        let callee = match self.known_ref(*self.func.arg_pool[values].last()?)? {
+            crate::fold::KnownRef::Func(callee) => callee,
+            // Leave the trap to runtime.
+            crate::fold::KnownRef::Null => return None,
+        };
+        if self.module.funcs[callee].sig() != sig_index {
+            return None;
+        }
+        log::trace!(
+            "call_ref at {}: reference is {}; devirtualizing",
             orig_inst,
-            op,
-            abs,
-            state
+            callee
         );
 
-        debug_assert_eq!(abs.len(), values.len());
+        let args = &self.func.arg_pool[values];
+        let args = args[..args.len() - 1].to_vec();
+        let args = self.func.arg_pool.from_iter(args.into_iter());
+        let tys = self.func.type_pool.from_iter(tys.iter().cloned());
+        let call = self.func.add_value(ValueDef::Operator(
+            Operator::Call {
+                function_index: callee,
+            },
+            args,
+            tys,
+        ));
+        self.func.source_locs[call] = loc;
+        self.func.blocks[new_block].insts.push(call);
+        Some(EvalResult::Alias(
+            AbstractValue::Runtime(Some(orig_inst)),
+            call,
+        ))
+    }
 
-        let intrinsic_result = self.abstract_eval_intrinsic(
-            orig_block,
-            new_block,
-            orig_inst,
-            op,
-            loc,
-            abs,
-            values,
-            orig_values,
-            state,
-        );
-        if intrinsic_result.is_handled() {
-            log::debug!(" -> intrinsic: {:?}", intrinsic_result);
-            return Ok(intrinsic_result);
+    /// Evaluate a direct call to a small side-effect-free callee by its
+    /// summary: forward the returned argument or constant, dropping
+    /// the call, or evaluate the returned field load, keeping the call
+    /// unless the load folds.
+    fn abstract_eval_summarized_call(
+        &mut self,
+        orig_inst: Value,
+        callee: Func,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+        orig_values: &[Value],
+        state: &mut PointState,
+    ) -> anyhow::Result<Option<EvalResult>> {
+        if self.opt_level < OptLevel::O2 {
+            return Ok(None);
         }
+        let summary = match self.summaries.get(&callee) {
+            Some(&summary) => summary,
+            None => return Ok(None),
+        };
+        Ok(match summary {
+            FuncSummary::ReturnsArg(index) => Some(EvalResult::Alias(
+                abs[index].clone(),
+                self.func.arg_pool[values][index],
+            )),
+            FuncSummary::ReturnsConst(k) => Some(EvalResult::Normal(AbstractValue::Concrete(k))),
+            FuncSummary::ReturnsLoad(index, load) => {
+                let ret = self.abstract_eval_unary(
+                    orig_inst,
+                    load,
+                    &abs[index],
+                    orig_values[index],
+                    state,
+                )?;
+                // Not a load in the generic function: don't report it.
+                self.pending_load_loss = None;
+                self.pending_oob_read = None;
+                match ret {
+                    AbstractValue::Top | AbstractValue::Runtime(_) => None,
+                    ret => Some(EvalResult::Normal(ret)),
+                }
+            }
+        })
+    }
 
-        let reg_result =
-            self.abstract_eval_regs(orig_inst, new_block, op, abs, values, tys, state)?;
-        if reg_result.is_handled() {
-            log::debug!(" -> specialization regs: {:?}", reg_result);
-            return Ok(reg_result);
+    /// Simplify an operator with some, but not all, operands known:
+    /// identities (`x + 0`, `x & -1`, `select(c, a, a)`, and a
+    /// `select` on a known condition), which become aliases of the
+    /// operand, `ref.is_null` of a known reference, absorbing
+    /// constants (`x & 0`), cheaper operators (`x * 2^k` to `x << k`),
+    /// and comparisons canonicalized to put the constant on the right.
+    fn abstract_eval_partial(
+        &mut self,
+        new_block: Block,
+        orig_inst: Value,
+        op: Operator,
+        loc: SourceLoc,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+        tys: &[Type],
+    ) -> Option<EvalResult> {
+        if matches!(op, Operator::Select | Operator::TypedSelect { .. }) {
+            let args = &self.func.arg_pool[values];
+            let chosen = if self.func.resolve_alias(args[0]) == self.func.resolve_alias(args[1]) {
+                0
+            } else {
+                // Forward the chosen operand, rather than emitting a
+                // `select` on a constant (and the constant).
+                match &abs[2] {
+                    AbstractValue::Concrete(v) => crate::fold::select_operand(*v),
+                    // Concrete-memory symbolic pointers are always truthy.
+                    AbstractValue::ConcreteMemory(..) => 0,
+                    _ => return None,
+                }
+            };
+            // A typed `select` may declare a supertype of its operands
+            // (e.g. a nullable reference over `ref.func`s); keep it, and
+            // the declared type, unless the operand has exactly that
+            // type.
+            let arg = args[chosen];
+            if self.func.values[arg].ty(&self.func.type_pool) != Some(tys[0]) {
+                return None;
+            }
+            return Some(EvalResult::Alias(abs[chosen].clone(), arg));
         }
-
-        let ret = if op.is_call() {
-            log::debug!(" -> call");
-            AbstractValue::Runtime(Some(orig_inst))
-        } else {
-            match abs.len() {
-                0 => self.abstract_eval_nullary(orig_inst, op, state),
-                1 => self.abstract_eval_unary(orig_inst, op, &abs[0], orig_values[0], state)?,
-                2 => self.abstract_eval_binary(orig_inst, op, &abs[0], &abs[1]),
-                3 => self.abstract_eval_ternary(orig_inst, op, &abs[0], &abs[1], &abs[2]),
-                _ => AbstractValue::Runtime(Some(orig_inst)),
+        if op == Operator::RefIsNull {
+            let x = self.known_ref(self.func.arg_pool[values][0])?;
+            let v = crate::fold::unary_ref(op, x)?;
+            log::trace!("ref fold at {}: {:?} is {:?}", orig_inst, x, v);
+            return Some(EvalResult::Normal(AbstractValue::Concrete(v)));
+        }
+        if abs.len() != 2 {
+            return None;
+        }
+        let partial = match (&abs[0], &abs[1]) {
+            (AbstractValue::Concrete(_), AbstractValue::Concrete(_)) => return None,
+            (AbstractValue::Concrete(x), _) => crate::fold::binary_partial(op, Some(*x), None)?,
+            (_, AbstractValue::Concrete(y)) => crate::fold::binary_partial(op, None, Some(*y))?,
+            _ => return None,
+        };
+        log::trace!("partial fold at {}: {:?}", orig_inst, partial);
+        let args = self.func.arg_pool[values].to_vec();
+        let (new_op, new_args) = match partial {
+            crate::fold::Partial::Const(v) => {
+                return Some(EvalResult::Normal(AbstractValue::Concrete(v)))
+            }
+            // Forward the operand itself rather than re-emitting the
+            // operator, so that uses see the original value (and its
+            // abstract value, e.g. a memory tag).
+            crate::fold::Partial::Operand(i) => {
+                return Some(EvalResult::Alias(abs[i].clone(), args[i]))
+            }
+            crate::fold::Partial::Reduced(new_op, k) => {
+                let x = if matches!(abs[0], AbstractValue::Concrete(_)) {
+                    args[1]
+                } else {
+                    args[0]
+                };
+                let ty = tys[0];
+                let k_op = match k {
+                    WasmVal::I32(value) => Operator::I32Const { value },
+                    WasmVal::I64(value) => Operator::I64Const { value },
+                    _ => unreachable!(),
+                };
+                let k_ty = self.func.single_type_list(ty);
+                let k = self
+                    .func
+                    .add_value(ValueDef::Operator(k_op, ListRef::default(), k_ty));
+                self.func.source_locs[k] = loc;
+                self.func.blocks[new_block].insts.push(k);
+                (new_op, [x, k])
             }
+            crate::fold::Partial::Swapped(new_op) => (new_op, [args[1], args[0]]),
         };
-
-        log::debug!(" -> result: {:?}", ret);
-        Ok(EvalResult::Normal(ret))
+        let new_args = self.func.arg_pool.double(new_args[0], new_args[1]);
+        let new_tys = self.func.type_pool.from_iter(tys.iter().cloned());
+        let new_value = self
+            .func
+            .add_value(ValueDef::Operator(new_op, new_args, new_tys));
+        self.func.source_locs[new_value] = loc;
+        self.func.blocks[new_block].insts.push(new_value);
+        Some(EvalResult::Alias(
+            AbstractValue::Runtime(Some(orig_inst)),
+            new_value,
+        ))
     }
 
     fn abstract_eval_intrinsic(
@@ -1340,13 +5100,28 @@ impl<'a> Evaluator<'a> {
         new_block: Block,
         orig_inst: Value,
         op: Operator,
-        _loc: SourceLoc,
+        loc: SourceLoc,
         abs: &[AbstractValue],
         values: ListRef<Value>,
         orig_values: &[Value],
         state: &mut PointState,
     ) -> EvalResult {
         match op {
+            Operator::Call { function_index }
+                if self.opt_level < OptLevel::O2
+                    && (Some(function_index) == self.intrinsics.push_context
+                        || Some(function_index) == self.intrinsics.pop_context
+                        || Some(function_index) == self.intrinsics.update_context) =>
+            {
+                // No context specialization below O2.
+                EvalResult::Elide
+            }
+            Operator::Call { function_index }
+                if self.opt_level < OptLevel::O2
+                    && Some(function_index) == self.intrinsics.specialize_value =>
+            {
+                EvalResult::Alias(abs[0].clone(), self.func.arg_pool[values][0])
+            }
             Operator::Call { function_index } => {
                 if Some(function_index) == self.intrinsics.push_context {
                     let pc = abs[0]
@@ -1370,14 +5145,24 @@ impl<'a> Evaluator<'a> {
                     log::trace!("update context at {}: PC is {:?}", orig_values[0], abs[0]);
                     let instantaneous_context = state.pending_context.unwrap_or(state.context);
                     let parent = self.state.contexts.pop_one_loop(instantaneous_context);
-                    let pending_context = if let Some(pc) = abs[0].as_const_u32_or_mem_offset() {
-                        Some(
+                    let pending_context = match abs[0].as_const_u32_or_mem_offset() {
+                        Some(pc) if self.pc_in_range(pc) => Some(
                             self.state
                                 .contexts
                                 .create(Some(parent), ContextElem::Loop(pc)),
-                        )
-                    } else {
-                        panic!("PC is a runtime value: {:?}", abs[0]);
+                        ),
+                        // Outside the specialized range, continue in the
+                        // generic loop; once the PC meets to a runtime
+                        // value there, it stays generic.
+                        Some(_) if self.pc_range.is_some() => Some(parent),
+                        None if self.pc_range.is_some() => {
+                            self.intrinsic_problem(
+                                "weval.update.context",
+                                format!("PC is {:?}; continuing in the generic loop", abs[0]),
+                            );
+                            Some(parent)
+                        }
+                        _ => panic!("PC is a runtime value: {:?}", abs[0]),
                     };
                     log::trace!("update context: now {:?}", pending_context);
                     state.pending_context = pending_context;
@@ -1406,17 +5191,98 @@ impl<'a> Evaluator<'a> {
                         panic!("Specialization reached a point it shouldn't have!");
                     }
                     EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.region_epoch {
+                    match (
+                        abs[0].as_const_u32(),
+                        abs[1].as_const_u32(),
+                        abs[2].as_const_u32(),
+                    ) {
+                        (Some(addr), Some(len), Some(epoch)) => {
+                            log::trace!(
+                                "region_epoch: region {:#x} len {:#x} at epoch {}",
+                                addr,
+                                len,
+                                epoch
+                            );
+                            if let Some(prev) = self.region_epochs.insert((addr, len), epoch) {
+                                if prev != epoch {
+                                    log::warn!(
+                                        "Region {:#x} len {:#x} seen at epochs {} and {} in one specialization",
+                                        addr,
+                                        len,
+                                        prev,
+                                        epoch
+                                    );
+                                }
+                            }
+                        }
+                        _ => {
+                            log::warn!("weval.region.epoch with non-constant arguments: {:?}", abs);
+                            self.intrinsic_problem(
+                                "weval.region.epoch",
+                                format!("arguments are {:?}; no epoch assumed", abs),
+                            );
+                        }
+                    }
+                    EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.peel_loop {
+                    // Handled when targeting the loop header.
+                    EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.trace_line {
                     let line_num = abs[0].as_const_u32().unwrap_or(0);
                     log::debug!("trace: line number {}: current context {} at block {}, pending context {:?}",
                                 line_num, state.context, orig_block, state.pending_context);
                     EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.trace_here {
+                    let instantaneous_context = state.pending_context.unwrap_or(state.context);
+                    let label = abs[0].as_const_u32().and_then(|ptr| {
+                        self.image.read_str(self.image.main_heap.unwrap(), ptr).ok()
+                    });
+                    match label {
+                        Some(label) => {
+                            log::debug!(
+                                "trace: \"{}\": context {} at block {} (specialized block {})",
+                                label,
+                                instantaneous_context,
+                                orig_block,
+                                new_block
+                            );
+                            self.labels.insert(instantaneous_context, label);
+                        }
+                        None => {
+                            log::warn!(
+                                "weval.trace.here with a name not in constant memory: {:?}",
+                                abs[0]
+                            );
+                            self.intrinsic_problem(
+                                "weval.trace.here",
+                                format!("name {:?} is not in constant memory", abs[0]),
+                            );
+                        }
+                    }
+                    EvalResult::Elide
                 } else if Some(function_index) == self.intrinsics.assert_const32 {
                     log::trace!("assert_const32: abs {:?} line {:?}", abs[0], abs[1]);
-                    if abs[0].as_const_u32().is_none() {
-                        panic!(
-                            "weval_assert_const32() failed: {:?}: line {:?}",
-                            abs[0], abs[1]
+                    if abs[0].as_const_u32().is_some() {
+                        self.assert_failures.remove(&(state.context, orig_inst));
+                    } else {
+                        let line = match abs[1].as_const_u32() {
+                            Some(line) => format!("line {}", line),
+                            None => "unknown line".to_owned(),
+                        };
+                        let loc = crate::analyze::source_loc_desc(self.module, loc)
+                            .map(|loc| format!(" ({})", loc))
+                            .unwrap_or_default();
+                        self.assert_failures.insert(
+                            (state.context, orig_inst),
+                            format!(
+                                "{}{}: value is {:?} in block {}, context [{}]",
+                                line,
+                                loc,
+                                abs[0],
+                                orig_block,
+                                self.context_stack_desc(state.context).join(", ")
+                            ),
                         );
                     }
                     EvalResult::Elide
@@ -1430,6 +5296,54 @@ impl<'a> Evaluator<'a> {
                     let val = abs[2].clone();
                     log::info!("print: line {}: {}: {:?}", line, message, val);
                     EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.print_value {
+                    let label = abs[0]
+                        .as_const_u32()
+                        .and_then(|ptr| {
+                            self.image.read_str(self.image.main_heap.unwrap(), ptr).ok()
+                        })
+                        .unwrap_or_else(|| "(unknown)".to_owned());
+                    log::debug!("print_value: {}: {:?}", label, abs[1]);
+                    self.printed_values.insert(
+                        (state.context, orig_inst),
+                        PrintedValue {
+                            id: self.directive.id(),
+                            user_id: self.directive.user_id,
+                            label,
+                            loc: crate::analyze::source_loc_desc(self.module, loc),
+                            context_stack: self.context_stack_desc(state.context),
+                            value: format!("{:?}", abs[1]),
+                        },
+                    );
+                    EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.env_u32 {
+                    let key = abs[0].as_const_u32().and_then(|ptr| {
+                        self.image.read_str(self.image.main_heap.unwrap(), ptr).ok()
+                    });
+                    let value = key.as_ref().and_then(|key| {
+                        self.site_env
+                            .and_then(|env| env.get(key))
+                            .or_else(|| self.env.get(key))
+                    });
+                    match (key, value) {
+                        (Some(key), Some(&value)) => {
+                            log::trace!("env_u32: {} = {}", key, value);
+                            EvalResult::Normal(AbstractValue::Concrete(WasmVal::I32(value)))
+                        }
+                        (key, _) => {
+                            log::warn!(
+                                "Specialization {} of site {}: no value for weval.env.u32 key {:?}; left as a runtime call",
+                                self.directive.id(),
+                                self.directive.user_id,
+                                key
+                            );
+                            self.intrinsic_problem(
+                                "weval.env.u32",
+                                format!("no value for key {:?}; left as a runtime call", key),
+                            );
+                            EvalResult::Unhandled
+                        }
+                    }
                 } else if Some(function_index) == self.intrinsics.read_specialization_global {
                     let index = abs[0].as_const_u32().unwrap() as usize;
                     let i64_ty = self.func.single_type_list(Type::I64);
@@ -1620,61 +5534,461 @@ impl<'a> Evaluator<'a> {
                         self.func.blocks[new_block].insts.push(store);
                         self.stats.local_writes_mem += 1;
                     }
-                    EvalResult::Elide
-                } else if Some(function_index) == self.intrinsics.read_local {
-                    self.stats.local_reads += 1;
-                    let ptr = self.func.arg_pool[values][0];
-                    let idx = abs[1].as_const_u32().unwrap();
-                    match state.flow.locals.get(&idx) {
-                        None => {
-                            let args = self.func.arg_pool.single(ptr);
-                            let i64_ty = self.func.single_type_list(Type::I64);
-                            let load = self.func.add_value(ValueDef::Operator(
-                                Operator::I64Load {
-                                    memory: MemoryArg {
-                                        align: 1,
-                                        offset: 0,
-                                        memory: self.image.main_heap().unwrap(),
-                                    },
-                                },
-                                args,
-                                i64_ty,
-                            ));
-                            self.func.blocks[new_block].insts.push(load);
-                            self.stats.local_reads_mem += 1;
-                            EvalResult::Alias(AbstractValue::Runtime(None), load)
-                        }
-                        Some((_, RegValue::Value { data, abs, .. })) => {
-                            EvalResult::Alias(abs.clone(), *data)
-                        }
-                        _ => unreachable!(),
+                    EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.read_local {
+                    self.stats.local_reads += 1;
+                    let ptr = self.func.arg_pool[values][0];
+                    let idx = abs[1].as_const_u32().unwrap();
+                    match state.flow.locals.get(&idx) {
+                        None => {
+                            let args = self.func.arg_pool.single(ptr);
+                            let i64_ty = self.func.single_type_list(Type::I64);
+                            let load = self.func.add_value(ValueDef::Operator(
+                                Operator::I64Load {
+                                    memory: MemoryArg {
+                                        align: 1,
+                                        offset: 0,
+                                        memory: self.image.main_heap().unwrap(),
+                                    },
+                                },
+                                args,
+                                i64_ty,
+                            ));
+                            self.func.blocks[new_block].insts.push(load);
+                            self.stats.local_reads_mem += 1;
+                            EvalResult::Alias(AbstractValue::Runtime(None), load)
+                        }
+                        Some((_, RegValue::Value { data, abs, .. })) => {
+                            EvalResult::Alias(abs.clone(), *data)
+                        }
+                        _ => unreachable!(),
+                    }
+                } else if Some(function_index) == self.intrinsics.write_local {
+                    self.stats.local_writes += 1;
+                    let ptr = self.func.arg_pool[values][0];
+                    let idx = abs[1].as_const_u32().unwrap();
+                    let data = self.func.arg_pool[values][2];
+                    state.flow.locals.insert(
+                        idx,
+                        (
+                            RegValue::Value {
+                                data: ptr,
+                                abs: abs[0].clone(),
+                                ty: Type::I32,
+                            },
+                            RegValue::Value {
+                                data,
+                                abs: abs[2].clone(),
+                                ty: Type::I64,
+                            },
+                        ),
+                    );
+                    EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.stack_declare {
+                    self.declare_operand_stack(abs, values, state);
+                    EvalResult::Elide
+                } else if Some(function_index) == self.intrinsics.ic_site {
+                    self.inline_cache_site(new_block, orig_inst, loc, abs, values)
+                } else if Some(function_index) == self.intrinsics.barrier_needed {
+                    let consts = (
+                        abs[0].as_const_u64(),
+                        abs[1].as_const_u64(),
+                        abs[2].as_const_u64(),
+                    );
+                    let elide = match (self.barrier_elision, consts) {
+                        (BarrierElision::None, _) => false,
+                        (BarrierElision::Immediates, (Some(value), Some(mask), Some(tag))) => {
+                            value & mask != tag
+                        }
+                        (BarrierElision::Immediates, _) => false,
+                        (BarrierElision::Constants, (value, _, _)) => value.is_some(),
+                    };
+                    let known = match (self.barrier_elision, consts) {
+                        (BarrierElision::None, _) => true,
+                        (BarrierElision::Immediates, (Some(_), Some(_), Some(_))) => true,
+                        (BarrierElision::Immediates, _) => false,
+                        (BarrierElision::Constants, (value, _, _)) => value.is_some(),
+                    };
+                    if !known {
+                        self.intrinsic_problem(
+                            "weval.barrier.needed",
+                            format!("arguments are {:?}; barrier kept", abs),
+                        );
+                    }
+                    if elide {
+                        log::trace!("barrier_needed: eliding barrier for {:?}", abs[0]);
+                        EvalResult::Normal(AbstractValue::Concrete(WasmVal::I32(0)))
+                    } else {
+                        EvalResult::Unhandled
+                    }
+                } else {
+                    EvalResult::Unhandled
+                }
+            }
+            _ => EvalResult::Unhandled,
+        }
+    }
+
+    /// Evaluate a `weval.ic.site`: read the inline-cache entry's
+    /// guard and target from the snapshot, and emit the fast path
+    /// `key == guard ? target : 0` in place of the call. The entry may
+    /// since have changed, but the guard keeps the result correct. An
+    /// empty or unreadable entry leaves the call, which always misses.
+    fn inline_cache_site(
+        &mut self,
+        new_block: Block,
+        orig_inst: Value,
+        loc: SourceLoc,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+    ) -> EvalResult {
+        let entry = abs[0].as_const_u32().and_then(|ptr| {
+            let heap = self.image.main_heap?;
+            let guard = self.image.read_u32(heap, ptr).ok()?;
+            let target = self.image.read_u32(heap, ptr.checked_add(4)?).ok()?;
+            Some((guard, target))
+        });
+        let (guard, target) = match entry {
+            Some((guard, target)) if guard != 0 => (guard, target),
+            _ => {
+                log::debug!("ic_site: no entry at {:?}; always misses", abs[0]);
+                self.intrinsic_problem(
+                    "weval.ic.site",
+                    format!("no entry at {:?}; always misses", abs[0]),
+                );
+                return EvalResult::Unhandled;
+            }
+        };
+        log::trace!("ic_site: guard {:#x} target {}", guard, target);
+        if let Some(key) = abs[1].as_const_u32() {
+            let result = if key == guard { target } else { 0 };
+            return EvalResult::Normal(AbstractValue::Concrete(WasmVal::I32(result)));
+        }
+
+        let key = self.func.arg_pool[values][1];
+        let i32_ty = self.func.single_type_list(Type::I32);
+        let mut push = |func: &mut FunctionBody, op, args| {
+            let value = func.add_value(ValueDef::Operator(op, args, i32_ty));
+            func.source_locs[value] = loc;
+            func.blocks[new_block].insts.push(value);
+            value
+        };
+        let guard = push(
+            &mut self.func,
+            Operator::I32Const { value: guard },
+            ListRef::default(),
+        );
+        let target = push(
+            &mut self.func,
+            Operator::I32Const { value: target },
+            ListRef::default(),
+        );
+        let miss = push(
+            &mut self.func,
+            Operator::I32Const { value: 0 },
+            ListRef::default(),
+        );
+        let eq_args = self.func.arg_pool.double(key, guard);
+        let hit = push(&mut self.func, Operator::I32Eq, eq_args);
+        let select_args = self
+            .func
+            .arg_pool
+            .from_iter([target, miss, hit].into_iter());
+        let result = push(&mut self.func, Operator::Select, select_args);
+        EvalResult::Alias(AbstractValue::Runtime(Some(orig_inst)), result)
+    }
+
+    /// Evaluate a `weval.stack.declare`: start tracking the operand
+    /// stack, unless its size is unknown or it was already declared
+    /// differently.
+    fn declare_operand_stack(
+        &mut self,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+        state: &mut PointState,
+    ) {
+        let declared = match (abs[1].as_const_u32(), abs[2].as_const_u32()) {
+            (Some(slot_size), Some(max_depth)) if slot_size > 0 => OperandStack {
+                base: self.func.resolve_alias(self.func.arg_pool[values][0]),
+                base_addr: abs[0].as_const_u32(),
+                slot_size,
+                max_depth,
+            },
+            _ => {
+                log::warn!(
+                    "Specialization {} of site {}: weval.stack.declare with non-constant slot size or depth {:?}; ignored",
+                    self.directive.id(),
+                    self.directive.user_id,
+                    &abs[1..]
+                );
+                self.intrinsic_problem(
+                    "weval.stack.declare",
+                    format!("slot size and depth are {:?}; ignored", &abs[1..]),
+                );
+                return;
+            }
+        };
+        if self.declared_stack.map_or(false, |stack| stack != declared) && !self.stack_conflict {
+            log::warn!(
+                "Specialization {} of site {}: operand stack declared more than once; not tracking it",
+                self.directive.id(),
+                self.directive.user_id
+            );
+            self.stack_conflict = true;
+        }
+        if self.stack_conflict {
+            state.flow.operand_stack = None;
+            state.flow.stack_slots.clear();
+            return;
+        }
+        log::trace!("declared operand stack: {:?}", declared);
+        self.declared_stack = Some(declared);
+        state.flow.operand_stack = Some(declared);
+    }
+
+    /// The abstract value of a use of `value`: `abs`, unless `value`
+    /// is the (runtime) base of the declared operand stack, in which
+    /// case it is offset zero from it, so that pointers computed from
+    /// it, and blockparams it is passed to, are tracked too.
+    fn stack_base_abs(
+        &self,
+        state: &PointState,
+        value: Value,
+        abs: AbstractValue,
+    ) -> AbstractValue {
+        match (&state.flow.operand_stack, &abs) {
+            (Some(stack), AbstractValue::Runtime(_))
+                if !self.stack_conflict && self.func.resolve_alias(value) == stack.base =>
+            {
+                AbstractValue::StackOffset(0)
+            }
+            _ => abs,
+        }
+    }
+
+    /// The byte offset of a pointer from the base of the declared
+    /// operand stack, if it is computed from the base.
+    fn stack_offset(&self, state: &PointState, abs: &AbstractValue) -> Option<i64> {
+        let stack = state.flow.operand_stack.as_ref()?;
+        match abs {
+            AbstractValue::StackOffset(offset) => Some(*offset as i64),
+            _ => Some(abs.as_const_u32()? as i64 - stack.base_addr? as i64),
+        }
+    }
+
+    /// The slot of the declared operand stack that an access of
+    /// `size` bytes at `ptr` (plus `offset`) reads or writes: `None`
+    /// if the access is outside the stack, `Some(None)` if it is not
+    /// exactly within one slot.
+    fn stack_slot(
+        &self,
+        state: &PointState,
+        abs: &AbstractValue,
+        memory: MemoryArg,
+        size: u32,
+    ) -> Option<Option<u32>> {
+        if Some(memory.memory) != self.image.main_heap {
+            return None;
+        }
+        let stack = state.flow.operand_stack.as_ref()?;
+        let start = self.stack_offset(state, abs)? + memory.offset as i64;
+        let len = stack.slot_size as i64 * stack.max_depth as i64;
+        if start + size as i64 <= 0 || start >= len {
+            return None;
+        }
+        let slot_size = stack.slot_size as i64;
+        if start >= 0 && start % slot_size == 0 && size as i64 <= slot_size {
+            Some(Some((start / slot_size) as u32))
+        } else {
+            Some(None)
+        }
+    }
+
+    /// Track the declared operand stack: pointer arithmetic on
+    /// pointers into it, and the values stored to its slots, which
+    /// are forwarded to later loads. Stores are kept (slots are
+    /// written through to memory), so only loads are replaced.
+    fn abstract_eval_operand_stack(
+        &mut self,
+        op: Operator,
+        abs: &[AbstractValue],
+        values: ListRef<Value>,
+        state: &mut PointState,
+    ) -> Option<EvalResult> {
+        if state.flow.operand_stack.is_none() {
+            return None;
+        }
+        let args = self.func.arg_pool[values].to_vec();
+        let is_const = |abs: &AbstractValue| abs.as_const_u32().is_some();
+        match op {
+            // Leave arithmetic on constants (a stack at a known
+            // address) to constant folding.
+            Operator::I32Add | Operator::I32Sub if !is_const(&abs[0]) || !is_const(&abs[1]) => {
+                let offset = match (&abs[0], &abs[1]) {
+                    // The distance between two pointers into the
+                    // stack (e.g. the depth, `sp - base`, which is
+                    // then shifted into an index) is a constant.
+                    (AbstractValue::StackOffset(x), AbstractValue::StackOffset(y))
+                        if op == Operator::I32Sub =>
+                    {
+                        return Some(EvalResult::Normal(AbstractValue::Concrete(WasmVal::I32(
+                            x.wrapping_sub(*y) as u32,
+                        ))));
+                    }
+                    // Likewise for two shifted pointers, as long as
+                    // the base is shifted by the same amount in both.
+                    (
+                        AbstractValue::ShiftedStackOffset(s1, x),
+                        AbstractValue::ShiftedStackOffset(s2, y),
+                    ) if op == Operator::I32Sub && s1 == s2 => {
+                        return Some(EvalResult::Normal(AbstractValue::Concrete(WasmVal::I32(
+                            x.wrapping_sub(*y) as u32,
+                        ))));
                     }
-                } else if Some(function_index) == self.intrinsics.write_local {
-                    self.stats.local_writes += 1;
-                    let ptr = self.func.arg_pool[values][0];
-                    let idx = abs[1].as_const_u32().unwrap();
-                    let data = self.func.arg_pool[values][2];
-                    state.flow.locals.insert(
-                        idx,
-                        (
-                            RegValue::Value {
-                                data: ptr,
-                                abs: abs[0].clone(),
-                                ty: Type::I32,
-                            },
+                    (
+                        AbstractValue::ShiftedStackOffset(shift, x),
+                        AbstractValue::Concrete(WasmVal::I32(k)),
+                    ) => {
+                        let k = *k as i32;
+                        let k = if op == Operator::I32Sub {
+                            k.wrapping_neg()
+                        } else {
+                            k
+                        };
+                        return Some(EvalResult::Normal(AbstractValue::ShiftedStackOffset(
+                            *shift,
+                            x.wrapping_add(k),
+                        )));
+                    }
+                    (
+                        AbstractValue::Concrete(WasmVal::I32(k)),
+                        AbstractValue::ShiftedStackOffset(shift, y),
+                    ) if op == Operator::I32Add => {
+                        return Some(EvalResult::Normal(AbstractValue::ShiftedStackOffset(
+                            *shift,
+                            y.wrapping_add(*k as i32),
+                        )));
+                    }
+                    (x, AbstractValue::Concrete(WasmVal::I32(k))) => {
+                        let k = *k as i32;
+                        let k = if op == Operator::I32Sub {
+                            k.wrapping_neg()
+                        } else {
+                            k
+                        };
+                        (self.stack_offset(state, x)? as i32).wrapping_add(k)
+                    }
+                    (AbstractValue::Concrete(WasmVal::I32(k)), y) if op == Operator::I32Add => {
+                        (self.stack_offset(state, y)? as i32).wrapping_add(*k as i32)
+                    }
+                    _ => return None,
+                };
+                Some(EvalResult::Normal(AbstractValue::StackOffset(offset)))
+            }
+            // `(base + offset) << k` is `(base << k) + (offset << k)`
+            // (modulo 2^32), so a shifted stack pointer keeps a
+            // constant offset from the shifted base. The shifts of the
+            // base add up; once they reach the width, give up rather
+            // than fold the base away.
+            Operator::I32Shl => {
+                let k = abs[1].as_const_u32()? % 32;
+                let (shift, offset) = match &abs[0] {
+                    AbstractValue::StackOffset(offset) => (0, *offset),
+                    AbstractValue::ShiftedStackOffset(shift, offset) => (*shift, *offset),
+                    _ => return None,
+                };
+                if k == 0 {
+                    return Some(EvalResult::Normal(abs[0].clone()));
+                }
+                let shift = shift.checked_add(k).filter(|&shift| shift < 32)?;
+                let offset = (offset as u32).checked_shl(k)? as i32;
+                Some(EvalResult::Normal(AbstractValue::ShiftedStackOffset(
+                    shift, offset,
+                )))
+            }
+            Operator::I32Load { memory }
+            | Operator::I64Load { memory }
+            | Operator::F32Load { memory }
+            | Operator::F64Load { memory } => {
+                let ty = match op {
+                    Operator::I32Load { .. } => Type::I32,
+                    Operator::I64Load { .. } => Type::I64,
+                    Operator::F32Load { .. } => Type::F32,
+                    _ => Type::F64,
+                };
+                let size = if matches!(ty, Type::I32 | Type::F32) {
+                    4
+                } else {
+                    8
+                };
+                let slot = self.stack_slot(state, &abs[0], memory, size)??;
+                match state.flow.stack_slots.get(&slot) {
+                    Some(RegValue::Value {
+                        data,
+                        abs,
+                        ty: stored_ty,
+                    }) if *stored_ty == ty => {
+                        log::trace!("operand stack slot {}: forwarding {}", slot, data);
+                        self.stats.stack_slot_forwards += 1;
+                        Some(EvalResult::Alias(abs.clone(), *data))
+                    }
+                    _ => None,
+                }
+            }
+            Operator::I32Store { memory }
+            | Operator::I64Store { memory }
+            | Operator::F32Store { memory }
+            | Operator::F64Store { memory }
+            | Operator::I32Store8 { memory }
+            | Operator::I32Store16 { memory }
+            | Operator::I64Store8 { memory }
+            | Operator::I64Store16 { memory }
+            | Operator::I64Store32 { memory } => {
+                let (size, ty) = match op {
+                    Operator::I32Store { .. } => (4, Some(Type::I32)),
+                    Operator::I64Store { .. } => (8, Some(Type::I64)),
+                    Operator::F32Store { .. } => (4, Some(Type::F32)),
+                    Operator::F64Store { .. } => (8, Some(Type::F64)),
+                    Operator::I32Store8 { .. } | Operator::I64Store8 { .. } => (1, None),
+                    Operator::I32Store16 { .. } | Operator::I64Store16 { .. } => (2, None),
+                    _ => (4, None),
+                };
+                match (self.stack_slot(state, &abs[0], memory, size), ty) {
+                    (Some(Some(slot)), Some(ty)) => {
+                        state.flow.stack_slots.insert(
+                            slot,
                             RegValue::Value {
-                                data,
-                                abs: abs[2].clone(),
-                                ty: Type::I64,
+                                data: args[1],
+                                abs: abs[1].clone(),
+                                ty,
                             },
-                        ),
-                    );
-                    EvalResult::Elide
-                } else {
-                    EvalResult::Unhandled
+                        );
+                    }
+                    (Some(Some(slot)), None) => {
+                        state.flow.stack_slots.remove(&slot);
+                    }
+                    (Some(None), _) => state.flow.stack_slots.clear(),
+                    // By the contract of `weval.stack.declare`, other
+                    // pointers don't point into the stack.
+                    (None, _) => {}
                 }
+                None
             }
-            _ => EvalResult::Unhandled,
+            Operator::Call { function_index } => {
+                if self.effects.may_write_memory(function_index) {
+                    state.flow.stack_slots.clear();
+                }
+                None
+            }
+            Operator::MemoryCopy { .. } | Operator::MemoryFill { .. } => {
+                state.flow.stack_slots.clear();
+                None
+            }
+            op if op.is_call() => {
+                state.flow.stack_slots.clear();
+                None
+            }
+            _ => None,
         }
     }
 
@@ -1758,7 +6072,8 @@ impl<'a> Evaluator<'a> {
             Operator::I32Const { .. }
             | Operator::I64Const { .. }
             | Operator::F32Const { .. }
-            | Operator::F64Const { .. } => AbstractValue::Concrete(WasmVal::try_from(op).unwrap()),
+            | Operator::F64Const { .. }
+            | Operator::V128Const { .. } => AbstractValue::Concrete(WasmVal::try_from(op).unwrap()),
             _ => AbstractValue::Runtime(Some(orig_inst)),
         }
     }
@@ -1771,70 +6086,20 @@ impl<'a> Evaluator<'a> {
         orig_x_val: Value,
         state: &mut PointState,
     ) -> anyhow::Result<AbstractValue> {
+        if let AbstractValue::Concrete(k) = x {
+            if let Some(v) = crate::fold::unary(op, *k) {
+                return Ok(AbstractValue::Concrete(v));
+            }
+        }
+
         match (op, x) {
             (Operator::GlobalSet { global_index }, av) => {
                 state.flow.globals.insert(global_index, av.clone());
                 Ok(AbstractValue::Runtime(Some(orig_inst)))
             }
-            (Operator::I32Eqz, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(if *k == 0 {
-                    1
-                } else {
-                    0
-                })))
-            }
-            (Operator::I64Eqz, AbstractValue::Concrete(WasmVal::I64(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I64(if *k == 0 {
-                    1
-                } else {
-                    0
-                })))
-            }
-            (Operator::I32Extend8S, AbstractValue::Concrete(WasmVal::I32(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I32(*k as i8 as i32 as u32)),
-            ),
-            (Operator::I32Extend16S, AbstractValue::Concrete(WasmVal::I32(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I32(*k as i16 as i32 as u32)),
-            ),
-            (Operator::I64Extend8S, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i8 as i64 as u64)),
-            ),
-            (Operator::I64Extend16S, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i16 as i64 as u64)),
-            ),
-            (Operator::I64Extend32S, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i32 as i64 as u64)),
-            ),
-            (Operator::I32Clz, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(k.leading_zeros())))
-            }
-            (Operator::I64Clz, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(k.leading_zeros() as u64)),
-            ),
-            (Operator::I32Ctz, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(k.trailing_zeros())))
-            }
-            (Operator::I64Ctz, AbstractValue::Concrete(WasmVal::I64(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(k.trailing_zeros() as u64)),
-            ),
-            (Operator::I32Popcnt, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(k.count_ones())))
-            }
-            (Operator::I64Popcnt, AbstractValue::Concrete(WasmVal::I64(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I64(k.count_ones() as u64)))
-            }
-            (Operator::I32WrapI64, AbstractValue::Concrete(WasmVal::I64(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I32(*k as u32)))
-            }
             (Operator::I32WrapI64, AbstractValue::ConcreteMemory(buf, off)) => {
                 Ok(AbstractValue::ConcreteMemory(buf.clone(), *off))
             }
-            (Operator::I64ExtendI32S, AbstractValue::Concrete(WasmVal::I32(k))) => Ok(
-                AbstractValue::Concrete(WasmVal::I64(*k as i32 as i64 as u64)),
-            ),
-            (Operator::I64ExtendI32U, AbstractValue::Concrete(WasmVal::I32(k))) => {
-                Ok(AbstractValue::Concrete(WasmVal::I64(*k as u64)))
-            }
 
             (Operator::I32Load { memory }, AbstractValue::ConcreteMemory(buf, offset))
             | (Operator::I32Load8U { memory }, AbstractValue::ConcreteMemory(buf, offset))
@@ -1864,13 +6129,30 @@ impl<'a> Evaluator<'a> {
                     _ => unreachable!(),
                 };
 
-                let offset = offset
-                    .checked_add(memory.offset)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid offset"))?;
+                let offset = match crate::fold::effective_addr(*offset, memory.offset.into()) {
+                    Some(offset) => offset,
+                    None => {
+                        let addr = format!(
+                            "offset {:#x}+{:#x} of const-memory arg {}",
+                            offset, memory.offset, buf.0
+                        );
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, size));
+                    }
+                };
                 let mem = self.directive_args.const_memory[buf.0 as usize]
                     .as_ref()
                     .unwrap();
-                let val = mem.read_size(offset, size)?;
+                if !mem.is_const(offset, size) {
+                    log::trace!(" -> load from mutable field at offset {}", offset);
+                    return Ok(self.unfolded_load(orig_inst, LoadLossReason::MutableField));
+                }
+                let val = match mem.read_size(offset, size) {
+                    Ok(val) => val,
+                    Err(_) => {
+                        let addr = format!("offset {:#x} of const-memory arg {}", offset, buf.0);
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, size));
+                    }
+                };
                 let val = AbstractValue::Concrete(WasmVal::I32(conv(val)));
                 log::trace!(" -> produces {:?}", val);
                 Ok(val)
@@ -1904,28 +6186,125 @@ impl<'a> Evaluator<'a> {
                     _ => unreachable!(),
                 };
 
-                let offset = offset
-                    .checked_add(memory.offset)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid offset"))?;
+                let offset = match crate::fold::effective_addr(*offset, memory.offset.into()) {
+                    Some(offset) => offset,
+                    None => {
+                        let addr = format!(
+                            "offset {:#x}+{:#x} of const-memory arg {}",
+                            offset, memory.offset, buf.0
+                        );
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, size));
+                    }
+                };
 
                 let mem = self.directive_args.const_memory[buf.0 as usize]
                     .as_ref()
                     .unwrap();
-                let val = mem.read_size(offset, size)?;
+                if !mem.is_const(offset, size) {
+                    log::trace!(" -> load from mutable field at offset {}", offset);
+                    return Ok(self.unfolded_load(orig_inst, LoadLossReason::MutableField));
+                }
+                let val = match mem.read_size(offset, size) {
+                    Ok(val) => val,
+                    Err(_) => {
+                        let addr = format!("offset {:#x} of const-memory arg {}", offset, buf.0);
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, size));
+                    }
+                };
                 let val = AbstractValue::Concrete(WasmVal::I64(conv(val)));
                 log::trace!(" -> produces {:?}", val);
                 Ok(val)
             }
 
+            // A whole vector (e.g., an operand packet), so that lane
+            // extracts from it fold even if other uses of it remain.
+            (Operator::V128Load { memory }, AbstractValue::ConcreteMemory(buf, offset)) => {
+                let offset = match crate::fold::effective_addr(*offset, memory.offset.into()) {
+                    Some(offset) => offset,
+                    None => {
+                        let addr = format!(
+                            "offset {:#x}+{:#x} of const-memory arg {}",
+                            offset, memory.offset, buf.0
+                        );
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, 16));
+                    }
+                };
+                let mem = self.directive_args.const_memory[buf.0 as usize]
+                    .as_ref()
+                    .unwrap();
+                if !mem.is_const(offset, 16) {
+                    log::trace!(" -> load from mutable field at offset {}", offset);
+                    return Ok(self.unfolded_load(orig_inst, LoadLossReason::MutableField));
+                }
+                let halves = offset
+                    .checked_add(8)
+                    .map(|hi| (mem.read_size(offset, 8), mem.read_size(hi, 8)));
+                match halves {
+                    Some((Ok(lo), Ok(hi))) => Ok(AbstractValue::Concrete(WasmVal::V128(
+                        (lo as u128) | ((hi as u128) << 64),
+                    ))),
+                    _ => {
+                        let addr = format!("offset {:#x} of const-memory arg {}", offset, buf.0);
+                        Ok(self.out_of_bounds_load(orig_inst, addr, 16))
+                    }
+                }
+            }
+
+            // Only what directives supply explicitly is constant in a
+            // shared memory.
+            (
+                Operator::I32Load { .. } | Operator::I64Load { .. } | Operator::V128Load { .. },
+                AbstractValue::StaticMemory(_),
+            ) if self.image.is_shared(self.image.main_heap()?) => {
+                Ok(self.unfolded_load(orig_inst, LoadLossReason::SharedMemory))
+            }
             (Operator::I32Load { memory }, AbstractValue::StaticMemory(addr)) => {
-                let addr = addr.checked_add(memory.offset).unwrap();
-                let val = self.image.read_u32(self.image.main_heap()?, addr)?;
-                Ok(AbstractValue::Concrete(WasmVal::I32(val)))
+                let addr = match crate::fold::effective_addr(*addr, memory.offset.into()) {
+                    Some(addr) => addr,
+                    None => {
+                        let addr = format!("{:#x}+{:#x}", addr, memory.offset);
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, 4));
+                    }
+                };
+                match self.image.read_u32(self.image.main_heap()?, addr) {
+                    Ok(val) => Ok(AbstractValue::Concrete(WasmVal::I32(val))),
+                    Err(_) => Ok(self.out_of_bounds_load(orig_inst, format!("{:#x}", addr), 4)),
+                }
             }
             (Operator::I64Load { memory }, AbstractValue::StaticMemory(addr)) => {
-                let addr = addr.checked_add(memory.offset).unwrap();
-                let val = self.image.read_u64(self.image.main_heap()?, addr)?;
-                Ok(AbstractValue::Concrete(WasmVal::I64(val)))
+                let addr = match crate::fold::effective_addr(*addr, memory.offset.into()) {
+                    Some(addr) => addr,
+                    None => {
+                        let addr = format!("{:#x}+{:#x}", addr, memory.offset);
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, 8));
+                    }
+                };
+                match self.image.read_u64(self.image.main_heap()?, addr) {
+                    Ok(val) => Ok(AbstractValue::Concrete(WasmVal::I64(val))),
+                    Err(_) => Ok(self.out_of_bounds_load(orig_inst, format!("{:#x}", addr), 8)),
+                }
+            }
+            (Operator::V128Load { memory }, AbstractValue::StaticMemory(addr)) => {
+                let addr = match crate::fold::effective_addr(*addr, memory.offset.into()) {
+                    Some(addr) => addr,
+                    None => {
+                        let addr = format!("{:#x}+{:#x}", addr, memory.offset);
+                        return Ok(self.out_of_bounds_load(orig_inst, addr, 16));
+                    }
+                };
+                let heap = self.image.main_heap()?;
+                let halves = addr.checked_add(8).map(|hi| {
+                    (
+                        self.image.read_u64(heap, addr),
+                        self.image.read_u64(heap, hi),
+                    )
+                });
+                match halves {
+                    Some((Ok(lo), Ok(hi))) => Ok(AbstractValue::Concrete(WasmVal::V128(
+                        (lo as u128) | ((hi as u128) << 64),
+                    ))),
+                    _ => Ok(self.out_of_bounds_load(orig_inst, format!("{:#x}", addr), 16)),
+                }
             }
 
             // TODO: FP and SIMD
@@ -1942,229 +6321,9 @@ impl<'a> Evaluator<'a> {
     ) -> AbstractValue {
         match (x, y) {
             (AbstractValue::Concrete(v1), AbstractValue::Concrete(v2)) => {
-                match (op, v1, v2) {
-                    // 32-bit comparisons.
-                    (Operator::I32Eq, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 == k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32Ne, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 != k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32LtS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) < (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32LtU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 < k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32GtS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) > (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32GtU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 > k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32LeS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) <= (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32LeU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 <= k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I32GeS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if (*k1 as i32) >= (*k2 as i32) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I32GeU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(if k1 >= k2 { 1 } else { 0 }))
-                    }
-
-                    // 64-bit comparisons.
-                    (Operator::I64Eq, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if k1 == k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64Ne, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if k1 != k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64LtS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if (*k1 as i64) < (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64LtU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if k1 < k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64GtS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if (*k1 as i64) > (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64GtU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if k1 > k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64LeS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if (*k1 as i64) <= (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64LeU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if k1 <= k2 { 1 } else { 0 }))
-                    }
-                    (Operator::I64GeS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if (*k1 as i64) >= (*k2 as i64) {
-                            1
-                        } else {
-                            0
-                        }))
-                    }
-                    (Operator::I64GeU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(if k1 >= k2 { 1 } else { 0 }))
-                    }
-
-                    // 32-bit integer arithmetic.
-                    (Operator::I32Add, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_add(*k2)))
-                    }
-                    (Operator::I32Sub, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_sub(*k2)))
-                    }
-                    (Operator::I32Mul, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_mul(*k2)))
-                    }
-                    (Operator::I32DivU, WasmVal::I32(k1), WasmVal::I32(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_div(*k2)))
-                    }
-                    (Operator::I32DivS, WasmVal::I32(k1), WasmVal::I32(k2))
-                        if *k2 != 0 && (*k1 != 0x8000_0000 || *k2 != 0xffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I32(
-                            (*k1 as i32).wrapping_div(*k2 as i32) as u32
-                        ))
-                    }
-                    (Operator::I32RemU, WasmVal::I32(k1), WasmVal::I32(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_rem(*k2)))
-                    }
-                    (Operator::I32RemS, WasmVal::I32(k1), WasmVal::I32(k2))
-                        if *k2 != 0 && (*k1 != 0x8000_0000 || *k2 != 0xffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I32(
-                            (*k1 as i32).wrapping_rem(*k2 as i32) as u32
-                        ))
-                    }
-                    (Operator::I32And, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1 & k2))
-                    }
-                    (Operator::I32Or, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1 | k2))
-                    }
-                    (Operator::I32Xor, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1 ^ k2))
-                    }
-                    (Operator::I32Shl, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_shl(k2 & 0x1f)))
-                    }
-                    (Operator::I32ShrU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(k1.wrapping_shr(k2 & 0x1f)))
-                    }
-                    (Operator::I32ShrS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I32(
-                            (*k1 as i32).wrapping_shr(*k2 & 0x1f) as u32
-                        ))
-                    }
-                    (Operator::I32Rotl, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        let amt = k2 & 0x1f;
-                        let result = k1.wrapping_shl(amt) | k1.wrapping_shr(32 - amt);
-                        AbstractValue::Concrete(WasmVal::I32(result))
-                    }
-                    (Operator::I32Rotr, WasmVal::I32(k1), WasmVal::I32(k2)) => {
-                        let amt = k2 & 0x1f;
-                        let result = k1.wrapping_shr(amt) | k1.wrapping_shl(32 - amt);
-                        AbstractValue::Concrete(WasmVal::I32(result))
-                    }
-
-                    // 64-bit integer arithmetic.
-                    (Operator::I64Add, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_add(*k2)))
-                    }
-                    (Operator::I64Sub, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_sub(*k2)))
-                    }
-                    (Operator::I64Mul, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_mul(*k2)))
-                    }
-                    (Operator::I64DivU, WasmVal::I64(k1), WasmVal::I64(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_div(*k2)))
-                    }
-                    (Operator::I64DivS, WasmVal::I64(k1), WasmVal::I64(k2))
-                        if *k2 != 0
-                            && (*k1 != 0x8000_0000_0000_0000 || *k2 != 0xffff_ffff_ffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I64(
-                            (*k1 as i64).wrapping_div(*k2 as i64) as u64
-                        ))
-                    }
-                    (Operator::I64RemU, WasmVal::I64(k1), WasmVal::I64(k2)) if *k2 != 0 => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_rem(*k2)))
-                    }
-                    (Operator::I64RemS, WasmVal::I64(k1), WasmVal::I64(k2))
-                        if *k2 != 0
-                            && (*k1 != 0x8000_0000_0000_0000 || *k2 != 0xffff_ffff_ffff_ffff) =>
-                    {
-                        AbstractValue::Concrete(WasmVal::I64(
-                            (*k1 as i64).wrapping_rem(*k2 as i64) as u64
-                        ))
-                    }
-                    (Operator::I64And, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(*k1 & *k2))
-                    }
-                    (Operator::I64Or, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(*k1 | *k2))
-                    }
-                    (Operator::I64Xor, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(*k1 ^ *k2))
-                    }
-                    (Operator::I64Shl, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_shl((*k2 & 0x3f) as u32)))
-                    }
-                    (Operator::I64ShrU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(k1.wrapping_shr((*k2 & 0x3f) as u32)))
-                    }
-                    (Operator::I64ShrS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        AbstractValue::Concrete(WasmVal::I64(
-                            (*k1 as i64).wrapping_shr((*k2 & 0x3f) as u32) as u64,
-                        ))
-                    }
-                    (Operator::I64Rotl, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        let amt = (*k2 & 0x3f) as u32;
-                        let result = k1.wrapping_shl(amt) | k1.wrapping_shr(64 - amt);
-                        AbstractValue::Concrete(WasmVal::I64(result))
-                    }
-                    (Operator::I64Rotr, WasmVal::I64(k1), WasmVal::I64(k2)) => {
-                        let amt = (*k2 & 0x3f) as u32;
-                        let result = k1.wrapping_shr(amt) | k1.wrapping_shl(64 - amt);
-                        AbstractValue::Concrete(WasmVal::I64(result))
-                    }
-
-                    // TODO: FP and SIMD ops.
-                    _ => AbstractValue::Runtime(Some(orig_inst)),
+                match crate::fold::binary(op, *v1, *v2) {
+                    Some(v) => AbstractValue::Concrete(v),
+                    None => AbstractValue::Runtime(Some(orig_inst)),
                 }
             }
 
@@ -2226,6 +6385,18 @@ impl<'a> Evaluator<'a> {
             // Concrete-memory symbolic pointers are always truthy.
             (Operator::Select, AbstractValue::ConcreteMemory(..))
             | (Operator::TypedSelect { .. }, AbstractValue::ConcreteMemory(..)) => x.clone(),
+            // On a runtime condition, the result keeps whatever the
+            // two operands have in common (e.g., the same pointer into
+            // constant memory, computed twice), so that loads through
+            // it still fold.
+            (Operator::Select, _) | (Operator::TypedSelect { .. }, _) => {
+                match AbstractValue::meet(x, y) {
+                    AbstractValue::Top | AbstractValue::Runtime(_) => {
+                        AbstractValue::Runtime(Some(orig_inst))
+                    }
+                    common => common,
+                }
+            }
             _ => AbstractValue::Runtime(Some(orig_inst)),
         }
     }
@@ -2234,7 +6405,7 @@ impl<'a> Evaluator<'a> {
         // Examine regs in block input state of each
         // specialized block, and create blockparams for all values
         // that in the end were `BlockParam`.
-        for (&(ctx, orig_block), &block) in &self.block_map {
+        for ((ctx, orig_block), block) in self.block_map.iter() {
             let succ_state = &self.state.block_entry[block];
 
             let mut regs = vec![];
@@ -2266,6 +6437,9 @@ impl<'a> Evaluator<'a> {
                 handle_value(RegSlot::LocalAddr(i), addr)?;
                 handle_value(RegSlot::LocalData(i), data)?;
             }
+            for (&i, value) in succ_state.stack_slots.iter() {
+                handle_value(RegSlot::StackSlot(i), value)?;
+            }
 
             for pred_idx in 0..self.func.blocks[block].preds.len() {
                 let pred = self.func.blocks[block].preds[pred_idx];
@@ -2279,6 +6453,7 @@ impl<'a> Evaluator<'a> {
                         RegSlot::StackData(i) => &pred_state.stack.get(i as usize).unwrap().1,
                         RegSlot::LocalAddr(i) => &pred_state.locals.get(&i).unwrap().0,
                         RegSlot::LocalData(i) => &pred_state.locals.get(&i).unwrap().1,
+                        RegSlot::StackSlot(i) => pred_state.stack_slots.get(&i).unwrap(),
                     };
                     let pred_val = pred_reg.value().unwrap();
                     self.func.blocks[pred]
@@ -2300,7 +6475,7 @@ impl<'a> Evaluator<'a> {
         //
         // Also look at `locals` and find locals present in pred and
         // not in some succ, and sync them.
-        for (_, &block) in &self.block_map {
+        for block in self.block_map.values() {
             if self.func.blocks[block].succs.is_empty() {
                 continue;
             }
@@ -2435,7 +6610,155 @@ impl<'a> Evaluator<'a> {
         pre_entry
     }
 
+    /// Seal any block still without a terminator. Every specialized
+    /// block is evaluated once created, so this should not happen;
+    /// but a block left unterminated fails validation far from its
+    /// cause, so name the generic block it came from.
+    fn seal_unterminated_blocks(&mut self) {
+        for block in self.func.blocks.iter().collect::<Vec<_>>() {
+            if !matches!(self.func.blocks[block].terminator, Terminator::None) {
+                continue;
+            }
+            let (ctx, orig_block) = self.block_rev_map[block];
+            if self.block_map.get(&(ctx, orig_block)) == Some(&block) {
+                log::warn!(
+                    "Specialization {} of site {}: block {} (orig {} in context [{}]) has no terminator; sealing with unreachable",
+                    self.directive.id(),
+                    self.directive.user_id,
+                    block,
+                    orig_block,
+                    self.context_stack_desc(ctx).join(", ")
+                );
+            } else {
+                log::warn!(
+                    "Specialization {} of site {}: block {} ({}) has no terminator; sealing with unreachable",
+                    self.directive.id(),
+                    self.directive.user_id,
+                    block,
+                    self.func.blocks[block].desc
+                );
+            }
+            self.func.blocks[block].terminator = Terminator::Unreachable;
+        }
+    }
+
+    /// Guard each specialized block with more than one predecessor
+    /// with a check that its blockparams have the values they were
+    /// assumed to have; see `PartialEvalOptions::debug_assert_consts`.
+    fn insert_const_assertions(&mut self, trap: Func) {
+        let mut blocks = self.block_map.values().collect::<Vec<_>>();
+        blocks.sort();
+        for block in blocks {
+            if self.func.blocks[block].preds.len() < 2 {
+                continue;
+            }
+            let (_, orig_block) = self.block_rev_map[block];
+            let n_params = self.generic.blocks[orig_block].params.len();
+            let checks = (0..n_params)
+                .filter_map(|i| {
+                    let (ty, param) = self.func.blocks[block].params[i];
+                    match (ty, &self.state.values[param]) {
+                        (Type::I32, AbstractValue::Concrete(WasmVal::I32(k)))
+                        | (Type::I32, AbstractValue::StaticMemory(k)) => {
+                            Some((param, ty, Operator::I32Const { value: *k }, Operator::I32Ne))
+                        }
+                        (Type::I64, AbstractValue::Concrete(WasmVal::I64(k))) => {
+                            Some((param, ty, Operator::I64Const { value: *k }, Operator::I64Ne))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect::<Vec<_>>();
+            if checks.is_empty() {
+                continue;
+            }
+
+            // Move the body to a new block, and check and branch to
+            // it from this one, which keeps the params.
+            let body = self.func.add_block();
+            self.func.blocks[body].insts = std::mem::take(&mut self.func.blocks[block].insts);
+            self.func.blocks[body].terminator =
+                std::mem::take(&mut self.func.blocks[block].terminator);
+            self.func.blocks[body].desc = format!("Body of {}", block);
+            if let Some(likely) = self.hinted_branches.remove(&block) {
+                self.hinted_branches.insert(body, likely);
+            }
+
+            let i32_ty = self.func.single_type_list(Type::I32);
+            let mut failed = None;
+            for (param, ty, const_op, ne_op) in checks {
+                let k_ty = self.func.single_type_list(ty);
+                let k = self
+                    .func
+                    .add_value(ValueDef::Operator(const_op, ListRef::default(), k_ty));
+                self.func.append_to_block(block, k);
+                let args = self.func.arg_pool.double(param, k);
+                let ne = self.func.add_value(ValueDef::Operator(ne_op, args, i32_ty));
+                self.func.append_to_block(block, ne);
+                failed = Some(match failed {
+                    None => ne,
+                    Some(prev) => {
+                        let args = self.func.arg_pool.double(prev, ne);
+                        let or =
+                            self.func
+                                .add_value(ValueDef::Operator(Operator::I32Or, args, i32_ty));
+                        self.func.append_to_block(block, or);
+                        or
+                    }
+                });
+            }
+
+            let fail = self.func.add_block();
+            self.func.blocks[fail].desc = format!("Failed constant assertion at {}", block);
+            let code = self.func.add_value(ValueDef::Operator(
+                Operator::I32Const {
+                    value: block.index() as u32,
+                },
+                ListRef::default(),
+                i32_ty,
+            ));
+            self.func.append_to_block(fail, code);
+            let args = self.func.arg_pool.single(code);
+            let call = self.func.add_value(ValueDef::Operator(
+                Operator::Call {
+                    function_index: trap,
+                },
+                args,
+                ListRef::default(),
+            ));
+            self.func.append_to_block(fail, call);
+            self.func.blocks[fail].terminator = Terminator::Unreachable;
+
+            self.func.blocks[block].terminator = Terminator::CondBr {
+                cond: failed.unwrap(),
+                if_true: BlockTarget {
+                    block: fail,
+                    args: vec![],
+                },
+                if_false: BlockTarget {
+                    block: body,
+                    args: vec![],
+                },
+            };
+        }
+        self.func.recompute_edges();
+    }
+
+    /// Consume the evaluator, returning the specialized body, stats,
+    /// and assumed region epochs, and dropping all other state.
+    fn into_output(mut self) -> (FunctionBody, SpecializationStats, Vec<RegionEpoch>) {
+        let region_epochs = self
+            .region_epochs
+            .iter()
+            .map(|(&(addr, len), &epoch)| RegionEpoch { addr, len, epoch })
+            .collect();
+        self.stats.contexts = self.state.contexts.len();
+        self.stats.folds = self.block_folds.values().sum();
+        (self.func, self.stats, region_epochs)
+    }
+
     fn finalize(&mut self) -> anyhow::Result<()> {
+        self.seal_unterminated_blocks();
         self.func.recompute_edges();
 
         self.add_blockparam_reg_args()?;
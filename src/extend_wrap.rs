@@ -0,0 +1,300 @@
+//! Peephole simplification of integer extend/wrap chains.
+//!
+//! Folding leaves many width conversions that cancel out: an `i32`
+//! extended to `i64` only to be wrapped back, a sign extension of a
+//! value already sign-extended from fewer bits, or an `i64` operation
+//! on extended `i32`s whose result is immediately wrapped. This pass
+//! rewrites
+//!
+//! - `i32.wrap_i64(i64.extend_i32_{s,u}(x))` to `x`, looking through
+//!   `i64.extend32_s`, which does not change the low 32 bits;
+//! - `i32.wrap_i64(op(a, b))`, for an `i64` `add`, `sub`, `mul`,
+//!   `and`, `or` or `xor` with no other use, whose operands are
+//!   extended `i32`s or constants, to the `i32` operation on the
+//!   unextended operands (the low 32 bits of the result depend only
+//!   on those of the operands);
+//! - a sign extension from N bits of a value already sign-extended
+//!   from at most N bits to that value, and from more than N bits to
+//!   the extension of the original operand;
+//! - `i64.extend32_s(i64.extend_i32_u(x))` to `i64.extend_i32_s(x)`.
+//!
+//! Replaced values become aliases; operands left unused are removed
+//! by DCE.
+
+use crate::collections::HashMap;
+use waffle::{pool::ListRef, Block, FunctionBody, Operator, Type, Value, ValueDef};
+
+/// The operator and (alias-resolved) arguments defining a value, if
+/// it is an operator.
+fn def(func: &FunctionBody, value: Value) -> Option<(Operator, Vec<Value>)> {
+    match &func.values[func.resolve_alias(value)] {
+        ValueDef::Operator(op, args, _) => Some((
+            *op,
+            func.arg_pool[*args]
+                .iter()
+                .map(|&arg| func.resolve_alias(arg))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+/// The number of bits a sign-extension operator extends from, and
+/// whether its operand and result are `i64`.
+fn sign_extend_bits(op: Operator) -> Option<(u32, bool)> {
+    match op {
+        Operator::I32Extend8S => Some((8, false)),
+        Operator::I32Extend16S => Some((16, false)),
+        Operator::I64Extend8S => Some((8, true)),
+        Operator::I64Extend16S => Some((16, true)),
+        Operator::I64Extend32S => Some((32, true)),
+        _ => None,
+    }
+}
+
+/// The `i32` counterpart of an `i64` operator whose low 32 result
+/// bits depend only on the low 32 bits of its operands.
+fn narrow_binop(op: Operator) -> Option<Operator> {
+    match op {
+        Operator::I64Add => Some(Operator::I32Add),
+        Operator::I64Sub => Some(Operator::I32Sub),
+        Operator::I64Mul => Some(Operator::I32Mul),
+        Operator::I64And => Some(Operator::I32And),
+        Operator::I64Or => Some(Operator::I32Or),
+        Operator::I64Xor => Some(Operator::I32Xor),
+        _ => None,
+    }
+}
+
+/// An `i32` value whose extension is the given `i64` operand, as
+/// either an existing value or a constant to create.
+enum Narrowed {
+    Value(Value),
+    Const(u32),
+}
+
+fn narrow_operand(func: &FunctionBody, value: Value) -> Option<Narrowed> {
+    match def(func, value)? {
+        (Operator::I64ExtendI32U | Operator::I64ExtendI32S, args) => Some(Narrowed::Value(args[0])),
+        (Operator::I64Const { value }, _) => Some(Narrowed::Const(value as u32)),
+        _ => None,
+    }
+}
+
+/// Number of uses of each value, by instructions and terminators.
+fn use_counts(func: &FunctionBody) -> HashMap<Value, usize> {
+    let mut counts: HashMap<Value, usize> = HashMap::default();
+    for (_, block) in func.blocks.entries() {
+        for &inst in &block.insts {
+            if let ValueDef::Operator(_, args, _) = &func.values[inst] {
+                for &arg in &func.arg_pool[*args] {
+                    *counts.entry(func.resolve_alias(arg)).or_default() += 1;
+                }
+            }
+        }
+        block.terminator.visit_uses(|arg| {
+            *counts.entry(func.resolve_alias(arg)).or_default() += 1;
+        });
+    }
+    counts
+}
+
+/// A rewrite of one instruction.
+enum Rewrite {
+    /// Replace with an alias of the given value.
+    Alias(Value),
+    /// Replace with the given unary operator on the given value.
+    Unary(Operator, Value),
+    /// Replace with the given `i32` binary operator on the narrowed
+    /// operands.
+    Binary(Operator, Narrowed, Narrowed),
+}
+
+fn rewrite(func: &FunctionBody, uses: &HashMap<Value, usize>, inst: Value) -> Option<Rewrite> {
+    let (op, args) = def(func, inst)?;
+    match op {
+        Operator::I32WrapI64 => {
+            let mut arg = args[0];
+            loop {
+                match def(func, arg)? {
+                    (Operator::I64ExtendI32U | Operator::I64ExtendI32S, inner) => {
+                        return Some(Rewrite::Alias(inner[0]));
+                    }
+                    (Operator::I64Extend32S, inner) => arg = inner[0],
+                    (op, inner) => {
+                        let narrowed = narrow_binop(op)?;
+                        if uses.get(&arg).copied() != Some(1) {
+                            return None;
+                        }
+                        let a = narrow_operand(func, inner[0])?;
+                        let b = narrow_operand(func, inner[1])?;
+                        return Some(Rewrite::Binary(narrowed, a, b));
+                    }
+                }
+            }
+        }
+        Operator::I64Extend32S => match def(func, args[0])? {
+            (Operator::I64ExtendI32S, _) => Some(Rewrite::Alias(args[0])),
+            (Operator::I64ExtendI32U, inner) => {
+                Some(Rewrite::Unary(Operator::I64ExtendI32S, inner[0]))
+            }
+            (inner_op, inner) => extend_of_extend(op, inner_op, args[0], inner[0]),
+        },
+        op if sign_extend_bits(op).is_some() => {
+            let (inner_op, inner) = def(func, args[0])?;
+            extend_of_extend(op, inner_op, args[0], inner[0])
+        }
+        _ => None,
+    }
+}
+
+/// Simplify the sign extension `outer(value)`, where `value` is
+/// `inner(inner_arg)`.
+fn extend_of_extend(
+    outer: Operator,
+    inner: Operator,
+    value: Value,
+    inner_arg: Value,
+) -> Option<Rewrite> {
+    let (outer_bits, outer_i64) = sign_extend_bits(outer)?;
+    let (inner_bits, inner_i64) = sign_extend_bits(inner)?;
+    if outer_i64 != inner_i64 {
+        return None;
+    }
+    if inner_bits <= outer_bits {
+        Some(Rewrite::Alias(value))
+    } else {
+        Some(Rewrite::Unary(outer, inner_arg))
+    }
+}
+
+/// Runs the pass, returning the number of instructions rewritten.
+pub fn run(func: &mut FunctionBody) -> usize {
+    let uses = use_counts(func);
+    let i32_ty = func.single_type_list(Type::I32);
+    let mut rewritten = 0;
+    for block in func.blocks.iter().collect::<Vec<Block>>() {
+        let mut insts = vec![];
+        for inst in std::mem::take(&mut func.blocks[block].insts) {
+            match rewrite(func, &uses, inst) {
+                None => {}
+                Some(Rewrite::Alias(value)) => {
+                    func.values[inst] = ValueDef::Alias(value);
+                    rewritten += 1;
+                    continue;
+                }
+                Some(Rewrite::Unary(op, arg)) => {
+                    let tys = match func.values[inst] {
+                        ValueDef::Operator(_, _, tys) => tys,
+                        _ => unreachable!(),
+                    };
+                    let args = func.arg_pool.single(arg);
+                    func.values[inst] = ValueDef::Operator(op, args, tys);
+                    rewritten += 1;
+                }
+                Some(Rewrite::Binary(op, a, b)) => {
+                    let mut operand = |func: &mut FunctionBody, narrowed: Narrowed| match narrowed {
+                        Narrowed::Value(value) => value,
+                        Narrowed::Const(value) => {
+                            let k = func.add_value(ValueDef::Operator(
+                                Operator::I32Const { value },
+                                ListRef::default(),
+                                i32_ty,
+                            ));
+                            insts.push(k);
+                            k
+                        }
+                    };
+                    let a = operand(func, a);
+                    let b = operand(func, b);
+                    let args = func.arg_pool.double(a, b);
+                    func.values[inst] = ValueDef::Operator(op, args, i32_ty);
+                    rewritten += 1;
+                }
+            }
+            insts.push(inst);
+        }
+        func.blocks[block].insts = insts;
+    }
+    if rewritten > 0 {
+        waffle::passes::resolve_aliases::run(func);
+    }
+    log::debug!("extend_wrap: rewrote {} instructions", rewritten);
+    rewritten
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+    use waffle::{FrontendOptions, Func, Module, Terminator};
+
+    /// Runs the pass over the first function of `wat`, and returns the
+    /// body, the value it returns, and its params.
+    fn simplify(wat: &str) -> (FunctionBody, Value, Vec<Value>) {
+        let bytes = wat::parse_str(wat).unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        let mut func = module.clone_and_expand_body(Func::new(0)).unwrap();
+        assert!(run(&mut func) > 0);
+        let ret = func
+            .blocks
+            .values()
+            .find_map(|block| match &block.terminator {
+                Terminator::Return { values } => Some(func.resolve_alias(values[0])),
+                _ => None,
+            })
+            .unwrap();
+        let params = func.blocks[func.entry]
+            .params
+            .iter()
+            .map(|&(_, param)| param)
+            .collect();
+        (func, ret, params)
+    }
+
+    #[test]
+    fn wrap_of_extend_cancels() {
+        let (_, ret, params) = simplify(
+            r#"
+            (module
+              (func (param i32) (result i32)
+                (i32.wrap_i64 (i64.extend32_s (i64.extend_i32_u (local.get 0))))))
+            "#,
+        );
+        assert_eq!(ret, params[0]);
+    }
+
+    #[test]
+    fn wrapped_i64_op_narrows() {
+        let (func, ret, params) = simplify(
+            r#"
+            (module
+              (func (param i32) (result i32)
+                (i32.wrap_i64
+                  (i64.add (i64.extend_i32_s (local.get 0)) (i64.const 5)))))
+            "#,
+        );
+        let (op, args) = def(&func, ret).unwrap();
+        assert_eq!(op, Operator::I32Add);
+        assert_eq!(args[0], params[0]);
+        assert_eq!(
+            def(&func, args[1]).unwrap().0,
+            Operator::I32Const { value: 5 }
+        );
+    }
+
+    #[test]
+    fn sign_extend_of_narrower_extend_is_redundant() {
+        let (func, ret, params) = simplify(
+            r#"
+            (module
+              (func (param i32) (result i32)
+                (i32.extend16_s (i32.extend8_s (local.get 0)))))
+            "#,
+        );
+        assert_eq!(
+            def(&func, ret).unwrap(),
+            (Operator::I32Extend8S, vec![params[0]])
+        );
+    }
+}
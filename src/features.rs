@@ -0,0 +1,152 @@
+//! Post-MVP features the output module may use.
+//!
+//! The output keeps whatever features the input already used; these
+//! options say which features passes may additionally introduce
+//! (e.g. `return_call` in stubs, or passive data segments) and which
+//! the final module is validated against, so that a module destined
+//! for an engine without, say, tail calls fails at weval time rather
+//! than at load time.
+
+/// An engine profile: the set of features a target engine supports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TargetProfile {
+    /// The 1.0 (MVP) spec, with no extensions.
+    Mvp,
+    /// The 2.0 spec: adds bulk memory, reference types, sign
+    /// extension, saturating float-to-int, multi-value, and SIMD.
+    Wasm2,
+    /// Everything weval knows how to emit, including tail calls.
+    #[default]
+    Latest,
+}
+
+impl std::str::FromStr for TargetProfile {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "mvp" => Ok(TargetProfile::Mvp),
+            "wasm2" => Ok(TargetProfile::Wasm2),
+            "latest" => Ok(TargetProfile::Latest),
+            _ => anyhow::bail!("Unknown target profile: {}", s),
+        }
+    }
+}
+
+impl TargetProfile {
+    /// The largest feature set the profile supports.
+    pub fn max_features(self) -> OutputFeatures {
+        match self {
+            TargetProfile::Mvp => OutputFeatures::none(),
+            TargetProfile::Wasm2 => OutputFeatures {
+                bulk_memory: true,
+                reference_types: true,
+                tail_call: false,
+            },
+            TargetProfile::Latest => OutputFeatures::default(),
+        }
+    }
+}
+
+/// Features the output module may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputFeatures {
+    /// Passive data segments, `memory.copy`/`memory.fill`, etc.
+    pub bulk_memory: bool,
+    /// `ref.func`, multiple tables, `table.*` instructions, etc.
+    pub reference_types: bool,
+    /// `return_call` and `return_call_indirect`.
+    pub tail_call: bool,
+}
+
+impl Default for OutputFeatures {
+    fn default() -> Self {
+        OutputFeatures {
+            bulk_memory: true,
+            reference_types: true,
+            tail_call: true,
+        }
+    }
+}
+
+/// Parses a comma-separated list of `bulk-memory`, `ref-types`, and
+/// `tail-call`, or `none`.
+impl std::str::FromStr for OutputFeatures {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut features = OutputFeatures::none();
+        if s == "none" {
+            return Ok(features);
+        }
+        for feature in s.split(',') {
+            match feature.trim() {
+                "bulk-memory" => features.bulk_memory = true,
+                "ref-types" => features.reference_types = true,
+                "tail-call" => features.tail_call = true,
+                other => anyhow::bail!("Unknown output feature: {}", other),
+            }
+        }
+        Ok(features)
+    }
+}
+
+impl OutputFeatures {
+    pub fn none() -> Self {
+        OutputFeatures {
+            bulk_memory: false,
+            reference_types: false,
+            tail_call: false,
+        }
+    }
+
+    /// Checks that the features are consistent and supported by the
+    /// profile.
+    pub fn check(&self, profile: TargetProfile) -> anyhow::Result<()> {
+        let max = profile.max_features();
+        for (name, requested, supported) in [
+            ("bulk-memory", self.bulk_memory, max.bulk_memory),
+            ("ref-types", self.reference_types, max.reference_types),
+            ("tail-call", self.tail_call, max.tail_call),
+        ] {
+            if requested && !supported {
+                anyhow::bail!(
+                    "Output feature {} is not supported by target profile {:?}",
+                    name,
+                    profile
+                );
+            }
+        }
+        if self.reference_types && !self.bulk_memory {
+            anyhow::bail!("Output feature ref-types requires bulk-memory");
+        }
+        Ok(())
+    }
+
+    fn wasm_features(&self, profile: TargetProfile) -> wasmparser::WasmFeatures {
+        let post_mvp = profile != TargetProfile::Mvp;
+        wasmparser::WasmFeatures {
+            bulk_memory: self.bulk_memory,
+            reference_types: self.reference_types,
+            tail_call: self.tail_call,
+            sign_extension: post_mvp,
+            saturating_float_to_int: post_mvp,
+            multi_value: post_mvp,
+            simd: post_mvp,
+            ..wasmparser::WasmFeatures::default()
+        }
+    }
+
+    /// Validates an output module against these features, within the
+    /// given profile.
+    pub fn validate(&self, profile: TargetProfile, module: &[u8]) -> anyhow::Result<()> {
+        let mut validator = wasmparser::Validator::new_with_features(self.wasm_features(profile));
+        validator.validate_all(module).map_err(|e| {
+            anyhow::anyhow!(
+                "Output module is invalid for target profile {:?} with {:?}: {}",
+                profile,
+                self,
+                e
+            )
+        })?;
+        Ok(())
+    }
+}
@@ -12,10 +12,38 @@
 //!   - If a return value, then the first arg is returned. Assert that types
 //!     match accordingly. Generate a drop (`0x1a`) for all remaining args.
 //!   - Otherwise, if any args, generate drops for all args.
+//! - Keep the `dylink.0` section of a side module first in the
+//!   output, growing its table size to cover any new table entries.
+//!   Its table elements and data segments, which come out of waffle at
+//!   constant offsets relative to the module's slots in the shared
+//!   table and memory, are placed at `__table_base` and
+//!   `__memory_base` again.
+//! - Emit the `weval.relocs` section, recording each function-index
+//!   write to memory with the function's final index.
+//! - Emit the branch-hint section, at the final offsets of the hinted
+//!   branches, ahead of the code section as engines expect.
+//! - Write back the input module's data segments, for `memory.init`
+//!   and `data.drop`, ahead of the output's own, with a data count
+//!   section to go with them.
 
-use fxhash::FxHashMap;
+use crate::branch_hints::FuncHints;
+use crate::collections::HashMap;
+use crate::gc::LiveItems;
+use std::collections::BTreeMap;
 use wasmparser::{ElementItems, ElementKind, ExternalKind, Parser, Payload, TypeRef, ValType};
 
+/// A write of a specialized function's table index into memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FuncIndexReloc {
+    /// Address in the main memory.
+    pub addr: u32,
+    /// The table index written.
+    pub table_index: u32,
+    /// The function at that table index, in the pre-filter index
+    /// space.
+    pub func: u32,
+}
+
 #[derive(Clone, Debug)]
 enum FuncRemap {
     Index(u32),
@@ -33,8 +61,90 @@ impl FuncRemap {
 
 #[derive(Default, Clone, Debug)]
 struct Rewrite {
-    func_remap: FxHashMap<u32, FuncRemap>,
+    func_remap: HashMap<u32, FuncRemap>,
     func_types: Vec<(Vec<ValType>, Vec<ValType>)>,
+    /// Functions (in original index space) to list in the
+    /// `weval.cold` custom section.
+    cold_funcs: Vec<u32>,
+    /// Function-index writes to record in `weval.relocs`.
+    relocs: Vec<FuncIndexReloc>,
+    /// Live items, if we are removing unreachable ones.
+    live: Option<LiveItems>,
+    /// Remapping of global indices, if any globals are removed.
+    global_remap: Option<HashMap<u32, u32>>,
+    /// Table size to record in the `dylink.0` section, if any.
+    dylink_table_size: u32,
+    /// For a side module, the imported `__memory_base` and
+    /// `__table_base` globals.
+    memory_base_global: Option<u32>,
+    table_base_global: Option<u32>,
+    /// Branch hints, by function (in original index space).
+    branch_hints: BTreeMap<u32, FuncHints>,
+    /// Branch hints as (offset, likely) pairs, by output function
+    /// index, as the code section is rewritten.
+    out_branch_hints: BTreeMap<u32, Vec<(u32, bool)>>,
+    /// Data segments of the input module to write as passive
+    /// segments ahead of the output's own; see
+    /// `Image::input_data_segments`.
+    input_segments: Vec<Vec<u8>>,
+}
+
+fn read_leb_u32(data: &[u8], pos: &mut usize) -> anyhow::Result<u32> {
+    let mut result = 0u32;
+    let mut shift = 0;
+    loop {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| anyhow::anyhow!("Truncated LEB128"))?;
+        *pos += 1;
+        if shift >= 32 {
+            anyhow::bail!("LEB128 too long");
+        }
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+/// Rewrite a `dylink.0` section so that its table size is at least
+/// `table_size`. Other subsections are copied as-is.
+fn patch_dylink(data: &[u8], table_size: u32) -> anyhow::Result<Vec<u8>> {
+    use wasm_encoder::Encode;
+    const WASM_DYLINK_MEM_INFO: u8 = 1;
+
+    let mut out = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let id = data[pos];
+        pos += 1;
+        let len = read_leb_u32(data, &mut pos)? as usize;
+        let payload = data
+            .get(pos..(pos + len))
+            .ok_or_else(|| anyhow::anyhow!("Truncated dylink.0 subsection"))?;
+        pos += len;
+        if id == WASM_DYLINK_MEM_INFO {
+            let mut p = 0;
+            let mem_size = read_leb_u32(payload, &mut p)?;
+            let mem_align = read_leb_u32(payload, &mut p)?;
+            let orig_table_size = read_leb_u32(payload, &mut p)?;
+            let table_align = read_leb_u32(payload, &mut p)?;
+            let mut new_payload = vec![];
+            mem_size.encode(&mut new_payload);
+            mem_align.encode(&mut new_payload);
+            std::cmp::max(orig_table_size, table_size).encode(&mut new_payload);
+            table_align.encode(&mut new_payload);
+            out.push(id);
+            new_payload.len().encode(&mut out);
+            out.extend(new_payload);
+        } else {
+            out.push(id);
+            len.encode(&mut out);
+            out.extend_from_slice(payload);
+        }
+    }
+    Ok(out)
 }
 
 fn gen_replacement_bytecode(
@@ -67,6 +177,21 @@ fn gen_replacement_bytecode(
         | "read.local"
         | "write.local" => Ok(vec![wasm_encoder::Instruction::Unreachable]),
 
+        // An inline-cache site always misses in generic code.
+        "ic.site" => Ok(vec![
+            wasm_encoder::Instruction::Drop,
+            wasm_encoder::Instruction::Drop,
+            wasm_encoder::Instruction::I32Const(0),
+        ]),
+
+        // A write barrier is always needed in generic code.
+        "barrier.needed" => Ok(vec![
+            wasm_encoder::Instruction::Drop,
+            wasm_encoder::Instruction::Drop,
+            wasm_encoder::Instruction::Drop,
+            wasm_encoder::Instruction::I32Const(1),
+        ]),
+
         // All other intrinsics have "pass through first arg" behavior
         // if they have a return value, and otherwise have no effect.
         _ => {
@@ -95,51 +220,226 @@ fn parser_to_encoder_ty(ty: wasmparser::ValType) -> wasm_encoder::ValType {
         wasmparser::ValType::F32 => wasm_encoder::ValType::F32,
         wasmparser::ValType::F64 => wasm_encoder::ValType::F64,
         wasmparser::ValType::V128 => wasm_encoder::ValType::V128,
-        wasmparser::ValType::Ref(wasmparser::RefType::FUNCREF) => {
-            wasm_encoder::ValType::Ref(wasm_encoder::RefType::FUNCREF)
-        }
-        wasmparser::ValType::Ref(wasmparser::RefType::EXTERNREF) => {
-            wasm_encoder::ValType::Ref(wasm_encoder::RefType::EXTERNREF)
-        }
-        wasmparser::ValType::Ref(r) => wasm_encoder::ValType::Ref(wasm_encoder::RefType {
+        wasmparser::ValType::Ref(r) => wasm_encoder::ValType::Ref(parser_to_encoder_ref_ty(r)),
+    }
+}
+
+fn parser_to_encoder_ref_ty(ty: wasmparser::RefType) -> wasm_encoder::RefType {
+    match ty {
+        wasmparser::RefType::FUNCREF => wasm_encoder::RefType::FUNCREF,
+        wasmparser::RefType::EXTERNREF => wasm_encoder::RefType::EXTERNREF,
+        r => wasm_encoder::RefType {
             nullable: r.is_nullable(),
             heap_type: wasm_encoder::HeapType::Concrete(
                 r.type_index().unwrap().as_module_index().unwrap(),
             ),
+        },
+    }
+}
+
+/// The entity type of a non-function import, to transcribe it.
+fn import_entity_type(ty: TypeRef) -> anyhow::Result<wasm_encoder::EntityType> {
+    Ok(match ty {
+        TypeRef::Memory(ty) => wasm_encoder::EntityType::Memory(wasm_encoder::MemoryType {
+            minimum: ty.initial,
+            maximum: ty.maximum,
+            memory64: ty.memory64,
+            shared: ty.shared,
         }),
+        TypeRef::Table(ty) => wasm_encoder::EntityType::Table(wasm_encoder::TableType {
+            element_type: parser_to_encoder_ref_ty(ty.element_type),
+            minimum: ty.initial,
+            maximum: ty.maximum,
+        }),
+        TypeRef::Global(ty) => wasm_encoder::EntityType::Global(wasm_encoder::GlobalType {
+            val_type: parser_to_encoder_ty(ty.content_type),
+            mutable: ty.mutable,
+        }),
+        ty => anyhow::bail!("import type {:?} not supported", ty),
+    })
+}
+
+/// A side module's offset expression: `base` (an imported global)
+/// plus `offset`. Offsets past the base need extended constant
+/// expressions, so only use them where needed.
+fn based_offset_expr(base: u32, offset: i32) -> wasm_encoder::ConstExpr {
+    use wasm_encoder::Encode;
+    if offset == 0 {
+        return wasm_encoder::ConstExpr::global_get(base);
     }
+    let mut bytes = vec![0x23]; // global.get
+    base.encode(&mut bytes);
+    bytes.push(0x41); // i32.const
+    offset.encode(&mut bytes);
+    bytes.push(0x6a); // i32.add
+    wasm_encoder::ConstExpr::raw(bytes)
+}
+
+/// The constant offset of an active segment.
+fn const_offset(offset_expr: &wasmparser::ConstExpr) -> anyhow::Result<i32> {
+    let mut offset = None;
+    for op in offset_expr.get_operators_reader() {
+        match op? {
+            wasmparser::Operator::I32Const { value } if offset.is_none() => offset = Some(value),
+            wasmparser::Operator::End => {}
+            op => anyhow::bail!("unexpected operator {:?} in segment offset", op),
+        }
+    }
+    offset.ok_or_else(|| anyhow::anyhow!("empty segment offset"))
 }
 
 impl Rewrite {
+    fn func_live(&self, idx: u32) -> bool {
+        self.live
+            .as_ref()
+            .map(|live| live.funcs.contains(&idx))
+            .unwrap_or(true)
+    }
+
+    fn elements_live(&self) -> bool {
+        self.live.as_ref().map(|live| live.elements).unwrap_or(true)
+    }
+
+    fn remap_global(&self, idx: u32) -> u32 {
+        match &self.global_remap {
+            Some(remap) => *remap.get(&idx).unwrap(),
+            None => idx,
+        }
+    }
+
+    /// Emit the branch-hint section, if any hints survived, then the
+    /// code section.
+    fn finish_code_section(
+        &self,
+        out: &mut wasm_encoder::Module,
+        code_section: &wasm_encoder::CodeSection,
+    ) {
+        if !self.out_branch_hints.is_empty() {
+            use wasm_encoder::Encode;
+            let mut data = vec![];
+            self.out_branch_hints.len().encode(&mut data);
+            for (func, hints) in &self.out_branch_hints {
+                func.encode(&mut data);
+                hints.len().encode(&mut data);
+                for &(offset, likely) in hints {
+                    offset.encode(&mut data);
+                    1u32.encode(&mut data);
+                    data.push(likely as u8);
+                }
+            }
+            out.section(&wasm_encoder::CustomSection {
+                name: crate::branch_hints::SECTION_NAME.into(),
+                data: data.into(),
+            });
+        }
+        out.section(code_section);
+    }
+
     pub fn process(mut self, module: &[u8]) -> anyhow::Result<Vec<u8>> {
         let parser = Parser::new(0);
         let mut out = wasm_encoder::Module::new();
         let mut orig_func_idx = 0;
         let mut out_func_idx = 0;
         let mut num_funcs = 0;
-        let mut num_funcs_emitted = 0;
+        let mut num_funcs_seen = 0;
+        let mut num_imported_funcs = 0;
         let mut out_code_section = wasm_encoder::CodeSection::new();
         let mut weval_globals = 0;
+        let mut num_imported_globals = 0;
 
-        // Scan globals section once to count globals.
+        // Scan imports and the globals section once to count globals
+        // (those that we keep, if removing unreachable ones). Global
+        // indices count imported globals first; these are always kept
+        // and map to themselves, and defined globals follow them.
+        let mut remap = HashMap::default();
+        let mut side_module = false;
         for payload in parser.clone().parse_all(module) {
             match payload? {
+                Payload::CustomSection(reader) if reader.name() == "dylink.0" => {
+                    side_module = true;
+                }
+                Payload::ImportSection(imports) => {
+                    for import in imports {
+                        let import = import?;
+                        if let TypeRef::Global(_) = import.ty {
+                            if side_module && import.name == "__memory_base" {
+                                self.memory_base_global = Some(num_imported_globals);
+                            }
+                            if side_module && import.name == "__table_base" {
+                                self.table_base_global = Some(num_imported_globals);
+                            }
+                            remap.insert(num_imported_globals, num_imported_globals);
+                            num_imported_globals += 1;
+                            weval_globals += 1;
+                        }
+                    }
+                }
                 Payload::GlobalSection(globals) => {
-                    for _ in globals.into_iter() {
-                        weval_globals += 1;
+                    for (i, _) in globals.into_iter().enumerate() {
+                        let i = num_imported_globals + i as u32;
+                        match self.live.as_ref().and_then(|live| live.globals.as_ref()) {
+                            Some(live_globals) if !live_globals.contains(&i) => {}
+                            _ => {
+                                remap.insert(i, weval_globals);
+                                weval_globals += 1;
+                            }
+                        }
                     }
                     break;
                 }
                 _ => {}
             }
         }
+        if self
+            .live
+            .as_ref()
+            .map(|live| live.globals.is_some())
+            .unwrap_or(false)
+        {
+            self.global_remap = Some(remap);
+        }
+
+        // The output's own data segments follow any written back from
+        // the input.
+        let mut num_data_segments = self.input_segments.len() as u32;
+        if !self.input_segments.is_empty() {
+            for payload in parser.clone().parse_all(module) {
+                if let Payload::DataSection(data) = payload? {
+                    num_data_segments += data.count();
+                }
+            }
+        }
+        let mut data_written = false;
+
+        // A side module's `dylink.0` section must come first.
+        for payload in parser.clone().parse_all(module) {
+            match payload? {
+                Payload::CustomSection(reader) if reader.name() == "dylink.0" => {
+                    out.section(&wasm_encoder::CustomSection {
+                        name: "dylink.0".into(),
+                        data: patch_dylink(reader.data(), self.dylink_table_size)?.into(),
+                    });
+                    break;
+                }
+                _ => {}
+            }
+        }
 
         for payload in parser.parse_all(module) {
             let payload = payload?;
             let raw_section = payload.as_section();
             let transcribe = match payload {
                 Payload::Version { .. } => false,
-                Payload::End(..) => false,
+                Payload::End(..) => {
+                    if !data_written && !self.input_segments.is_empty() {
+                        let mut out_data = wasm_encoder::DataSection::new();
+                        for segment in &self.input_segments {
+                            out_data.passive(segment.iter().cloned());
+                        }
+                        out.section(&out_data);
+                    }
+                    false
+                }
 
                 // Type section: copy all function types so we can refer to them later.
                 Payload::TypeSection(types) => {
@@ -153,7 +453,9 @@ impl Rewrite {
 
                 // Import section: transcribe manually, removing
                 // intrinsic imports and noting remappings for each
-                // imported function.
+                // imported function. Other imports (a side module's
+                // memory, table, and base and stack-pointer globals,
+                // or any imported global) are kept unchanged.
                 Payload::ImportSection(imports) => {
                     let mut out_imports = wasm_encoder::ImportSection::new();
 
@@ -163,6 +465,7 @@ impl Rewrite {
                             TypeRef::Func(fty) => {
                                 let orig_idx = orig_func_idx;
                                 orig_func_idx += 1;
+                                num_imported_funcs += 1;
 
                                 if import.module == "weval" {
                                     // Omit the import, and add a rewriting to the func_remap info.
@@ -187,7 +490,13 @@ impl Rewrite {
                                     out_func_idx += 1;
                                 }
                             }
-                            ty => anyhow::bail!("import type {:?} not supported", ty),
+                            ty => {
+                                out_imports.import(
+                                    import.module,
+                                    import.name,
+                                    import_entity_type(ty)?,
+                                );
+                            }
                         }
                     }
 
@@ -198,8 +507,13 @@ impl Rewrite {
                 // Globals section: add two mut i64 globals for {read,write}.global.{0,1}.
                 Payload::GlobalSection(globals) => {
                     let mut out_globals = wasm_encoder::GlobalSection::new();
-                    for global in globals.into_iter() {
+                    for (i, global) in globals.into_iter().enumerate() {
                         let global = global?;
+                        if let Some(remap) = &self.global_remap {
+                            if !remap.contains_key(&(num_imported_globals + i as u32)) {
+                                continue;
+                            }
+                        }
                         let val_type = match global.ty.content_type {
                             wasmparser::ValType::I32 => wasm_encoder::ValType::I32,
                             wasmparser::ValType::I64 => wasm_encoder::ValType::I64,
@@ -255,15 +569,21 @@ impl Rewrite {
                 }
 
                 Payload::FunctionSection(funcs) => {
+                    let mut out_funcs = wasm_encoder::FunctionSection::new();
                     for fty in funcs {
-                        let _fty = fty?;
+                        let fty = fty?;
                         let orig_idx = orig_func_idx;
                         orig_func_idx += 1;
+                        if !self.func_live(orig_idx) {
+                            continue;
+                        }
                         let out_idx = out_func_idx;
                         out_func_idx += 1;
                         self.func_remap.insert(orig_idx, FuncRemap::Index(out_idx));
+                        out_funcs.function(fty);
                     }
-                    true
+                    out.section(&out_funcs);
+                    false
                 }
 
                 Payload::ExportSection(exports) => {
@@ -279,9 +599,10 @@ impl Rewrite {
                                 (export.index, wasm_encoder::ExportKind::Memory)
                             }
                             ExternalKind::Table => (export.index, wasm_encoder::ExportKind::Table),
-                            ExternalKind::Global => {
-                                (export.index, wasm_encoder::ExportKind::Global)
-                            }
+                            ExternalKind::Global => (
+                                self.remap_global(export.index),
+                                wasm_encoder::ExportKind::Global,
+                            ),
                             ExternalKind::Tag => (export.index, wasm_encoder::ExportKind::Tag),
                         };
                         out_exports.export(export.name, kind, index);
@@ -291,21 +612,33 @@ impl Rewrite {
                     false
                 }
 
+                Payload::StartSection { func, .. } => {
+                    out.section(&wasm_encoder::StartSection {
+                        function_index: self.func_remap.get(&func).unwrap().as_index()?,
+                    });
+                    false
+                }
+
+                Payload::ElementSection(_) if !self.elements_live() => false,
+
                 Payload::ElementSection(elements) => {
                     let mut out_elements = wasm_encoder::ElementSection::new();
+                    // A side module's slots in the shared table,
+                    // relative to `__table_base`.
+                    let mut side_slots: Vec<Option<u32>> = vec![];
                     for element in elements {
                         let element = element?;
 
-                        let mut out_items = vec![];
+                        let mut funcs = vec![];
                         let mut out_exprs = vec![];
                         let out_items = match element.items {
                             ElementItems::Functions(funcs) => {
                                 for f in funcs {
                                     let f = f?;
                                     let new = self.func_remap.get(&f).unwrap().as_index()?;
-                                    out_items.push(new);
+                                    funcs.push(new);
                                 }
-                                wasm_encoder::Elements::Functions(&out_items[..])
+                                wasm_encoder::Elements::Functions(&funcs[..])
                             }
                             ElementItems::Expressions(ty, exprs) => {
                                 let sig = ty.type_index().unwrap().as_module_index().unwrap();
@@ -330,6 +663,7 @@ impl Rewrite {
                                         })
                                         .next()
                                         .unwrap();
+                                    funcs.push(func);
                                     out_exprs.push(wasm_encoder::ConstExpr::ref_func(func));
                                 }
                                 wasm_encoder::Elements::Expressions(
@@ -347,44 +681,155 @@ impl Rewrite {
                                 table_index,
                                 offset_expr,
                             } => {
-                                let mut const_expr = None;
-                                for op in offset_expr.get_operators_reader() {
-                                    let op = op?;
-                                    match op {
-                                        wasmparser::Operator::I32Const { value } => {
-                                            if const_expr.is_none() {
-                                                const_expr =
-                                                    Some(wasm_encoder::ConstExpr::i32_const(value));
-                                            } else {
-                                                anyhow::bail!("More than one i32const in active table elem expr");
-                                            }
-                                        }
-                                        wasmparser::Operator::End => {}
-                                        _ => anyhow::bail!(
-                                            "unexpected operator in active table elem expr"
-                                        ),
+                                let offset = const_offset(&offset_expr)?;
+                                if self.table_base_global.is_some() && table_index.unwrap_or(0) == 0
+                                {
+                                    let offset = offset as usize;
+                                    let end = offset + funcs.len();
+                                    if side_slots.len() < end {
+                                        side_slots.resize(end, None);
+                                    }
+                                    for (slot, &func) in
+                                        side_slots[offset..end].iter_mut().zip(funcs.iter())
+                                    {
+                                        *slot = Some(func);
                                     }
+                                    continue;
                                 }
-                                out_elements.active(table_index, &const_expr.unwrap(), out_items);
+                                out_elements.active(
+                                    table_index,
+                                    &wasm_encoder::ConstExpr::i32_const(offset),
+                                    out_items,
+                                );
                             }
                             _ => panic!("Unsupported element kind for element section"),
                         }
                     }
 
+                    // Place all of a side module's slots, including
+                    // any for specialized functions, in one segment
+                    // at its base in the shared table.
+                    if let Some(base) = self.table_base_global.filter(|_| !side_slots.is_empty()) {
+                        let offset = wasm_encoder::ConstExpr::global_get(base);
+                        if side_slots.iter().all(|slot| slot.is_some()) {
+                            let funcs = side_slots
+                                .iter()
+                                .map(|slot| slot.unwrap())
+                                .collect::<Vec<_>>();
+                            out_elements.active(
+                                None,
+                                &offset,
+                                wasm_encoder::Elements::Functions(&funcs[..]),
+                            );
+                        } else {
+                            let exprs = side_slots
+                                .iter()
+                                .map(|slot| match slot {
+                                    Some(func) => wasm_encoder::ConstExpr::ref_func(*func),
+                                    None => wasm_encoder::ConstExpr::ref_null(
+                                        wasm_encoder::HeapType::Func,
+                                    ),
+                                })
+                                .collect::<Vec<_>>();
+                            out_elements.active(
+                                None,
+                                &offset,
+                                wasm_encoder::Elements::Expressions(
+                                    wasm_encoder::RefType::FUNCREF,
+                                    &exprs[..],
+                                ),
+                            );
+                        }
+                    }
+
                     out.section(&out_elements);
                     false
                 }
 
+                // A side module's data goes at its base in the shared
+                // memory.
+                Payload::DataSection(data) if self.memory_base_global.is_some() => {
+                    let base = self.memory_base_global.unwrap();
+                    let mut out_data = wasm_encoder::DataSection::new();
+                    for segment in data {
+                        let segment = segment?;
+                        match segment.kind {
+                            wasmparser::DataKind::Active {
+                                memory_index,
+                                offset_expr,
+                            } => {
+                                let offset = const_offset(&offset_expr)?;
+                                out_data.active(
+                                    memory_index,
+                                    &based_offset_expr(base, offset),
+                                    segment.data.iter().cloned(),
+                                );
+                            }
+                            wasmparser::DataKind::Passive => {
+                                out_data.passive(segment.data.iter().cloned());
+                            }
+                        }
+                    }
+                    out.section(&out_data);
+                    false
+                }
+
+                Payload::DataCountSection { .. } if !self.input_segments.is_empty() => false,
+
+                Payload::DataSection(data) if !self.input_segments.is_empty() => {
+                    let mut out_data = wasm_encoder::DataSection::new();
+                    for segment in &self.input_segments {
+                        out_data.passive(segment.iter().cloned());
+                    }
+                    for segment in data {
+                        let segment = segment?;
+                        match segment.kind {
+                            wasmparser::DataKind::Active {
+                                memory_index,
+                                offset_expr,
+                            } => {
+                                let offset = const_offset(&offset_expr)?;
+                                out_data.active(
+                                    memory_index,
+                                    &wasm_encoder::ConstExpr::i32_const(offset),
+                                    segment.data.iter().cloned(),
+                                );
+                            }
+                            wasmparser::DataKind::Passive => {
+                                out_data.passive(segment.data.iter().cloned());
+                            }
+                        }
+                    }
+                    out.section(&out_data);
+                    data_written = true;
+                    false
+                }
+
                 Payload::CodeSectionStart { count, .. } => {
+                    if !self.input_segments.is_empty() {
+                        out.section(&wasm_encoder::DataCountSection {
+                            count: num_data_segments,
+                        });
+                    }
                     num_funcs = count;
                     false
                 }
 
                 Payload::CodeSectionEntry(code) => {
+                    let orig_idx = num_imported_funcs + num_funcs_seen;
+                    num_funcs_seen += 1;
+                    if !self.func_live(orig_idx) {
+                        if num_funcs_seen == num_funcs {
+                            self.finish_code_section(&mut out, &out_code_section);
+                        }
+                        continue;
+                    }
+
                     // Rewrite calls, ref.funcs, and return_calls
                     // according to `func_remap`. (The latter two
                     // become errors; intrinsics can only be used for
-                    // ordinary calls.)
+                    // ordinary calls.) Also rewrite global indices if
+                    // any globals were removed.
 
                     let mut locals = vec![];
                     for local in code.get_locals_reader()? {
@@ -396,6 +841,9 @@ impl Rewrite {
                     let mut func = wasm_encoder::Function::new(locals);
                     let mut last_offset = code.range().start;
                     let mut skip = true;
+                    let func_hints = self.branch_hints.remove(&orig_idx);
+                    let mut branches = 0;
+                    let mut hints = vec![];
                     for entry in code.get_operators_reader()?.into_iter_with_offsets() {
                         let (op, offset) = entry?;
                         if !skip {
@@ -403,6 +851,19 @@ impl Rewrite {
                         }
                         last_offset = offset;
 
+                        // Everything before this op has been emitted,
+                        // so its offset in the output is the body's
+                        // length so far.
+                        if crate::branch_hints::is_branch(&op) {
+                            if let Some(&likely) = func_hints
+                                .as_ref()
+                                .and_then(|func_hints| func_hints.hints.get(&branches))
+                            {
+                                hints.push((func.byte_len() as u32, likely));
+                            }
+                            branches += 1;
+                        }
+
                         skip = match op {
                             wasmparser::Operator::Call { function_index } => {
                                 match self.func_remap.get(&function_index).unwrap() {
@@ -443,6 +904,27 @@ impl Rewrite {
                             {
                                 anyhow::bail!("ref.func taken of intrinsic");
                             }
+                            wasmparser::Operator::RefFunc { function_index } => {
+                                let i = self.func_remap.get(&function_index).unwrap().as_index()?;
+                                func.instruction(&wasm_encoder::Instruction::RefFunc(i));
+                                true
+                            }
+                            wasmparser::Operator::GlobalGet { global_index }
+                                if self.global_remap.is_some() =>
+                            {
+                                func.instruction(&wasm_encoder::Instruction::GlobalGet(
+                                    self.remap_global(global_index),
+                                ));
+                                true
+                            }
+                            wasmparser::Operator::GlobalSet { global_index }
+                                if self.global_remap.is_some() =>
+                            {
+                                func.instruction(&wasm_encoder::Instruction::GlobalSet(
+                                    self.remap_global(global_index),
+                                ));
+                                true
+                            }
                             _ => false,
                         };
                     }
@@ -451,10 +933,25 @@ impl Rewrite {
                     }
 
                     out_code_section.function(&func);
-                    num_funcs_emitted += 1;
 
-                    if num_funcs_emitted == num_funcs {
-                        out.section(&out_code_section);
+                    match func_hints {
+                        Some(func_hints) if func_hints.branches != branches => {
+                            log::warn!(
+                                "Function {} has {} branches, but hints for {}; dropping its branch hints",
+                                orig_idx,
+                                branches,
+                                func_hints.branches
+                            );
+                        }
+                        _ if !hints.is_empty() => {
+                            let out_idx = self.func_remap.get(&orig_idx).unwrap().as_index()?;
+                            self.out_branch_hints.insert(out_idx, hints);
+                        }
+                        _ => {}
+                    }
+
+                    if num_funcs_seen == num_funcs {
+                        self.finish_code_section(&mut out, &out_code_section);
                     }
 
                     false
@@ -485,6 +982,8 @@ impl Rewrite {
                     out.section(&names);
                     false
                 }
+                // Others are copied from the input module; see
+                // `custom_sections`.
                 Payload::CustomSection(..) => false,
                 _ => true,
             };
@@ -498,11 +997,79 @@ impl Rewrite {
             }
         }
 
+        // Emit the cold-function hints, if any: a count followed by
+        // function indices, all as LEB128 u32s.
+        if !self.cold_funcs.is_empty() {
+            use wasm_encoder::Encode;
+            let mut data = vec![];
+            self.cold_funcs.len().encode(&mut data);
+            for func in &self.cold_funcs {
+                self.func_remap
+                    .get(func)
+                    .unwrap()
+                    .as_index()?
+                    .encode(&mut data);
+            }
+            out.section(&wasm_encoder::CustomSection {
+                name: "weval.cold".into(),
+                data: data.into(),
+            });
+        }
+
+        // Emit the function-index relocations, if any: a count
+        // followed by (address, table index, function index) triples,
+        // all as LEB128 u32s.
+        if !self.relocs.is_empty() {
+            use wasm_encoder::Encode;
+            let mut data = vec![];
+            self.relocs.len().encode(&mut data);
+            for reloc in &self.relocs {
+                reloc.addr.encode(&mut data);
+                reloc.table_index.encode(&mut data);
+                self.func_remap
+                    .get(&reloc.func)
+                    .unwrap()
+                    .as_index()?
+                    .encode(&mut data);
+            }
+            out.section(&wasm_encoder::CustomSection {
+                name: "weval.relocs".into(),
+                data: data.into(),
+            });
+        }
+
         Ok(out.finish())
     }
 }
 
-pub fn filter(module: &[u8]) -> anyhow::Result<Vec<u8>> {
-    let rewrite = Rewrite::default();
+/// Filter the final module. `dylink_table_size` is the minimum table
+/// size to record for a side module, if it has a `dylink.0` section.
+pub fn filter(
+    module: &[u8],
+    cold_funcs: &[u32],
+    relocs: &[FuncIndexReloc],
+    branch_hints: &BTreeMap<u32, FuncHints>,
+    input_segments: &[Vec<u8>],
+    gc: bool,
+    dylink_table_size: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let live = if gc {
+        Some(crate::gc::compute_live(module)?)
+    } else {
+        None
+    };
+    let rewrite = Rewrite {
+        cold_funcs: cold_funcs
+            .iter()
+            .cloned()
+            .filter(|f| live.as_ref().map(|l| l.funcs.contains(f)).unwrap_or(true))
+            .collect(),
+        relocs: relocs.to_vec(),
+        branch_hints: branch_hints.clone(),
+        input_segments: input_segments.to_vec(),
+        live,
+        dylink_table_size,
+        ..Rewrite::default()
+    };
     rewrite.process(module)
 }
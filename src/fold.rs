@@ -0,0 +1,1081 @@
+//! Constant folding of individual operators over concrete values.
+//!
+//! These are the pure parts of the abstract transfer functions: given
+//! fully-known inputs, compute the operator's result exactly as a
+//! Wasm engine would. Returns `None` when the operator is not handled
+//! or when evaluating it would trap (e.g., division by zero), in
+//! which case the caller leaves the operator to runtime.
+
+use crate::value::WasmVal;
+use waffle::{Func, Operator};
+
+fn bool_val(b: bool) -> WasmVal {
+    WasmVal::I32(if b { 1 } else { 0 })
+}
+
+/// The bits of lane `lane`, of `width` bits, of a vector, in the low
+/// bits of the result.
+fn lane_bits(k: u128, lane: u8, width: u32) -> u128 {
+    k >> (lane as u32 * width)
+}
+
+pub fn unary(op: Operator, x: WasmVal) -> Option<WasmVal> {
+    match (op, x) {
+        (Operator::I32Eqz, WasmVal::I32(k)) => Some(bool_val(k == 0)),
+        (Operator::I64Eqz, WasmVal::I64(k)) => Some(bool_val(k == 0)),
+        (Operator::I32Extend8S, WasmVal::I32(k)) => Some(WasmVal::I32(k as i8 as i32 as u32)),
+        (Operator::I32Extend16S, WasmVal::I32(k)) => Some(WasmVal::I32(k as i16 as i32 as u32)),
+        (Operator::I64Extend8S, WasmVal::I64(k)) => Some(WasmVal::I64(k as i8 as i64 as u64)),
+        (Operator::I64Extend16S, WasmVal::I64(k)) => Some(WasmVal::I64(k as i16 as i64 as u64)),
+        (Operator::I64Extend32S, WasmVal::I64(k)) => Some(WasmVal::I64(k as i32 as i64 as u64)),
+        (Operator::I32Clz, WasmVal::I32(k)) => Some(WasmVal::I32(k.leading_zeros())),
+        (Operator::I64Clz, WasmVal::I64(k)) => Some(WasmVal::I64(k.leading_zeros() as u64)),
+        (Operator::I32Ctz, WasmVal::I32(k)) => Some(WasmVal::I32(k.trailing_zeros())),
+        (Operator::I64Ctz, WasmVal::I64(k)) => Some(WasmVal::I64(k.trailing_zeros() as u64)),
+        (Operator::I32Popcnt, WasmVal::I32(k)) => Some(WasmVal::I32(k.count_ones())),
+        (Operator::I64Popcnt, WasmVal::I64(k)) => Some(WasmVal::I64(k.count_ones() as u64)),
+        (Operator::I32WrapI64, WasmVal::I64(k)) => Some(WasmVal::I32(k as u32)),
+        (Operator::I64ExtendI32S, WasmVal::I32(k)) => Some(WasmVal::I64(k as i32 as i64 as u64)),
+        (Operator::I64ExtendI32U, WasmVal::I32(k)) => Some(WasmVal::I64(k as u64)),
+
+        // Lane extraction. Lane 0 is the least significant (first in
+        // memory).
+        (Operator::I8x16ExtractLaneS { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::I32(lane_bits(k, lane, 8) as i8 as i32 as u32))
+        }
+        (Operator::I8x16ExtractLaneU { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::I32(lane_bits(k, lane, 8) as u8 as u32))
+        }
+        (Operator::I16x8ExtractLaneS { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::I32(lane_bits(k, lane, 16) as i16 as i32 as u32))
+        }
+        (Operator::I16x8ExtractLaneU { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::I32(lane_bits(k, lane, 16) as u16 as u32))
+        }
+        (Operator::I32x4ExtractLane { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::I32(lane_bits(k, lane, 32) as u32))
+        }
+        (Operator::I64x2ExtractLane { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::I64(lane_bits(k, lane, 64) as u64))
+        }
+        (Operator::F32x4ExtractLane { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::F32(lane_bits(k, lane, 32) as u32))
+        }
+        (Operator::F64x2ExtractLane { lane }, WasmVal::V128(k)) => {
+            Some(WasmVal::F64(lane_bits(k, lane, 64) as u64))
+        }
+
+        // TODO: FP and other SIMD.
+        _ => None,
+    }
+}
+
+pub fn binary(op: Operator, x: WasmVal, y: WasmVal) -> Option<WasmVal> {
+    match (op, x, y) {
+        // 32-bit comparisons.
+        (Operator::I32Eq, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(bool_val(k1 == k2)),
+        (Operator::I32Ne, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(bool_val(k1 != k2)),
+        (Operator::I32LtS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(bool_val((k1 as i32) < (k2 as i32)))
+        }
+        (Operator::I32LtU, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(bool_val(k1 < k2)),
+        (Operator::I32GtS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(bool_val((k1 as i32) > (k2 as i32)))
+        }
+        (Operator::I32GtU, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(bool_val(k1 > k2)),
+        (Operator::I32LeS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(bool_val((k1 as i32) <= (k2 as i32)))
+        }
+        (Operator::I32LeU, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(bool_val(k1 <= k2)),
+        (Operator::I32GeS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(bool_val((k1 as i32) >= (k2 as i32)))
+        }
+        (Operator::I32GeU, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(bool_val(k1 >= k2)),
+
+        // 64-bit comparisons. (Note that these produce an i32.)
+        (Operator::I64Eq, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(bool_val(k1 == k2)),
+        (Operator::I64Ne, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(bool_val(k1 != k2)),
+        (Operator::I64LtS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(bool_val((k1 as i64) < (k2 as i64)))
+        }
+        (Operator::I64LtU, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(bool_val(k1 < k2)),
+        (Operator::I64GtS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(bool_val((k1 as i64) > (k2 as i64)))
+        }
+        (Operator::I64GtU, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(bool_val(k1 > k2)),
+        (Operator::I64LeS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(bool_val((k1 as i64) <= (k2 as i64)))
+        }
+        (Operator::I64LeU, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(bool_val(k1 <= k2)),
+        (Operator::I64GeS, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(bool_val((k1 as i64) >= (k2 as i64)))
+        }
+        (Operator::I64GeU, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(bool_val(k1 >= k2)),
+
+        // 32-bit integer arithmetic.
+        (Operator::I32Add, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32(k1.wrapping_add(k2)))
+        }
+        (Operator::I32Sub, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32(k1.wrapping_sub(k2)))
+        }
+        (Operator::I32Mul, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32(k1.wrapping_mul(k2)))
+        }
+        (Operator::I32DivU, WasmVal::I32(k1), WasmVal::I32(k2)) if k2 != 0 => {
+            Some(WasmVal::I32(k1.wrapping_div(k2)))
+        }
+        (Operator::I32DivS, WasmVal::I32(k1), WasmVal::I32(k2))
+            if k2 != 0 && (k1 != 0x8000_0000 || k2 != 0xffff_ffff) =>
+        {
+            Some(WasmVal::I32((k1 as i32).wrapping_div(k2 as i32) as u32))
+        }
+        (Operator::I32RemU, WasmVal::I32(k1), WasmVal::I32(k2)) if k2 != 0 => {
+            Some(WasmVal::I32(k1.wrapping_rem(k2)))
+        }
+        // Note: `i32.rem_s` of INT_MIN by -1 is defined (it is zero);
+        // only the division traps.
+        (Operator::I32RemS, WasmVal::I32(k1), WasmVal::I32(k2)) if k2 != 0 => {
+            Some(WasmVal::I32((k1 as i32).wrapping_rem(k2 as i32) as u32))
+        }
+        (Operator::I32And, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(WasmVal::I32(k1 & k2)),
+        (Operator::I32Or, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(WasmVal::I32(k1 | k2)),
+        (Operator::I32Xor, WasmVal::I32(k1), WasmVal::I32(k2)) => Some(WasmVal::I32(k1 ^ k2)),
+        (Operator::I32Shl, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32(k1.wrapping_shl(k2 & 0x1f)))
+        }
+        (Operator::I32ShrU, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32(k1.wrapping_shr(k2 & 0x1f)))
+        }
+        (Operator::I32ShrS, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32((k1 as i32).wrapping_shr(k2 & 0x1f) as u32))
+        }
+        (Operator::I32Rotl, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32(k1.rotate_left(k2 & 0x1f)))
+        }
+        (Operator::I32Rotr, WasmVal::I32(k1), WasmVal::I32(k2)) => {
+            Some(WasmVal::I32(k1.rotate_right(k2 & 0x1f)))
+        }
+
+        // 64-bit integer arithmetic.
+        (Operator::I64Add, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(WasmVal::I64(k1.wrapping_add(k2)))
+        }
+        (Operator::I64Sub, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(WasmVal::I64(k1.wrapping_sub(k2)))
+        }
+        (Operator::I64Mul, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(WasmVal::I64(k1.wrapping_mul(k2)))
+        }
+        (Operator::I64DivU, WasmVal::I64(k1), WasmVal::I64(k2)) if k2 != 0 => {
+            Some(WasmVal::I64(k1.wrapping_div(k2)))
+        }
+        (Operator::I64DivS, WasmVal::I64(k1), WasmVal::I64(k2))
+            if k2 != 0 && (k1 != 0x8000_0000_0000_0000 || k2 != 0xffff_ffff_ffff_ffff) =>
+        {
+            Some(WasmVal::I64((k1 as i64).wrapping_div(k2 as i64) as u64))
+        }
+        (Operator::I64RemU, WasmVal::I64(k1), WasmVal::I64(k2)) if k2 != 0 => {
+            Some(WasmVal::I64(k1.wrapping_rem(k2)))
+        }
+        (Operator::I64RemS, WasmVal::I64(k1), WasmVal::I64(k2)) if k2 != 0 => {
+            Some(WasmVal::I64((k1 as i64).wrapping_rem(k2 as i64) as u64))
+        }
+        (Operator::I64And, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(WasmVal::I64(k1 & k2)),
+        (Operator::I64Or, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(WasmVal::I64(k1 | k2)),
+        (Operator::I64Xor, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(WasmVal::I64(k1 ^ k2)),
+        (Operator::I64Shl, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(WasmVal::I64(k1.wrapping_shl((k2 & 0x3f) as u32)))
+        }
+        (Operator::I64ShrU, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(WasmVal::I64(k1.wrapping_shr((k2 & 0x3f) as u32)))
+        }
+        (Operator::I64ShrS, WasmVal::I64(k1), WasmVal::I64(k2)) => Some(WasmVal::I64(
+            (k1 as i64).wrapping_shr((k2 & 0x3f) as u32) as u64,
+        )),
+        (Operator::I64Rotl, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(WasmVal::I64(k1.rotate_left((k2 & 0x3f) as u32)))
+        }
+        (Operator::I64Rotr, WasmVal::I64(k1), WasmVal::I64(k2)) => {
+            Some(WasmVal::I64(k1.rotate_right((k2 & 0x3f) as u32)))
+        }
+
+        // TODO: FP and SIMD ops.
+        _ => None,
+    }
+}
+
+/// Simplification of a binary operator with exactly one constant
+/// operand (a "strength reduction").
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Partial {
+    /// The result is this constant.
+    Const(WasmVal),
+    /// The result is the given (non-constant) operand, unchanged.
+    Operand(usize),
+    /// The result is `op(x, k)`, where `x` is the non-constant
+    /// operand and `op` is cheaper than the original.
+    Reduced(Operator, WasmVal),
+    /// The result is `op(y, x)`: the operands swapped to put the
+    /// constant on the right, with a mirrored comparison if needed.
+    Swapped(Operator),
+}
+
+/// The kind of an integer binary operator, independent of width.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Add,
+    Sub,
+    Mul,
+    DivS,
+    DivU,
+    RemU,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrS,
+    ShrU,
+    Rotl,
+    Rotr,
+    Eq,
+    Ne,
+    LtS,
+    LtU,
+    GtS,
+    GtU,
+    LeS,
+    LeU,
+    GeS,
+    GeU,
+}
+
+/// The kind and width of an integer binary operator.
+pub fn classify(op: Operator) -> Option<(Kind, u32)> {
+    Some(match op {
+        Operator::I32Add => (Kind::Add, 32),
+        Operator::I32Sub => (Kind::Sub, 32),
+        Operator::I32Mul => (Kind::Mul, 32),
+        Operator::I32DivS => (Kind::DivS, 32),
+        Operator::I32DivU => (Kind::DivU, 32),
+        Operator::I32RemU => (Kind::RemU, 32),
+        Operator::I32And => (Kind::And, 32),
+        Operator::I32Or => (Kind::Or, 32),
+        Operator::I32Xor => (Kind::Xor, 32),
+        Operator::I32Shl => (Kind::Shl, 32),
+        Operator::I32ShrS => (Kind::ShrS, 32),
+        Operator::I32ShrU => (Kind::ShrU, 32),
+        Operator::I32Rotl => (Kind::Rotl, 32),
+        Operator::I32Rotr => (Kind::Rotr, 32),
+        Operator::I32Eq => (Kind::Eq, 32),
+        Operator::I32Ne => (Kind::Ne, 32),
+        Operator::I32LtS => (Kind::LtS, 32),
+        Operator::I32LtU => (Kind::LtU, 32),
+        Operator::I32GtS => (Kind::GtS, 32),
+        Operator::I32GtU => (Kind::GtU, 32),
+        Operator::I32LeS => (Kind::LeS, 32),
+        Operator::I32LeU => (Kind::LeU, 32),
+        Operator::I32GeS => (Kind::GeS, 32),
+        Operator::I32GeU => (Kind::GeU, 32),
+        Operator::I64Add => (Kind::Add, 64),
+        Operator::I64Sub => (Kind::Sub, 64),
+        Operator::I64Mul => (Kind::Mul, 64),
+        Operator::I64DivS => (Kind::DivS, 64),
+        Operator::I64DivU => (Kind::DivU, 64),
+        Operator::I64RemU => (Kind::RemU, 64),
+        Operator::I64And => (Kind::And, 64),
+        Operator::I64Or => (Kind::Or, 64),
+        Operator::I64Xor => (Kind::Xor, 64),
+        Operator::I64Shl => (Kind::Shl, 64),
+        Operator::I64ShrS => (Kind::ShrS, 64),
+        Operator::I64ShrU => (Kind::ShrU, 64),
+        Operator::I64Rotl => (Kind::Rotl, 64),
+        Operator::I64Rotr => (Kind::Rotr, 64),
+        Operator::I64Eq => (Kind::Eq, 64),
+        Operator::I64Ne => (Kind::Ne, 64),
+        Operator::I64LtS => (Kind::LtS, 64),
+        Operator::I64LtU => (Kind::LtU, 64),
+        Operator::I64GtS => (Kind::GtS, 64),
+        Operator::I64GtU => (Kind::GtU, 64),
+        Operator::I64LeS => (Kind::LeS, 64),
+        Operator::I64LeU => (Kind::LeU, 64),
+        Operator::I64GeS => (Kind::GeS, 64),
+        Operator::I64GeU => (Kind::GeU, 64),
+        _ => return None,
+    })
+}
+
+fn operator(kind: Kind, width: u32) -> Operator {
+    match (kind, width) {
+        (Kind::And, 32) => Operator::I32And,
+        (Kind::Shl, 32) => Operator::I32Shl,
+        (Kind::ShrU, 32) => Operator::I32ShrU,
+        (Kind::Eq, 32) => Operator::I32Eq,
+        (Kind::Ne, 32) => Operator::I32Ne,
+        (Kind::LtS, 32) => Operator::I32LtS,
+        (Kind::LtU, 32) => Operator::I32LtU,
+        (Kind::GtS, 32) => Operator::I32GtS,
+        (Kind::GtU, 32) => Operator::I32GtU,
+        (Kind::LeS, 32) => Operator::I32LeS,
+        (Kind::LeU, 32) => Operator::I32LeU,
+        (Kind::GeS, 32) => Operator::I32GeS,
+        (Kind::GeU, 32) => Operator::I32GeU,
+        (Kind::And, 64) => Operator::I64And,
+        (Kind::Shl, 64) => Operator::I64Shl,
+        (Kind::ShrU, 64) => Operator::I64ShrU,
+        (Kind::Eq, 64) => Operator::I64Eq,
+        (Kind::Ne, 64) => Operator::I64Ne,
+        (Kind::LtS, 64) => Operator::I64LtS,
+        (Kind::LtU, 64) => Operator::I64LtU,
+        (Kind::GtS, 64) => Operator::I64GtS,
+        (Kind::GtU, 64) => Operator::I64GtU,
+        (Kind::LeS, 64) => Operator::I64LeS,
+        (Kind::LeU, 64) => Operator::I64LeU,
+        (Kind::GeS, 64) => Operator::I64GeS,
+        (Kind::GeU, 64) => Operator::I64GeU,
+        _ => unreachable!(),
+    }
+}
+
+/// The comparison with operands swapped: `x < y` iff `y > x`.
+fn mirror(kind: Kind) -> Kind {
+    match kind {
+        Kind::LtS => Kind::GtS,
+        Kind::LtU => Kind::GtU,
+        Kind::GtS => Kind::LtS,
+        Kind::GtU => Kind::LtU,
+        Kind::LeS => Kind::GeS,
+        Kind::LeU => Kind::GeU,
+        Kind::GeS => Kind::LeS,
+        Kind::GeU => Kind::LeU,
+        k => k,
+    }
+}
+
+fn is_comparison(kind: Kind) -> bool {
+    matches!(
+        kind,
+        Kind::Eq
+            | Kind::Ne
+            | Kind::LtS
+            | Kind::LtU
+            | Kind::GtS
+            | Kind::GtU
+            | Kind::LeS
+            | Kind::LeU
+            | Kind::GeS
+            | Kind::GeU
+    )
+}
+
+/// Simplify `op(x, y)` where exactly one of `x` and `y` is known.
+/// Returns `None` if no simplification applies.
+pub fn binary_partial(op: Operator, x: Option<WasmVal>, y: Option<WasmVal>) -> Option<Partial> {
+    let (kind, width) = classify(op)?;
+    let (c, k) = match (x, y) {
+        (Some(k), None) => (0, k),
+        (None, Some(k)) => (1, k),
+        _ => return None,
+    };
+    let k = match (k, width) {
+        (WasmVal::I32(k), 32) => k as u64,
+        (WasmVal::I64(k), 64) => k,
+        _ => return None,
+    };
+    let other = 1 - c;
+    let ones = if width == 32 {
+        u32::MAX as u64
+    } else {
+        u64::MAX
+    };
+    let smin = 1u64 << (width - 1);
+    let smax = smin - 1;
+    let val = |v: u64| {
+        if width == 32 {
+            WasmVal::I32(v as u32)
+        } else {
+            WasmVal::I64(v)
+        }
+    };
+
+    // Commutative operators: the constant may be on either side.
+    match kind {
+        Kind::Add | Kind::Xor if k == 0 => return Some(Partial::Operand(other)),
+        Kind::Mul if k == 0 => return Some(Partial::Const(val(0))),
+        Kind::Mul if k == 1 => return Some(Partial::Operand(other)),
+        Kind::Mul if k.is_power_of_two() => {
+            return Some(Partial::Reduced(
+                operator(Kind::Shl, width),
+                val(k.trailing_zeros() as u64),
+            ))
+        }
+        Kind::And if k == 0 => return Some(Partial::Const(val(0))),
+        Kind::And if k == ones => return Some(Partial::Operand(other)),
+        Kind::Or if k == 0 => return Some(Partial::Operand(other)),
+        Kind::Or if k == ones => return Some(Partial::Const(val(ones))),
+        _ => {}
+    }
+
+    if c == 0 {
+        return match kind {
+            // Shifting or rotating zero (or sign-shifting all-ones)
+            // yields the same constant.
+            Kind::Shl | Kind::ShrS | Kind::ShrU | Kind::Rotl | Kind::Rotr if k == 0 => {
+                Some(Partial::Const(val(0)))
+            }
+            Kind::ShrS | Kind::Rotl | Kind::Rotr if k == ones => Some(Partial::Const(val(ones))),
+            // Canonicalize comparisons to put the constant on the
+            // right, then see if that form folds.
+            kind if is_comparison(kind) => {
+                let mirrored = operator(mirror(kind), width);
+                match binary_partial(mirrored, y, x) {
+                    Some(Partial::Const(v)) => Some(Partial::Const(v)),
+                    _ => Some(Partial::Swapped(mirrored)),
+                }
+            }
+            _ => None,
+        };
+    }
+
+    match kind {
+        Kind::Sub if k == 0 => Some(Partial::Operand(0)),
+        Kind::Shl | Kind::ShrS | Kind::ShrU | Kind::Rotl | Kind::Rotr
+            if k & (width as u64 - 1) == 0 =>
+        {
+            Some(Partial::Operand(0))
+        }
+        Kind::DivU | Kind::DivS if k == 1 => Some(Partial::Operand(0)),
+        Kind::DivU if k.is_power_of_two() => Some(Partial::Reduced(
+            operator(Kind::ShrU, width),
+            val(k.trailing_zeros() as u64),
+        )),
+        Kind::RemU if k == 1 => Some(Partial::Const(val(0))),
+        Kind::RemU if k.is_power_of_two() => {
+            Some(Partial::Reduced(operator(Kind::And, width), val(k - 1)))
+        }
+        Kind::LtU if k == 0 => Some(Partial::Const(bool_val(false))),
+        Kind::GeU if k == 0 => Some(Partial::Const(bool_val(true))),
+        Kind::GtU if k == ones => Some(Partial::Const(bool_val(false))),
+        Kind::LeU if k == ones => Some(Partial::Const(bool_val(true))),
+        Kind::LtS if k == smin => Some(Partial::Const(bool_val(false))),
+        Kind::GeS if k == smin => Some(Partial::Const(bool_val(true))),
+        Kind::GtS if k == smax => Some(Partial::Const(bool_val(false))),
+        Kind::LeS if k == smax => Some(Partial::Const(bool_val(true))),
+        _ => None,
+    }
+}
+
+/// A reference whose referent is known at specialization time: the
+/// result of a `ref.func` or `ref.null`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KnownRef {
+    Func(Func),
+    Null,
+}
+
+/// Fold an operator on a known reference.
+pub fn unary_ref(op: Operator, x: KnownRef) -> Option<WasmVal> {
+    match op {
+        Operator::RefIsNull => Some(bool_val(x == KnownRef::Null)),
+        _ => None,
+    }
+}
+
+/// The operand, 0 or 1, that a `select` on the given condition picks.
+pub fn select_operand(cond: WasmVal) -> usize {
+    if cond.is_truthy() {
+        0
+    } else {
+        1
+    }
+}
+
+/// The effective address of a memory access at `addr` with the
+/// memarg's static `offset`. Per the spec the sum does not wrap: an
+/// access whose effective address exceeds the 32-bit address space
+/// traps, so returns `None` for the caller to leave the access to
+/// runtime. (The memarg's alignment is only a hint and does not
+/// affect the result.)
+pub fn effective_addr(addr: u32, offset: u64) -> Option<u32> {
+    u64::from(addr)
+        .checked_add(offset)
+        .and_then(|addr| u32::try_from(addr).ok())
+}
+
+/// Differential tests: run each folded operator on random and
+/// edge-case inputs both through the folding functions above and
+/// through Wasmtime executing a one-operator module, and compare.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{
+        CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction,
+        TypeSection, ValType,
+    };
+
+    const ITERS: usize = 500;
+
+    const EDGES_32: &[u32] = &[
+        0,
+        1,
+        2,
+        7,
+        8,
+        15,
+        16,
+        31,
+        32,
+        33,
+        0x7f,
+        0x80,
+        0xff,
+        0x7fff,
+        0x8000,
+        0xffff,
+        0x7fff_ffff,
+        0x8000_0000,
+        0xffff_fffe,
+        0xffff_ffff,
+    ];
+    const EDGES_64: &[u64] = &[
+        0,
+        1,
+        2,
+        31,
+        32,
+        63,
+        64,
+        65,
+        0x7f,
+        0x80,
+        0x7fff_ffff,
+        0x8000_0000,
+        0xffff_ffff,
+        0x1_0000_0000,
+        0x7fff_ffff_ffff_ffff,
+        0x8000_0000_0000_0000,
+        0xffff_ffff_ffff_fffe,
+        0xffff_ffff_ffff_ffff,
+    ];
+
+    /// Small deterministic xorshift PRNG so failures are reproducible.
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn val(&mut self, ty: ValType) -> WasmVal {
+            let pick_edge = self.next() % 4 == 0;
+            match ty {
+                ValType::I32 if pick_edge => {
+                    WasmVal::I32(EDGES_32[(self.next() as usize) % EDGES_32.len()])
+                }
+                ValType::I32 => WasmVal::I32(self.next() as u32),
+                ValType::I64 if pick_edge => {
+                    WasmVal::I64(EDGES_64[(self.next() as usize) % EDGES_64.len()])
+                }
+                ValType::I64 => WasmVal::I64(self.next()),
+                ValType::V128 => WasmVal::V128(((self.next() as u128) << 64) | self.next() as u128),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn to_wasmtime(v: WasmVal) -> wasmtime::Val {
+        match v {
+            WasmVal::I32(k) => wasmtime::Val::I32(k as i32),
+            WasmVal::I64(k) => wasmtime::Val::I64(k as i64),
+            WasmVal::V128(k) => wasmtime::Val::V128(k.into()),
+            _ => unreachable!(),
+        }
+    }
+
+    fn from_wasmtime(v: &wasmtime::Val) -> WasmVal {
+        match v {
+            wasmtime::Val::I32(k) => WasmVal::I32(*k as u32),
+            wasmtime::Val::I64(k) => WasmVal::I64(*k as u64),
+            wasmtime::Val::F32(bits) => WasmVal::F32(*bits),
+            wasmtime::Val::F64(bits) => WasmVal::F64(*bits),
+            _ => unreachable!(),
+        }
+    }
+
+    /// A reference implementation of one operator: a compiled module
+    /// exporting `f`, which applies the operator to its params.
+    struct Reference {
+        store: wasmtime::Store<()>,
+        func: wasmtime::Func,
+    }
+
+    impl Reference {
+        fn new(
+            engine: &wasmtime::Engine,
+            params: &[ValType],
+            result: ValType,
+            inst: Instruction,
+        ) -> Reference {
+            let mut types = TypeSection::new();
+            types.function(params.iter().cloned(), [result]);
+            let mut funcs = FunctionSection::new();
+            funcs.function(0);
+            let mut exports = ExportSection::new();
+            exports.export("f", ExportKind::Func, 0);
+            let mut body = Function::new(vec![]);
+            for i in 0..params.len() {
+                body.instruction(&Instruction::LocalGet(i as u32));
+            }
+            body.instruction(&inst);
+            body.instruction(&Instruction::End);
+            let mut code = CodeSection::new();
+            code.function(&body);
+
+            let mut module = wasm_encoder::Module::new();
+            module.section(&types);
+            module.section(&funcs);
+            module.section(&exports);
+            module.section(&code);
+            let bytes = module.finish();
+
+            let module = wasmtime::Module::new(engine, &bytes[..]).unwrap();
+            let mut store = wasmtime::Store::new(engine, ());
+            let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+            let func = instance.get_func(&mut store, "f").unwrap();
+            Reference { store, func }
+        }
+
+        /// Returns `None` if the operator traps.
+        fn run(&mut self, args: &[WasmVal]) -> Option<WasmVal> {
+            let args = args.iter().cloned().map(to_wasmtime).collect::<Vec<_>>();
+            let mut results = [wasmtime::Val::I32(0)];
+            match self.func.call(&mut self.store, &args[..], &mut results[..]) {
+                Ok(()) => Some(from_wasmtime(&results[0])),
+                Err(_) => None,
+            }
+        }
+    }
+
+    fn check(
+        engine: &wasmtime::Engine,
+        rng: &mut Rng,
+        op: Operator,
+        inst: Instruction,
+        params: &[ValType],
+        result: ValType,
+    ) {
+        let mut reference = Reference::new(engine, params, result, inst);
+        for _ in 0..ITERS {
+            let args = params.iter().map(|&ty| rng.val(ty)).collect::<Vec<_>>();
+            let expected = reference.run(&args[..]);
+            let actual = match &args[..] {
+                &[x] => unary(op, x),
+                &[x, y] => binary(op, x, y),
+                _ => unreachable!(),
+            };
+            match (expected, actual) {
+                (Some(expected), Some(actual)) => assert_eq!(
+                    expected, actual,
+                    "{:?} on {:?}: reference gives {:?}, folding gives {:?}",
+                    op, args, expected, actual
+                ),
+                // A trap must never be folded to a value.
+                (None, Some(actual)) => panic!(
+                    "{:?} on {:?}: reference traps, folding gives {:?}",
+                    op, args, actual
+                ),
+                // Declining to fold is always sound.
+                (_, None) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn unary_ops_match_reference() {
+        use ValType::{I32, I64};
+        let engine = wasmtime::Engine::default();
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let ops: &[(Operator, Instruction, ValType, ValType)] = &[
+            (Operator::I32Eqz, Instruction::I32Eqz, I32, I32),
+            (Operator::I64Eqz, Instruction::I64Eqz, I64, I32),
+            (Operator::I32Extend8S, Instruction::I32Extend8S, I32, I32),
+            (Operator::I32Extend16S, Instruction::I32Extend16S, I32, I32),
+            (Operator::I64Extend8S, Instruction::I64Extend8S, I64, I64),
+            (Operator::I64Extend16S, Instruction::I64Extend16S, I64, I64),
+            (Operator::I64Extend32S, Instruction::I64Extend32S, I64, I64),
+            (Operator::I32Clz, Instruction::I32Clz, I32, I32),
+            (Operator::I64Clz, Instruction::I64Clz, I64, I64),
+            (Operator::I32Ctz, Instruction::I32Ctz, I32, I32),
+            (Operator::I64Ctz, Instruction::I64Ctz, I64, I64),
+            (Operator::I32Popcnt, Instruction::I32Popcnt, I32, I32),
+            (Operator::I64Popcnt, Instruction::I64Popcnt, I64, I64),
+            (Operator::I32WrapI64, Instruction::I32WrapI64, I64, I32),
+            (Operator::I64ExtendI32S, Instruction::I64ExtendI32S, I32, I64),
+            (Operator::I64ExtendI32U, Instruction::I64ExtendI32U, I32, I64),
+        ];
+        for (op, inst, param, result) in ops {
+            check(&engine, &mut rng, *op, inst.clone(), &[*param], *result);
+        }
+    }
+
+    #[test]
+    fn lane_extracts_match_reference() {
+        use ValType::{F32, F64, I32, I64, V128};
+        let engine = wasmtime::Engine::default();
+        let mut rng = Rng(0x8cb9_2ba7_2f3d_8dd7);
+        let mut ops: Vec<(Operator, Instruction, ValType)> = vec![];
+        for lane in 0..16 {
+            ops.push((
+                Operator::I8x16ExtractLaneS { lane },
+                Instruction::I8x16ExtractLaneS(lane),
+                I32,
+            ));
+            ops.push((
+                Operator::I8x16ExtractLaneU { lane },
+                Instruction::I8x16ExtractLaneU(lane),
+                I32,
+            ));
+        }
+        for lane in 0..8 {
+            ops.push((
+                Operator::I16x8ExtractLaneS { lane },
+                Instruction::I16x8ExtractLaneS(lane),
+                I32,
+            ));
+            ops.push((
+                Operator::I16x8ExtractLaneU { lane },
+                Instruction::I16x8ExtractLaneU(lane),
+                I32,
+            ));
+        }
+        for lane in 0..4 {
+            ops.push((
+                Operator::I32x4ExtractLane { lane },
+                Instruction::I32x4ExtractLane(lane),
+                I32,
+            ));
+            ops.push((
+                Operator::F32x4ExtractLane { lane },
+                Instruction::F32x4ExtractLane(lane),
+                F32,
+            ));
+        }
+        for lane in 0..2 {
+            ops.push((
+                Operator::I64x2ExtractLane { lane },
+                Instruction::I64x2ExtractLane(lane),
+                I64,
+            ));
+            ops.push((
+                Operator::F64x2ExtractLane { lane },
+                Instruction::F64x2ExtractLane(lane),
+                F64,
+            ));
+        }
+        for (op, inst, result) in ops {
+            check(&engine, &mut rng, op, inst, &[V128], result);
+        }
+    }
+
+    #[test]
+    fn binary_ops_match_reference() {
+        use ValType::{I32, I64};
+        let engine = wasmtime::Engine::default();
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let ops: &[(Operator, Instruction, ValType, ValType)] = &[
+            (Operator::I32Eq, Instruction::I32Eq, I32, I32),
+            (Operator::I32Ne, Instruction::I32Ne, I32, I32),
+            (Operator::I32LtS, Instruction::I32LtS, I32, I32),
+            (Operator::I32LtU, Instruction::I32LtU, I32, I32),
+            (Operator::I32GtS, Instruction::I32GtS, I32, I32),
+            (Operator::I32GtU, Instruction::I32GtU, I32, I32),
+            (Operator::I32LeS, Instruction::I32LeS, I32, I32),
+            (Operator::I32LeU, Instruction::I32LeU, I32, I32),
+            (Operator::I32GeS, Instruction::I32GeS, I32, I32),
+            (Operator::I32GeU, Instruction::I32GeU, I32, I32),
+            (Operator::I64Eq, Instruction::I64Eq, I64, I32),
+            (Operator::I64Ne, Instruction::I64Ne, I64, I32),
+            (Operator::I64LtS, Instruction::I64LtS, I64, I32),
+            (Operator::I64LtU, Instruction::I64LtU, I64, I32),
+            (Operator::I64GtS, Instruction::I64GtS, I64, I32),
+            (Operator::I64GtU, Instruction::I64GtU, I64, I32),
+            (Operator::I64LeS, Instruction::I64LeS, I64, I32),
+            (Operator::I64LeU, Instruction::I64LeU, I64, I32),
+            (Operator::I64GeS, Instruction::I64GeS, I64, I32),
+            (Operator::I64GeU, Instruction::I64GeU, I64, I32),
+            (Operator::I32Add, Instruction::I32Add, I32, I32),
+            (Operator::I32Sub, Instruction::I32Sub, I32, I32),
+            (Operator::I32Mul, Instruction::I32Mul, I32, I32),
+            (Operator::I32DivU, Instruction::I32DivU, I32, I32),
+            (Operator::I32DivS, Instruction::I32DivS, I32, I32),
+            (Operator::I32RemU, Instruction::I32RemU, I32, I32),
+            (Operator::I32RemS, Instruction::I32RemS, I32, I32),
+            (Operator::I32And, Instruction::I32And, I32, I32),
+            (Operator::I32Or, Instruction::I32Or, I32, I32),
+            (Operator::I32Xor, Instruction::I32Xor, I32, I32),
+            (Operator::I32Shl, Instruction::I32Shl, I32, I32),
+            (Operator::I32ShrU, Instruction::I32ShrU, I32, I32),
+            (Operator::I32ShrS, Instruction::I32ShrS, I32, I32),
+            (Operator::I32Rotl, Instruction::I32Rotl, I32, I32),
+            (Operator::I32Rotr, Instruction::I32Rotr, I32, I32),
+            (Operator::I64Add, Instruction::I64Add, I64, I64),
+            (Operator::I64Sub, Instruction::I64Sub, I64, I64),
+            (Operator::I64Mul, Instruction::I64Mul, I64, I64),
+            (Operator::I64DivU, Instruction::I64DivU, I64, I64),
+            (Operator::I64DivS, Instruction::I64DivS, I64, I64),
+            (Operator::I64RemU, Instruction::I64RemU, I64, I64),
+            (Operator::I64RemS, Instruction::I64RemS, I64, I64),
+            (Operator::I64And, Instruction::I64And, I64, I64),
+            (Operator::I64Or, Instruction::I64Or, I64, I64),
+            (Operator::I64Xor, Instruction::I64Xor, I64, I64),
+            (Operator::I64Shl, Instruction::I64Shl, I64, I64),
+            (Operator::I64ShrU, Instruction::I64ShrU, I64, I64),
+            (Operator::I64ShrS, Instruction::I64ShrS, I64, I64),
+            (Operator::I64Rotl, Instruction::I64Rotl, I64, I64),
+            (Operator::I64Rotr, Instruction::I64Rotr, I64, I64),
+        ];
+        for (op, inst, param, result) in ops {
+            check(
+                &engine,
+                &mut rng,
+                *op,
+                inst.clone(),
+                &[*param, *param],
+                *result,
+            );
+        }
+    }
+
+    /// Each strength reduction must agree with the full fold for
+    /// every value of the unknown operand.
+    #[test]
+    fn partial_folds_match_full_folds() {
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        let ops = [
+            Operator::I32Add,
+            Operator::I32Sub,
+            Operator::I32Mul,
+            Operator::I32DivS,
+            Operator::I32DivU,
+            Operator::I32RemU,
+            Operator::I32And,
+            Operator::I32Or,
+            Operator::I32Xor,
+            Operator::I32Shl,
+            Operator::I32ShrS,
+            Operator::I32ShrU,
+            Operator::I32Rotl,
+            Operator::I32Rotr,
+            Operator::I32Eq,
+            Operator::I32Ne,
+            Operator::I32LtS,
+            Operator::I32LtU,
+            Operator::I32GtS,
+            Operator::I32GtU,
+            Operator::I32LeS,
+            Operator::I32LeU,
+            Operator::I32GeS,
+            Operator::I32GeU,
+            Operator::I64Add,
+            Operator::I64Sub,
+            Operator::I64Mul,
+            Operator::I64DivS,
+            Operator::I64DivU,
+            Operator::I64RemU,
+            Operator::I64And,
+            Operator::I64Or,
+            Operator::I64Xor,
+            Operator::I64Shl,
+            Operator::I64ShrS,
+            Operator::I64ShrU,
+            Operator::I64Rotl,
+            Operator::I64Rotr,
+            Operator::I64Eq,
+            Operator::I64Ne,
+            Operator::I64LtS,
+            Operator::I64LtU,
+            Operator::I64GtS,
+            Operator::I64GtU,
+            Operator::I64LeS,
+            Operator::I64LeU,
+            Operator::I64GeS,
+            Operator::I64GeU,
+        ];
+        for op in ops {
+            let (_, width) = classify(op).unwrap();
+            let ty = if width == 32 {
+                ValType::I32
+            } else {
+                ValType::I64
+            };
+            let edges: Vec<WasmVal> = if width == 32 {
+                EDGES_32.iter().map(|&k| WasmVal::I32(k)).collect()
+            } else {
+                EDGES_64.iter().map(|&k| WasmVal::I64(k)).collect()
+            };
+            for k in edges {
+                for c in 0..2 {
+                    let partial = if c == 0 {
+                        binary_partial(op, Some(k), None)
+                    } else {
+                        binary_partial(op, None, Some(k))
+                    };
+                    let Some(partial) = partial else {
+                        continue;
+                    };
+                    for _ in 0..ITERS {
+                        let x = rng.val(ty);
+                        let (a, b) = if c == 0 { (k, x) } else { (x, k) };
+                        let expected = binary(op, a, b);
+                        let actual = match partial {
+                            Partial::Const(v) => Some(v),
+                            Partial::Operand(_) => Some(x),
+                            Partial::Reduced(op, k) => binary(op, x, k),
+                            Partial::Swapped(op) => binary(op, b, a),
+                        };
+                        assert_eq!(
+                            actual, expected,
+                            "{:?}({:?}, {:?}) via {:?}",
+                            op, a, b, partial
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ref_folds_match_reference() {
+        use waffle::entity::EntityRef;
+        let mut config = wasmtime::Config::new();
+        config.wasm_function_references(true);
+        let engine = wasmtime::Engine::new(&config).unwrap();
+        // A typed `select` on two function references, feeding a
+        // `call_ref`; the callees return 0 and 1, which tells us the
+        // operand picked.
+        let bytes = wat::parse_str(
+            r#"
+            (module
+              (type $t (func (result i32)))
+              (func $a (type $t) (i32.const 0))
+              (func $b (type $t) (i32.const 1))
+              (elem declare func $a $b)
+              (func (export "select") (param i32) (result i32)
+                (call_ref $t
+                  (select (result (ref $t))
+                    (ref.func $a) (ref.func $b) (local.get 0))))
+              (func (export "is_null_func") (result i32)
+                (ref.is_null (ref.func $a)))
+              (func (export "is_null_null") (result i32)
+                (ref.is_null (ref.null func))))
+            "#,
+        )
+        .unwrap();
+        let module = wasmtime::Module::new(&engine, &bytes).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+
+        let select = instance
+            .get_typed_func::<i32, i32>(&mut store, "select")
+            .unwrap();
+        let mut rng = Rng(0xd1b5_4a32_d192_ed03);
+        let conds = EDGES_32
+            .iter()
+            .cloned()
+            .chain((0..ITERS).map(|_| rng.next() as u32));
+        for cond in conds {
+            let expected = select.call(&mut store, cond as i32).unwrap() as usize;
+            let actual = select_operand(WasmVal::I32(cond));
+            assert_eq!(expected, actual, "select on {:#x}", cond);
+        }
+
+        for (name, x) in [
+            ("is_null_func", KnownRef::Func(Func::new(0))),
+            ("is_null_null", KnownRef::Null),
+        ] {
+            let f = instance
+                .get_typed_func::<(), i32>(&mut store, name)
+                .unwrap();
+            let expected = WasmVal::I32(f.call(&mut store, ()).unwrap() as u32);
+            assert_eq!(
+                Some(expected),
+                unary_ref(Operator::RefIsNull, x),
+                "ref.is_null of {:?}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn effective_addrs_match_reference() {
+        let engine = wasmtime::Engine::default();
+        let data = (0..16u8).map(|i| i * 17 + 1).collect::<Vec<_>>();
+        let data_str = data
+            .iter()
+            .map(|b| format!("\\{:02x}", b))
+            .collect::<String>();
+        let image_u32 = |addr: u32| {
+            let addr = addr as usize;
+            let mut bytes = [0u8; 4];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = data.get(addr + i).cloned().unwrap_or(0);
+            }
+            u32::from_le_bytes(bytes)
+        };
+        let offsets = [
+            0,
+            4,
+            0xffff,
+            0x1_0000,
+            0x7fff_ffff,
+            0xffff_fff0,
+            0xffff_fffc,
+            0xffff_ffff,
+        ];
+        for offset in offsets {
+            let bytes = wat::parse_str(format!(
+                r#"
+                (module
+                  (memory 1)
+                  (data (i32.const 0) "{}")
+                  (func (export "load") (param i32) (result i32)
+                    (i32.load offset={} (local.get 0))))
+                "#,
+                data_str, offset
+            ))
+            .unwrap();
+            let module = wasmtime::Module::new(&engine, &bytes).unwrap();
+            let mut store = wasmtime::Store::new(&engine, ());
+            let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+            let load = instance
+                .get_typed_func::<i32, i32>(&mut store, "load")
+                .unwrap();
+            for &addr in EDGES_32 {
+                let expected = load.call(&mut store, addr as i32).ok();
+                let actual = effective_addr(addr, u64::from(offset));
+                match (expected, actual) {
+                    (Some(expected), Some(actual)) => assert_eq!(
+                        expected as u32,
+                        image_u32(actual),
+                        "load at {:#x} offset {:#x}: effective address {:#x}",
+                        addr,
+                        offset,
+                        actual
+                    ),
+                    (Some(expected), None) => panic!(
+                        "load at {:#x} offset {:#x}: reference gives {:#x}, no effective address",
+                        addr, offset, expected
+                    ),
+                    // The image is one page, so in-range addresses past
+                    // it trap in the reference too.
+                    (None, _) => {}
+                }
+                // Wrapping must never be mistaken for a valid access.
+                if u64::from(addr) + u64::from(offset) > u64::from(u32::MAX) {
+                    assert_eq!(actual, None, "load at {:#x} offset {:#x}", addr, offset);
+                }
+            }
+        }
+    }
+}
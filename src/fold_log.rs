@@ -0,0 +1,65 @@
+//! Fold log: a record of every fold the evaluator performed, for
+//! auditing the semantic transformations in a specialized module and
+//! for reproducing them.
+//!
+//! A fold is an operator in the generic function that was replaced,
+//! in some context, by a constant or by another value, or elided.
+//! Blocks may be evaluated several times before their states reach a
+//! fixpoint; only the folds of the final evaluation are logged.
+
+use crate::directive::DirectiveId;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// One folded operator in one context.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fold {
+    /// The context stack, outermost first.
+    pub context: Vec<String>,
+    /// The operator's value in the generic function.
+    pub generic_value: usize,
+    /// The operator.
+    pub op: String,
+    /// Abstract values of its inputs.
+    pub inputs: Vec<String>,
+    /// What it was folded to.
+    pub result: String,
+}
+
+/// The folds of one specialized function.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FoldLog {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// Name of the generic function.
+    pub func_name: String,
+    pub folds: Vec<Fold>,
+}
+
+/// Renders fold logs one fold per line, as tab-separated fields:
+/// directive ID, user ID, generic function, context stack (joined
+/// with `;`), generic value, operator, inputs (joined with `,`), and
+/// result.
+pub fn render(logs: &[FoldLog]) -> String {
+    let mut out = String::new();
+    for log in logs {
+        for fold in &log.folds {
+            writeln!(
+                &mut out,
+                "{}\t{}\t{}\t{}\tv{}\t{}\t{}\t{}",
+                log.id,
+                log.user_id,
+                log.func_name,
+                fold.context.join(";"),
+                fold.generic_value,
+                fold.op,
+                fold.inputs.join(","),
+                fold.result
+            )
+            .unwrap();
+        }
+    }
+    out
+}
@@ -0,0 +1,708 @@
+//! Macro-op fusion: data-driven peephole rewriting of specialized code.
+//!
+//! With constant bytecode, specialization folds an interpreter's
+//! opcode loads and dispatch branches away, but some of its common
+//! sequences survive in a longer form than needed: the stack pointer
+//! adjusted by a push and again by the following pop, a PC advanced
+//! in several steps, or a decoded condition tested with `i32.eqz`
+//! before a branch. This pass rewrites such sequences to their
+//! minimal equivalents.
+//!
+//! The rewrites are given as data, one per line, as
+//!
+//! ```text
+//! # name: pattern => replacement
+//! sub-add-i32: (i32.sub (i32.add $x (i32.const $a)) (i32.const $b)) => (i32.add $x (i32.sub (i32.const $a) (i32.const $b)))
+//! ```
+//!
+//! with blank lines and `#` comments ignored. Patterns are trees of
+//! operators (in Wasm text names), `$name` variables, which match any
+//! value (the same value, if repeated), and `(i32.const K)` or
+//! `(i64.const K)` constants, where `K` is a literal or a variable
+//! matching any constant. A replacement builds a tree of the same
+//! kinds from the pattern's variables; any operator in it whose
+//! operands are all constants is folded.
+//!
+//! A pattern matches at any instruction whose operand trees it
+//! matches, provided that each operator inside the tree (but the
+//! root) has no other use, so that the replaced tree dies. Only
+//! integer operators that cannot trap are supported, so that a
+//! rewrite never removes a trap, and patterns are type-checked, and
+//! must have a replacement with no more operators than the pattern,
+//! when parsed. Memory accesses (e.g. a push stored to the operand
+//! stack and then loaded back) are left to `store_forward`.
+//!
+//! `DEFAULT_PATTERNS` covers the sequences above; embedders and the
+//! `--fusion-patterns` option can add more.
+
+use crate::collections::HashMap;
+use crate::value::WasmVal;
+use waffle::{pool::ListRef, Block, FunctionBody, Operator, Type, Value, ValueDef};
+
+/// The built-in patterns.
+pub const DEFAULT_PATTERNS: &str = "\
+# Constant adjustments of the stack pointer or PC, one after another.
+add-add-i32: (i32.add (i32.add $x (i32.const $a)) (i32.const $b)) => (i32.add $x (i32.add (i32.const $a) (i32.const $b)))
+sub-add-i32: (i32.sub (i32.add $x (i32.const $a)) (i32.const $b)) => (i32.add $x (i32.sub (i32.const $a) (i32.const $b)))
+add-sub-i32: (i32.add (i32.sub $x (i32.const $a)) (i32.const $b)) => (i32.add $x (i32.sub (i32.const $b) (i32.const $a)))
+sub-sub-i32: (i32.sub (i32.sub $x (i32.const $a)) (i32.const $b)) => (i32.sub $x (i32.add (i32.const $a) (i32.const $b)))
+add-zero-i32: (i32.add $x (i32.const 0)) => $x
+sub-zero-i32: (i32.sub $x (i32.const 0)) => $x
+add-add-i64: (i64.add (i64.add $x (i64.const $a)) (i64.const $b)) => (i64.add $x (i64.add (i64.const $a) (i64.const $b)))
+sub-add-i64: (i64.sub (i64.add $x (i64.const $a)) (i64.const $b)) => (i64.add $x (i64.sub (i64.const $a) (i64.const $b)))
+add-sub-i64: (i64.add (i64.sub $x (i64.const $a)) (i64.const $b)) => (i64.add $x (i64.sub (i64.const $b) (i64.const $a)))
+sub-sub-i64: (i64.sub (i64.sub $x (i64.const $a)) (i64.const $b)) => (i64.sub $x (i64.add (i64.const $a) (i64.const $b)))
+add-zero-i64: (i64.add $x (i64.const 0)) => $x
+sub-zero-i64: (i64.sub $x (i64.const 0)) => $x
+
+# Inverted branch conditions.
+eqz-eq-i32: (i32.eqz (i32.eq $a $b)) => (i32.ne $a $b)
+eqz-ne-i32: (i32.eqz (i32.ne $a $b)) => (i32.eq $a $b)
+eqz-lt_s-i32: (i32.eqz (i32.lt_s $a $b)) => (i32.ge_s $a $b)
+eqz-lt_u-i32: (i32.eqz (i32.lt_u $a $b)) => (i32.ge_u $a $b)
+eqz-gt_s-i32: (i32.eqz (i32.gt_s $a $b)) => (i32.le_s $a $b)
+eqz-gt_u-i32: (i32.eqz (i32.gt_u $a $b)) => (i32.le_u $a $b)
+eqz-le_s-i32: (i32.eqz (i32.le_s $a $b)) => (i32.gt_s $a $b)
+eqz-le_u-i32: (i32.eqz (i32.le_u $a $b)) => (i32.gt_u $a $b)
+eqz-ge_s-i32: (i32.eqz (i32.ge_s $a $b)) => (i32.lt_s $a $b)
+eqz-ge_u-i32: (i32.eqz (i32.ge_u $a $b)) => (i32.lt_u $a $b)
+eqz-eq-i64: (i32.eqz (i64.eq $a $b)) => (i64.ne $a $b)
+eqz-ne-i64: (i32.eqz (i64.ne $a $b)) => (i64.eq $a $b)
+eqz-lt_s-i64: (i32.eqz (i64.lt_s $a $b)) => (i64.ge_s $a $b)
+eqz-lt_u-i64: (i32.eqz (i64.lt_u $a $b)) => (i64.ge_u $a $b)
+eqz-gt_s-i64: (i32.eqz (i64.gt_s $a $b)) => (i64.le_s $a $b)
+eqz-gt_u-i64: (i32.eqz (i64.gt_u $a $b)) => (i64.le_u $a $b)
+eqz-le_s-i64: (i32.eqz (i64.le_s $a $b)) => (i64.gt_s $a $b)
+eqz-le_u-i64: (i32.eqz (i64.le_u $a $b)) => (i64.gt_u $a $b)
+eqz-ge_s-i64: (i32.eqz (i64.ge_s $a $b)) => (i64.lt_s $a $b)
+eqz-ge_u-i64: (i32.eqz (i64.ge_u $a $b)) => (i64.lt_u $a $b)
+eqz-eqz: (i32.eqz (i32.eqz (i32.eqz $x))) => (i32.eqz $x)
+ne-zero-eq-i32: (i32.ne (i32.eq $a $b) (i32.const 0)) => (i32.eq $a $b)
+ne-zero-ne-i32: (i32.ne (i32.ne $a $b) (i32.const 0)) => (i32.ne $a $b)
+";
+
+macro_rules! operators {
+    ($($name:literal => $op:ident: [$($param:ident),*] -> $result:ident,)*) => {
+        /// The operator of the given text name, with its parameter and
+        /// result types, if supported.
+        fn operator(name: &str) -> Option<(Operator, &'static [Type], Type)> {
+            match name {
+                $($name => Some((Operator::$op, &[$(Type::$param),*], Type::$result)),)*
+                _ => None,
+            }
+        }
+
+        fn operator_name(op: Operator) -> &'static str {
+            match op {
+                $(Operator::$op => $name,)*
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+operators! {
+    "i32.add" => I32Add: [I32, I32] -> I32,
+    "i32.sub" => I32Sub: [I32, I32] -> I32,
+    "i32.mul" => I32Mul: [I32, I32] -> I32,
+    "i32.and" => I32And: [I32, I32] -> I32,
+    "i32.or" => I32Or: [I32, I32] -> I32,
+    "i32.xor" => I32Xor: [I32, I32] -> I32,
+    "i32.shl" => I32Shl: [I32, I32] -> I32,
+    "i32.shr_s" => I32ShrS: [I32, I32] -> I32,
+    "i32.shr_u" => I32ShrU: [I32, I32] -> I32,
+    "i32.rotl" => I32Rotl: [I32, I32] -> I32,
+    "i32.rotr" => I32Rotr: [I32, I32] -> I32,
+    "i32.eq" => I32Eq: [I32, I32] -> I32,
+    "i32.ne" => I32Ne: [I32, I32] -> I32,
+    "i32.lt_s" => I32LtS: [I32, I32] -> I32,
+    "i32.lt_u" => I32LtU: [I32, I32] -> I32,
+    "i32.gt_s" => I32GtS: [I32, I32] -> I32,
+    "i32.gt_u" => I32GtU: [I32, I32] -> I32,
+    "i32.le_s" => I32LeS: [I32, I32] -> I32,
+    "i32.le_u" => I32LeU: [I32, I32] -> I32,
+    "i32.ge_s" => I32GeS: [I32, I32] -> I32,
+    "i32.ge_u" => I32GeU: [I32, I32] -> I32,
+    "i32.eqz" => I32Eqz: [I32] -> I32,
+    "i32.clz" => I32Clz: [I32] -> I32,
+    "i32.ctz" => I32Ctz: [I32] -> I32,
+    "i32.popcnt" => I32Popcnt: [I32] -> I32,
+    "i32.extend8_s" => I32Extend8S: [I32] -> I32,
+    "i32.extend16_s" => I32Extend16S: [I32] -> I32,
+    "i32.wrap_i64" => I32WrapI64: [I64] -> I32,
+    "i64.add" => I64Add: [I64, I64] -> I64,
+    "i64.sub" => I64Sub: [I64, I64] -> I64,
+    "i64.mul" => I64Mul: [I64, I64] -> I64,
+    "i64.and" => I64And: [I64, I64] -> I64,
+    "i64.or" => I64Or: [I64, I64] -> I64,
+    "i64.xor" => I64Xor: [I64, I64] -> I64,
+    "i64.shl" => I64Shl: [I64, I64] -> I64,
+    "i64.shr_s" => I64ShrS: [I64, I64] -> I64,
+    "i64.shr_u" => I64ShrU: [I64, I64] -> I64,
+    "i64.rotl" => I64Rotl: [I64, I64] -> I64,
+    "i64.rotr" => I64Rotr: [I64, I64] -> I64,
+    "i64.eq" => I64Eq: [I64, I64] -> I32,
+    "i64.ne" => I64Ne: [I64, I64] -> I32,
+    "i64.lt_s" => I64LtS: [I64, I64] -> I32,
+    "i64.lt_u" => I64LtU: [I64, I64] -> I32,
+    "i64.gt_s" => I64GtS: [I64, I64] -> I32,
+    "i64.gt_u" => I64GtU: [I64, I64] -> I32,
+    "i64.le_s" => I64LeS: [I64, I64] -> I32,
+    "i64.le_u" => I64LeU: [I64, I64] -> I32,
+    "i64.ge_s" => I64GeS: [I64, I64] -> I32,
+    "i64.ge_u" => I64GeU: [I64, I64] -> I32,
+    "i64.eqz" => I64Eqz: [I64] -> I32,
+    "i64.clz" => I64Clz: [I64] -> I64,
+    "i64.ctz" => I64Ctz: [I64] -> I64,
+    "i64.popcnt" => I64Popcnt: [I64] -> I64,
+    "i64.extend8_s" => I64Extend8S: [I64] -> I64,
+    "i64.extend16_s" => I64Extend16S: [I64] -> I64,
+    "i64.extend32_s" => I64Extend32S: [I64] -> I64,
+    "i64.extend_i32_s" => I64ExtendI32S: [I32] -> I64,
+    "i64.extend_i32_u" => I64ExtendI32U: [I32] -> I64,
+}
+
+/// A constant in a pattern or replacement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Const {
+    Literal(u64),
+    Var(String),
+}
+
+/// A pattern or replacement tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Var(String),
+    Const(Type, Const),
+    Op(Operator, Vec<Expr>),
+}
+
+impl Expr {
+    fn ops(&self) -> usize {
+        match self {
+            Expr::Op(_, args) => 1 + args.iter().map(Expr::ops).sum::<usize>(),
+            _ => 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Var(name) => write!(f, "${}", name),
+            Expr::Const(ty, Const::Literal(k)) => write!(f, "({}.const {})", type_name(*ty), k),
+            Expr::Const(ty, Const::Var(name)) => {
+                write!(f, "({}.const ${})", type_name(*ty), name)
+            }
+            Expr::Op(op, args) => {
+                write!(f, "({}", operator_name(*op))?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A rewrite rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    pub name: String,
+    pub pattern: Expr,
+    pub replacement: Expr,
+}
+
+/// Parse patterns in the text form above.
+pub fn parse(text: &str) -> anyhow::Result<Vec<Pattern>> {
+    let mut patterns = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let pattern = line
+            .parse::<Pattern>()
+            .map_err(|e| anyhow::anyhow!("Fusion pattern on line {}: {}", i + 1, e))?;
+        patterns.push(pattern);
+    }
+    Ok(patterns)
+}
+
+/// The built-in patterns, parsed.
+pub fn default_patterns() -> Vec<Pattern> {
+    parse(DEFAULT_PATTERNS).unwrap()
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Pattern> {
+        let (name, rule) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expected <name>: <pattern> => <replacement>"))?;
+        let (pattern, replacement) = rule
+            .split_once("=>")
+            .ok_or_else(|| anyhow::anyhow!("Expected <pattern> => <replacement>"))?;
+        let pattern = parse_expr(pattern)?;
+        let replacement = parse_expr(replacement)?;
+        if let Expr::Var(_) = pattern {
+            anyhow::bail!("Pattern must be an operator");
+        }
+
+        let mut vars = HashMap::default();
+        let ty = check(&pattern, &mut vars, true)?;
+        let replacement_ty = check(&replacement, &mut vars, false)?;
+        if replacement_ty != ty {
+            anyhow::bail!(
+                "Replacement has type {} but pattern has type {}",
+                type_name(replacement_ty),
+                type_name(ty)
+            );
+        }
+        if replacement.ops() > pattern.ops() {
+            anyhow::bail!("Replacement has more operators than pattern");
+        }
+        Ok(Pattern {
+            name: name.trim().to_owned(),
+            pattern,
+            replacement,
+        })
+    }
+}
+
+fn tokenize(s: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, c) in s.char_indices() {
+        if c == '(' || c == ')' || c.is_whitespace() {
+            if let Some(start) = start.take() {
+                tokens.push(&s[start..i]);
+            }
+            if !c.is_whitespace() {
+                tokens.push(&s[i..i + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&s[start..]);
+    }
+    tokens
+}
+
+fn parse_expr(s: &str) -> anyhow::Result<Expr> {
+    let tokens = tokenize(s);
+    let mut pos = 0;
+    let expr = parse_tokens(&tokens, &mut pos)?;
+    if pos != tokens.len() {
+        anyhow::bail!("Trailing input after {}", expr);
+    }
+    Ok(expr)
+}
+
+fn var_name(token: &str) -> Option<&str> {
+    token.strip_prefix('$').filter(|name| !name.is_empty())
+}
+
+fn next<'a>(tokens: &[&'a str], pos: &mut usize) -> anyhow::Result<&'a str> {
+    let token = tokens
+        .get(*pos)
+        .ok_or_else(|| anyhow::anyhow!("Unexpected end of input"))?;
+    *pos += 1;
+    Ok(*token)
+}
+
+fn parse_tokens(tokens: &[&str], pos: &mut usize) -> anyhow::Result<Expr> {
+    let token = next(tokens, pos)?;
+    if let Some(name) = var_name(token) {
+        return Ok(Expr::Var(name.to_owned()));
+    }
+    if token != "(" {
+        anyhow::bail!("Unexpected {:?}", token);
+    }
+    let head = next(tokens, pos)?;
+    let expr = match head {
+        "i32.const" | "i64.const" => {
+            let ty = if head == "i32.const" {
+                Type::I32
+            } else {
+                Type::I64
+            };
+            let k = next(tokens, pos)?;
+            let k = match var_name(k) {
+                Some(name) => Const::Var(name.to_owned()),
+                None => Const::Literal(parse_literal(ty, k)?),
+            };
+            Expr::Const(ty, k)
+        }
+        _ => {
+            let (op, params, _) =
+                operator(head).ok_or_else(|| anyhow::anyhow!("Unsupported operator {}", head))?;
+            let mut args = vec![];
+            for _ in 0..params.len() {
+                args.push(parse_tokens(tokens, pos)?);
+            }
+            Expr::Op(op, args)
+        }
+    };
+    if next(tokens, pos)? != ")" {
+        anyhow::bail!("Expected ) after {}", expr);
+    }
+    Ok(expr)
+}
+
+/// Parse an integer literal of the given type, signed or unsigned,
+/// in decimal or (with `0x`) hex.
+fn parse_literal(ty: Type, s: &str) -> anyhow::Result<u64> {
+    let parsed = match (ty, s.strip_prefix("0x")) {
+        (Type::I32, Some(hex)) => u32::from_str_radix(hex, 16).ok().map(u64::from),
+        (_, Some(hex)) => u64::from_str_radix(hex, 16).ok(),
+        (Type::I32, None) => s
+            .parse::<u32>()
+            .ok()
+            .or_else(|| s.parse::<i32>().ok().map(|k| k as u32))
+            .map(u64::from),
+        (_, None) => s
+            .parse::<u64>()
+            .ok()
+            .or_else(|| s.parse::<i64>().ok().map(|k| k as u64)),
+    };
+    parsed.ok_or_else(|| anyhow::anyhow!("Bad {} literal {:?}", type_name(ty), s))
+}
+
+fn type_name(ty: Type) -> &'static str {
+    match ty {
+        Type::I32 => "i32",
+        Type::I64 => "i64",
+        _ => unreachable!(),
+    }
+}
+
+/// Bind, or check the binding of, a variable of the given type, as
+/// a value or a constant.
+fn check_var(
+    name: &str,
+    ty: Type,
+    is_const: bool,
+    vars: &mut HashMap<String, (Type, bool)>,
+    binding: bool,
+) -> anyhow::Result<Type> {
+    match vars.get(name) {
+        Some(&bound) if bound != (ty, is_const) => {
+            anyhow::bail!("${} used inconsistently", name)
+        }
+        Some(_) => Ok(ty),
+        None if binding => {
+            vars.insert(name.to_owned(), (ty, is_const));
+            Ok(ty)
+        }
+        None => anyhow::bail!("${} is not bound by the pattern", name),
+    }
+}
+
+/// Type-check a pattern (binding its variables' types) or a
+/// replacement (whose variables must be bound), returning its type.
+fn check(
+    expr: &Expr,
+    vars: &mut HashMap<String, (Type, bool)>,
+    binding: bool,
+) -> anyhow::Result<Type> {
+    match expr {
+        Expr::Const(ty, Const::Var(name)) => check_var(name, *ty, true, vars, binding),
+        Expr::Const(ty, Const::Literal(_)) => Ok(*ty),
+        // An operand variable's type is that of its operand, checked
+        // below; only a replacement can be a bare variable.
+        Expr::Var(name) => match vars.get(name) {
+            Some(&(ty, false)) => Ok(ty),
+            Some(_) => anyhow::bail!("${} used inconsistently", name),
+            None => anyhow::bail!("${} is not bound by the pattern", name),
+        },
+        Expr::Op(op, args) => {
+            let (_, params, result) = operator(operator_name(*op)).unwrap();
+            for (arg, &param) in args.iter().zip(params.iter()) {
+                let ty = match arg {
+                    Expr::Var(name) => check_var(name, param, false, vars, binding)?,
+                    _ => check(arg, vars, binding)?,
+                };
+                if ty != param {
+                    anyhow::bail!(
+                        "Operand {} of {} has type {}, expected {}",
+                        arg,
+                        operator_name(*op),
+                        type_name(ty),
+                        type_name(param)
+                    );
+                }
+            }
+            Ok(result)
+        }
+    }
+}
+
+/// A value bound to a pattern variable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Bound {
+    Value(Value),
+    Const(WasmVal),
+}
+
+/// The operator and (alias-resolved) arguments defining a value, if
+/// it is an operator.
+fn def(func: &FunctionBody, value: Value) -> Option<(Operator, Vec<Value>)> {
+    match &func.values[func.resolve_alias(value)] {
+        ValueDef::Operator(op, args, _) => Some((
+            *op,
+            func.arg_pool[*args]
+                .iter()
+                .map(|&arg| func.resolve_alias(arg))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+fn const_value(ty: Type, k: u64) -> WasmVal {
+    match ty {
+        Type::I32 => WasmVal::I32(k as u32),
+        _ => WasmVal::I64(k),
+    }
+}
+
+/// Number of uses of each value, by instructions and terminators.
+fn use_counts(func: &FunctionBody) -> HashMap<Value, usize> {
+    let mut counts: HashMap<Value, usize> = HashMap::default();
+    for (_, block) in func.blocks.entries() {
+        for &inst in &block.insts {
+            if let ValueDef::Operator(_, args, _) = &func.values[inst] {
+                for &arg in &func.arg_pool[*args] {
+                    *counts.entry(func.resolve_alias(arg)).or_default() += 1;
+                }
+            }
+        }
+        block.terminator.visit_uses(|arg| {
+            *counts.entry(func.resolve_alias(arg)).or_default() += 1;
+        });
+    }
+    counts
+}
+
+/// Whether `value` matches `expr`, binding its variables.
+fn matches(
+    func: &FunctionBody,
+    uses: &HashMap<Value, usize>,
+    expr: &Expr,
+    value: Value,
+    root: bool,
+    bindings: &mut HashMap<String, Bound>,
+) -> bool {
+    let value = func.resolve_alias(value);
+    let mut bind = |name: &str, bound: Bound| match bindings.get(name) {
+        Some(&prev) => prev == bound,
+        None => {
+            bindings.insert(name.to_owned(), bound);
+            true
+        }
+    };
+    match expr {
+        Expr::Var(name) => bind(name, Bound::Value(value)),
+        Expr::Const(ty, k) => {
+            let actual = match def(func, value) {
+                Some((Operator::I32Const { value }, _)) if *ty == Type::I32 => value as u64,
+                Some((Operator::I64Const { value }, _)) if *ty == Type::I64 => value,
+                _ => return false,
+            };
+            match k {
+                Const::Literal(k) => const_value(*ty, *k) == const_value(*ty, actual),
+                Const::Var(name) => bind(name, Bound::Const(const_value(*ty, actual))),
+            }
+        }
+        Expr::Op(op, args) => {
+            if !root && uses.get(&value).copied() != Some(1) {
+                return false;
+            }
+            match def(func, value) {
+                Some((actual, actual_args)) if actual == *op => args
+                    .iter()
+                    .zip(actual_args)
+                    .all(|(arg, value)| matches(func, uses, arg, value, false, bindings)),
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Build a replacement's operand, pushing any instructions it needs
+/// to `insts`; constant operators are folded.
+fn build(
+    func: &mut FunctionBody,
+    expr: &Expr,
+    bindings: &HashMap<String, Bound>,
+    insts: &mut Vec<Value>,
+) -> Option<Bound> {
+    match expr {
+        Expr::Var(name) => bindings.get(name).copied(),
+        Expr::Const(ty, Const::Literal(k)) => Some(Bound::Const(const_value(*ty, *k))),
+        Expr::Const(_, Const::Var(name)) => bindings.get(name).copied(),
+        Expr::Op(op, args) => {
+            let args = args
+                .iter()
+                .map(|arg| build(func, arg, bindings, insts))
+                .collect::<Option<Vec<_>>>()?;
+            let folded = match &args[..] {
+                &[Bound::Const(x)] => crate::fold::unary(*op, x),
+                &[Bound::Const(x), Bound::Const(y)] => crate::fold::binary(*op, x, y),
+                _ => None,
+            };
+            if let Some(k) = folded {
+                return Some(Bound::Const(k));
+            }
+            let (op, args, ty) = materialize(func, *op, &args, insts);
+            let tys = func.single_type_list(ty);
+            let value = func.add_value(ValueDef::Operator(op, args, tys));
+            insts.push(value);
+            Some(Bound::Value(value))
+        }
+    }
+}
+
+/// The operands of an operator as values, creating any constants.
+fn materialize(
+    func: &mut FunctionBody,
+    op: Operator,
+    args: &[Bound],
+    insts: &mut Vec<Value>,
+) -> (Operator, ListRef<Value>, Type) {
+    let args = args
+        .iter()
+        .map(|&arg| match arg {
+            Bound::Value(value) => value,
+            Bound::Const(k) => {
+                let def = const_def(func, k);
+                let value = func.add_value(def);
+                insts.push(value);
+                value
+            }
+        })
+        .collect::<Vec<_>>();
+    let (_, _, ty) = operator(operator_name(op)).unwrap();
+    (op, func.arg_pool.from_iter(args.into_iter()), ty)
+}
+
+fn const_def(func: &mut FunctionBody, k: WasmVal) -> ValueDef {
+    let (op, ty) = match k {
+        WasmVal::I32(value) => (Operator::I32Const { value }, Type::I32),
+        WasmVal::I64(value) => (Operator::I64Const { value }, Type::I64),
+        _ => unreachable!(),
+    };
+    ValueDef::Operator(op, ListRef::default(), func.single_type_list(ty))
+}
+
+/// Runs the pass with the given patterns, returning the number of
+/// instructions rewritten.
+pub fn run(func: &mut FunctionBody, patterns: &[Pattern]) -> usize {
+    if patterns.is_empty() {
+        return 0;
+    }
+    let uses = use_counts(func);
+    let mut rewritten = 0;
+    let mut counts: HashMap<&str, usize> = HashMap::default();
+    for block in func.blocks.iter().collect::<Vec<Block>>() {
+        let mut insts = vec![];
+        for inst in std::mem::take(&mut func.blocks[block].insts) {
+            for pattern in patterns {
+                let mut bindings = HashMap::default();
+                if !matches(func, &uses, &pattern.pattern, inst, true, &mut bindings) {
+                    continue;
+                }
+                // The root keeps its value number, and so its uses,
+                // whatever it becomes.
+                let def = match &pattern.replacement {
+                    Expr::Op(op, args) => {
+                        let args = args
+                            .iter()
+                            .map(|arg| build(func, arg, &bindings, &mut insts))
+                            .collect::<Option<Vec<_>>>();
+                        let folded = match args.as_deref() {
+                            Some(&[Bound::Const(x)]) => crate::fold::unary(*op, x),
+                            Some(&[Bound::Const(x), Bound::Const(y)]) => {
+                                crate::fold::binary(*op, x, y)
+                            }
+                            _ => None,
+                        };
+                        match (folded, args) {
+                            (Some(k), _) => const_def(func, k),
+                            (None, Some(args)) => {
+                                let (op, args, ty) = materialize(func, *op, &args, &mut insts);
+                                ValueDef::Operator(op, args, func.single_type_list(ty))
+                            }
+                            (None, None) => continue,
+                        }
+                    }
+                    replacement => match build(func, replacement, &bindings, &mut insts) {
+                        Some(Bound::Value(value)) => ValueDef::Alias(value),
+                        Some(Bound::Const(k)) => const_def(func, k),
+                        None => continue,
+                    },
+                };
+                log::trace!("fusion: {} at {}", pattern.name, inst);
+                func.values[inst] = def;
+                *counts.entry(pattern.name.as_str()).or_default() += 1;
+                rewritten += 1;
+                break;
+            }
+            if !matches!(func.values[inst], ValueDef::Alias(_)) {
+                insts.push(inst);
+            }
+        }
+        func.blocks[block].insts = insts;
+    }
+    if rewritten > 0 {
+        waffle::passes::resolve_aliases::run(func);
+    }
+    log::debug!("fusion: rewrote {} instructions: {:?}", rewritten, counts);
+    rewritten
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_patterns_parse() {
+        let patterns = default_patterns();
+        assert!(patterns.iter().any(|p| p.name == "sub-add-i32"));
+    }
+
+    #[test]
+    fn patterns_are_checked() {
+        let bad = [
+            // Unbound variable in the replacement.
+            "a: (i32.add $x (i32.const 0)) => $y",
+            // Type mismatch.
+            "b: (i32.eqz (i64.eq $a $b)) => $a",
+            // Larger replacement.
+            "c: (i32.add $x $y) => (i32.add (i32.add $x $y) (i32.const 0))",
+            // Trapping operators are unsupported.
+            "d: (i32.div_u $x (i32.const 1)) => $x",
+            // A variable as both a value and a constant.
+            "e: (i32.add $x (i32.const $x)) => $x",
+        ];
+        for rule in bad {
+            assert!(
+                rule.parse::<Pattern>().is_err(),
+                "{} should not parse",
+                rule
+            );
+        }
+        let rule = "f: (i64.sub (i64.add $x (i64.const -8)) (i64.const 0x10)) => (i64.add $x (i64.sub (i64.const -8) (i64.const 0x10)))";
+        let pattern = rule.parse::<Pattern>().unwrap();
+        assert_eq!(
+            pattern.pattern.to_string(),
+            format!(
+                "(i64.sub (i64.add $x (i64.const {})) (i64.const 16))",
+                -8i64 as u64
+            )
+        );
+    }
+}
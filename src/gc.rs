@@ -0,0 +1,169 @@
+//! Module-level reachability ("tree shaking") analysis.
+//!
+//! Computes the set of defined functions and globals reachable from
+//! the module's roots: exports, the start function, and (if the
+//! table may be used at all) the functions in element segments. The
+//! filter pass uses the result to drop everything else when
+//! re-encoding the output module, so that generic functions and
+//! helpers made dead by specialization don't stay around forever.
+
+use crate::collections::HashSet;
+use wasmparser::{ElementItems, ExternalKind, Parser, Payload, TypeRef};
+
+/// The live items of a module, in original index space.
+#[derive(Clone, Debug, Default)]
+pub struct LiveItems {
+    /// Live functions, including all imported functions.
+    pub funcs: HashSet<u32>,
+    /// Live globals, or `None` if globals cannot be safely removed
+    /// (e.g., because a section we transcribe verbatim refers to
+    /// them).
+    pub globals: Option<HashSet<u32>>,
+    /// Whether element segments must be retained (the table may be
+    /// accessed).
+    pub elements: bool,
+}
+
+#[derive(Default)]
+struct FuncUses {
+    funcs: Vec<u32>,
+    globals: Vec<u32>,
+    uses_table: bool,
+}
+
+pub fn compute_live(module: &[u8]) -> anyhow::Result<LiveItems> {
+    let mut num_imported_funcs = 0;
+    let mut roots = vec![];
+    let mut global_roots = vec![];
+    let mut table_exported = false;
+    let mut element_funcs = vec![];
+    let mut uses = vec![];
+    let mut globals_removable = true;
+
+    for payload in Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    match import?.ty {
+                        TypeRef::Func(_) => {
+                            roots.push(num_imported_funcs);
+                            num_imported_funcs += 1;
+                        }
+                        // An imported table may be used by other
+                        // modules, as with an exported one.
+                        TypeRef::Table(_) => table_exported = true,
+                        _ => {}
+                    }
+                }
+            }
+            Payload::ExportSection(exports) => {
+                for export in exports {
+                    let export = export?;
+                    match export.kind {
+                        ExternalKind::Func => roots.push(export.index),
+                        ExternalKind::Global => global_roots.push(export.index),
+                        ExternalKind::Table => table_exported = true,
+                        _ => {}
+                    }
+                }
+            }
+            Payload::StartSection { func, .. } => roots.push(func),
+            Payload::ElementSection(elements) => {
+                for element in elements {
+                    match element?.items {
+                        ElementItems::Functions(funcs) => {
+                            for f in funcs {
+                                element_funcs.push(f?);
+                            }
+                        }
+                        ElementItems::Expressions(_, exprs) => {
+                            for expr in exprs {
+                                for op in expr?.get_operators_reader() {
+                                    if let wasmparser::Operator::RefFunc { function_index } = op? {
+                                        element_funcs.push(function_index);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Payload::DataSection(data) => {
+                // Data segments are transcribed verbatim, so any
+                // reference to a global in an offset expression pins
+                // the global index space.
+                for segment in data {
+                    if let wasmparser::DataKind::Active { offset_expr, .. } = segment?.kind {
+                        for op in offset_expr.get_operators_reader() {
+                            if let wasmparser::Operator::GlobalGet { .. } = op? {
+                                globals_removable = false;
+                            }
+                        }
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let mut func_uses = FuncUses::default();
+                for op in body.get_operators_reader()? {
+                    match op? {
+                        wasmparser::Operator::Call { function_index }
+                        | wasmparser::Operator::ReturnCall { function_index }
+                        | wasmparser::Operator::RefFunc { function_index } => {
+                            func_uses.funcs.push(function_index)
+                        }
+                        wasmparser::Operator::GlobalGet { global_index }
+                        | wasmparser::Operator::GlobalSet { global_index } => {
+                            func_uses.globals.push(global_index)
+                        }
+                        wasmparser::Operator::CallIndirect { .. }
+                        | wasmparser::Operator::ReturnCallIndirect { .. }
+                        | wasmparser::Operator::TableGet { .. }
+                        | wasmparser::Operator::TableSet { .. }
+                        | wasmparser::Operator::TableSize { .. }
+                        | wasmparser::Operator::TableGrow { .. }
+                        | wasmparser::Operator::TableFill { .. }
+                        | wasmparser::Operator::TableCopy { .. }
+                        | wasmparser::Operator::TableInit { .. } => func_uses.uses_table = true,
+                        _ => {}
+                    }
+                }
+                uses.push(func_uses);
+            }
+            _ => {}
+        }
+    }
+
+    let mut live = LiveItems::default();
+    let mut live_globals = global_roots.into_iter().collect::<HashSet<_>>();
+    let mut queue = roots;
+    if table_exported {
+        live.elements = true;
+        queue.extend(element_funcs.iter().cloned());
+    }
+    while let Some(func) = queue.pop() {
+        if !live.funcs.insert(func) || func < num_imported_funcs {
+            continue;
+        }
+        let func_uses = &uses[(func - num_imported_funcs) as usize];
+        queue.extend(func_uses.funcs.iter().cloned());
+        live_globals.extend(func_uses.globals.iter().cloned());
+        if func_uses.uses_table && !live.elements {
+            live.elements = true;
+            queue.extend(element_funcs.iter().cloned());
+        }
+    }
+
+    log::info!(
+        "gc: {} of {} functions live; element segments {}",
+        live.funcs.len(),
+        num_imported_funcs as usize + uses.len(),
+        if live.elements { "live" } else { "dead" },
+    );
+
+    live.globals = if globals_removable {
+        Some(live_globals)
+    } else {
+        None
+    };
+    Ok(live)
+}
@@ -0,0 +1,218 @@
+//! Guarded devirtualization of polymorphic `call_indirect`s.
+//!
+//! The evaluator turns a `call_indirect` into a direct call when its
+//! table index is a single known constant. Interpreter dispatch often
+//! leaves sites whose index is instead one of a few constants chosen
+//! at runtime: a `select` between two constants, or a blockparam
+//! whose incoming args are all constants (e.g., a handler chosen on
+//! each side of a type check). For such a site, with between two and
+//! `MAX_TARGETS` candidate indices, we emit a chain of equality
+//! checks, each guarding a direct call to the candidate's function,
+//! with the original `call_indirect` as the fallback. The direct
+//! calls may then be inlined by the engine.
+//!
+//! Candidates whose table slot is empty or whose function has a
+//! different signature are left to the fallback. Only calls through
+//! tables whose contents never change (see
+//! `Image::detect_immutable_tables`) are guarded, as the guard checks
+//! the index, not the function in the slot.
+
+use crate::collections::HashMap;
+use crate::image::Image;
+use std::collections::BTreeSet;
+use waffle::{
+    pool::ListRef, Block, BlockTarget, FunctionBody, Module, Operator, Terminator, Value, ValueDef,
+};
+
+/// Most candidate indices for which we emit guards.
+const MAX_TARGETS: usize = 4;
+
+fn const_u32(func: &FunctionBody, value: Value) -> Option<u32> {
+    match &func.values[func.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(*value),
+        _ => None,
+    }
+}
+
+/// The set of constants `value` may take, if it is one of a few.
+fn candidate_indices(
+    func: &FunctionBody,
+    incoming: &HashMap<Block, Vec<Vec<Value>>>,
+    value: Value,
+) -> Option<BTreeSet<u32>> {
+    let mut indices = BTreeSet::new();
+    match &func.values[func.resolve_alias(value)] {
+        ValueDef::Operator(Operator::Select | Operator::TypedSelect { .. }, args, _) => {
+            let args = &func.arg_pool[*args];
+            indices.insert(const_u32(func, args[0])?);
+            indices.insert(const_u32(func, args[1])?);
+        }
+        ValueDef::BlockParam(block, index, _) => {
+            for args in incoming.get(block)? {
+                indices.insert(const_u32(func, args[*index as usize])?);
+            }
+        }
+        _ => return None,
+    }
+    if indices.len() < 2 || indices.len() > MAX_TARGETS {
+        return None;
+    }
+    Some(indices)
+}
+
+pub fn run(func: &mut FunctionBody, module: &Module, image: &Image) {
+    // Args passed to each block's params over all incoming edges.
+    // Splitting blocks below moves terminators but not their args, so
+    // this remains valid for the original blocks.
+    let mut incoming: HashMap<Block, Vec<Vec<Value>>> = HashMap::default();
+    for (_, def) in func.blocks.entries() {
+        def.terminator.visit_targets(|target| {
+            incoming
+                .entry(target.block)
+                .or_default()
+                .push(target.args.clone());
+        });
+    }
+
+    let mut changed = false;
+    let mut block_idx = 0;
+    // Blocks split off below are appended and visited in turn.
+    while block_idx < func.blocks.len() {
+        let block = Block::new(block_idx);
+        block_idx += 1;
+
+        for i in 0..func.blocks[block].insts.len() {
+            let inst = func.blocks[block].insts[i];
+            let (sig_index, table_index, args, tys) = match &func.values[inst] {
+                ValueDef::Operator(
+                    Operator::CallIndirect {
+                        sig_index,
+                        table_index,
+                    },
+                    args,
+                    tys,
+                ) if tys.len() <= 1 && image.is_table_immutable(*table_index) => {
+                    (*sig_index, *table_index, *args, *tys)
+                }
+                _ => continue,
+            };
+            let args = func.arg_pool[args].to_vec();
+            let (&index, call_args) = args.split_last().unwrap();
+            let indices = match candidate_indices(func, &incoming, index) {
+                Some(indices) => indices,
+                None => continue,
+            };
+            let targets = indices
+                .into_iter()
+                .filter_map(|idx| {
+                    let callee = *image.tables.get(&table_index)?.get(idx as usize)?;
+                    (callee.is_valid() && module.funcs[callee].sig() == sig_index)
+                        .then_some((idx, callee))
+                })
+                .collect::<Vec<_>>();
+            if targets.is_empty() {
+                continue;
+            }
+            log::debug!(
+                "guarded devirtualization of {} in {}: targets {:?}",
+                inst,
+                block,
+                targets
+            );
+            let loc = func.source_locs[inst];
+            let ty = func.type_pool[tys].first().copied();
+
+            // Move the rest of the block, and its terminator, to a
+            // join block that receives the call's result.
+            let rest = func.blocks[block].insts.split_off(i + 1);
+            func.blocks[block].insts.pop();
+            let join = func.add_block();
+            func.blocks[join].insts = rest;
+            func.blocks[join].terminator = std::mem::take(&mut func.blocks[block].terminator);
+            func.blocks[join].desc = format!("Join after guarded call at {}", inst);
+            let result = ty.map(|ty| func.add_blockparam(join, ty));
+
+            let mut guard = block;
+            for (idx, callee) in targets {
+                let idx_tys = func.single_type_list(waffle::Type::I32);
+                let k = func.add_value(ValueDef::Operator(
+                    Operator::I32Const { value: idx },
+                    ListRef::default(),
+                    idx_tys,
+                ));
+                func.append_to_block(guard, k);
+                let cmp_args = func.arg_pool.double(index, k);
+                let cmp = func.add_value(ValueDef::Operator(Operator::I32Eq, cmp_args, idx_tys));
+                func.append_to_block(guard, cmp);
+
+                let direct = func.add_block();
+                func.blocks[direct].desc = format!("Guarded direct call to {}", callee);
+                let direct_args = func.arg_pool.from_iter(call_args.iter().cloned());
+                let call = func.add_value(ValueDef::Operator(
+                    Operator::Call {
+                        function_index: callee,
+                    },
+                    direct_args,
+                    tys,
+                ));
+                func.source_locs[call] = loc;
+                func.append_to_block(direct, call);
+                func.blocks[direct].terminator = Terminator::Br {
+                    target: BlockTarget {
+                        block: join,
+                        args: result.map(|_| call).into_iter().collect(),
+                    },
+                };
+
+                let next = func.add_block();
+                func.blocks[guard].terminator = Terminator::CondBr {
+                    cond: cmp,
+                    if_true: BlockTarget {
+                        block: direct,
+                        args: vec![],
+                    },
+                    if_false: BlockTarget {
+                        block: next,
+                        args: vec![],
+                    },
+                };
+                guard = next;
+            }
+
+            // The last guard falls back to the original indirect
+            // call.
+            func.blocks[guard].desc = format!("Fallback indirect call at {}", inst);
+            let fallback_args = func.arg_pool.from_iter(args.iter().cloned());
+            let fallback = func.add_value(ValueDef::Operator(
+                Operator::CallIndirect {
+                    sig_index,
+                    table_index,
+                },
+                fallback_args,
+                tys,
+            ));
+            func.source_locs[fallback] = loc;
+            func.append_to_block(guard, fallback);
+            func.blocks[guard].terminator = Terminator::Br {
+                target: BlockTarget {
+                    block: join,
+                    args: result.map(|_| fallback).into_iter().collect(),
+                },
+            };
+
+            // Uses of the original call now see the joined result.
+            func.values[inst] = match result {
+                Some(result) => ValueDef::Alias(result),
+                None => ValueDef::None,
+            };
+            changed = true;
+
+            // The rest of this block is now in `join`, visited later.
+            break;
+        }
+    }
+
+    if changed {
+        func.recompute_edges();
+    }
+}
@@ -1,17 +1,105 @@
 //! Static module image summary.
 
+use crate::passive::PassiveOp;
 use crate::value::WasmVal;
-use std::collections::BTreeMap;
-use waffle::{Func, Global, Memory, MemoryData, MemorySegment, Module, Table};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use waffle::{
+    entity::EntityRef, Func, Global, ImportKind, Memory, MemoryData, MemorySegment, Module, Table,
+};
 
 #[derive(Clone, Debug)]
 pub struct Image {
     pub memories: BTreeMap<Memory, MemImage>,
     pub globals: BTreeMap<Global, WasmVal>,
+    /// Globals that are immutable, hence whose values in `globals`
+    /// hold for the whole execution.
+    pub immutable_globals: BTreeSet<Global>,
     pub tables: BTreeMap<Table, Vec<Func>>,
     pub stack_pointer: Option<Global>,
     pub main_heap: Option<Memory>,
     pub main_table: Option<Table>,
+    /// Side modules sharing this image's memory and main table.
+    /// Module index `i + 1` refers to `linked_modules[i]`; index 0 is
+    /// the module this image was built from.
+    pub linked_modules: Vec<LinkedModule>,
+    /// Memories declared `shared` (threads proposal). Other threads
+    /// may write these at any time, so nothing in their images is
+    /// constant; see `detect_shared_memories`.
+    pub shared_memories: BTreeSet<Memory>,
+    /// Tables whose contents in `tables` hold for the whole
+    /// execution: defined, neither imported nor exported, and never
+    /// written; see `detect_immutable_tables`.
+    pub immutable_tables: BTreeSet<Table>,
+    /// Passive data segments of the input module, by segment index;
+    /// see `detect_passive_segments`.
+    pub passive_segments: BTreeMap<u32, PassiveSegment>,
+    /// The number of data segments, active and passive, in the input
+    /// module.
+    pub num_data_segments: u32,
+    /// Helpers standing in for `memory.init` and `data.drop`; see
+    /// `crate::passive`.
+    pub passive_ops: BTreeMap<Func, PassiveOp>,
+}
+
+/// A passive data segment.
+#[derive(Clone, Debug)]
+pub struct PassiveSegment {
+    pub data: Vec<u8>,
+    /// Whether some `data.drop` in the module names this segment, so
+    /// that it may be empty by the time any `memory.init` runs.
+    pub dropped: bool,
+}
+
+/// A side module linked against the main module's memory and table,
+/// Emscripten dynamic-linking style.
+#[derive(Clone, Debug)]
+pub struct LinkedModule {
+    /// Base of the side module's static data in the shared memory
+    /// (the value of its `__memory_base` import).
+    pub memory_base: u32,
+    /// Base of the side module's slots in the shared table (the value
+    /// of its `__table_base` import).
+    pub table_base: u32,
+    /// The side module's table elements, in order from `table_base`.
+    pub funcs: Vec<Func>,
+}
+
+/// A side module to specialize, as given on the command line:
+/// `<input>,<output>,<memory_base>,<table_base>`.
+#[derive(Clone, Debug)]
+pub struct SideModuleArg {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub memory_base: u32,
+    pub table_base: u32,
+}
+
+/// Parses a decimal or `0x`-prefixed hex `u32`.
+pub fn parse_u32(s: &str) -> anyhow::Result<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => Ok(u32::from_str_radix(hex, 16)?),
+        None => Ok(s.parse()?),
+    }
+}
+
+impl std::str::FromStr for SideModuleArg {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parts = s.split(',').collect::<Vec<_>>();
+        if parts.len() != 4 {
+            anyhow::bail!(
+                "Expected <input>,<output>,<memory_base>,<table_base>, got: {}",
+                s
+            );
+        }
+        Ok(SideModuleArg {
+            input: parts[0].into(),
+            output: parts[1].into(),
+            memory_base: parse_u32(parts[2])?,
+            table_base: parse_u32(parts[3])?,
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -40,6 +128,12 @@ pub fn build_image(module: &Module, snapshot_bytes: Option<&[u8]>) -> anyhow::Re
                 _ => None,
             })
             .collect(),
+        immutable_globals: module
+            .globals
+            .entries()
+            .filter(|(_, data)| !data.mutable)
+            .map(|(global_id, _)| global_id)
+            .collect(),
         tables: module
             .tables
             .entries()
@@ -51,9 +145,206 @@ pub fn build_image(module: &Module, snapshot_bytes: Option<&[u8]>) -> anyhow::Re
         main_heap: module.memories.iter().next(),
         // HACK: assume first table is used for function pointers.
         main_table: module.tables.iter().next(),
+        linked_modules: vec![],
+        shared_memories: BTreeSet::new(),
+        immutable_tables: BTreeSet::new(),
+        passive_segments: BTreeMap::new(),
+        num_data_segments: 0,
+        passive_ops: BTreeMap::new(),
     })
 }
 
+/// One piece of externally snapshotted state, for
+/// `Image::from_raw_segments`.
+#[derive(Clone, Debug)]
+pub enum RawSegment {
+    /// Bytes of a memory at the given offset.
+    Memory {
+        memory: Memory,
+        offset: usize,
+        data: Vec<u8>,
+    },
+    /// The value of a global.
+    Global { global: Global, value: WasmVal },
+    /// Function elements of a table from the given index.
+    Table {
+        table: Table,
+        offset: usize,
+        funcs: Vec<Func>,
+    },
+}
+
+impl Image {
+    /// Build an image from state snapshotted by some tool other than
+    /// Wizer: the module's own initial state, overlaid with the given
+    /// segments. Memories grow (in whole pages) to fit the data given
+    /// for them.
+    pub fn from_raw_segments(
+        module: &Module,
+        segments: impl IntoIterator<Item = RawSegment>,
+    ) -> anyhow::Result<Image> {
+        let mut im = build_image(module, None)?;
+        for segment in segments {
+            match segment {
+                RawSegment::Memory {
+                    memory,
+                    offset,
+                    data,
+                } => {
+                    let image = &mut im
+                        .memories
+                        .get_mut(&memory)
+                        .ok_or_else(|| anyhow::anyhow!("Snapshot of unknown memory {}", memory))?
+                        .image;
+                    let end = offset
+                        .checked_add(data.len())
+                        .ok_or_else(|| anyhow::anyhow!("Snapshot data out of range"))?;
+                    if end > image.len() {
+                        image.resize((end + WASM_PAGE - 1) & !(WASM_PAGE - 1), 0);
+                    }
+                    image[offset..end].copy_from_slice(&data[..]);
+                }
+                RawSegment::Global { global, value } => {
+                    if module.globals.get(global).is_none() {
+                        anyhow::bail!("Snapshot of unknown global {}", global);
+                    }
+                    im.globals.insert(global, value);
+                }
+                RawSegment::Table {
+                    table,
+                    offset,
+                    funcs,
+                } => {
+                    let elems = im
+                        .tables
+                        .get_mut(&table)
+                        .ok_or_else(|| anyhow::anyhow!("Snapshot of unknown table {}", table))?;
+                    let end = offset + funcs.len();
+                    if end > elems.len() {
+                        elems.resize(end, Func::invalid());
+                    }
+                    elems[offset..end].copy_from_slice(&funcs[..]);
+                }
+            }
+        }
+        Ok(im)
+    }
+
+    /// Build an image from a Wasm core dump, as written by Wasmtime
+    /// (`wasmtime run -D coredump=<file>`), of a single instance of
+    /// `module`: the dump's memories and globals, in order, are the
+    /// module's. Tables are not part of a core dump, and come from
+    /// the module.
+    pub fn from_wasmtime_snapshot(module: &Module, bytes: &[u8]) -> anyhow::Result<Image> {
+        let mut segments = vec![];
+        let mut global_index = 0;
+        for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+            match payload? {
+                wasmparser::Payload::GlobalSection(reader) => {
+                    for global in reader {
+                        let global = global?;
+                        segments.push(RawSegment::Global {
+                            global: Global::new(global_index),
+                            value: const_expr_value(&global.init_expr)?,
+                        });
+                        global_index += 1;
+                    }
+                }
+                wasmparser::Payload::DataSection(reader) => {
+                    for data in reader {
+                        let data = data?;
+                        if let wasmparser::DataKind::Active {
+                            memory_index,
+                            offset_expr,
+                        } = data.kind
+                        {
+                            let offset = const_expr_value(&offset_expr)?
+                                .integer_value()
+                                .ok_or_else(|| anyhow::anyhow!("Non-integer data offset"))?;
+                            segments.push(RawSegment::Memory {
+                                memory: Memory::new(memory_index as usize),
+                                offset: usize::try_from(offset)?,
+                                data: data.data.to_vec(),
+                            });
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Image::from_raw_segments(module, segments)
+    }
+}
+
+/// Evaluates a constant expression consisting of a single constant.
+fn const_expr_value(expr: &wasmparser::ConstExpr) -> anyhow::Result<WasmVal> {
+    let mut ops = expr.get_operators_reader();
+    match ops.read()? {
+        wasmparser::Operator::I32Const { value } => Ok(WasmVal::I32(value as u32)),
+        wasmparser::Operator::I64Const { value } => Ok(WasmVal::I64(value as u64)),
+        wasmparser::Operator::F32Const { value } => Ok(WasmVal::F32(value.bits())),
+        wasmparser::Operator::F64Const { value } => Ok(WasmVal::F64(value.bits())),
+        op => anyhow::bail!("Unsupported constant expression in snapshot: {:?}", op),
+    }
+}
+
+/// Build the image seen by a linked side module: the shared memory
+/// of `main`, with the side module's own globals, tables and imports
+/// of the memory and table bases resolved.
+pub fn build_side_module_image(main: &Image, index: usize, side: &Module) -> anyhow::Result<Image> {
+    let linked = &main.linked_modules[index - 1];
+    let mut im = build_image(side, None)?;
+    im.memories = main.memories.clone();
+    im.shared_memories = main.shared_memories.clone();
+    im.stack_pointer = None;
+    for import in &side.imports {
+        if let ImportKind::Global(global) = import.kind {
+            match &import.name[..] {
+                "__memory_base" => {
+                    im.globals.insert(global, WasmVal::I32(linked.memory_base));
+                    im.immutable_globals.insert(global);
+                }
+                "__table_base" => {
+                    im.globals.insert(global, WasmVal::I32(linked.table_base));
+                    im.immutable_globals.insert(global);
+                }
+                "__stack_pointer" => im.stack_pointer = Some(global),
+                _ => {}
+            }
+        }
+    }
+    Ok(im)
+}
+
+/// How to treat the module's start function, if any, relative to the
+/// image that specialization reads and that is written back into the
+/// output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StartFuncMode {
+    /// Keep the start function in the output. The image is the
+    /// module's state *before* the start function runs.
+    #[default]
+    Keep,
+    /// Run the start function at weval time, record its effects on
+    /// memory in the image, and remove it from the output so that
+    /// they are not applied twice.
+    Run,
+    /// Remove the start function from the output without running it.
+    Remove,
+}
+
+impl std::str::FromStr for StartFuncMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "keep" => Ok(StartFuncMode::Keep),
+            "run" => Ok(StartFuncMode::Run),
+            "remove" => Ok(StartFuncMode::Remove),
+            _ => anyhow::bail!("Unknown start function mode: {}", s),
+        }
+    }
+}
+
 const WASM_PAGE: usize = 1 << 16;
 
 fn maybe_mem_image(mem: &MemoryData, snapshot_bytes: Option<&[u8]>) -> Option<MemImage> {
@@ -73,6 +364,28 @@ fn maybe_mem_image(mem: &MemoryData, snapshot_bytes: Option<&[u8]>) -> Option<Me
 }
 
 pub fn update(module: &mut Module, im: &Image) {
+    // Globals may differ from the module's if the image came from an
+    // external snapshot.
+    let imported = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Global(global) => Some(global),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+    for (&global, value) in &im.globals {
+        let bits = match *value {
+            WasmVal::I32(v) => u64::from(v),
+            WasmVal::I64(v) => v,
+            WasmVal::F32(v) => u64::from(v),
+            WasmVal::F64(v) => v,
+            WasmVal::V128(_) => continue,
+        };
+        if !imported.contains(&global) && module.globals[global].mutable {
+            module.globals[global].value = Some(bits);
+        }
+    }
     for (&mem_id, mem) in &im.memories {
         module.memories[mem_id].segments.clear();
         module.memories[mem_id].segments.push(MemorySegment {
@@ -86,6 +399,178 @@ pub fn update(module: &mut Module, im: &Image) {
 }
 
 impl Image {
+    /// Record which memories the module declares (or imports) as
+    /// shared. The IR does not carry this, so we read the module's
+    /// memory types directly; memories are indexed with imports
+    /// first, as in the IR.
+    pub fn detect_shared_memories(&mut self, module: &[u8]) -> anyhow::Result<()> {
+        let mut index = 0;
+        for payload in wasmparser::Parser::new(0).parse_all(module) {
+            let mut memory = |ty: wasmparser::MemoryType| {
+                if ty.shared {
+                    self.shared_memories.insert(Memory::new(index));
+                }
+                index += 1;
+            };
+            match payload? {
+                wasmparser::Payload::ImportSection(imports) => {
+                    for import in imports {
+                        if let wasmparser::TypeRef::Memory(ty) = import?.ty {
+                            memory(ty);
+                        }
+                    }
+                }
+                wasmparser::Payload::MemorySection(memories) => {
+                    for ty in memories {
+                        memory(ty?);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Record which tables are never changed after instantiation, so
+    /// that calls through them may be devirtualized. As for shared
+    /// memories, we read the module directly: a table is mutable if
+    /// it is imported or exported (another module may write it), or
+    /// if any function body writes it.
+    pub fn detect_immutable_tables(&mut self, module: &[u8]) -> anyhow::Result<()> {
+        let mut num_imported = 0;
+        let mut num_defined = 0;
+        let mut mutable = BTreeSet::new();
+        for payload in wasmparser::Parser::new(0).parse_all(module) {
+            match payload? {
+                wasmparser::Payload::ImportSection(imports) => {
+                    for import in imports {
+                        if let wasmparser::TypeRef::Table(_) = import?.ty {
+                            mutable.insert(num_imported);
+                            num_imported += 1;
+                        }
+                    }
+                }
+                wasmparser::Payload::TableSection(tables) => {
+                    num_defined = tables.count();
+                }
+                wasmparser::Payload::ExportSection(exports) => {
+                    for export in exports {
+                        let export = export?;
+                        if export.kind == wasmparser::ExternalKind::Table {
+                            mutable.insert(export.index);
+                        }
+                    }
+                }
+                wasmparser::Payload::CodeSectionEntry(body) => {
+                    for op in body.get_operators_reader()? {
+                        match op? {
+                            wasmparser::Operator::TableSet { table }
+                            | wasmparser::Operator::TableGrow { table }
+                            | wasmparser::Operator::TableFill { table }
+                            | wasmparser::Operator::TableInit { table, .. }
+                            | wasmparser::Operator::TableCopy {
+                                dst_table: table, ..
+                            } => {
+                                mutable.insert(table);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.immutable_tables = (num_imported..(num_imported + num_defined))
+            .filter(|table| !mutable.contains(table))
+            .map(|table| Table::new(table as usize))
+            .collect();
+        Ok(())
+    }
+
+    /// Record the module's passive data segments, and which of them
+    /// may be dropped. The IR drops passive segments, so we read them
+    /// from the module directly, as for shared memories.
+    pub fn detect_passive_segments(&mut self, module: &[u8]) -> anyhow::Result<()> {
+        let mut dropped = BTreeSet::new();
+        for payload in wasmparser::Parser::new(0).parse_all(module) {
+            match payload? {
+                wasmparser::Payload::DataSection(segments) => {
+                    self.num_data_segments = segments.count();
+                    for (index, segment) in segments.into_iter().enumerate() {
+                        let segment = segment?;
+                        if let wasmparser::DataKind::Passive = segment.kind {
+                            self.passive_segments.insert(
+                                index as u32,
+                                PassiveSegment {
+                                    data: segment.data.to_vec(),
+                                    dropped: false,
+                                },
+                            );
+                        }
+                    }
+                }
+                wasmparser::Payload::CodeSectionEntry(body) => {
+                    for op in body.get_operators_reader()? {
+                        if let wasmparser::Operator::DataDrop { data_index } = op? {
+                            dropped.insert(data_index);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for index in dropped {
+            if let Some(segment) = self.passive_segments.get_mut(&index) {
+                segment.dropped = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// The contents of a passive segment, from `offset` for `len`
+    /// bytes, if it is never dropped and the range is in bounds (so
+    /// that `memory.init` from it cannot trap on the source side).
+    pub fn passive_segment_range(&self, segment: u32, offset: u32, len: u32) -> Option<&[u8]> {
+        let segment = self.passive_segments.get(&segment)?;
+        if segment.dropped {
+            return None;
+        }
+        let end = offset.checked_add(len)?;
+        segment.data.get(offset as usize..end as usize)
+    }
+
+    /// The data segments to write back ahead of the image's own, so
+    /// that segment indices in `memory.init` and `data.drop` keep
+    /// their meaning: each passive segment at its original index, and
+    /// an empty passive one in place of each active segment (which is
+    /// dropped at instantiation, and so is empty to those
+    /// instructions). Empty if the module has no passive segments
+    /// and uses neither instruction.
+    pub fn input_data_segments(&self) -> Vec<Vec<u8>> {
+        if self.passive_segments.is_empty() && self.passive_ops.is_empty() {
+            return vec![];
+        }
+        (0..self.num_data_segments)
+            .map(|index| {
+                self.passive_segments
+                    .get(&index)
+                    .map(|segment| segment.data.clone())
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
+    /// Whether the given table's contents never change, so that a
+    /// function found in it can be called directly.
+    pub fn is_table_immutable(&self, table: Table) -> bool {
+        self.immutable_tables.contains(&table)
+    }
+
+    /// Whether the given memory is shared between threads.
+    pub fn is_shared(&self, memory: Memory) -> bool {
+        self.shared_memories.contains(&memory)
+    }
+
     pub fn can_read(&self, memory: Memory, addr: u32, size: u32) -> bool {
         let end = match addr.checked_add(size) {
             Some(end) => end,
@@ -144,16 +629,22 @@ impl Image {
 
     pub fn read_u64(&self, id: Memory, addr: u32) -> anyhow::Result<u64> {
         let low = self.read_u32(id, addr)?;
-        let high = self.read_u32(id, addr + 4)?;
+        let high = self.read_u32(id, Self::next_addr(addr, 4)?)?;
         Ok((high as u64) << 32 | (low as u64))
     }
 
     pub fn read_u128(&self, id: Memory, addr: u32) -> anyhow::Result<u128> {
         let low = self.read_u64(id, addr)?;
-        let high = self.read_u64(id, addr + 8)?;
+        let high = self.read_u64(id, Self::next_addr(addr, 8)?)?;
         Ok((high as u128) << 64 | (low as u128))
     }
 
+    /// `addr + delta`, which must not wrap around the address space.
+    fn next_addr(addr: u32, delta: u32) -> anyhow::Result<u32> {
+        addr.checked_add(delta)
+            .ok_or_else(|| anyhow::anyhow!("Out of bounds"))
+    }
+
     pub fn read_size(&self, id: Memory, addr: u32, size: u8) -> anyhow::Result<u64> {
         match size {
             1 => self.read_u8(id, addr).map(|x| x as u64),
@@ -172,7 +663,7 @@ impl Image {
                 break;
             }
             bytes.push(byte);
-            addr += 1;
+            addr = Self::next_addr(addr, 1)?;
         }
         Ok(std::str::from_utf8(&bytes[..])?.to_owned())
     }
@@ -186,7 +677,15 @@ impl Image {
         Ok(())
     }
 
+    /// Patch a word of the image. This changes only the initial
+    /// contents written to the output, before any instance exists, so
+    /// no thread can observe the patch in progress; in a shared
+    /// memory, the word must still be aligned, as the guest may read
+    /// it with atomics.
     pub fn write_u32(&mut self, id: Memory, addr: u32, value: u32) -> anyhow::Result<()> {
+        if self.is_shared(id) && addr % 4 != 0 {
+            anyhow::bail!("Unaligned write at {:#x} to shared memory", addr);
+        }
         let image = self.memories.get_mut(&id).unwrap();
         let addr = addr as usize;
         if (addr + 4) > image.len() {
@@ -210,6 +709,35 @@ impl Image {
             .ok_or_else(|| anyhow::anyhow!("func ptr out of bounds"))?)
     }
 
+    /// Resolve a function pointer in the shared table to the module
+    /// that defines it (0 for this module, otherwise a linked side
+    /// module) and the function in that module's index space.
+    pub fn resolve_func_ptr(&self, idx: u32) -> anyhow::Result<(usize, Func)> {
+        for (i, linked) in self.linked_modules.iter().enumerate() {
+            if let Some(local) = idx.checked_sub(linked.table_base) {
+                if let Some(&func) = linked.funcs.get(local as usize) {
+                    return Ok((i + 1, func));
+                }
+            }
+        }
+        Ok((0, self.func_ptr(idx)?))
+    }
+
+    /// Register a side module sharing this image's memory and table.
+    pub fn link_side_module(&mut self, side: &Module, memory_base: u32, table_base: u32) {
+        let funcs = side
+            .tables
+            .values()
+            .next()
+            .and_then(|table| table.func_elements.clone())
+            .unwrap_or(vec![]);
+        self.linked_modules.push(LinkedModule {
+            memory_base,
+            table_base,
+            funcs,
+        });
+    }
+
     pub fn append_data(&mut self, id: Memory, data: Vec<u8>) {
         let image = self.memories.get_mut(&id).unwrap();
         let orig_len = image.len();
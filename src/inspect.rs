@@ -0,0 +1,177 @@
+//! Snapshot inspection: `weval inspect`.
+//!
+//! Authoring directives means knowing what the snapshot holds at
+//! specialization time: where an interpreter's tables live, what a
+//! global's value is, which function a function pointer refers to.
+//! This module answers such queries against the memory image, one
+//! command per line:
+//!
+//! - `mem <addr> <len> [hex|u8|u16|u32|u64|f32|f64|str]`: dump memory.
+//! - `globals`: list globals with their values.
+//! - `chase <addr> [<offset>...]`: follow a pointer chain, reading a
+//!   pointer at `addr`, then at that pointer plus each offset in turn.
+//! - `table <index>`: show the function at a table index.
+//!
+//! Numbers may be decimal or `0x`-prefixed hex.
+
+use crate::image::{parse_u32, Image};
+use std::fmt::Write;
+use waffle::{ExportKind, Module};
+
+pub const HELP: &str = "\
+commands:
+  mem <addr> <len> [hex|u8|u16|u32|u64|f32|f64|str]
+  globals
+  chase <addr> [<offset>...]
+  table <index>
+  help
+  quit
+";
+
+/// Runs one command, returning its output.
+pub fn run_command(module: &Module, im: &Image, line: &str) -> anyhow::Result<String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let mut out = String::new();
+    match &words[..] {
+        [] => {}
+        ["help"] => out.push_str(HELP),
+        ["mem", addr, len, format @ ..] => {
+            let addr = parse_u32(addr)?;
+            let len = parse_u32(len)?;
+            let format = match format {
+                [] => "hex",
+                [f] => *f,
+                _ => anyhow::bail!("Usage: mem <addr> <len> [format]"),
+            };
+            dump_mem(im, addr, len, format, &mut out)?;
+        }
+        ["globals"] => list_globals(module, im, &mut out),
+        ["chase", addr, offsets @ ..] => {
+            let heap = im.main_heap()?;
+            let mut ptr = im.read_u32(heap, parse_u32(addr)?)?;
+            writeln!(&mut out, "*{} = {:#x}", addr, ptr).unwrap();
+            for offset in offsets {
+                let addr = ptr.wrapping_add(parse_u32(offset)?);
+                ptr = im.read_u32(heap, addr)?;
+                writeln!(&mut out, "*({:#x}) = {:#x}", addr, ptr).unwrap();
+            }
+        }
+        ["table", index] => {
+            let index = parse_u32(index)?;
+            let (module_index, func) = im.resolve_func_ptr(index)?;
+            if module_index == 0 {
+                let sig = module.funcs[func].sig();
+                writeln!(
+                    &mut out,
+                    "table[{}] = {} ({}): {:?}",
+                    index,
+                    func,
+                    module.funcs[func].name(),
+                    module.signatures[sig]
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    &mut out,
+                    "table[{}] = {} in side module {}",
+                    index, func, module_index
+                )
+                .unwrap();
+            }
+        }
+        _ => anyhow::bail!("Unknown command: {} (try `help`)", line.trim()),
+    }
+    Ok(out)
+}
+
+fn dump_mem(im: &Image, addr: u32, len: u32, format: &str, out: &mut String) -> anyhow::Result<()> {
+    let heap = im.main_heap()?;
+    let bytes = im.read_slice(heap, addr, len)?;
+    let width = match format {
+        "hex" | "u8" | "str" => 1,
+        "u16" => 2,
+        "u32" | "f32" => 4,
+        "u64" | "f64" => 8,
+        _ => anyhow::bail!("Unknown format: {}", format),
+    };
+    match format {
+        "hex" => {
+            for (i, line) in bytes.chunks(16).enumerate() {
+                let hex = line
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let ascii = line
+                    .iter()
+                    .map(|&b| {
+                        if b.is_ascii_graphic() || b == b' ' {
+                            b as char
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect::<String>();
+                writeln!(
+                    out,
+                    "{:08x}: {:<47}  {}",
+                    addr as usize + i * 16,
+                    hex,
+                    ascii
+                )
+                .unwrap();
+            }
+        }
+        "str" => {
+            writeln!(out, "{:?}", String::from_utf8_lossy(bytes)).unwrap();
+        }
+        _ => {
+            for (i, chunk) in bytes.chunks_exact(width).enumerate() {
+                let mut raw = [0u8; 8];
+                raw[..width].copy_from_slice(chunk);
+                let raw = u64::from_le_bytes(raw);
+                let value = match format {
+                    "f32" => format!("{}", f32::from_bits(raw as u32)),
+                    "f64" => format!("{}", f64::from_bits(raw)),
+                    _ => format!("{:#x} ({})", raw, raw),
+                };
+                writeln!(out, "{:08x}: {}", addr as usize + i * width, value).unwrap();
+            }
+        }
+    }
+    Ok(())
+}
+
+fn list_globals(module: &Module, im: &Image, out: &mut String) {
+    for (global, data) in module.globals.entries() {
+        let names = module
+            .exports
+            .iter()
+            .filter(|e| matches!(e.kind, ExportKind::Global(g) if g == global))
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>();
+        let value = match im.globals.get(&global) {
+            Some(value) => format!("{:?}", value),
+            None => "unknown".to_owned(),
+        };
+        writeln!(
+            out,
+            "{}: {:?}{}{} = {}{}",
+            global,
+            data.ty,
+            if data.mutable { " mut" } else { "" },
+            if names.is_empty() {
+                String::new()
+            } else {
+                format!(" (export {})", names.join(", "))
+            },
+            value,
+            if Some(global) == im.stack_pointer {
+                " [stack pointer]"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+    }
+}
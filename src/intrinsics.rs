@@ -1,5 +1,7 @@
 //! Discovery of intrinsics.
 
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
 use waffle::{ExportKind, Func, ImportKind, Module, Operator, Terminator, Type, ValueDef};
 
 #[derive(Clone, Debug)]
@@ -12,9 +14,11 @@ pub struct Intrinsics {
     pub context_bucket: Option<Func>,
     pub abort_specialization: Option<Func>,
     pub trace_line: Option<Func>,
+    pub trace_here: Option<Func>,
     pub assert_const32: Option<Func>,
     pub specialize_value: Option<Func>,
     pub print: Option<Func>,
+    pub print_value: Option<Func>,
     pub read_specialization_global: Option<Func>,
     pub push_stack: Option<Func>,
     pub sync_stack: Option<Func>,
@@ -23,6 +27,12 @@ pub struct Intrinsics {
     pub pop_stack: Option<Func>,
     pub read_local: Option<Func>,
     pub write_local: Option<Func>,
+    pub stack_declare: Option<Func>,
+    pub region_epoch: Option<Func>,
+    pub peel_loop: Option<Func>,
+    pub env_u32: Option<Func>,
+    pub ic_site: Option<Func>,
+    pub barrier_needed: Option<Func>,
 }
 
 impl Intrinsics {
@@ -41,6 +51,7 @@ impl Intrinsics {
                 &[],
             ),
             trace_line: find_imported_intrinsic(module, "trace.line", &[Type::I32], &[]),
+            trace_here: find_imported_intrinsic(module, "trace.here", &[Type::I32], &[]),
             assert_const32: find_imported_intrinsic(
                 module,
                 "assert.const32",
@@ -59,6 +70,12 @@ impl Intrinsics {
                 &[Type::I32, Type::I32, Type::I32],
                 &[],
             ),
+            print_value: find_imported_intrinsic(
+                module,
+                "print.value",
+                &[Type::I32, Type::I32],
+                &[],
+            ),
             read_specialization_global: find_imported_intrinsic(
                 module,
                 "read.specialization.global",
@@ -93,8 +110,139 @@ impl Intrinsics {
                 &[Type::I32, Type::I32, Type::I64],
                 &[],
             ),
+            stack_declare: find_imported_intrinsic(
+                module,
+                "stack.declare",
+                &[Type::I32, Type::I32, Type::I32],
+                &[],
+            ),
+            region_epoch: find_imported_intrinsic(
+                module,
+                "region.epoch",
+                &[Type::I32, Type::I32, Type::I32],
+                &[],
+            ),
+            peel_loop: find_imported_intrinsic(module, "peel.loop", &[], &[]),
+            env_u32: find_imported_intrinsic(module, "env.u32", &[Type::I32], &[Type::I32]),
+            ic_site: find_imported_intrinsic(
+                module,
+                "ic.site",
+                &[Type::I32, Type::I32],
+                &[Type::I32],
+            ),
+            barrier_needed: find_imported_intrinsic(
+                module,
+                "barrier.needed",
+                &[Type::I64, Type::I64, Type::I64],
+                &[Type::I32],
+            ),
         }
     }
+
+    /// Each intrinsic's import name, with its function if imported
+    /// with the expected signature. The `weval-guest` crate's imports
+    /// are checked against this (see `tests/guest_abi.rs`).
+    pub fn by_name(&self) -> Vec<(&'static str, Option<Func>)> {
+        vec![
+            ("read.reg", self.read_reg),
+            ("write.reg", self.write_reg),
+            ("push.context", self.push_context),
+            ("pop.context", self.pop_context),
+            ("update.context", self.update_context),
+            ("context.bucket", self.context_bucket),
+            ("abort.specialization", self.abort_specialization),
+            ("trace.line", self.trace_line),
+            ("trace.here", self.trace_here),
+            ("assert.const32", self.assert_const32),
+            ("specialize.value", self.specialize_value),
+            ("print", self.print),
+            ("print.value", self.print_value),
+            (
+                "read.specialization.global",
+                self.read_specialization_global,
+            ),
+            ("push.stack", self.push_stack),
+            ("sync.stack", self.sync_stack),
+            ("read.stack", self.read_stack),
+            ("write.stack", self.write_stack),
+            ("pop.stack", self.pop_stack),
+            ("read.local", self.read_local),
+            ("write.local", self.write_local),
+            ("stack.declare", self.stack_declare),
+            ("region.epoch", self.region_epoch),
+            ("peel.loop", self.peel_loop),
+            ("env.u32", self.env_u32),
+            ("ic.site", self.ic_site),
+            ("barrier.needed", self.barrier_needed),
+        ]
+    }
+}
+
+/// A function that calls weval intrinsics but is not the target of
+/// any directive, so the calls will never take effect.
+#[derive(Clone, Debug)]
+pub struct UntargetedIntrinsicUse {
+    pub func: Func,
+    pub func_name: String,
+    /// Names of the intrinsics called, with the number of call sites.
+    pub intrinsics: BTreeMap<String, usize>,
+}
+
+/// Scan all function bodies for calls to `weval` imports, and report
+/// those in functions not in `targeted`.
+pub fn find_untargeted_intrinsic_uses(
+    module: &Module,
+    targeted: &BTreeSet<Func>,
+) -> anyhow::Result<Vec<UntargetedIntrinsicUse>> {
+    let weval_imports = module
+        .imports
+        .iter()
+        .filter(|im| im.module == "weval")
+        .filter_map(|im| match &im.kind {
+            &ImportKind::Func(f) => Some((f, im.name.clone())),
+            _ => None,
+        })
+        .collect::<BTreeMap<_, _>>();
+    if weval_imports.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let funcs = module
+        .funcs
+        .entries()
+        .filter(|(func, _)| !targeted.contains(func))
+        .map(|(func, _)| func)
+        .collect::<Vec<_>>();
+    let mut uses = funcs
+        .par_iter()
+        .map(|&func| -> anyhow::Result<Option<UntargetedIntrinsicUse>> {
+            let mut decl = module.funcs[func].clone();
+            decl.parse(module)?;
+            let body = match decl.body() {
+                Some(body) => body,
+                None => return Ok(None),
+            };
+            let mut intrinsics = BTreeMap::new();
+            for def in body.values.values() {
+                if let ValueDef::Operator(Operator::Call { function_index }, _, _) = def {
+                    if let Some(name) = weval_imports.get(function_index) {
+                        *intrinsics.entry(name.clone()).or_insert(0) += 1;
+                    }
+                }
+            }
+            if intrinsics.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(UntargetedIntrinsicUse {
+                func,
+                func_name: module.funcs[func].name().to_owned(),
+                intrinsics,
+            }))
+        })
+        .filter_map(|r| r.transpose())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    uses.sort_by_key(|u| u.func);
+    Ok(uses)
 }
 
 fn sig_matches(module: &Module, f: Func, in_tys: &[Type], out_tys: &[Type]) -> bool {
@@ -0,0 +1,390 @@
+//! Folding masks and comparisons on values of known width.
+//!
+//! Interpreters decode bytecode operands with masks (`x & 0xff`) and
+//! range checks (`x < 256`) that specialization cannot fold, as the
+//! operand is loaded at runtime, but that are redundant given how the
+//! value was computed: a byte load, another mask, a shift. This pass
+//! computes, for each integer value in a specialized body, the number
+//! of low bits that may be set (so `0 <= x < 2^bits` as unsigned),
+//! and then
+//!
+//! - replaces `x & m`, where `m` is a constant with all of those bits
+//!   set, with `x`;
+//! - replaces a comparison of `x` against a constant that the range
+//!   `[0, 2^bits)` decides with that result.
+//!
+//! Widths flow through blockparams; they are computed optimistically
+//! (starting from zero bits) and iterated to a fixed point.
+
+use crate::collections::HashMap;
+use crate::fold::{classify, Kind};
+use waffle::{pool::ListRef, Block, FunctionBody, Operator, Type, Value, ValueDef};
+
+fn int_width(ty: Type) -> Option<u32> {
+    match ty {
+        Type::I32 => Some(32),
+        Type::I64 => Some(64),
+        _ => None,
+    }
+}
+
+/// Number of significant bits of a constant.
+fn const_bits(k: u64) -> u32 {
+    64 - k.leading_zeros()
+}
+
+/// The largest value with the given number of low bits.
+fn max_value(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+fn const_value(op: &Operator) -> Option<u64> {
+    match *op {
+        Operator::I32Const { value } => Some(u64::from(value)),
+        Operator::I64Const { value } => Some(value),
+        _ => None,
+    }
+}
+
+struct Analysis<'a> {
+    func: &'a FunctionBody,
+    /// Bits that may be set in each integer value computed so far.
+    bits: HashMap<Value, u32>,
+}
+
+impl<'a> Analysis<'a> {
+    /// The constant a value is, if any.
+    fn constant(&self, value: Value) -> Option<u64> {
+        match &self.func.values[self.func.resolve_alias(value)] {
+            ValueDef::Operator(op, _, _) => const_value(op),
+            _ => None,
+        }
+    }
+
+    /// Bits that may be set in a value of the given width. Values the
+    /// analysis computes but has not yet are assumed (optimistically)
+    /// to have none; others (e.g. results of multi-value calls) may
+    /// have all.
+    fn get(&self, value: Value, width: u32) -> u32 {
+        let value = self.func.resolve_alias(value);
+        match &self.func.values[value] {
+            ValueDef::BlockParam(..) => {}
+            ValueDef::Operator(_, _, tys) if self.func.type_pool[*tys].len() == 1 => {}
+            _ => return width,
+        }
+        self.bits.get(&value).copied().unwrap_or(0).min(width)
+    }
+
+    /// Bits that may be set in the result of an operator.
+    fn transfer(&self, op: &Operator, args: &[Value], width: u32) -> u32 {
+        if let Some(k) = const_value(op) {
+            return const_bits(k);
+        }
+        match op {
+            Operator::I32Load8U { .. } | Operator::I64Load8U { .. } => return 8,
+            Operator::I32Load16U { .. } | Operator::I64Load16U { .. } => return 16,
+            Operator::I64Load32U { .. } => return 32,
+            Operator::I32Eqz | Operator::I64Eqz => return 1,
+            Operator::I32Clz
+            | Operator::I32Ctz
+            | Operator::I32Popcnt
+            | Operator::I64Clz
+            | Operator::I64Ctz
+            | Operator::I64Popcnt => return 7,
+            Operator::I64ExtendI32U => return self.get(args[0], 32),
+            Operator::I32WrapI64 => return self.get(args[0], 64).min(32),
+            Operator::Select | Operator::TypedSelect { .. } => {
+                return self.get(args[0], width).max(self.get(args[1], width));
+            }
+            _ => {}
+        }
+        let kind = match classify(*op) {
+            Some((kind, _)) => kind,
+            None => return width,
+        };
+        let a = self.get(args[0], width);
+        let b = self.get(args[1], width);
+        let shift = self
+            .constant(args[1])
+            .map(|k| (k % u64::from(width)) as u32);
+        let bits = match kind {
+            Kind::And => a.min(b),
+            Kind::Or | Kind::Xor => a.max(b),
+            Kind::Add => a.max(b) + 1,
+            Kind::Mul => a + b,
+            Kind::DivU => a,
+            Kind::RemU => a.min(b),
+            Kind::ShrU => match shift {
+                Some(shift) => a.saturating_sub(shift),
+                None => a,
+            },
+            Kind::Shl => match shift {
+                Some(shift) => a + shift,
+                None => width,
+            },
+            Kind::Eq
+            | Kind::Ne
+            | Kind::LtS
+            | Kind::LtU
+            | Kind::GtS
+            | Kind::GtU
+            | Kind::LeS
+            | Kind::LeU
+            | Kind::GeS
+            | Kind::GeU => 1,
+            Kind::Sub | Kind::DivS | Kind::ShrS | Kind::Rotl | Kind::Rotr => width,
+        };
+        bits.min(width)
+    }
+
+    fn run(&mut self) {
+        let func = self.func;
+        let blocks = func.blocks.iter().collect::<Vec<Block>>();
+        for &(ty, param) in &func.blocks[func.entry].params {
+            if let Some(width) = int_width(ty) {
+                self.bits.insert(param, width);
+            }
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &blocks {
+                for &inst in &func.blocks[block].insts {
+                    if let ValueDef::Operator(op, args, tys) = &func.values[inst] {
+                        let tys = &func.type_pool[*tys];
+                        if tys.len() != 1 {
+                            continue;
+                        }
+                        if let Some(width) = int_width(tys[0]) {
+                            let bits = self.transfer(op, &func.arg_pool[*args], width);
+                            changed |= self.widen(inst, bits);
+                        }
+                    }
+                }
+                func.blocks[block].terminator.visit_targets(|target| {
+                    let params = &func.blocks[target.block].params;
+                    for (&arg, &(ty, param)) in target.args.iter().zip(params.iter()) {
+                        if let Some(width) = int_width(ty) {
+                            let bits = self.get(arg, width);
+                            changed |= self.widen(param, bits);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    /// Raise a value's bits to at least `bits`, returning whether it
+    /// changed.
+    fn widen(&mut self, value: Value, bits: u32) -> bool {
+        let entry = self.bits.entry(value).or_insert(0);
+        if bits > *entry {
+            *entry = bits;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The result of a comparison of a value in `[0, max]` against the
+/// constant `k`, if the range decides it.
+fn decide(kind: Kind, max: u64, k: u64, width: u32) -> Option<bool> {
+    // Sign-extend `k` for the signed comparisons; values in range are
+    // nonnegative only if their top bit is clear.
+    let k_s = if width == 32 {
+        i64::from(k as u32 as i32)
+    } else {
+        k as i64
+    };
+    let nonneg = max < (1 << (width - 1));
+    let max_s = max as i64;
+    match kind {
+        Kind::Eq if k > max => Some(false),
+        Kind::Ne if k > max => Some(true),
+        Kind::LtU if k > max => Some(true),
+        Kind::GeU if k > max => Some(false),
+        Kind::GtU if k >= max => Some(false),
+        Kind::LeU if k >= max => Some(true),
+        Kind::LtS if nonneg && k_s > max_s => Some(true),
+        Kind::LtS if nonneg && k_s <= 0 => Some(false),
+        Kind::GeS if nonneg && k_s > max_s => Some(false),
+        Kind::GeS if nonneg && k_s <= 0 => Some(true),
+        Kind::GtS if nonneg && k_s >= max_s => Some(false),
+        Kind::GtS if nonneg && k_s < 0 => Some(true),
+        Kind::LeS if nonneg && k_s >= max_s => Some(true),
+        Kind::LeS if nonneg && k_s < 0 => Some(false),
+        _ => None,
+    }
+}
+
+/// Runs the pass, returning the number of instructions folded.
+pub fn run(func: &mut FunctionBody) -> usize {
+    let mut analysis = Analysis {
+        func,
+        bits: HashMap::default(),
+    };
+    analysis.run();
+    let bits = analysis.bits;
+
+    // The evaluator puts constant operands on the right (see
+    // `fold::binary_partial`).
+    let mut aliases = vec![];
+    let mut consts = vec![];
+    for (_, block) in func.blocks.entries() {
+        for &inst in &block.insts {
+            let (op, args) = match &func.values[inst] {
+                ValueDef::Operator(op, args, _) => (*op, &func.arg_pool[*args]),
+                _ => continue,
+            };
+            let (kind, width) = match classify(op) {
+                Some(classified) => classified,
+                None => continue,
+            };
+            let k = match &func.values[func.resolve_alias(args[1])] {
+                ValueDef::Operator(op, _, _) => match const_value(op) {
+                    Some(k) => k & max_value(width),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            let x = func.resolve_alias(args[0]);
+            let x_bits = match bits.get(&x) {
+                Some(&x_bits) => x_bits.min(width),
+                None => continue,
+            };
+            let max = max_value(x_bits);
+            if kind == Kind::And {
+                if k & max == max {
+                    aliases.push((inst, x));
+                }
+            } else if let Some(result) = decide(kind, max, k, width) {
+                consts.push((inst, result));
+            }
+        }
+    }
+
+    let folded = aliases.len() + consts.len();
+    for (inst, x) in aliases {
+        func.values[inst] = ValueDef::Alias(x);
+    }
+    let i32_ty = func.single_type_list(Type::I32);
+    for (inst, result) in consts {
+        func.values[inst] = ValueDef::Operator(
+            Operator::I32Const {
+                value: result as u32,
+            },
+            ListRef::default(),
+            i32_ty,
+        );
+    }
+    if folded > 0 {
+        for block in func.blocks.iter().collect::<Vec<Block>>() {
+            let values = &func.values;
+            func.blocks[block]
+                .insts
+                .retain(|&inst| !matches!(values[inst], ValueDef::Alias(_)));
+        }
+        waffle::passes::resolve_aliases::run(func);
+    }
+    log::debug!("known_bits: folded {} masks and comparisons", folded);
+    folded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+    use waffle::{FrontendOptions, Func, Module, Terminator};
+
+    /// Runs the pass over the first function of `wat`, and returns the
+    /// number of folds, the body, and the value it returns.
+    fn fold(wat: &str) -> (usize, FunctionBody, Value) {
+        let bytes = wat::parse_str(wat).unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        let mut func = module.clone_and_expand_body(Func::new(0)).unwrap();
+        let folded = run(&mut func);
+        let ret = func
+            .blocks
+            .values()
+            .find_map(|block| match &block.terminator {
+                Terminator::Return { values } => Some(func.resolve_alias(values[0])),
+                _ => None,
+            })
+            .unwrap();
+        (folded, func, ret)
+    }
+
+    fn op(func: &FunctionBody, value: Value) -> Option<Operator> {
+        match &func.values[value] {
+            ValueDef::Operator(op, _, _) => Some(*op),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn redundant_mask_is_removed() {
+        let (folded, func, ret) = fold(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32) (result i32)
+                (i32.and (i32.load8_u (local.get 0)) (i32.const 0xff))))
+            "#,
+        );
+        assert_eq!(folded, 1);
+        assert!(matches!(op(&func, ret), Some(Operator::I32Load8U { .. })));
+    }
+
+    #[test]
+    fn narrowing_mask_is_kept() {
+        let (folded, func, ret) = fold(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32) (result i32)
+                (i32.and (i32.load16_u (local.get 0)) (i32.const 0xff))))
+            "#,
+        );
+        assert_eq!(folded, 0);
+        assert_eq!(op(&func, ret), Some(Operator::I32And));
+    }
+
+    #[test]
+    fn range_decides_comparison() {
+        let (folded, func, ret) = fold(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32) (result i32)
+                (i32.lt_u (i32.load8_u (local.get 0)) (i32.const 256))))
+            "#,
+        );
+        assert_eq!(folded, 1);
+        assert_eq!(op(&func, ret), Some(Operator::I32Const { value: 1 }));
+    }
+
+    #[test]
+    fn widths_flow_through_blockparams() {
+        // The mask applies to the join of a byte load and a small
+        // constant.
+        let (folded, func, ret) = fold(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32 i32) (result i32)
+                (i32.and
+                  (if (result i32) (local.get 1)
+                    (then (i32.load8_u (local.get 0)))
+                    (else (i32.const 7)))
+                  (i32.const 0xff))))
+            "#,
+        );
+        assert_eq!(folded, 1);
+        assert!(matches!(func.values[ret], ValueDef::BlockParam(..)));
+    }
+}
@@ -0,0 +1,55 @@
+//! weval: the WebAssembly partial evaluator.
+//!
+//! The library holds the evaluator itself and everything it needs:
+//! directives, the memory image, abstract state and values, and the
+//! analyses and passes run over specialized bodies. It does no
+//! process-level work (argument parsing, snapshotting with Wizer,
+//! running modules under Wasmtime); that lives in the `weval` binary,
+//! which is built with the default `cli` feature. Embedders that only
+//! need to specialize an already-snapshotted module can depend on the
+//! crate with `default-features = false`.
+
+pub mod alias;
+pub mod analyze;
+pub mod branch_hints;
+pub mod callgraph;
+pub mod collections;
+pub mod const_pool;
+pub mod constant_offsets;
+pub mod cost;
+pub mod ctx_block_map;
+pub mod custom_sections;
+pub mod dce;
+pub mod directive;
+pub mod directives_section;
+pub mod effects;
+pub mod escape;
+pub mod estimate;
+pub mod eval;
+pub mod extend_wrap;
+pub mod features;
+pub mod filter;
+pub mod fold;
+pub mod fold_log;
+pub mod fusion;
+pub mod gc;
+pub mod guarded_devirt;
+pub mod image;
+pub mod inspect;
+pub mod intrinsics;
+pub mod known_bits;
+pub mod liveness;
+pub mod manifest;
+pub mod meta;
+pub mod passive;
+pub mod preflight;
+pub mod schedule;
+pub mod select_diamond;
+pub mod size_report;
+pub mod split;
+pub mod state;
+pub mod stats;
+pub mod store_forward;
+pub mod summary;
+pub mod telemetry;
+pub mod value;
@@ -1,11 +1,11 @@
 //! Liveness analysis: analyze the register pressure of original and
 //! specialized functions.
 
-use fxhash::FxHashSet;
+use crate::collections::HashSet;
 use std::collections::VecDeque;
 use waffle::{cfg::CFGInfo, entity::PerEntity, Block, FunctionBody, Terminator, Value, ValueDef};
 
-pub type LiveSet = FxHashSet<Value>;
+pub type LiveSet = HashSet<Value>;
 
 #[derive(Clone, Debug)]
 pub struct Liveness<'a> {
@@ -67,8 +67,8 @@ impl<'a> Liveness<'a> {
         };
 
         let mut workqueue = VecDeque::new();
-        let mut workqueue_set = FxHashSet::default();
-        let mut processed = FxHashSet::default();
+        let mut workqueue_set = HashSet::default();
+        let mut processed = HashSet::default();
         for (block, block_def) in func.blocks.entries() {
             match &block_def.terminator {
                 Terminator::Return { .. } | Terminator::Unreachable => {
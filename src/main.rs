@@ -1,21 +1,15 @@
 #![allow(dead_code)]
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
 use structopt::StructOpt;
+use waffle::entity::EntityRef;
 
-mod constant_offsets;
-mod dce;
-mod directive;
-mod escape;
-mod eval;
-mod filter;
-mod image;
-mod intrinsics;
-mod liveness;
-mod state;
-mod stats;
-mod value;
+use weval::{
+    alias, analyze, branch_hints, callgraph, custom_sections, directive, directives_section,
+    estimate, eval, features, filter, fold_log, fusion, image, inspect, intrinsics, manifest, meta,
+    passive, size_report, telemetry,
+};
 
 const STUBS: &'static str = include_str!("../lib/weval-stubs.wat");
 
@@ -47,6 +41,424 @@ pub enum Command {
         /// Output IR for generic and specialized functions to files in a directory.
         #[structopt(long = "output-ir")]
         output_ir: Option<PathBuf>,
+
+        /// Output the final abstract state at entry to each
+        /// specialized block, bincode-serialized, to the given file.
+        #[structopt(long = "output-block-states")]
+        output_block_states: Option<PathBuf>,
+
+        /// What to do with the generic function targeted by a weval
+        /// site after specialization, as `<user_id>=<policy>`, where
+        /// policy is `keep` (default), `cold`, or `remove`.
+        #[structopt(long = "generic-func-policy")]
+        generic_func_policy: Vec<directive::GenericFuncPolicyArg>,
+
+        /// Keep functions, globals, and element segments that are
+        /// unreachable from the module's exports, start function, and
+        /// table, rather than removing them from the output.
+        #[structopt(long = "no-gc")]
+        no_gc: bool,
+
+        /// What to do with the module's start function, if any:
+        /// `keep` it (specialization sees memory as before it runs),
+        /// `run` it now and bake its memory effects into the output
+        /// in its place, or `remove` it. Irrelevant with `-w`, as
+        /// Wizer runs and removes the start function.
+        #[structopt(long = "start-func", default_value = "keep")]
+        start_func: image::StartFuncMode,
+
+        /// Precision of alias analysis used by memory optimizations
+        /// on specialized code: `none`, `base-offset` (default), or
+        /// `region` (also distinguishes static data from the shadow
+        /// stack).
+        #[structopt(long = "alias-precision", default_value = "base-offset")]
+        alias_precision: alias::AliasPrecision,
+
+        /// A side module sharing the main module's memory and table
+        /// (Emscripten dynamic linking), as
+        /// `<input>,<output>,<memory_base>,<table_base>`. Requests
+        /// whose function pointer falls in the side module's table
+        /// slots are specialized into its output, against the main
+        /// module's memory image. The bases must match where the
+        /// loader places the side module.
+        #[structopt(long = "side-module")]
+        side_module: Vec<image::SideModuleArg>,
+
+        /// Output a manifest of all specializations, with the region
+        /// epochs each assumed and whether it is stale,
+        /// bincode-serialized, to the given file.
+        #[structopt(long = "output-manifest")]
+        output_manifest: Option<PathBuf>,
+
+        /// Maximum number of extra blocks per specialization created
+        /// by specializing a block on a constant blockparam whose
+        /// predecessors pass different constants (e.g. an
+        /// interpreter's current opcode). Zero disables this.
+        #[structopt(long = "max-hoisted-blocks", default_value = "256")]
+        max_hoisted_blocks: usize,
+
+        /// Drop the values of globals from the per-block states of a
+        /// context once its region (the context and those nested in
+        /// it) has no blocks left to evaluate. This bounds the memory
+        /// held for functions with very many specialized blocks, at
+        /// the cost of precision if the context is entered again:
+        /// globals are then unknown on entry to its blocks.
+        #[structopt(long = "retire-drained-contexts")]
+        retire_drained_contexts: bool,
+
+        /// The engine profile the output must load on: `mvp`,
+        /// `wasm2`, or `latest` (default). The output is validated
+        /// against it.
+        #[structopt(long = "target-profile", default_value = "latest")]
+        target_profile: features::TargetProfile,
+
+        /// Post-MVP features the output may use, as a comma-separated
+        /// list of `bulk-memory`, `ref-types`, and `tail-call`, or
+        /// `none`. Defaults to all that the target profile supports.
+        #[structopt(long = "output-features")]
+        output_features: Option<features::OutputFeatures>,
+
+        /// Priority of a weval site's requests, as
+        /// `<user_id>=<priority>`. Higher-priority requests are
+        /// specialized first and skipped last under
+        /// `--max-added-bytes`. Sites not listed have priority 0.
+        #[structopt(long = "priority")]
+        priority: Vec<directive::PriorityArg>,
+
+        /// Maximum total size in bytes of specialized function
+        /// bodies added to the output (across the main module and
+        /// any side modules). Once a request does not fit, it and all
+        /// lower-priority requests are skipped and reported.
+        #[structopt(long = "max-added-bytes")]
+        max_added_bytes: Option<usize>,
+
+        /// Under `--max-added-bytes`, emit requests that do not fit
+        /// as wrappers binding their constant params (as with
+        /// `-O wrap`) if those fit, rather than skipping them.
+        #[structopt(long = "wrap-over-budget")]
+        wrap_over_budget: bool,
+
+        /// Optimization level: `wrap` (emit only a wrapper calling
+        /// the generic function with constant params bound), 0 (emit
+        /// the generic function with constant params bound), 1
+        /// (constant-fold only), 2 (also specialize on contexts), or 3
+        /// (also optimize memory accesses; default).
+        #[structopt(short = "O", long = "opt-level", default_value = "3")]
+        opt_level: directive::OptLevel,
+
+        /// Optimization level for a weval site's requests, as
+        /// `<user_id>=<level>`, overriding `-O`.
+        #[structopt(long = "opt-level-for")]
+        opt_level_for: Vec<directive::OptLevelArg>,
+
+        /// Do not specialize paths that always reach `unreachable`
+        /// (e.g., an invalid-opcode handler on a constant opcode):
+        /// emit a trap in their place and warn. Side effects on such
+        /// paths, such as printing an error, are dropped.
+        #[structopt(long = "prune-unreachable")]
+        prune_unreachable: bool,
+
+        /// Index of a metering (fuel) global that the module's
+        /// instrumentation decrements per executed instruction. Each
+        /// specialized block is charged for the generic instructions
+        /// folded away, so that metering the output charges the same
+        /// as the generic code. Applies to the main module only.
+        #[structopt(long = "metering-global")]
+        metering_global: Option<u32>,
+
+        /// Units charged to `--metering-global` per folded
+        /// instruction.
+        #[structopt(long = "metering-cost", default_value = "1")]
+        metering_cost: u64,
+
+        /// Specialize the first iteration of each loop annotated with
+        /// `weval_peel_loop()` separately, so that state it
+        /// establishes (e.g., a lazily initialized cache) may be
+        /// constant in the rest of the loop.
+        #[structopt(long = "peel-loops")]
+        peel_loops: bool,
+
+        /// After specializing, also run waffle's optimization passes
+        /// over every other function in the module. Slower: functions
+        /// are otherwise copied through without being recompiled.
+        #[structopt(long = "optimize-all-funcs")]
+        optimize_all_funcs: bool,
+
+        /// A global to specialize a weval site's code on, as
+        /// `<user_id>=<global index>`; may be given more than once.
+        /// Wherever the global holds a known integer, blocks are
+        /// specialized on its value as with a PC, so that it stays
+        /// constant across loop backedges. Requires -O2 or higher.
+        #[structopt(long = "context-global")]
+        context_global: Vec<directive::ContextGlobalArg>,
+
+        /// Report, per request, every load from a known address whose
+        /// value was not folded, with the reason (e.g., memory not
+        /// tagged constant), its block, and the number of contexts.
+        #[structopt(long = "explain-loads")]
+        explain_loads: bool,
+
+        /// Specialize against the memory and globals in this Wasm
+        /// core dump (e.g., from `wasmtime run -D coredump=...`) of
+        /// the input module, rather than the module's own initial
+        /// state, for state snapshotted without Wizer. The snapshot
+        /// is written into the output. Conflicts with -w.
+        #[structopt(long = "snapshot")]
+        snapshot: Option<PathBuf>,
+
+        /// An environment constant for `weval_env_u32()`, as
+        /// `<key>=<value>`, for every request; may be given more than
+        /// once.
+        #[structopt(long = "env")]
+        env: Vec<directive::EnvArg>,
+
+        /// An environment constant for one weval site's requests, as
+        /// `<user_id>=<key>=<value>`; overrides `--env`.
+        #[structopt(long = "env-for")]
+        env_for: Vec<directive::SiteEnvArg>,
+
+        /// Attribute the bytes of each specialized function to the
+        /// generic blocks and contexts they came from, and write the
+        /// result to the given file in folded-stack format (for
+        /// `flamegraph.pl` or `inferno-flamegraph`).
+        #[structopt(long = "output-size-report")]
+        output_size_report: Option<PathBuf>,
+
+        /// At indirect calls whose table index is one of a few
+        /// constants chosen at runtime (e.g., a `select` of two
+        /// handlers), emit a direct call per candidate, guarded by a
+        /// check of the index, with the indirect call as a fallback.
+        #[structopt(long = "guarded-devirt")]
+        guarded_devirt: bool,
+
+        /// In functions with no context intrinsics, detect dispatch
+        /// loops -- loops with a `br_table` or `select` on a value
+        /// loaded through a blockparam of the loop header, taken as
+        /// the PC -- and specialize them per PC as if annotated with
+        /// `weval_push_context()` and friends.
+        #[structopt(long = "auto-loop-headers")]
+        auto_loop_headers: bool,
+
+        /// Append a table of the specializations (site, generic
+        /// function, function pointer, and argument hash) to memory,
+        /// and export `weval_metadata()` returning its address. See
+        /// `weval_metadata_t` in `weval.h`.
+        #[structopt(long = "emit-metadata")]
+        emit_metadata: bool,
+
+        /// At each specialized join point, check at runtime that
+        /// blockparams assumed constant hold their assumed values,
+        /// and trap otherwise, in `weval_const_assertion_failed` with
+        /// the specialized block's index as argument. For qualifying
+        /// new directive sets.
+        #[structopt(long = "debug-assert-consts")]
+        debug_assert_consts: bool,
+
+        /// Process only the request with this ID, as printed in logs
+        /// and reports (16 hex digits, derived from the function,
+        /// arguments and output address). May be given more than
+        /// once.
+        #[structopt(long = "only-id")]
+        only_id: Vec<directive::DirectiveId>,
+
+        /// Assume a value for an imported global, given as
+        /// `<module>.<name>=<value>` (floats as bits), so that reads
+        /// of it fold. The global must be immutable. May be given
+        /// more than once.
+        #[structopt(long = "imported-global")]
+        imported_global: Vec<directive::ImportedGlobalArg>,
+
+        /// For a weval site's requests, point function-table entries
+        /// (from active element segments) that refer to the generic
+        /// function at its specialization instead, so that dispatch
+        /// through the table reaches specialized code without
+        /// patching memory. Only valid if every call through those
+        /// entries passes the request's constant arguments. Skipped,
+        /// with a warning, if the site has more than one
+        /// specialization of the function. May be given more than
+        /// once.
+        #[structopt(long = "rewrite-elems-for")]
+        rewrite_elems_for: Vec<u32>,
+
+        /// An imported function, as `<module>.<name>`, known not to
+        /// write guest memory (e.g., a clock, random source or
+        /// logger), so that calls to it do not stop forwarding stored
+        /// values to later loads. May be given more than once.
+        #[structopt(long = "benign-import")]
+        benign_import: Vec<directive::ImportName>,
+
+        /// Move each constant (larger than a `global.get`) defined in
+        /// at least this many specialized functions into an immutable
+        /// global, read with `global.get` instead. Specialized
+        /// functions are compiled a batch at a time, so those compiled
+        /// before a constant reaches the count keep their definitions.
+        #[structopt(long = "const-globals")]
+        const_globals: Option<usize>,
+
+        /// Split each specialized function into one function per this
+        /// many bytecode PCs, entered through tail calls, so that an
+        /// engine compiling functions lazily compiles only the regions
+        /// that run. Requires the `tail-call` output feature.
+        #[structopt(long = "split-pc-range")]
+        split_pc_range: Option<u32>,
+
+        /// Specialize the directive with the first ID relative to the
+        /// one with the second, which targets the same function with a
+        /// subset of its constants: reuse the base's contexts and the
+        /// facts it found. Given as `<id>=<base-id>`; may be repeated.
+        #[structopt(long = "delta")]
+        delta: Vec<directive::DeltaArg>,
+
+        /// Do not fold operators of the given classes in the weval site
+        /// with the given user ID, as `<user_id>=<class>[,<class>...]`
+        /// where a class is one of `int`, `compare`, `float`, `simd`,
+        /// `load`, `select`, `call`, or `all`; may be repeated. A
+        /// workaround for, and a way to bisect, a suspected mis-fold.
+        #[structopt(long = "no-fold-for")]
+        no_fold_for: Vec<directive::NoFoldArg>,
+
+        /// Write a log of every fold performed to the given file, one
+        /// per line: the directive, context, generic value, operator,
+        /// abstract values of its inputs, and result.
+        #[structopt(long = "emit-fold-log")]
+        emit_fold_log: Option<PathBuf>,
+
+        /// What to do when a load from constant memory reads out of
+        /// bounds: `degrade` (default) leaves it to runtime, `warn`
+        /// also reports its address, size, load and context, and
+        /// `fail` also leaves the directive unspecialized.
+        #[structopt(long = "oob-const-reads", default_value = "degrade")]
+        oob_const_reads: directive::OutOfBoundsReads,
+
+        /// Specialize only bytecode PCs in `[lo, hi)` for the weval
+        /// site with the given user ID, as `<user_id>=<lo>..<hi>`; may
+        /// be repeated. Other PCs run in the generic dispatch loop,
+        /// keeping cold regions of a large interpreter out of the
+        /// output.
+        #[structopt(long = "pc-range-for")]
+        pc_range_for: Vec<directive::PcRangeArg>,
+
+        /// Fold `weval_barrier_needed` checks on constants to "not
+        /// needed", eliding the write barriers they guard: `none`
+        /// (default), `immediates` for constants that are not heap
+        /// references, or `constants` for all constants, including
+        /// references into the snapshot (sound only if the GC never
+        /// needs a barrier for those).
+        #[structopt(long = "elide-barriers", default_value = "none")]
+        elide_barriers: directive::BarrierElision,
+
+        /// Specialize a module with a shared (threads) memory. Other
+        /// threads may write such a memory at any time, so nothing in
+        /// it is treated as constant except what directives supply
+        /// explicitly (constant-memory arguments).
+        #[structopt(long = "allow-shared-memory")]
+        allow_shared_memory: bool,
+
+        /// Write metrics of the run and of each directive (durations,
+        /// queue lengths, fold counts, and delta-base hits) to the
+        /// given file, as JSON.
+        #[structopt(long = "metrics")]
+        metrics: Option<PathBuf>,
+
+        /// Export the same metrics to the OpenTelemetry collector at
+        /// the given OTLP/HTTP endpoint. Requires weval built with
+        /// the `otel` feature.
+        #[structopt(long = "otlp-endpoint")]
+        otlp_endpoint: Option<String>,
+
+        /// What to do about calls to weval intrinsics whose arguments
+        /// can't be acted on (e.g. `weval_update_context` with a
+        /// runtime PC, or `weval_env_u32` with an unknown key), which
+        /// otherwise degrade silently: `off` (default), `warn` to list
+        /// them per specialization, or `error` to fail.
+        #[structopt(long = "strict-intrinsics", default_value = "off")]
+        strict_intrinsics: directive::StrictIntrinsics,
+
+        /// Apply macro-op fusion to specialized code at `-O3`:
+        /// rewrite common interpreter sequences (stack-pointer and PC
+        /// adjustments, inverted branch conditions) to minimal
+        /// equivalents.
+        #[structopt(long = "fuse")]
+        fuse: bool,
+
+        /// Extra fusion patterns, one per line, as `name: pattern =>
+        /// replacement` (see the `fusion` module). Implies `--fuse`.
+        #[structopt(long = "fusion-patterns")]
+        fusion_patterns: Option<PathBuf>,
+
+        /// Time in milliseconds to allow each specialization. One that
+        /// runs out keeps what it has specialized and continues in a
+        /// copy of the generic function wherever it stopped, entered
+        /// through tail calls; it is reported as partial. Requires
+        /// the `tail-call` output feature.
+        #[structopt(long = "timeout")]
+        timeout: Option<u64>,
+
+        /// Override `--timeout` for the weval site with the given user
+        /// ID, as `<user_id>=<ms>`; may be repeated.
+        #[structopt(long = "timeout-for")]
+        timeout_for: Vec<directive::TimeoutArg>,
+    },
+
+    /// Run the abstract interpreter over all weval requests without
+    /// emitting code, and report where precision is lost (runtime
+    /// branches and loads from unproven memory) or, with
+    /// `--estimate`, how large their specializations would be.
+    Analyze {
+        /// The input Wasm module.
+        #[structopt(short = "i")]
+        input_module: PathBuf,
+
+        /// Whether to Wizen the module first.
+        #[structopt(short = "w")]
+        wizen: bool,
+
+        /// A collection of pre-collected weval requests, if any, to
+        /// analyze as well.
+        #[structopt(short = "c")]
+        corpus: Option<PathBuf>,
+
+        /// Also write the analysis results, bincode-serialized, to
+        /// the given file.
+        #[structopt(short = "o")]
+        output: Option<PathBuf>,
+
+        /// Instead of reporting precision losses, estimate the size
+        /// of each directive's specialization (contexts, blocks and
+        /// bytes of code) and each site's total, to triage which
+        /// directives are worth specializing.
+        #[structopt(long = "estimate")]
+        estimate: bool,
+
+        /// With `--estimate`, evaluate at most this many directives
+        /// per weval site, evenly spaced, and extrapolate the site's
+        /// totals from them.
+        #[structopt(long = "estimate-sample")]
+        estimate_sample: Option<usize>,
+
+        /// With `--estimate`, stop evaluating a directive once it has
+        /// this many blocks; its estimate is then a lower bound.
+        #[structopt(long = "estimate-max-blocks")]
+        estimate_max_blocks: Option<usize>,
+    },
+
+    /// Inspect the snapshot that specialization would see: dump
+    /// memory, list globals, follow pointers, and look up table
+    /// entries. Runs the given commands, or reads commands from
+    /// stdin if none are given.
+    Inspect {
+        /// The input Wasm module.
+        #[structopt(short = "i")]
+        input_module: PathBuf,
+
+        /// Whether to Wizen the module first.
+        #[structopt(short = "w")]
+        wizen: bool,
+
+        /// A command to run (e.g. `mem 0x1000 64 u32`); may be given
+        /// more than once. Run with `-e help` for a list.
+        #[structopt(short = "e")]
+        command: Vec<String>,
     },
 
     /// Pre-compile a Wasm module for weval request collection, using
@@ -102,6 +514,55 @@ pub enum Command {
         #[structopt(last = true)]
         inputs: Vec<PathBuf>,
     },
+
+    /// Compare the manifests (from `--output-manifest`) of two runs:
+    /// list the directives added, removed, or specialized under
+    /// changed code or assumptions, and count the specializations
+    /// the new run could not reuse from the old.
+    DiffDirectives {
+        /// The manifest of the old run.
+        old: PathBuf,
+
+        /// The manifest of the new run.
+        new: PathBuf,
+    },
+
+    /// Convert directives between the `weval.directives` custom
+    /// section and its text form, so that build scripts can embed
+    /// directives in a module.
+    Directives(DirectivesCommand),
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub enum DirectivesCommand {
+    /// Encode directives in the text form as the section.
+    Encode {
+        /// The directives, in the text form.
+        #[structopt(short = "i")]
+        input: PathBuf,
+
+        /// The output: the section's contents, or with `-m`, the
+        /// module with the section appended.
+        #[structopt(short = "o")]
+        output: PathBuf,
+
+        /// A Wasm module to which to append the section.
+        #[structopt(short = "m")]
+        module: Option<PathBuf>,
+
+        /// Write the section as a WAT custom-section annotation
+        /// instead, for inclusion in a text-format module.
+        #[structopt(long = "wat")]
+        wat: bool,
+    },
+
+    /// Decode the section, from a Wasm module or as raw contents, to
+    /// the text form on stdout.
+    Decode {
+        /// The Wasm module or section contents.
+        #[structopt(short = "i")]
+        input: PathBuf,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
@@ -116,6 +577,57 @@ fn main() -> anyhow::Result<()> {
             corpus,
             show_stats,
             output_ir,
+            output_block_states,
+            generic_func_policy,
+            no_gc,
+            start_func,
+            alias_precision,
+            side_module,
+            output_manifest,
+            max_hoisted_blocks,
+            retire_drained_contexts,
+            target_profile,
+            output_features,
+            priority,
+            max_added_bytes,
+            opt_level,
+            opt_level_for,
+            prune_unreachable,
+            metering_global,
+            metering_cost,
+            peel_loops,
+            optimize_all_funcs,
+            context_global,
+            explain_loads,
+            snapshot,
+            env,
+            env_for,
+            output_size_report,
+            guarded_devirt,
+            auto_loop_headers,
+            emit_metadata,
+            debug_assert_consts,
+            only_id,
+            imported_global,
+            wrap_over_budget,
+            rewrite_elems_for,
+            benign_import,
+            const_globals,
+            split_pc_range,
+            delta,
+            no_fold_for,
+            emit_fold_log,
+            oob_const_reads,
+            pc_range_for,
+            elide_barriers,
+            allow_shared_memory,
+            metrics,
+            otlp_endpoint,
+            strict_intrinsics,
+            fuse,
+            fusion_patterns,
+            timeout,
+            timeout_for,
         } => weval(
             input_module,
             output_module,
@@ -123,7 +635,82 @@ fn main() -> anyhow::Result<()> {
             corpus,
             show_stats,
             output_ir,
+            output_block_states,
+            generic_func_policy,
+            !no_gc,
+            start_func,
+            alias_precision,
+            side_module,
+            output_manifest,
+            max_hoisted_blocks,
+            retire_drained_contexts,
+            target_profile,
+            output_features,
+            priority,
+            max_added_bytes,
+            opt_level,
+            opt_level_for,
+            prune_unreachable,
+            metering_global,
+            metering_cost,
+            peel_loops,
+            optimize_all_funcs,
+            context_global,
+            explain_loads,
+            snapshot,
+            env,
+            env_for,
+            output_size_report,
+            guarded_devirt,
+            auto_loop_headers,
+            emit_metadata,
+            debug_assert_consts,
+            only_id,
+            imported_global,
+            wrap_over_budget,
+            rewrite_elems_for,
+            benign_import,
+            const_globals,
+            split_pc_range,
+            delta,
+            no_fold_for,
+            emit_fold_log,
+            oob_const_reads,
+            pc_range_for,
+            elide_barriers,
+            allow_shared_memory,
+            metrics,
+            otlp_endpoint,
+            strict_intrinsics,
+            fuse,
+            fusion_patterns,
+            timeout,
+            timeout_for,
         ),
+        Command::Analyze {
+            input_module,
+            wizen,
+            corpus,
+            output,
+            estimate,
+            estimate_sample,
+            estimate_max_blocks,
+        } => {
+            let estimate = if estimate {
+                Some(estimate::EstimateOptions {
+                    sample: estimate_sample,
+                    max_blocks: estimate_max_blocks,
+                })
+            } else {
+                None
+            };
+            analyze(input_module, wizen, corpus, output, estimate)
+        }
+        Command::Inspect {
+            input_module,
+            wizen,
+            command,
+        } => inspect(input_module, wizen, command),
         Command::Precompile {
             input_module,
             output_precompiled,
@@ -144,6 +731,8 @@ fn main() -> anyhow::Result<()> {
             args,
         ),
         Command::Merge { output, inputs } => merge(output, inputs),
+        Command::DiffDirectives { old, new } => diff_directives(old, new),
+        Command::Directives(cmd) => directives(cmd),
     }
 }
 
@@ -165,63 +754,422 @@ fn weval(
     corpus: Option<PathBuf>,
     show_stats: bool,
     output_ir: Option<PathBuf>,
+    output_block_states: Option<PathBuf>,
+    generic_func_policy: Vec<directive::GenericFuncPolicyArg>,
+    gc: bool,
+    start_func: image::StartFuncMode,
+    alias_precision: alias::AliasPrecision,
+    side_modules: Vec<image::SideModuleArg>,
+    output_manifest: Option<PathBuf>,
+    max_hoisted_blocks: usize,
+    retire_drained_contexts: bool,
+    target_profile: features::TargetProfile,
+    output_features: Option<features::OutputFeatures>,
+    priority: Vec<directive::PriorityArg>,
+    max_added_bytes: Option<usize>,
+    opt_level: directive::OptLevel,
+    opt_level_for: Vec<directive::OptLevelArg>,
+    prune_unreachable: bool,
+    metering_global: Option<u32>,
+    metering_cost: u64,
+    peel_loops: bool,
+    optimize_all_funcs: bool,
+    context_global: Vec<directive::ContextGlobalArg>,
+    explain_loads: bool,
+    snapshot_file: Option<PathBuf>,
+    env: Vec<directive::EnvArg>,
+    env_for: Vec<directive::SiteEnvArg>,
+    output_size_report: Option<PathBuf>,
+    guarded_devirt: bool,
+    auto_loop_headers: bool,
+    emit_metadata: bool,
+    debug_assert_consts: bool,
+    only_id: Vec<directive::DirectiveId>,
+    imported_global: Vec<directive::ImportedGlobalArg>,
+    wrap_over_budget: bool,
+    rewrite_elems_for: Vec<u32>,
+    benign_import: Vec<directive::ImportName>,
+    const_globals: Option<usize>,
+    split_pc_range: Option<u32>,
+    delta: Vec<directive::DeltaArg>,
+    no_fold_for: Vec<directive::NoFoldArg>,
+    emit_fold_log: Option<PathBuf>,
+    oob_const_reads: directive::OutOfBoundsReads,
+    pc_range_for: Vec<directive::PcRangeArg>,
+    elide_barriers: directive::BarrierElision,
+    allow_shared_memory: bool,
+    metrics: Option<PathBuf>,
+    otlp_endpoint: Option<String>,
+    strict_intrinsics: directive::StrictIntrinsics,
+    fuse: bool,
+    fusion_patterns: Option<PathBuf>,
+    timeout: Option<u64>,
+    timeout_for: Vec<directive::TimeoutArg>,
 ) -> anyhow::Result<()> {
+    let mut timer = telemetry::Timer::new();
+    if do_wizen && snapshot_file.is_some() {
+        anyhow::bail!("--snapshot cannot be combined with -w");
+    }
+
+    let output_features = output_features.unwrap_or(target_profile.max_features());
+    output_features.check(target_profile)?;
+    if split_pc_range.is_some() && !output_features.tail_call {
+        anyhow::bail!("--split-pc-range requires the tail-call output feature");
+    }
+    if (timeout.is_some() || !timeout_for.is_empty()) && !output_features.tail_call {
+        anyhow::bail!("--timeout requires the tail-call output feature");
+    }
+    if split_pc_range == Some(0) {
+        anyhow::bail!("--split-pc-range must be at least 1");
+    }
+    #[cfg(not(feature = "otel"))]
+    if otlp_endpoint.is_some() {
+        anyhow::bail!("--otlp-endpoint requires weval built with the `otel` feature");
+    }
+
     let raw_bytes = std::fs::read(&input_module)?;
+    let custom_sections = custom_sections::read(&raw_bytes[..])?;
 
     // Optionally, Wizen the module first.
     let module_bytes = if do_wizen {
-        wizen(raw_bytes)?
+        let bytes = wizen(raw_bytes)?;
+        timer.phase("wizen");
+        bytes
     } else {
         raw_bytes
     };
 
-    // Load module.
+    // Load module. The frontend does not handle `memory.init` or
+    // `data.drop`, so those are outlined into helpers first.
     let mut frontend_opts = waffle::FrontendOptions::default();
     frontend_opts.debug = true;
-    let module = waffle::Module::from_wasm_bytes(&module_bytes[..], &frontend_opts)?;
-
-    // Build module image.
-    let mut im = image::build_image(&module, None)?;
+    let outlined = passive::outline(&module_bytes[..])?;
+    let mut module = match &outlined {
+        Some((bytes, helpers)) => {
+            let mut module = waffle::Module::from_wasm_bytes(&bytes[..], &frontend_opts)?;
+            passive::install(&mut module, helpers);
+            module
+        }
+        None => waffle::Module::from_wasm_bytes(&module_bytes[..], &frontend_opts)?,
+    };
+    let branch_hints = branch_hints::read(&module_bytes[..])?;
 
-    // Collect directives.
-    let directives = directive::collect(&module, &mut im)?;
-    log::debug!("Directives: {:?}", directives);
+    // Handle the start function, if any, so that the image we
+    // specialize against and write back is consistent with whether it
+    // runs.
+    let snapshot = match (start_func, module.start_func) {
+        (_, None) => None,
+        (image::StartFuncMode::Keep, Some(f)) => {
+            log::warn!(
+                "Module has start function {}: specialization assumes memory as before it runs",
+                f
+            );
+            None
+        }
+        (image::StartFuncMode::Run, Some(f)) => {
+            if let Some(effect) = callgraph::find_unrecordable_effect(&module, f)? {
+                anyhow::bail!(
+                    "Cannot run start function {} at weval time: {}; use -w instead",
+                    f,
+                    effect
+                );
+            }
+            log::info!("Running start function {} and snapshotting memory", f);
+            let bytes = run_start(&module_bytes[..])?;
+            module.start_func = None;
+            Some(bytes)
+        }
+        (image::StartFuncMode::Remove, Some(f)) => {
+            log::info!("Removing start function {}", f);
+            module.start_func = None;
+            None
+        }
+    };
 
-    // Get any corpus of pre-collected directives as well.
-    let corpus = match corpus {
+    // Build module image.
+    let mut im = match &snapshot_file {
         Some(path) => {
-            let bytes = std::fs::read(&path)?;
-            let directives: Vec<directive::Directive> = bincode::deserialize(&bytes[..])?;
-            directives
-        }
-        None => {
-            vec![]
+            if snapshot.is_some() {
+                anyhow::bail!("--snapshot cannot be combined with running the start function");
+            }
+            image::Image::from_wasmtime_snapshot(&module, &std::fs::read(path)?[..])?
         }
+        None => image::build_image(&module, snapshot.as_deref())?,
     };
+    im.detect_shared_memories(&module_bytes[..])?;
+    im.detect_immutable_tables(&module_bytes[..])?;
+    im.detect_passive_segments(&module_bytes[..])?;
+    if let Some((_, helpers)) = outlined {
+        im.passive_ops = helpers;
+    }
+    if !im.shared_memories.is_empty() && !allow_shared_memory {
+        anyhow::bail!(
+            "Module has a shared memory, whose contents other threads may change; \
+             pass --allow-shared-memory to specialize it without treating them as constant"
+        );
+    }
+
+    // Load any side modules, and link them into the image so that
+    // function pointers into their table slots resolve.
+    let side_bytes = side_modules
+        .iter()
+        .map(|arg| std::fs::read(&arg.input))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut side = vec![];
+    for (arg, bytes) in side_modules.iter().zip(side_bytes.iter()) {
+        let side_module = waffle::Module::from_wasm_bytes(&bytes[..], &frontend_opts)?;
+        im.link_side_module(&side_module, arg.memory_base, arg.table_base);
+        side.push(side_module);
+    }
+
+    timer.phase("load");
+
+    // Collect directives, and any corpus of pre-collected directives
+    // as well.
+    let (directives, corpus) = directive::collect_from(
+        &mut directive_providers(corpus, &module_bytes[..])?,
+        &module,
+        &mut im,
+    )?;
+    log::debug!("Directives: {:?}", directives);
+    timer.phase("collect");
+    let (directives, side_directives): (Vec<_>, Vec<_>) =
+        directives.into_iter().partition(|d| d.module == 0);
 
     // Make sure IR output directory exists.
     if let Some(dir) = &output_ir {
         std::fs::create_dir_all(dir)?;
     }
 
+    let fusion_patterns = match (fuse, fusion_patterns) {
+        (false, None) => vec![],
+        (_, extra) => {
+            let mut patterns = fusion::default_patterns();
+            if let Some(path) = extra {
+                patterns.extend(fusion::parse(&std::fs::read_to_string(path)?)?);
+            }
+            patterns
+        }
+    };
+
     // Partially evaluate.
-    let progress = indicatif::ProgressBar::new(0);
+    let opts = eval::PartialEvalOptions {
+        output_ir,
+        output_block_states,
+        generic_func_policies: generic_func_policy
+            .iter()
+            .map(|arg| (arg.user_id, arg.policy))
+            .collect(),
+        alias_precision,
+        max_hoisted_blocks,
+        retire_drained_contexts,
+        priorities: priority
+            .iter()
+            .map(|arg| (arg.user_id, arg.priority))
+            .collect(),
+        max_added_bytes,
+        opt_level,
+        opt_levels: opt_level_for
+            .iter()
+            .map(|arg| (arg.user_id, arg.level))
+            .collect(),
+        prune_unreachable,
+        metering: metering_global.map(|global| eval::Metering {
+            global: waffle::Global::from(global),
+            cost: metering_cost,
+        }),
+        peel_loops,
+        optimize_all_funcs,
+        context_globals: context_global
+            .iter()
+            .fold(BTreeMap::new(), |mut globals, arg| {
+                globals
+                    .entry(arg.user_id)
+                    .or_insert_with(Vec::new)
+                    .push(arg.global);
+                globals
+            }),
+        explain_loads,
+        env: env.into_iter().map(|arg| (arg.key, arg.value)).collect(),
+        site_env: env_for
+            .into_iter()
+            .fold(BTreeMap::new(), |mut site_env, arg| {
+                site_env
+                    .entry(arg.user_id)
+                    .or_insert_with(BTreeMap::new)
+                    .insert(arg.env.key, arg.env.value);
+                site_env
+            }),
+        size_report: output_size_report.is_some(),
+        guarded_devirt,
+        auto_loop_headers,
+        emit_metadata,
+        debug_assert_consts,
+        only_ids: only_id,
+        imported_globals: imported_global
+            .into_iter()
+            .map(|arg| ((arg.module, arg.name), arg.value))
+            .collect(),
+        wrap_over_budget,
+        rewrite_elems: rewrite_elems_for.into_iter().collect(),
+        benign_imports: benign_import
+            .into_iter()
+            .map(|import| (import.module, import.name))
+            .collect(),
+        const_globals_min_defs: const_globals,
+        split_pc_range,
+        delta_bases: delta.iter().map(|arg| (arg.id, arg.base)).collect(),
+        no_fold: no_fold_for
+            .into_iter()
+            .fold(BTreeMap::new(), |mut map, arg| {
+                map.entry(arg.user_id)
+                    .or_insert_with(BTreeSet::new)
+                    .extend(arg.classes);
+                map
+            }),
+        fold_log: emit_fold_log.is_some(),
+        oob_reads: oob_const_reads,
+        pc_ranges: pc_range_for
+            .iter()
+            .map(|arg| (arg.user_id, (arg.lo, arg.hi)))
+            .collect(),
+        barrier_elision: elide_barriers,
+        metrics: metrics.is_some() || otlp_endpoint.is_some(),
+        strict_intrinsics,
+        fusion_patterns,
+        timeout: timeout.map(std::time::Duration::from_millis),
+        timeouts: timeout_for
+            .iter()
+            .map(|arg| (arg.user_id, arg.timeout))
+            .collect(),
+        ..Default::default()
+    };
+    let progress = indicatif::ProgressBar::new(directives.len() as u64);
+    let tick = || progress.inc(1);
     let mut result = eval::partially_evaluate(
         module,
         &mut im,
         &directives[..],
         &corpus[..],
-        Some(progress),
-        output_ir,
+        Some(&tick),
+        &opts,
+        &branch_hints,
     )?;
+    timer.phase("specialize");
+
+    // Specialize side modules against the shared memory. They import
+    // it, so only the main module carries the updated image.
+    for (i, (arg, side_module)) in side_modules.iter().zip(side.into_iter()).enumerate() {
+        let index = i + 1;
+        let directives = side_directives
+            .iter()
+            .filter(|d| d.module == index)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut side_im = image::build_side_module_image(&im, index, &side_module)?;
+        let side_opts = eval::PartialEvalOptions {
+            table_base: arg.table_base,
+            metering: None,
+            max_added_bytes: opts
+                .max_added_bytes
+                .map(|max| max.saturating_sub(result.added_bytes)),
+            ..opts.clone()
+        };
+        let side_result = eval::partially_evaluate(
+            side_module,
+            &mut side_im,
+            &directives[..],
+            &[],
+            None,
+            &side_opts,
+            &branch_hints::read(&side_bytes[i][..])?,
+        )?;
+        im.memories = side_im.memories;
+        result.added_bytes += side_result.added_bytes;
+        result
+            .size_reports
+            .extend(side_result.size_reports.iter().cloned());
+        result
+            .fold_logs
+            .extend(side_result.fold_logs.iter().cloned());
+        result
+            .directive_metrics
+            .extend(side_result.directive_metrics.iter().cloned());
+        result.skipped.extend(side_result.skipped.iter().cloned());
+        result.wrapped.extend(side_result.wrapped.iter().cloned());
+        result.partial.extend(side_result.partial.iter().cloned());
+        result
+            .manifest
+            .entries
+            .extend(side_result.manifest.entries.iter().cloned());
+
+        let table_size = side_result
+            .module
+            .tables
+            .values()
+            .next()
+            .and_then(|table| table.func_elements.as_ref())
+            .map(|elems| elems.len() as u32)
+            .unwrap_or(0);
+        let bytes = side_result.module.to_wasm_bytes()?;
+        let cold_funcs = side_result
+            .cold_funcs
+            .iter()
+            .map(|f| f.index() as u32)
+            .collect::<Vec<_>>();
+        let mut bytes = filter::filter(
+            &bytes[..],
+            &cold_funcs[..],
+            &side_result.relocs[..],
+            &func_indices(&side_result.branch_hints),
+            &[],
+            gc,
+            table_size,
+        )?;
+        custom_sections::append(&mut bytes, &custom_sections::read(&side_bytes[i][..])?[..]);
+        meta::Meta::new(
+            &side_opts,
+            &output_features,
+            &directives[..],
+            &side_result.manifest,
+        )?
+        .append_to(&mut bytes);
+        output_features.validate(target_profile, &bytes[..])?;
+        std::fs::write(&arg.output, &bytes[..])?;
+        report_untargeted_intrinsic_uses(&side_result.untargeted_intrinsic_uses[..]);
+        report_printed_values(&side_result.printed_values[..]);
+        if explain_loads {
+            print!("{}", analyze::load_report(&side_result.load_reports[..]));
+        }
+    }
+    if !side_modules.is_empty() {
+        timer.phase("side-modules");
+    }
 
     // Update memories in module.
     image::update(&mut result.module, &im);
 
     log::debug!("Final module:\n{}", result.module.display());
 
+    // Side modules' entries were appended; restore the order.
+    result.manifest.sort();
+
+    if let Some(path) = &output_manifest {
+        result.manifest.mark_stale();
+        let dump = bincode::serialize(&result.manifest)?;
+        std::fs::write(path, dump)?;
+    }
+
+    if let Some(path) = &output_size_report {
+        std::fs::write(path, size_report::folded_stacks(&result.size_reports[..]))?;
+    }
+
+    if let Some(path) = &emit_fold_log {
+        std::fs::write(path, fold_log::render(&result.fold_logs[..]))?;
+    }
+
     if show_stats {
-        for stats in result.stats {
+        for stats in &result.stats {
             eprintln!(
                 "Function {}: {} blocks, {} insts)",
                 stats.generic, stats.generic_blocks, stats.generic_insts,
@@ -244,23 +1192,319 @@ fn weval(
                 stats.local_writes,
                 stats.local_writes_mem
             );
+            eprintln!(
+                "   operand stack: {} loads forwarded",
+                stats.stack_slot_forwards
+            );
+            eprintln!(
+                "   branches converted to selects: {}",
+                stats.select_diamonds
+            );
             eprintln!(
                 "   live values at block starts: {} ({} per block)",
                 stats.live_value_at_block_start,
                 (stats.live_value_at_block_start as f64) / (stats.specialized_blocks as f64),
             );
+            eprintln!(
+                "   block evaluations: {} ({} per specialized block)",
+                stats.block_evaluations,
+                (stats.block_evaluations as f64) / (stats.specialized_blocks as f64),
+            );
+            if stats.resumed_blocks > 0 {
+                eprintln!(
+                    "   blocks resuming generic code after a timeout: {}",
+                    stats.resumed_blocks
+                );
+            }
+            if stats.retired_contexts > 0 {
+                eprintln!(
+                    "   contexts retired once drained: {} ({} entered again)",
+                    stats.retired_contexts, stats.reentered_contexts
+                );
+            }
         }
     }
 
     let bytes = result.module.to_wasm_bytes()?;
 
-    let bytes = filter::filter(&bytes[..])?;
+    let cold_funcs = result
+        .cold_funcs
+        .iter()
+        .map(|f| f.index() as u32)
+        .collect::<Vec<_>>();
+    let mut bytes = filter::filter(
+        &bytes[..],
+        &cold_funcs[..],
+        &result.relocs[..],
+        &func_indices(&result.branch_hints),
+        &im.input_data_segments(),
+        gc,
+        0,
+    )?;
+    custom_sections::append(&mut bytes, &custom_sections[..]);
+    let all_directives = [&directives[..], &corpus[..]].concat();
+    meta::Meta::new(
+        &opts,
+        &output_features,
+        &all_directives[..],
+        &result.manifest,
+    )?
+    .append_to(&mut bytes);
+    output_features.validate(target_profile, &bytes[..])?;
 
     std::fs::write(&output_module, &bytes[..])?;
+    timer.phase("emit");
+
+    if metrics.is_some() || otlp_endpoint.is_some() {
+        let run_metrics = telemetry::RunMetrics::new(&timer, &result, bytes.len());
+        if let Some(path) = &metrics {
+            std::fs::write(path, run_metrics.to_json()?)?;
+        }
+        #[cfg(feature = "otel")]
+        if let Some(endpoint) = &otlp_endpoint {
+            run_metrics.export_otlp(endpoint)?;
+        }
+    }
+
+    report_untargeted_intrinsic_uses(&result.untargeted_intrinsic_uses[..]);
+    report_skipped_directives(&result.skipped[..]);
+    report_wrapped_directives(&result.wrapped[..]);
+    report_partial_directives(&result.partial[..]);
+    report_printed_values(&result.printed_values[..]);
+    if explain_loads {
+        print!("{}", analyze::load_report(&result.load_reports[..]));
+    }
+
+    Ok(())
+}
+
+/// Key a per-function map by the functions' indices, as the filter
+/// pass expects.
+fn func_indices<T: Clone>(map: &BTreeMap<waffle::Func, T>) -> BTreeMap<u32, T> {
+    map.iter()
+        .map(|(func, value)| (func.index() as u32, value.clone()))
+        .collect()
+}
+
+fn report_skipped_directives(skipped: &[directive::Directive]) {
+    for d in skipped {
+        eprintln!(
+            "warning: weval request {} for site {} (priority {}, {} arg bytes) skipped: output size budget exceeded",
+            d.id(),
+            d.user_id,
+            d.priority,
+            d.args.len()
+        );
+    }
+}
+
+fn report_wrapped_directives(wrapped: &[directive::Directive]) {
+    for d in wrapped {
+        eprintln!(
+            "warning: weval request {} for site {} (priority {}, {} arg bytes) not specialized: output size budget exceeded; added a wrapper binding its constant params instead",
+            d.id(),
+            d.user_id,
+            d.priority,
+            d.args.len()
+        );
+    }
+}
+
+fn report_partial_directives(partial: &[directive::Directive]) {
+    for d in partial {
+        eprintln!(
+            "warning: weval request {} for site {} ({} arg bytes) only partially specialized: timed out; the rest runs in generic code",
+            d.id(),
+            d.user_id,
+            d.args.len()
+        );
+    }
+}
+
+fn report_untargeted_intrinsic_uses(uses: &[intrinsics::UntargetedIntrinsicUse]) {
+    for u in uses {
+        let intrinsics = u
+            .intrinsics
+            .iter()
+            .map(|(name, count)| format!("weval.{} ({}x)", name, count))
+            .collect::<Vec<_>>();
+        eprintln!(
+            "warning: function {} ({}) calls intrinsics but is not targeted by any weval request; they will have no effect: {}",
+            u.func,
+            u.func_name,
+            intrinsics.join(", ")
+        );
+    }
+}
+
+fn report_printed_values(values: &[eval::PrintedValue]) {
+    for v in values {
+        eprintln!(
+            "note: weval.print.value at site {} (directive {}): {}{}: in context [{}]: {}",
+            v.user_id,
+            v.id,
+            v.label,
+            v.loc
+                .as_ref()
+                .map(|loc| format!(" ({})", loc))
+                .unwrap_or_default(),
+            v.context_stack.join(", "),
+            v.value
+        );
+    }
+}
+
+/// Instantiate the module, which runs its start function, and return
+/// a snapshot of its main memory afterward.
+fn run_start(module_bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let engine = wasmtime::Engine::new(&wasmtime::Config::default())?;
+    let module = wasmtime::Module::new(&engine, module_bytes)?;
+
+    let mut wasi = wasmtime_wasi::WasiCtxBuilder::new();
+    wasi.inherit_stdout().inherit_stderr();
+    let wasi = wasi.build_p1();
+
+    let mut linker = wasmtime::Linker::new(&engine);
+    let mut store = wasmtime::Store::new(&engine, wasi);
+    wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |s| s)?;
+    let stubs_module = wasmtime::Module::new(&engine, STUBS.as_bytes())?;
+    let stubs = wasmtime::Instance::new(&mut store, &stubs_module, &[])?;
+    linker.instance(&mut store, "weval", stubs)?;
+    let instance = linker.instantiate(&mut store, &module)?;
+    let memory = instance
+        .exports(&mut store)
+        .filter_map(|e| e.into_memory())
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no exported memory to snapshot"))?;
+    Ok(memory.data(&store)[..].to_vec())
+}
+
+/// The built-in directive sources: the snapshot's pending-request
+/// list, the module's `weval.directives` section if present, and a
+/// corpus file if given.
+fn directive_providers(
+    corpus: Option<PathBuf>,
+    module_bytes: &[u8],
+) -> anyhow::Result<Vec<Box<dyn directive::DirectiveProvider>>> {
+    let mut providers: Vec<Box<dyn directive::DirectiveProvider>> =
+        vec![Box::new(directive::PendingRequests)];
+    if let Some(section) = directives_section::SectionDirectives::from_module(module_bytes)? {
+        providers.push(Box::new(section));
+    }
+    if let Some(path) = corpus {
+        providers.push(Box::new(directive::CorpusFile(path)));
+    }
+    Ok(providers)
+}
+
+fn analyze(
+    input_module: PathBuf,
+    do_wizen: bool,
+    corpus: Option<PathBuf>,
+    output: Option<PathBuf>,
+    estimate: Option<estimate::EstimateOptions>,
+) -> anyhow::Result<()> {
+    let raw_bytes = std::fs::read(&input_module)?;
+    let module_bytes = if do_wizen {
+        wizen(raw_bytes)?
+    } else {
+        raw_bytes
+    };
+
+    let mut frontend_opts = waffle::FrontendOptions::default();
+    frontend_opts.debug = true;
+    let module = waffle::Module::from_wasm_bytes(&module_bytes[..], &frontend_opts)?;
+    let mut im = image::build_image(&module, None)?;
+    im.detect_shared_memories(&module_bytes[..])?;
+    im.detect_immutable_tables(&module_bytes[..])?;
+    let (directives, corpus) = directive::collect_from(
+        &mut directive_providers(corpus, &module_bytes[..])?,
+        &module,
+        &mut im,
+    )?;
+    log::debug!("Directives: {:?}", directives);
+
+    let opts = eval::PartialEvalOptions {
+        analyze: estimate.is_none(),
+        estimate,
+        ..Default::default()
+    };
+    let result = eval::partially_evaluate(
+        module,
+        &mut im,
+        &directives[..],
+        &corpus[..],
+        None,
+        &opts,
+        &BTreeMap::new(),
+    )?;
+
+    if estimate.is_some() {
+        print!(
+            "{}",
+            estimate::report(&result.estimates[..], &result.site_estimates[..])
+        );
+        if let Some(path) = &output {
+            let dump = bincode::serialize(&(&result.estimates, &result.site_estimates))?;
+            std::fs::write(path, dump)?;
+        }
+        return Ok(());
+    }
+
+    print!("{}", analyze::report(&result.analyses[..]));
+    report_untargeted_intrinsic_uses(&result.untargeted_intrinsic_uses[..]);
+    report_printed_values(&result.printed_values[..]);
+
+    if let Some(path) = &output {
+        let dump = bincode::serialize(&result.analyses)?;
+        std::fs::write(path, dump)?;
+    }
 
     Ok(())
 }
 
+fn inspect(input_module: PathBuf, do_wizen: bool, commands: Vec<String>) -> anyhow::Result<()> {
+    let raw_bytes = std::fs::read(&input_module)?;
+    let module_bytes = if do_wizen {
+        wizen(raw_bytes)?
+    } else {
+        raw_bytes
+    };
+
+    let frontend_opts = waffle::FrontendOptions::default();
+    let module = waffle::Module::from_wasm_bytes(&module_bytes[..], &frontend_opts)?;
+    let im = image::build_image(&module, None)?;
+
+    if !commands.is_empty() {
+        for command in &commands {
+            print!("{}", inspect::run_command(&module, &im, command)?);
+        }
+        return Ok(());
+    }
+
+    use std::io::{BufRead, Write};
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        eprint!("weval> ");
+        std::io::stderr().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        if line.trim() == "quit" {
+            break;
+        }
+        // Report errors and keep going, as a typo should not end the
+        // session.
+        match inspect::run_command(&module, &im, &line) {
+            Ok(out) => print!("{}", out),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+    Ok(())
+}
+
 fn precompile(input_module: PathBuf, output_precompiled: PathBuf) -> anyhow::Result<()> {
     let engine = wasmtime::Engine::new(&wasmtime::Config::default())?;
     let module = wasmtime::Module::from_file(&engine, &input_module)?;
@@ -359,3 +1603,48 @@ fn merge(output: PathBuf, inputs: Vec<PathBuf>) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+fn diff_directives(old: PathBuf, new: PathBuf) -> anyhow::Result<()> {
+    let old: manifest::Manifest = bincode::deserialize(&std::fs::read(&old)?)?;
+    let new: manifest::Manifest = bincode::deserialize(&std::fs::read(&new)?)?;
+    print!("{}", manifest::ManifestDiff::new(&old, &new).report());
+    Ok(())
+}
+
+fn directives(cmd: DirectivesCommand) -> anyhow::Result<()> {
+    match cmd {
+        DirectivesCommand::Encode {
+            input,
+            output,
+            module,
+            wat,
+        } => {
+            let directives = directives_section::from_text(&std::fs::read_to_string(&input)?)?;
+            let contents = directives_section::encode(&directives[..]);
+            let bytes = match (module, wat) {
+                (Some(_), true) => anyhow::bail!("-m cannot be combined with --wat"),
+                (Some(module), false) => {
+                    directives_section::append(&std::fs::read(&module)?[..], &contents[..])?
+                }
+                (None, true) => directives_section::to_wat(&contents[..]).into_bytes(),
+                (None, false) => contents,
+            };
+            std::fs::write(&output, bytes)?;
+        }
+        DirectivesCommand::Decode { input } => {
+            let bytes = std::fs::read(&input)?;
+            let contents = if bytes.starts_with(b"\0asm") {
+                directives_section::read(&bytes[..])?.ok_or_else(|| {
+                    anyhow::anyhow!("No {} section", directives_section::SECTION_NAME)
+                })?
+            } else {
+                bytes
+            };
+            print!(
+                "{}",
+                directives_section::to_text(&directives_section::decode(&contents[..])?[..])
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,199 @@
+//! Manifest of specialized functions in the output.
+//!
+//! One entry per specialization, recording the request it came from,
+//! where the result lives, and the assumptions it was made under
+//! (currently the epochs of memory regions it declared reading via
+//! `weval.region.epoch`). The runtime may consult the manifest to
+//! decide whether a specialization is still valid, and `weval
+//! diff-directives` compares the manifests of two runs to tell which
+//! specializations a change to the guest invalidates.
+
+use crate::directive::DirectiveId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// A memory region declared at a given version by the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RegionEpoch {
+    pub addr: u32,
+    pub len: u32,
+    pub epoch: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// The directive's argument bytestring.
+    pub args: Vec<u8>,
+    /// Index of the generic function.
+    pub generic_func: usize,
+    /// Hash of the generic function's body as read from the input,
+    /// so that a change to the code being specialized is visible
+    /// even where the directive itself is the same.
+    pub generic_fingerprint: u64,
+    /// Index of the specialized function in the output module.
+    pub specialized_func: usize,
+    /// Index of the specialized function in the function table.
+    pub table_index: u32,
+    /// Estimated cost of the specialized function; see `cost`.
+    pub cost: u64,
+    /// Estimated cost of the generic function, for comparison.
+    pub generic_cost: u64,
+    /// Region epochs the specialization assumed.
+    pub region_epochs: Vec<RegionEpoch>,
+    /// Whether a newer epoch was seen for any of the regions this
+    /// specialization assumed, i.e., the guest has since modified
+    /// the region.
+    pub stale: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Sort entries by site, then argument bytestring, so that the
+    /// manifest (and the metadata table built from it) doesn't
+    /// depend on the order in which specializations were admitted.
+    pub fn sort(&mut self) {
+        self.entries
+            .sort_by(|a, b| (a.user_id, &a.args, a.id).cmp(&(b.user_id, &b.args, b.id)));
+    }
+
+    /// Mark as stale every entry that assumed an epoch for a region
+    /// older than the newest epoch any entry assumed for it.
+    pub fn mark_stale(&mut self) {
+        let mut newest: BTreeMap<(u32, u32), u32> = BTreeMap::new();
+        for entry in &self.entries {
+            for r in &entry.region_epochs {
+                let e = newest.entry((r.addr, r.len)).or_insert(r.epoch);
+                *e = std::cmp::max(*e, r.epoch);
+            }
+        }
+        for entry in &mut self.entries {
+            entry.stale = entry
+                .region_epochs
+                .iter()
+                .any(|r| newest[&(r.addr, r.len)] > r.epoch);
+            if entry.stale {
+                log::info!(
+                    "Specialization {} of site {} (table index {}) is stale",
+                    entry.id,
+                    entry.user_id,
+                    entry.table_index
+                );
+            }
+        }
+    }
+}
+
+/// How a directive's specialization differs between two runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DirectiveChange {
+    /// Specialized only in the new run.
+    Added,
+    /// Specialized only in the old run.
+    Removed,
+    /// Specialized in both, under different code or assumptions;
+    /// the reasons are listed.
+    Changed(Vec<&'static str>),
+    /// Specialized in both, identically as far as the manifests tell.
+    Unchanged,
+}
+
+/// A comparison of the directives of two runs, by directive ID.
+#[derive(Clone, Debug, Default)]
+pub struct ManifestDiff {
+    pub entries: Vec<(DirectiveChange, ManifestEntry)>,
+}
+
+impl ManifestDiff {
+    pub fn new(old: &Manifest, new: &Manifest) -> ManifestDiff {
+        let by_id = |manifest: &Manifest| {
+            manifest
+                .entries
+                .iter()
+                .map(|entry| (entry.id, entry))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let (old, new) = (by_id(old), by_id(new));
+
+        let mut entries = vec![];
+        for (id, &entry) in &new {
+            let change = match old.get(id) {
+                None => DirectiveChange::Added,
+                Some(old) => {
+                    let mut reasons = vec![];
+                    if old.generic_fingerprint != entry.generic_fingerprint {
+                        reasons.push("generic function changed");
+                    }
+                    if old.region_epochs != entry.region_epochs {
+                        reasons.push("region epochs changed");
+                    }
+                    if reasons.is_empty() {
+                        DirectiveChange::Unchanged
+                    } else {
+                        DirectiveChange::Changed(reasons)
+                    }
+                }
+            };
+            entries.push((change, entry.clone()));
+        }
+        for (id, &entry) in &old {
+            if !new.contains_key(id) {
+                entries.push((DirectiveChange::Removed, entry.clone()));
+            }
+        }
+        ManifestDiff { entries }
+    }
+
+    fn count(&self, pred: impl Fn(&DirectiveChange) -> bool) -> usize {
+        self.entries
+            .iter()
+            .filter(|(change, _)| pred(change))
+            .count()
+    }
+
+    /// The number of specializations that the new run cannot reuse
+    /// from the old one (e.g., from a cache keyed by directive and
+    /// generic function): the added and changed ones.
+    pub fn recompute(&self) -> usize {
+        self.count(|change| matches!(change, DirectiveChange::Added | DirectiveChange::Changed(_)))
+    }
+
+    /// A human-readable report: one line per added, removed, or
+    /// changed directive, then totals.
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        for (change, entry) in &self.entries {
+            let what = match change {
+                DirectiveChange::Added => "added".to_owned(),
+                DirectiveChange::Removed => "removed".to_owned(),
+                DirectiveChange::Changed(reasons) => format!("changed ({})", reasons.join(", ")),
+                DirectiveChange::Unchanged => continue,
+            };
+            writeln!(
+                &mut s,
+                "{} site {} func {}: {}",
+                entry.id, entry.user_id, entry.generic_func, what
+            )
+            .unwrap();
+        }
+        writeln!(
+            &mut s,
+            "{} added, {} removed, {} changed, {} unchanged; {} specializations to recompute",
+            self.count(|change| *change == DirectiveChange::Added),
+            self.count(|change| *change == DirectiveChange::Removed),
+            self.count(|change| matches!(change, DirectiveChange::Changed(_))),
+            self.count(|change| *change == DirectiveChange::Unchanged),
+            self.recompute()
+        )
+        .unwrap();
+        s
+    }
+}
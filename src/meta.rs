@@ -0,0 +1,126 @@
+//! The `weval.meta` custom section: how an output module was produced.
+//!
+//! The section records the weval version, the options and output
+//! features the module was specialized with, and the number and a
+//! hash of the directives, so that a deployed artifact can be traced
+//! back to the exact run that produced it, and so that caches of
+//! wevaled modules can be invalidated when weval changes. It also
+//! records the estimated cost (see `cost`) of each specialization and
+//! of its generic function, for a runtime's tiering policy.
+//!
+//! The contents are UTF-8 `key=value` lines, with one `cost` line per
+//! specialization giving its directive ID, its cost, and its generic
+//! function's cost:
+//!
+//! ```text
+//! version=0.1.0
+//! options=PartialEvalOptions { ... }
+//! features=OutputFeatures { ... }
+//! directives=12
+//! directive-hash=8f3a61c2d07be519
+//! cost=5d1c0e7a93b2f468 214 1873
+//! ```
+
+use crate::directive::Directive;
+use crate::directive::DirectiveId;
+use crate::eval::PartialEvalOptions;
+use crate::manifest::Manifest;
+use std::fmt::Write;
+
+/// Name of the custom section.
+pub const SECTION_NAME: &str = "weval.meta";
+
+#[derive(Clone, Debug)]
+pub struct Meta {
+    /// Version of the weval crate.
+    pub version: &'static str,
+    /// The specialization options, as their `Debug` form.
+    pub options: String,
+    /// The output feature set, as its `Debug` form.
+    pub features: String,
+    /// Number of directives processed for this module.
+    pub directives: usize,
+    /// FNV-1a hash of the serialized directives, in sorted order.
+    pub directive_hash: u64,
+    /// Estimated cost of each specialization and of its generic
+    /// function, in manifest order.
+    pub costs: Vec<(DirectiveId, u64, u64)>,
+}
+
+impl Meta {
+    pub fn new(
+        opts: &PartialEvalOptions,
+        features: &impl std::fmt::Debug,
+        directives: &[Directive],
+        manifest: &Manifest,
+    ) -> anyhow::Result<Meta> {
+        // Debug-output paths don't affect the result, and shouldn't
+        // leak into artifacts.
+        let opts = PartialEvalOptions {
+            output_ir: None,
+            ..opts.clone()
+        };
+
+        let mut directives = directives.iter().collect::<Vec<_>>();
+        directives.sort();
+        let mut hash = FNV_OFFSET_BASIS;
+        for directive in &directives {
+            hash = fnv1a(hash, &bincode::serialize(directive)?[..]);
+        }
+
+        Ok(Meta {
+            version: env!("CARGO_PKG_VERSION"),
+            options: format!("{:?}", opts),
+            features: format!("{:?}", features),
+            directives: directives.len(),
+            directive_hash: hash,
+            costs: manifest
+                .entries
+                .iter()
+                .map(|entry| (entry.id, entry.cost, entry.generic_cost))
+                .collect(),
+        })
+    }
+
+    fn contents(&self) -> String {
+        let mut s = String::new();
+        writeln!(&mut s, "version={}", self.version).unwrap();
+        writeln!(&mut s, "options={}", self.options).unwrap();
+        writeln!(&mut s, "features={}", self.features).unwrap();
+        writeln!(&mut s, "directives={}", self.directives).unwrap();
+        writeln!(&mut s, "directive-hash={:016x}", self.directive_hash).unwrap();
+        for (id, cost, generic_cost) in &self.costs {
+            writeln!(&mut s, "cost={} {} {}", id, cost, generic_cost).unwrap();
+        }
+        s
+    }
+
+    /// Appends the section to an encoded module. Custom sections may
+    /// appear anywhere, so this is valid after any section.
+    pub fn append_to(&self, bytes: &mut Vec<u8>) {
+        use wasm_encoder::{Encode, Section};
+        let section = wasm_encoder::CustomSection {
+            name: SECTION_NAME.into(),
+            data: self.contents().into_bytes().into(),
+        };
+        bytes.push(section.id());
+        section.encode(bytes);
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a hash of a bytestring; stable across platforms and
+/// versions, unlike `std`'s hashers.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET_BASIS, bytes)
+}
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
@@ -0,0 +1,286 @@
+//! Passive data segments, `memory.init` and `data.drop`.
+//!
+//! The IR has no passive data segments, and no instructions that use
+//! them: the frontend drops the segments and rejects the
+//! instructions. So before the module is parsed, `outline` replaces
+//! each `memory.init` and `data.drop` in a function body with a call
+//! to a helper function, one per segment (and memory) and kind of
+//! instruction. Helpers are appended after all other functions, so
+//! no existing index changes. Once the module is parsed, `install`
+//! gives the helpers compiled bodies holding the real instructions.
+//!
+//! The image keeps the segments themselves (see
+//! `Image::detect_passive_segments`): the evaluator folds calls to
+//! `memory.init` helpers against their contents where it can, and the
+//! final filter pass writes them back at their original indices.
+
+use std::collections::BTreeMap;
+use waffle::{entity::EntityRef, Func, FuncDecl, Memory, Module};
+use wasm_encoder::Encode;
+use wasmparser::{Parser, Payload, TypeRef};
+
+/// What a helper function does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PassiveOp {
+    /// `memory.init`: (dst, src, len) -> ().
+    Init { segment: u32, memory: Memory },
+    /// `data.drop`: () -> ().
+    Drop { segment: u32 },
+}
+
+/// Placeholder body of a helper, until `install` replaces it: no
+/// locals, `unreachable`, `end`.
+const PLACEHOLDER_BODY: [u8; 3] = [0x00, 0x00, 0x0b];
+
+/// Length of the LEB128 at the start of `bytes`.
+fn leb_len(bytes: &[u8]) -> usize {
+    bytes.iter().position(|b| b & 0x80 == 0).unwrap() + 1
+}
+
+/// Outline every `memory.init` and `data.drop` in `module` into calls
+/// to helpers. Returns the rewritten module and the helpers, or
+/// `None` if no function body uses either instruction.
+pub fn outline(module: &[u8]) -> anyhow::Result<Option<(Vec<u8>, BTreeMap<Func, PassiveOp>)>> {
+    // Find the uses, and what the new types and helpers will be
+    // numbered.
+    let mut num_types = 0;
+    let mut num_funcs = 0;
+    let mut memory64 = vec![];
+    let mut ops = BTreeMap::new();
+    for payload in Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::TypeSection(types) => num_types = types.count(),
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    match import?.ty {
+                        TypeRef::Func(_) => num_funcs += 1,
+                        TypeRef::Memory(ty) => memory64.push(ty.memory64),
+                        _ => {}
+                    }
+                }
+            }
+            Payload::FunctionSection(funcs) => num_funcs += funcs.count(),
+            Payload::MemorySection(memories) => {
+                for ty in memories {
+                    memory64.push(ty?.memory64);
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                for op in body.get_operators_reader()? {
+                    let op = match op? {
+                        wasmparser::Operator::MemoryInit { data_index, mem } => {
+                            if memory64.get(mem as usize).copied().unwrap_or(false) {
+                                anyhow::bail!("memory.init on a 64-bit memory is not supported");
+                            }
+                            PassiveOp::Init {
+                                segment: data_index,
+                                memory: Memory::new(mem as usize),
+                            }
+                        }
+                        wasmparser::Operator::DataDrop { data_index } => PassiveOp::Drop {
+                            segment: data_index,
+                        },
+                        _ => continue,
+                    };
+                    let next = num_funcs + ops.len() as u32;
+                    ops.entry(op).or_insert(next);
+                }
+            }
+            _ => {}
+        }
+    }
+    if ops.is_empty() {
+        return Ok(None);
+    }
+    let init_ty = num_types;
+    let drop_ty = num_types + 1;
+    let mut helpers = ops.iter().map(|(&op, &f)| (f, op)).collect::<Vec<_>>();
+    helpers.sort();
+
+    let mut out = wasm_encoder::Module::new();
+    let mut code = wasm_encoder::CodeSection::new();
+    let mut num_bodies = 0;
+    let mut num_bodies_seen = 0;
+    let mut rewritten = 0;
+    for payload in Parser::new(0).parse_all(module) {
+        let payload = payload?;
+        let raw_section = payload.as_section();
+        match payload {
+            Payload::TypeSection(types) => {
+                let range = types.range();
+                let mut data = vec![];
+                (types.count() + 2).encode(&mut data);
+                data.extend_from_slice(
+                    &module[range.start + leb_len(&module[range.clone()])..range.end],
+                );
+                // (i32, i32, i32) -> () and () -> ().
+                data.extend_from_slice(&[0x60, 0x03, 0x7f, 0x7f, 0x7f, 0x00]);
+                data.extend_from_slice(&[0x60, 0x00, 0x00]);
+                out.section(&wasm_encoder::RawSection { id: 1, data: &data });
+                rewritten += 1;
+            }
+            Payload::FunctionSection(funcs) => {
+                let range = funcs.range();
+                let mut data = vec![];
+                (funcs.count() + helpers.len() as u32).encode(&mut data);
+                data.extend_from_slice(
+                    &module[range.start + leb_len(&module[range.clone()])..range.end],
+                );
+                for (_, op) in &helpers {
+                    match op {
+                        PassiveOp::Init { .. } => init_ty.encode(&mut data),
+                        PassiveOp::Drop { .. } => drop_ty.encode(&mut data),
+                    }
+                }
+                out.section(&wasm_encoder::RawSection { id: 3, data: &data });
+                rewritten += 1;
+            }
+            Payload::CodeSectionStart { count, .. } => {
+                num_bodies = count;
+            }
+            Payload::CodeSectionEntry(body) => {
+                let range = body.range();
+                let mut data = vec![];
+                let mut last_offset = range.start;
+                let mut reader = body.get_operators_reader()?;
+                while !reader.eof() {
+                    let (op, offset) = reader.read_with_offset()?;
+                    let op = match op {
+                        wasmparser::Operator::MemoryInit { data_index, mem } => PassiveOp::Init {
+                            segment: data_index,
+                            memory: Memory::new(mem as usize),
+                        },
+                        wasmparser::Operator::DataDrop { data_index } => PassiveOp::Drop {
+                            segment: data_index,
+                        },
+                        _ => continue,
+                    };
+                    data.extend_from_slice(&module[last_offset..offset]);
+                    data.push(0x10);
+                    ops[&op].encode(&mut data);
+                    last_offset = reader.original_position();
+                }
+                data.extend_from_slice(&module[last_offset..range.end]);
+                code.raw(&data);
+
+                num_bodies_seen += 1;
+                if num_bodies_seen == num_bodies {
+                    for _ in &helpers {
+                        code.raw(&PLACEHOLDER_BODY);
+                    }
+                    out.section(&code);
+                    rewritten += 1;
+                }
+            }
+            _ => {
+                if let Some((id, range)) = raw_section {
+                    out.section(&wasm_encoder::RawSection {
+                        id,
+                        data: &module[range],
+                    });
+                }
+            }
+        }
+    }
+    anyhow::ensure!(
+        rewritten == 3,
+        "Malformed module: missing type, function or code section"
+    );
+
+    let helpers = helpers
+        .into_iter()
+        .map(|(f, op)| (Func::new(f as usize), op))
+        .collect();
+    Ok(Some((out.finish(), helpers)))
+}
+
+/// Give the helpers found by `outline` their real bodies. They are
+/// already compiled, so passes over IR bodies skip them.
+pub fn install(module: &mut Module, helpers: &BTreeMap<Func, PassiveOp>) {
+    for (&func, &op) in helpers {
+        let sig = module.funcs[func].sig();
+        let mut body = wasm_encoder::Function::new(vec![]);
+        let name = match op {
+            PassiveOp::Init { segment, memory } => {
+                body.instruction(&wasm_encoder::Instruction::LocalGet(0));
+                body.instruction(&wasm_encoder::Instruction::LocalGet(1));
+                body.instruction(&wasm_encoder::Instruction::LocalGet(2));
+                body.instruction(&wasm_encoder::Instruction::MemoryInit {
+                    mem: memory.index() as u32,
+                    data_index: segment,
+                });
+                format!("memory.init {} {}", segment, memory)
+            }
+            PassiveOp::Drop { segment } => {
+                body.instruction(&wasm_encoder::Instruction::DataDrop(segment));
+                format!("data.drop {}", segment)
+            }
+        };
+        body.instruction(&wasm_encoder::Instruction::End);
+        module.funcs[func] = FuncDecl::Compiled(sig, name, body);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn outlines_into_appended_helpers() {
+        let wat = r#"
+        (module
+          (memory 1)
+          (data $a "hello")
+          (data $b "world")
+          (func $f (param i32)
+            (memory.init $a (local.get 0) (i32.const 0) (i32.const 5))
+            (memory.init $b (local.get 0) (i32.const 0) (i32.const 5))
+            (data.drop $a))
+          (func $g
+            (memory.init $a (i32.const 16) (i32.const 1) (i32.const 4))
+            (data.drop $a)))
+        "#;
+        let bytes = wat::parse_str(wat).unwrap();
+        let (outlined, helpers) = outline(&bytes[..]).unwrap().unwrap();
+        let memory = Memory::new(0);
+        assert_eq!(
+            helpers.into_iter().collect::<Vec<_>>(),
+            vec![
+                (Func::new(2), PassiveOp::Init { segment: 0, memory }),
+                (Func::new(3), PassiveOp::Init { segment: 1, memory }),
+                (Func::new(4), PassiveOp::Drop { segment: 0 }),
+            ]
+        );
+
+        wasmparser::Validator::new()
+            .validate_all(&outlined[..])
+            .unwrap();
+        let mut calls = vec![];
+        for payload in Parser::new(0).parse_all(&outlined[..]) {
+            if let Payload::CodeSectionEntry(body) = payload.unwrap() {
+                let mut body_calls = vec![];
+                for op in body.get_operators_reader().unwrap() {
+                    match op.unwrap() {
+                        wasmparser::Operator::Call { function_index } => {
+                            body_calls.push(function_index)
+                        }
+                        wasmparser::Operator::MemoryInit { .. }
+                        | wasmparser::Operator::DataDrop { .. } => panic!("not outlined"),
+                        _ => {}
+                    }
+                }
+                calls.push(body_calls);
+            }
+        }
+        assert_eq!(
+            calls,
+            vec![vec![2, 3, 4], vec![2, 4], vec![], vec![], vec![]]
+        );
+    }
+
+    #[test]
+    fn no_uses_no_rewrite() {
+        let bytes = wat::parse_str(r#"(module (memory 1) (data "x") (func))"#).unwrap();
+        assert!(outline(&bytes[..]).unwrap().is_none());
+    }
+}
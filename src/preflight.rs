@@ -0,0 +1,135 @@
+//! Pre-flight checks on directives.
+//!
+//! A bad request (a stale function pointer, a truncated argument
+//! string, an output address outside memory) would otherwise fail
+//! partway through specialization, after the work on all earlier
+//! directives, with an error about only the first. Instead we check
+//! every directive against the module and image up front and report
+//! all problems at once.
+
+use crate::directive::{Directive, DirectiveArgs};
+use crate::image::Image;
+use crate::intrinsics::{find_global_data_by_exported_func, Intrinsics};
+use waffle::{entity::EntityRef, FuncDecl, ImportKind, Module, Table};
+
+/// Checks live requests and corpus directives, returning an error
+/// listing every problem found.
+pub fn check(
+    module: &Module,
+    im: &Image,
+    intrinsics: &Intrinsics,
+    directives: &[Directive],
+    corpus: &[Directive],
+) -> anyhow::Result<()> {
+    let mut problems = vec![];
+
+    check_intrinsics(module, intrinsics, &mut problems);
+    for directive in directives {
+        check_directive(module, im, directive, &mut problems);
+    }
+    for directive in corpus {
+        check_corpus_directive(module, directive, &mut problems);
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "{} problem(s) found before specialization:\n{}",
+        problems.len(),
+        problems
+            .iter()
+            .map(|p| format!("  - {}", p))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+/// An import of a known intrinsic with the wrong signature is not
+/// recognized, and would silently be left as a call.
+fn check_intrinsics(module: &Module, intrinsics: &Intrinsics, problems: &mut Vec<String>) {
+    for import in module.imports.iter().filter(|im| im.module == "weval") {
+        if !matches!(import.kind, ImportKind::Func(_)) {
+            continue;
+        }
+        if let Some((_, None)) = intrinsics
+            .by_name()
+            .into_iter()
+            .find(|(name, _)| *name == import.name)
+        {
+            problems.push(format!(
+                "intrinsic weval.{} is imported with an unexpected signature",
+                import.name
+            ));
+        }
+    }
+}
+
+fn check_directive(module: &Module, im: &Image, directive: &Directive, problems: &mut Vec<String>) {
+    let site = format!("request at site {}", directive.user_id);
+
+    if directive.func_index_out_addr != 0 {
+        let in_heap = im.main_heap.map_or(false, |heap| {
+            im.can_read(heap, directive.func_index_out_addr, 4)
+        });
+        if !in_heap {
+            problems.push(format!(
+                "{}: output address {:#x} is outside the main heap",
+                site, directive.func_index_out_addr
+            ));
+        }
+    }
+
+    if directive.func.index() >= module.funcs.len() {
+        problems.push(format!(
+            "{}: function {} does not exist",
+            site, directive.func
+        ));
+        return;
+    }
+    let name = module.funcs[directive.func].name();
+    if let FuncDecl::Import(..) = &module.funcs[directive.func] {
+        problems.push(format!(
+            "{}: function {} ({}) is an import",
+            site, directive.func, name
+        ));
+        return;
+    }
+
+    let sig = &module.signatures[module.funcs[directive.func].sig()];
+    if let Err(e) = DirectiveArgs::decode(&directive.args[..])
+        .and_then(|mut args| args.coerce_to_params(directive.num_globals as usize, &sig.params[..]))
+    {
+        problems.push(format!(
+            "{}: bad arguments for function {} ({}): {}",
+            site, directive.func, name, e
+        ));
+    }
+}
+
+/// Corpus directives name their function by weval site; the site
+/// must still be exported, and point into the table.
+fn check_corpus_directive(module: &Module, directive: &Directive, problems: &mut Vec<String>) {
+    let export = format!("weval.func.{}", directive.user_id);
+    let func_ptr = match find_global_data_by_exported_func(module, &export) {
+        Some(func_ptr) => func_ptr,
+        None => {
+            problems.push(format!(
+                "corpus directive for site {}: no {} export",
+                directive.user_id, export
+            ));
+            return;
+        }
+    };
+    let table_len = module
+        .tables
+        .get(Table::from(0))
+        .and_then(|table| table.func_elements.as_ref())
+        .map_or(0, |elems| elems.len());
+    if func_ptr as usize >= table_len {
+        problems.push(format!(
+            "corpus directive for site {}: function pointer {} is outside the table",
+            directive.user_id, func_ptr
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+//! Intra-block instruction scheduling.
+//!
+//! Specialized blocks come out of the evaluator in the generic
+//! function's order, with constants and address computations
+//! scattered among the remains of folded-away code. Baseline
+//! compilers (e.g. Liftoff) do noticeably better when each value is
+//! computed right before its use, so we list-schedule each block:
+//! instructions with side effects (including loads) stay in their
+//! original relative order, and each pure instruction is sunk to just
+//! before its first use in the block, after its own operands. Pure
+//! instructions used only by the terminator or by other blocks go at
+//! the end, in their original order.
+//!
+//! As in DCE, we assume the program does not trap, so pure operators
+//! may be moved past side-effecting ones.
+
+use crate::collections::HashSet;
+use waffle::{FunctionBody, Value, ValueDef};
+
+fn is_anchor(func: &FunctionBody, value: Value) -> bool {
+    match &func.values[value] {
+        ValueDef::Operator(op, _, _) => !op.is_pure(),
+        ValueDef::PickOutput(..) => false,
+        _ => true,
+    }
+}
+
+fn operands(func: &FunctionBody, value: Value) -> Vec<Value> {
+    match &func.values[value] {
+        ValueDef::Operator(_, args, _) => func.arg_pool[*args].to_vec(),
+        &ValueDef::PickOutput(source, _, _) => vec![source],
+        _ => vec![],
+    }
+}
+
+/// Emit `value` (if defined in this block and not yet emitted), after
+/// its operands.
+fn emit(
+    func: &FunctionBody,
+    value: Value,
+    in_block: &HashSet<Value>,
+    emitted: &mut HashSet<Value>,
+    out: &mut Vec<Value>,
+) {
+    if !in_block.contains(&value) || emitted.contains(&value) {
+        return;
+    }
+    // Iterative post-order DFS, to avoid deep recursion on long
+    // expression chains.
+    let mut stack = vec![(value, false)];
+    while let Some((v, expanded)) = stack.pop() {
+        if emitted.contains(&v) {
+            continue;
+        }
+        if expanded {
+            emitted.insert(v);
+            out.push(v);
+            continue;
+        }
+        stack.push((v, true));
+        for arg in operands(func, v).into_iter().rev() {
+            if in_block.contains(&arg) && !emitted.contains(&arg) {
+                stack.push((arg, false));
+            }
+        }
+    }
+}
+
+pub fn run(func: &mut FunctionBody) {
+    for block in func.blocks.iter().collect::<Vec<_>>() {
+        let insts = std::mem::take(&mut func.blocks[block].insts);
+        let in_block = insts.iter().cloned().collect::<HashSet<_>>();
+        let mut emitted = HashSet::default();
+        let mut out = Vec::with_capacity(insts.len());
+
+        for &inst in &insts {
+            if is_anchor(func, inst) {
+                emit(func, inst, &in_block, &mut emitted, &mut out);
+            }
+        }
+        for &inst in &insts {
+            emit(func, inst, &in_block, &mut emitted, &mut out);
+        }
+
+        debug_assert_eq!(out.len(), insts.len());
+        func.blocks[block].insts = out;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+    use waffle::{FrontendOptions, Func, Module, Operator};
+
+    #[test]
+    fn pure_values_sink_to_first_use() {
+        let bytes = wat::parse_str(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32)
+                (local i32)
+                (local.set 1 (i32.add (local.get 0) (i32.const 1)))
+                (i32.store (i32.const 0) (local.get 0))
+                (i32.store (i32.const 4) (local.get 1))))
+            "#,
+        )
+        .unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        let mut func = module.clone_and_expand_body(Func::new(0)).unwrap();
+        run(&mut func);
+
+        let insts = &func.blocks[func.entry].insts;
+        let position = |pred: &dyn Fn(&Operator) -> bool| {
+            insts
+                .iter()
+                .position(
+                    |&inst| matches!(&func.values[inst], ValueDef::Operator(op, ..) if pred(op)),
+                )
+                .unwrap()
+        };
+        let stores = insts
+            .iter()
+            .filter(|&&inst| {
+                matches!(
+                    func.values[inst],
+                    ValueDef::Operator(Operator::I32Store { .. }, ..)
+                )
+            })
+            .count();
+        assert_eq!(stores, 2);
+        let first_store = position(&|op| matches!(op, Operator::I32Store { .. }));
+        let add = position(&|op| matches!(op, Operator::I32Add));
+        // The add is used only by the second store, so it moves past
+        // the first.
+        assert!(
+            add > first_store,
+            "add at {}, store at {}",
+            add,
+            first_store
+        );
+        assert_eq!(insts.len(), insts.iter().collect::<HashSet<_>>().len());
+    }
+}
@@ -0,0 +1,198 @@
+//! Conditional-move conversion of small branch diamonds.
+//!
+//! Specializing dispatch leaves many conditional branches, on values
+//! known only at runtime, whose arms do nothing but pick one of two
+//! values for their join block: a constant each, or a small pure
+//! computation each. Engines predict such branches poorly. This pass
+//! computes both arms' values unconditionally in the branching block
+//! and picks between them with `select`s, branching straight to the
+//! join.
+//!
+//! An arm is either a direct edge to the join, or a block reached
+//! only from the branch, without blockparams, holding at most
+//! `MAX_ARM_INSTS` pure (hence non-trapping) instructions and ending
+//! in a branch to the join. At most `MAX_SELECTS` join args may
+//! differ between the arms. Branches with a branch hint are
+//! predictable and are left alone.
+
+use crate::collections::HashMap;
+use waffle::{Block, BlockTarget, FunctionBody, Operator, Terminator, Type, Value, ValueDef};
+
+/// Most instructions in an arm that is hoisted.
+const MAX_ARM_INSTS: usize = 2;
+/// Most `select`s emitted per converted branch.
+const MAX_SELECTS: usize = 2;
+
+/// An arm of a diamond: its block, if it is not a direct edge to the
+/// join, and the args it passes to the join.
+struct Arm {
+    block: Option<Block>,
+    args: Vec<Value>,
+}
+
+/// The arm starting with the edge to `target`, and the join it
+/// reaches.
+fn arm(func: &FunctionBody, branch: Block, target: &BlockTarget) -> (Block, Arm) {
+    let def = &func.blocks[target.block];
+    let is_arm_block = target.block != branch
+        && def.preds == [branch]
+        && def.params.is_empty()
+        && def.insts.len() <= MAX_ARM_INSTS
+        && def.insts.iter().all(|&inst| match &func.values[inst] {
+            ValueDef::Operator(op, _, tys) => op.is_pure() && func.type_pool[*tys].len() == 1,
+            _ => false,
+        });
+    match &def.terminator {
+        Terminator::Br { target: to_join } if is_arm_block => (
+            to_join.block,
+            Arm {
+                block: Some(target.block),
+                args: to_join.args.clone(),
+            },
+        ),
+        _ => (
+            target.block,
+            Arm {
+                block: None,
+                args: target.args.clone(),
+            },
+        ),
+    }
+}
+
+/// Whether `select` (without a type immediate) can pick between
+/// values of this type.
+fn selectable(ty: Type) -> bool {
+    matches!(
+        ty,
+        Type::I32 | Type::I64 | Type::F32 | Type::F64 | Type::V128
+    )
+}
+
+/// Converts the diamonds of `func`, skipping branches in `hinted`.
+/// Returns the number converted.
+pub fn run(func: &mut FunctionBody, hinted: &HashMap<Block, bool>) -> usize {
+    func.recompute_edges();
+    let mut converted = 0;
+    for block in func.blocks.iter().collect::<Vec<_>>() {
+        if hinted.contains_key(&block) {
+            continue;
+        }
+        let (cond, if_true, if_false) = match &func.blocks[block].terminator {
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } => (*cond, if_true.clone(), if_false.clone()),
+            _ => continue,
+        };
+        let (join_t, arm_t) = arm(func, block, &if_true);
+        let (join_f, arm_f) = arm(func, block, &if_false);
+        if join_t != join_f
+            || join_t == block
+            || (arm_t.block.is_some() && arm_t.block == arm_f.block)
+        {
+            continue;
+        }
+        let join = join_t;
+        let params = func.blocks[join]
+            .params
+            .iter()
+            .map(|&(ty, _)| ty)
+            .collect::<Vec<_>>();
+        if arm_t.args.len() != params.len() || arm_f.args.len() != params.len() {
+            continue;
+        }
+        let differing = (0..params.len())
+            .filter(|&i| func.resolve_alias(arm_t.args[i]) != func.resolve_alias(arm_f.args[i]))
+            .collect::<Vec<_>>();
+        if differing.len() > MAX_SELECTS || differing.iter().any(|&i| !selectable(params[i])) {
+            continue;
+        }
+
+        // Hoist both arms' instructions, then pick each differing
+        // join arg.
+        for arm_block in [arm_t.block, arm_f.block].into_iter().flatten() {
+            let insts = std::mem::take(&mut func.blocks[arm_block].insts);
+            for inst in insts {
+                func.append_to_block(block, inst);
+            }
+            func.blocks[arm_block].terminator = Terminator::Unreachable;
+        }
+        let mut args = arm_t.args.clone();
+        for &i in &differing {
+            let tys = func.single_type_list(params[i]);
+            let select_args = func
+                .arg_pool
+                .from_iter([arm_t.args[i], arm_f.args[i], cond].into_iter());
+            let select = func.add_value(ValueDef::Operator(Operator::Select, select_args, tys));
+            func.append_to_block(block, select);
+            args[i] = select;
+        }
+        func.blocks[block].terminator = Terminator::Br {
+            target: BlockTarget { block: join, args },
+        };
+        converted += 1;
+    }
+
+    if converted > 0 {
+        func.recompute_edges();
+        log::debug!("select_diamond: converted {} branches", converted);
+    }
+    converted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+    use waffle::{FrontendOptions, Func, Module};
+
+    fn body(wat: &str) -> FunctionBody {
+        let bytes = wat::parse_str(wat).unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        module.clone_and_expand_body(Func::new(0)).unwrap()
+    }
+
+    #[test]
+    fn pure_diamond_becomes_select() {
+        let wat = r#"
+            (module
+              (func (param i32 i32) (result i32)
+                (if (result i32) (local.get 0)
+                  (then (i32.const 1))
+                  (else (i32.add (local.get 1) (i32.const 2))))))
+            "#;
+        let mut func = body(wat);
+        assert_eq!(run(&mut func, &HashMap::default()), 1);
+        let entry = &func.blocks[func.entry];
+        assert!(matches!(entry.terminator, Terminator::Br { .. }));
+        assert!(entry
+            .insts
+            .iter()
+            .any(|&inst| matches!(func.values[inst], ValueDef::Operator(Operator::Select, ..))));
+
+        // A hinted branch is predictable, so is left alone.
+        let mut func = body(wat);
+        let hinted = [(func.entry, true)].into_iter().collect();
+        assert_eq!(run(&mut func, &hinted), 0);
+    }
+
+    #[test]
+    fn trapping_arm_stays_a_branch() {
+        let mut func = body(
+            r#"
+            (module
+              (func (param i32 i32) (result i32)
+                (if (result i32) (local.get 0)
+                  (then (i32.const 1))
+                  (else (i32.div_u (i32.const 7) (local.get 1))))))
+            "#,
+        );
+        assert_eq!(run(&mut func, &HashMap::default()), 0);
+        assert!(matches!(
+            func.blocks[func.entry].terminator,
+            Terminator::CondBr { .. }
+        ));
+    }
+}
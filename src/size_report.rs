@@ -0,0 +1,122 @@
+//! Output size attribution: which generic blocks, in which contexts,
+//! the bytes of each specialized function come from.
+//!
+//! The compiled size of a function body is known only as a whole, so
+//! it is apportioned to specialized blocks by their instruction
+//! counts (plus one for the terminator). Each specialized block has a
+//! single origin, a (context, generic block) pair; blocks the
+//! evaluator adds itself (e.g. the pre-entry block) are reported as
+//! glue.
+
+use crate::directive::DirectiveId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use waffle::{Block, FunctionBody};
+
+/// Bytes attributed to one generic block in one context.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockSize {
+    /// The block in the generic function, or `None` for blocks added
+    /// by the evaluator.
+    pub orig_block: Option<usize>,
+    /// The context stack, outermost first.
+    pub context: Vec<String>,
+    pub bytes: usize,
+}
+
+/// Size attribution for one specialized function.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SizeReport {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// The directive's argument bytestring.
+    pub args: Vec<u8>,
+    /// Name of the generic function.
+    pub func_name: String,
+    /// Total compiled body size.
+    pub bytes: usize,
+    pub blocks: Vec<BlockSize>,
+}
+
+impl SizeReport {
+    /// Apportions `bytes` over the blocks of `body`, given the origin
+    /// of each block created by the evaluator.
+    pub fn new(
+        id: DirectiveId,
+        user_id: u32,
+        args: Vec<u8>,
+        func_name: String,
+        body: &FunctionBody,
+        origins: &BTreeMap<Block, (usize, Vec<String>)>,
+        bytes: usize,
+    ) -> SizeReport {
+        let mut weights: BTreeMap<(Option<usize>, Vec<String>), usize> = BTreeMap::new();
+        for (block, def) in body.blocks.entries() {
+            let key = match origins.get(&block) {
+                Some((orig_block, context)) => (Some(*orig_block), context.clone()),
+                None => (None, vec![]),
+            };
+            *weights.entry(key).or_insert(0) += def.insts.len() + 1;
+        }
+
+        // Apportion by cumulative weight so that the parts sum
+        // exactly to the total.
+        let total = weights.values().sum::<usize>().max(1);
+        let mut cumulative = 0;
+        let mut assigned = 0;
+        let mut blocks = vec![];
+        for ((orig_block, context), weight) in weights {
+            cumulative += weight;
+            let upto = bytes * cumulative / total;
+            blocks.push(BlockSize {
+                orig_block,
+                context,
+                bytes: upto - assigned,
+            });
+            assigned = upto;
+        }
+        blocks.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+
+        SizeReport {
+            id,
+            user_id,
+            args,
+            func_name,
+            bytes,
+            blocks,
+        }
+    }
+}
+
+/// Produces the attribution in the folded-stack format consumed by
+/// `flamegraph.pl` and `inferno-flamegraph`: one line per (function,
+/// context, generic block), with frames separated by `;` and the byte
+/// count last.
+pub fn folded_stacks(reports: &[SizeReport]) -> String {
+    let mut s = String::new();
+    for report in reports {
+        for block in &report.blocks {
+            if block.bytes == 0 {
+                continue;
+            }
+            write!(
+                &mut s,
+                "{} (site {}, directive {})",
+                report.func_name, report.user_id, report.id
+            )
+            .unwrap();
+            for frame in &block.context {
+                write!(&mut s, ";{}", frame.replace(';', ",")).unwrap();
+            }
+            match block.orig_block {
+                Some(orig_block) => write!(&mut s, ";block{}", orig_block).unwrap(),
+                None => write!(&mut s, ";(glue)").unwrap(),
+            }
+            writeln!(&mut s, " {}", block.bytes).unwrap();
+        }
+    }
+    s
+}
@@ -0,0 +1,501 @@
+//! Splitting a specialized function by bytecode PC range.
+//!
+//! Engines that compile lazily, a function at a time, still compile
+//! all of a specialized interpreter body on its first call, though
+//! much of the bytecode it was specialized on may never run. This
+//! splits the body into parts by range of PCs: each part holds the
+//! blocks whose context's innermost PC falls in its range, and an
+//! edge into another part becomes a `return_call` of that part, so
+//! that a part is compiled only once control first reaches it.
+//!
+//! A part may be entered at several blocks: any target of an edge from
+//! another part, and, for the part holding it, the function entry. It
+//! takes an `i32` selector choosing the block, then "slots" holding
+//! that block's params; entries share slots where their types allow.
+//! The body is first converted to max-SSA form with cuts at exactly
+//! the entries, so nothing but their params is live into them. The
+//! function itself becomes a trampoline into the part holding its
+//! entry.
+//!
+//! The same machinery lets a specialization that ran out of time
+//! continue in the generic function at the blocks it did not finish:
+//! see `Resumable`.
+
+use crate::state::PC;
+use std::collections::{BTreeMap, BTreeSet};
+use waffle::cfg::CFGInfo;
+use waffle::entity::EntityRef;
+use waffle::pool::ListRef;
+use waffle::{
+    Block, BlockTarget, Func, FuncDecl, FunctionBody, Module, Operator, Signature, Terminator,
+    Type, Value, ValueDef,
+};
+
+/// The most params a Wasm function may have.
+const MAX_PARAMS: usize = 1000;
+
+/// One function of a split body.
+#[derive(Debug)]
+pub struct Part {
+    pub body: FunctionBody,
+    /// Param types: for a part, the selector then the slots.
+    pub params: Vec<Type>,
+    /// The `return_call`s in `body`, and the part each calls. Their
+    /// function indices are placeholders until the parts are added to
+    /// the module.
+    calls: Vec<(Value, usize)>,
+}
+
+impl Part {
+    fn resolve(&mut self, first_part: usize) {
+        for &(call, callee) in &self.calls {
+            if let ValueDef::Operator(Operator::ReturnCall { function_index }, _, _) =
+                &mut self.body.values[call]
+            {
+                *function_index = Func::new(first_part + callee);
+            }
+        }
+    }
+}
+
+/// A specialized body, split.
+#[derive(Debug)]
+pub struct SplitFunc {
+    /// The new body of the function itself.
+    pub trampoline: Part,
+    pub parts: Vec<Part>,
+}
+
+impl SplitFunc {
+    /// The compiled size of the function and its parts. Calls between
+    /// them are compiled with placeholder indices, so this may
+    /// overestimate slightly.
+    pub fn compiled_size(&self) -> anyhow::Result<usize> {
+        let mut size = 0;
+        for part in std::iter::once(&self.trampoline).chain(self.parts.iter()) {
+            size += part.body.compile()?.byte_len();
+        }
+        Ok(size)
+    }
+
+    /// Add the function, with signature `sig`, to `module`, followed
+    /// directly by its parts. Returns the function.
+    pub fn add_to_module(self, module: &mut Module, sig: Signature, name: &str) -> Func {
+        let SplitFunc {
+            mut trampoline,
+            parts,
+        } = self;
+        let first_part = module.funcs.len() + 1;
+        trampoline.resolve(first_part);
+        let func = module
+            .funcs
+            .push(FuncDecl::Body(sig, name.to_owned(), trampoline.body));
+        let returns = module.signatures[sig].returns.clone();
+        for (i, mut part) in parts.into_iter().enumerate() {
+            part.resolve(first_part);
+            let part_sig = module
+                .signatures
+                .entries()
+                .find(|(_, sig)| sig.params == part.params && sig.returns == returns)
+                .map(|(sig, _)| sig)
+                .unwrap_or_else(|| {
+                    module.signatures.push(waffle::SignatureData {
+                        params: part.params.clone(),
+                        returns: returns.clone(),
+                    })
+                });
+            module.funcs.push(FuncDecl::Body(
+                part_sig,
+                format!("{} (part {})", name, i),
+                part.body,
+            ));
+        }
+        func
+    }
+}
+
+/// How a part's entries are passed their params.
+#[derive(Debug, Default)]
+struct Layout {
+    /// Types of the slots, after the selector.
+    slots: Vec<Type>,
+    /// Each entry block, in selector order, and the slot of each of
+    /// its params.
+    entries: Vec<(Block, Vec<usize>)>,
+}
+
+impl Layout {
+    fn new(body: &FunctionBody, entries: &[Block]) -> Layout {
+        let mut layout = Layout::default();
+        for &entry in entries {
+            let mut seen = vec![];
+            let mut slots = vec![];
+            for &(ty, _) in &body.blocks[entry].params {
+                // The k-th param of a type goes in the k-th slot of
+                // that type.
+                let k = seen.iter().filter(|&&seen_ty| seen_ty == ty).count();
+                seen.push(ty);
+                let slot = layout
+                    .slots
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &slot_ty)| slot_ty == ty)
+                    .nth(k)
+                    .map(|(slot, _)| slot)
+                    .unwrap_or_else(|| {
+                        layout.slots.push(ty);
+                        layout.slots.len() - 1
+                    });
+                slots.push(slot);
+            }
+            layout.entries.push((entry, slots));
+        }
+        layout
+    }
+
+    fn params(&self) -> Vec<Type> {
+        std::iter::once(Type::I32)
+            .chain(self.slots.iter().copied())
+            .collect()
+    }
+
+    /// Args for a call entering at `entry` with `params`, computed in
+    /// `block` of `body`.
+    fn args(
+        &self,
+        body: &mut FunctionBody,
+        block: Block,
+        entry: Block,
+        params: &[Value],
+    ) -> Vec<Value> {
+        let selector = self
+            .entries
+            .iter()
+            .position(|(block, _)| *block == entry)
+            .unwrap();
+        let mut slot_values = vec![None; self.slots.len()];
+        for (&slot, &param) in self.entries[selector].1.iter().zip(params.iter()) {
+            slot_values[slot] = Some(param);
+        }
+        let mut args = vec![add_const(
+            body,
+            block,
+            Type::I32,
+            Operator::I32Const {
+                value: selector as u32,
+            },
+        )];
+        for (slot, value) in slot_values.into_iter().enumerate() {
+            let ty = self.slots[slot];
+            args.push(value.unwrap_or_else(|| add_const(body, block, ty, zero(ty).unwrap())));
+        }
+        args
+    }
+}
+
+/// Give `body` a new entry block, taking the selector and slots of
+/// `layout` and dispatching on the selector to its entries.
+fn add_dispatch(body: &mut FunctionBody, layout: &Layout) {
+    let entry = body.add_block();
+    let selector = body.add_blockparam(entry, Type::I32);
+    let slots = layout
+        .slots
+        .iter()
+        .map(|&ty| body.add_blockparam(entry, ty))
+        .collect::<Vec<_>>();
+    let mut targets = layout
+        .entries
+        .iter()
+        .map(|(block, block_slots)| BlockTarget {
+            block: *block,
+            args: block_slots.iter().map(|&slot| slots[slot]).collect(),
+        })
+        .collect::<Vec<_>>();
+    body.blocks[entry].terminator = if targets.len() == 1 {
+        Terminator::Br {
+            target: targets.pop().unwrap(),
+        }
+    } else {
+        Terminator::Select {
+            value: selector,
+            default: targets.last().cloned().unwrap(),
+            targets,
+        }
+    };
+    body.entry = entry;
+    body.recompute_edges();
+}
+
+/// Whether a part with `layout` can be called: it takes few enough
+/// params, none of reference type.
+fn callable(layout: &Layout) -> bool {
+    if 1 + layout.slots.len() > MAX_PARAMS {
+        log::info!("A part would take {} params", 1 + layout.slots.len());
+        return false;
+    }
+    if layout.slots.iter().any(|&ty| zero(ty).is_none()) {
+        log::info!("A part would take a reference-typed param");
+        return false;
+    }
+    true
+}
+
+fn zero(ty: Type) -> Option<Operator> {
+    match ty {
+        Type::I32 => Some(Operator::I32Const { value: 0 }),
+        Type::I64 => Some(Operator::I64Const { value: 0 }),
+        Type::F32 => Some(Operator::F32Const { value: 0 }),
+        Type::F64 => Some(Operator::F64Const { value: 0 }),
+        Type::V128 => Some(Operator::V128Const { value: 0 }),
+        _ => None,
+    }
+}
+
+fn add_const(body: &mut FunctionBody, block: Block, ty: Type, op: Operator) -> Value {
+    let tys = body.single_type_list(ty);
+    let value = body.add_value(ValueDef::Operator(op, ListRef::default(), tys));
+    body.append_to_block(block, value);
+    value
+}
+
+/// End `block` with a `return_call` of a part (to be resolved later).
+fn add_return_call(body: &mut FunctionBody, block: Block, args: Vec<Value>) -> Value {
+    let args = body.arg_pool.from_iter(args.into_iter());
+    let call = body.add_value(ValueDef::Operator(
+        Operator::ReturnCall {
+            function_index: Func::invalid(),
+        },
+        args,
+        ListRef::default(),
+    ));
+    body.append_to_block(block, call);
+    body.blocks[block].terminator = Terminator::Unreachable;
+    call
+}
+
+/// Split `body`, of a function with signature `sig`, into parts of
+/// `range` PCs each, given the PCs of blocks in `block_pcs`. A block
+/// without a PC joins the part of its first predecessor in RPO (or
+/// the entry's, if none). Returns `None` if there would be only one
+/// part, or the parts couldn't be called.
+pub fn split(
+    module: &Module,
+    sig: Signature,
+    body: &FunctionBody,
+    block_pcs: &BTreeMap<Block, PC>,
+    range: u32,
+) -> Option<SplitFunc> {
+    let cfg = CFGInfo::new(body);
+    let mut keys: BTreeMap<Block, Option<u32>> = BTreeMap::new();
+    for &block in cfg.rpo.values() {
+        let key = match block_pcs.get(&block) {
+            Some(pc) => Some(pc / range),
+            None => cfg.preds[block]
+                .iter()
+                .find_map(|pred| keys.get(pred).copied())
+                .unwrap_or(None),
+        };
+        keys.insert(block, key);
+    }
+    let part_keys = keys.values().copied().collect::<BTreeSet<_>>();
+    if part_keys.len() < 2 {
+        return None;
+    }
+    let part_index = part_keys
+        .iter()
+        .enumerate()
+        .map(|(part, &key)| (key, part))
+        .collect::<BTreeMap<_, _>>();
+    let part_of = keys
+        .iter()
+        .map(|(&block, key)| (block, part_index[key]))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut entries = vec![vec![]; part_keys.len()];
+    let mut cut_blocks = std::collections::HashSet::default();
+    for (&block, &part) in &part_of {
+        let entered = block == body.entry
+            || cfg.preds[block]
+                .iter()
+                .any(|pred| part_of.get(pred).map_or(false, |&other| other != part));
+        if entered {
+            entries[part].push(block);
+            cut_blocks.insert(block);
+        }
+    }
+
+    let mut body = body.clone();
+    body.convert_to_max_ssa(Some(cut_blocks));
+
+    let layouts = entries
+        .iter()
+        .map(|part_entries| Layout::new(&body, &part_entries[..]))
+        .collect::<Vec<_>>();
+    if !layouts.iter().all(callable) {
+        log::info!("Not splitting: a part couldn't be called");
+        return None;
+    }
+
+    let parts = layouts
+        .iter()
+        .enumerate()
+        .map(|(part, layout)| {
+            let mut part_body = body.clone();
+            let mut calls = vec![];
+
+            // Drop the other parts' blocks, and send edges into them
+            // through stubs calling their parts.
+            for block in 0..part_body.blocks.len() {
+                let block = Block::new(block);
+                if part_of.get(&block) != Some(&part) {
+                    let def = &mut part_body.blocks[block];
+                    def.insts.clear();
+                    def.params.clear();
+                    def.terminator = Terminator::Unreachable;
+                }
+            }
+            let mut exits = BTreeSet::new();
+            for (&block, _) in part_of.iter().filter(|&(_, &p)| p == part) {
+                part_body.blocks[block].terminator.visit_targets(|target| {
+                    if part_of[&target.block] != part {
+                        exits.insert(target.block);
+                    }
+                });
+            }
+            let mut stubs = BTreeMap::new();
+            for exit in exits {
+                let stub = part_body.add_block();
+                let params = body.blocks[exit]
+                    .params
+                    .iter()
+                    .map(|&(ty, _)| part_body.add_blockparam(stub, ty))
+                    .collect::<Vec<_>>();
+                let callee = part_of[&exit];
+                let args = layouts[callee].args(&mut part_body, stub, exit, &params[..]);
+                calls.push((add_return_call(&mut part_body, stub, args), callee));
+                stubs.insert(exit, stub);
+            }
+            for (&block, _) in part_of.iter().filter(|&(_, &p)| p == part) {
+                let mut terminator = std::mem::take(&mut part_body.blocks[block].terminator);
+                terminator.update_targets(|target| {
+                    if let Some(&stub) = stubs.get(&target.block) {
+                        target.block = stub;
+                    }
+                });
+                part_body.blocks[block].terminator = terminator;
+            }
+
+            add_dispatch(&mut part_body, layout);
+
+            Part {
+                body: part_body,
+                params: layout.params(),
+                calls,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut trampoline = FunctionBody::new(module, sig);
+    let trampoline_params = module.signatures[sig].params.clone();
+    let entry = trampoline.entry;
+    let params = trampoline.blocks[entry]
+        .params
+        .iter()
+        .map(|&(_, param)| param)
+        .collect::<Vec<_>>();
+    let callee = part_of[&body.entry];
+    let args = layouts[callee].args(&mut trampoline, entry, body.entry, &params[..]);
+    let call = add_return_call(&mut trampoline, entry, args);
+
+    log::info!(
+        "Split into {} parts with {} entries",
+        parts.len(),
+        entries
+            .iter()
+            .map(|part_entries| part_entries.len())
+            .sum::<usize>()
+    );
+    Some(SplitFunc {
+        trampoline: Part {
+            body: trampoline,
+            params: trampoline_params,
+            calls: vec![(call, callee)],
+        },
+        parts,
+    })
+}
+
+/// A generic function made resumable at some of its blocks, for a
+/// specialization cut short (see `PartialEvalOptions::timeouts`) to
+/// continue in where it stopped.
+///
+/// The blocks must be cut blocks of the generic body's max-SSA form,
+/// so that nothing but their params is live into them. The variant is
+/// a copy of the body with a new entry, taking a selector and slots
+/// as a part does, and the specialization ends each block it could
+/// not finish with a `return_call` of it.
+#[derive(Debug)]
+pub struct Resumable {
+    layout: Layout,
+    body: FunctionBody,
+    /// The `return_call`s of the variant in the specialized body.
+    calls: Vec<Value>,
+}
+
+impl Resumable {
+    /// A variant of `generic` resumable at `entries`, or `None` if it
+    /// couldn't be called.
+    pub fn new(generic: &FunctionBody, entries: &[Block]) -> Option<Resumable> {
+        let layout = Layout::new(generic, entries);
+        if !callable(&layout) {
+            return None;
+        }
+        let mut body = generic.clone();
+        add_dispatch(&mut body, &layout);
+        Some(Resumable {
+            layout,
+            body,
+            calls: vec![],
+        })
+    }
+
+    /// End `block` of `body` by resuming the generic function at
+    /// `entry`, with `params` for its params.
+    pub fn resume(
+        &mut self,
+        body: &mut FunctionBody,
+        block: Block,
+        entry: Block,
+        params: &[Value],
+    ) {
+        let args = self.layout.args(body, block, entry, params);
+        self.calls.push(add_return_call(body, block, args));
+    }
+
+    /// The number of blocks resumed at.
+    pub fn entries(&self) -> usize {
+        self.layout.entries.len()
+    }
+
+    /// The variant's body.
+    pub fn body(&self) -> &FunctionBody {
+        &self.body
+    }
+
+    /// Stitch `body`, of a function with params `params`, to the
+    /// variant, as a function of one part.
+    pub fn stitch(self, body: FunctionBody, params: Vec<Type>) -> SplitFunc {
+        SplitFunc {
+            trampoline: Part {
+                body,
+                params,
+                calls: self.calls.into_iter().map(|call| (call, 0)).collect(),
+            },
+            parts: vec![Part {
+                body: self.body,
+                params: self.layout.params(),
+                calls: vec![],
+            }],
+        }
+    }
+}
@@ -34,11 +34,12 @@
 //! examine the state at a given program point and using a different
 //! context implies leaving the current loop.
 
+use crate::collections::HashMap;
 use crate::image::Image;
 use crate::value::{AbstractValue, WasmVal};
-use fxhash::FxHashMap as HashMap;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
 use waffle::entity::{EntityRef, EntityVec, PerEntity};
 use waffle::{Block, FunctionBody, Global, Type, Value};
 
@@ -52,6 +53,13 @@ pub enum ContextElem {
     Root,
     Loop(PC),
     Specialized(Value, u32),
+    /// The first iteration of the annotated loop with this header.
+    Peeled(Block),
+    /// A keyed global holding this (integer) value.
+    Global(Global, u64),
+    /// An iteration of the automatically detected dispatch loop with
+    /// this header, at this PC.
+    AutoLoop(Block, PC),
 }
 
 /// Arena of contexts.
@@ -75,6 +83,11 @@ impl Contexts {
         }
     }
 
+    /// The number of contexts created.
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
     pub fn parent(&self, context: Context) -> Context {
         self.contexts[context].0
     }
@@ -83,6 +96,35 @@ impl Contexts {
         self.contexts[context].1.clone()
     }
 
+    /// The elements of `context`, from the root to the leaf.
+    pub fn path(&self, mut context: Context) -> Vec<ContextElem> {
+        let mut path = vec![];
+        while context.is_valid() {
+            path.push(self.contexts[context].1.clone());
+            context = self.contexts[context].0;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Canonical IDs for all contexts, numbered in order of their
+    /// element paths. Context IDs as created depend on the order in
+    /// which the evaluator's queue happened to reach them; these do
+    /// not, so they are what should appear in any output.
+    pub fn canonical_ids(&self) -> PerEntity<Context, usize> {
+        let mut paths = self
+            .contexts
+            .entries()
+            .map(|(context, _)| (self.path(context), context))
+            .collect::<Vec<_>>();
+        paths.sort();
+        let mut ids = PerEntity::default();
+        for (id, (_, context)) in paths.into_iter().enumerate() {
+            ids[context] = id;
+        }
+        ids
+    }
+
     pub fn pop_one_loop(&self, mut context: Context) -> Context {
         loop {
             match &self.contexts[context] {
@@ -112,6 +154,25 @@ pub struct ProgPointState {
     /// Virtualized locals, with (address, data) pairs for spilling
     /// back to memory at sync points.
     pub locals: BTreeMap<u32, (RegValue, RegValue)>,
+    /// The operand stack declared with `weval.stack.declare`, if it
+    /// was declared on every path here.
+    pub operand_stack: Option<OperandStack>,
+    /// Known contents of the declared operand stack, by slot index:
+    /// the value last stored to each slot. The stores themselves are
+    /// kept, so these only serve to forward loads.
+    pub stack_slots: BTreeMap<u32, RegValue>,
+}
+
+/// An interpreter's operand stack, as declared by the guest: an array
+/// of `max_depth` slots of `slot_size` bytes each, starting at `base`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OperandStack {
+    /// The base pointer, in the specialized function.
+    pub base: Value,
+    /// The base address, if known at specialization time.
+    pub base_addr: Option<u32>,
+    pub slot_size: u32,
+    pub max_depth: u32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -121,6 +182,7 @@ pub enum RegSlot {
     LocalData(u32),
     StackData(u32),
     StackAddr(u32),
+    StackSlot(u32),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -193,6 +255,43 @@ impl RegValue {
             RegValue::Merge { ty, .. } => *ty,
         }
     }
+
+    pub fn abs(&self) -> &AbstractValue {
+        match self {
+            RegValue::Value { abs, .. } => abs,
+            RegValue::Merge { abs, .. } => abs,
+        }
+    }
+}
+
+/// A serializable summary of the flow-sensitive state at entry to
+/// one specialized block, for consumption by external tools (e.g.,
+/// checking the specializer's assumptions against a dynamic trace).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockEntryState {
+    /// Canonical index of the context this block was specialized in
+    /// (see `Contexts::canonical_ids`).
+    pub context: usize,
+    /// Human-readable description of each element of the context
+    /// stack, from root to leaf.
+    pub context_stack: Vec<String>,
+    /// The label most recently given by `weval.trace.here` in the
+    /// context, if any.
+    pub label: Option<String>,
+    /// Block index in the generic function.
+    pub orig_block: usize,
+    /// Block index in the specialized function.
+    pub specialized_block: usize,
+    /// Abstract values of the block's own params.
+    pub params: Vec<AbstractValue>,
+    /// Abstract values of Wasm globals, by global index.
+    pub globals: BTreeMap<usize, AbstractValue>,
+    /// Abstract values of specialization registers.
+    pub regs: BTreeMap<u32, AbstractValue>,
+    /// Abstract values of the virtualized stack, top first.
+    pub stack: Vec<AbstractValue>,
+    /// Abstract values of virtualized locals, by local index.
+    pub locals: BTreeMap<u32, AbstractValue>,
 }
 
 /// The state for a function body during analysis.
@@ -263,17 +362,6 @@ fn map_meet_with<
     changed
 }
 
-fn set_union<K: PartialEq + Eq + PartialOrd + Ord + Copy>(
-    this: &mut BTreeSet<K>,
-    other: &BTreeSet<K>,
-) -> bool {
-    let mut inserted = false;
-    for &elt in other {
-        inserted |= this.insert(elt);
-    }
-    inserted
-}
-
 impl ProgPointState {
     pub fn entry(im: &Image) -> ProgPointState {
         let globals: BTreeMap<Global, AbstractValue> = im
@@ -286,6 +374,9 @@ impl ProgPointState {
                 } else if let &WasmVal::I32(addr) = init_val {
                     // GOT base global.
                     (*global, AbstractValue::StaticMemory(addr))
+                } else if im.immutable_globals.contains(global) {
+                    // An immutable global keeps its initial value.
+                    (*global, AbstractValue::Concrete(*init_val))
                 } else {
                     (*global, AbstractValue::Runtime(None))
                 }
@@ -297,6 +388,8 @@ impl ProgPointState {
             globals,
             stack: vec![],
             locals: BTreeMap::new(),
+            operand_stack: None,
+            stack_slots: BTreeMap::new(),
         }
     }
 
@@ -331,9 +424,55 @@ impl ProgPointState {
             None,
         );
 
+        // A slot is known only if known, with the same type, on both
+        // sides; a stack declared differently is not known at all.
+        if self.operand_stack != other.operand_stack {
+            changed |= self.operand_stack.is_some() || !self.stack_slots.is_empty();
+            self.operand_stack = None;
+            self.stack_slots.clear();
+        }
+        let before = self.stack_slots.clone();
+        self.stack_slots.retain(|slot, value| {
+            matches!(other.stack_slots.get(slot), Some(other) if other.ty() == value.ty())
+        });
+        for (slot, value) in self.stack_slots.iter_mut() {
+            *value = RegValue::meet(value, &other.stack_slots[slot]);
+        }
+        changed |= self.stack_slots != before;
+
         changed
     }
 
+    /// Refine with facts known to hold at the same point from a
+    /// specialization with a subset of this one's constants (a delta
+    /// directive's base): where this state has a global or register
+    /// only at runtime that `base` knows more about, take `base`'s
+    /// abstract value.
+    pub fn refine_with(&mut self, base: &ProgPointState) {
+        let known =
+            |abs: &AbstractValue| !matches!(abs, AbstractValue::Runtime(_) | AbstractValue::Top);
+        for (global, abs) in self.globals.iter_mut() {
+            match base.globals.get(global) {
+                Some(base_abs) if matches!(abs, AbstractValue::Runtime(_)) && known(base_abs) => {
+                    *abs = base_abs.clone();
+                }
+                _ => {}
+            }
+        }
+        for (slot, value) in self.regs.iter_mut() {
+            let base_value = match base.regs.get(slot) {
+                Some(base_value) if base_value.ty() == value.ty() => base_value,
+                _ => continue,
+            };
+            let abs = match value {
+                RegValue::Value { abs, .. } | RegValue::Merge { abs, .. } => abs,
+            };
+            if matches!(abs, AbstractValue::Runtime(_)) && known(base_value.abs()) {
+                *abs = base_value.abs().clone();
+            }
+        }
+    }
+
     pub fn update_across_edge(&mut self) {
         let create_merge = |value: &mut RegValue| {
             if let RegValue::Value { ty, abs, .. } = value {
@@ -357,6 +496,9 @@ impl ProgPointState {
             create_merge(addr);
             create_merge(data);
         }
+        for value in self.stack_slots.values_mut() {
+            create_merge(value);
+        }
     }
 
     pub fn update_at_block_entry<C, GB: FnMut(&mut C, RegSlot, Type) -> Value>(
@@ -386,6 +528,9 @@ impl ProgPointState {
             handle_value(RegSlot::LocalAddr(*i), addr);
             handle_value(RegSlot::LocalData(*i), value);
         }
+        for (&i, value) in self.stack_slots.iter_mut() {
+            handle_value(RegSlot::StackSlot(i), value);
+        }
 
         Ok(())
     }
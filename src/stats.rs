@@ -1,6 +1,6 @@
 //! Post-specialization stats.
 
-use fxhash::FxHashSet;
+use crate::collections::HashSet;
 use waffle::{Block, Func, FunctionBody};
 
 /// Stats per original/generic function.
@@ -23,7 +23,25 @@ pub struct SpecializationStats {
     pub local_writes: usize,
     pub local_reads_mem: usize,
     pub local_writes_mem: usize,
+    pub stack_slot_forwards: usize,
+    pub select_diamonds: usize,
     pub live_value_at_block_start: usize,
+    pub block_evaluations: usize,
+    pub contexts: usize,
+    /// Generic operators folded away, once per context in which they
+    /// were.
+    pub folds: usize,
+    /// Longest the queue of blocks to evaluate grew (the maximum over
+    /// specializations, not the sum).
+    pub max_queue_len: usize,
+    /// Blocks at which a specialization cut short by its timeout
+    /// resumes the generic function.
+    pub resumed_blocks: usize,
+    /// Contexts whose block states dropped their globals once the
+    /// context's region drained from the queue.
+    pub retired_contexts: usize,
+    /// Retired contexts with blocks queued again afterward.
+    pub reentered_contexts: usize,
 }
 
 impl SpecializationStats {
@@ -48,13 +66,22 @@ impl SpecializationStats {
         self.local_reads_mem += stats.local_reads_mem;
         self.local_writes += stats.local_writes;
         self.local_writes_mem += stats.local_writes_mem;
+        self.stack_slot_forwards += stats.stack_slot_forwards;
+        self.select_diamonds += stats.select_diamonds;
         self.live_value_at_block_start += stats.live_value_at_block_start;
+        self.block_evaluations += stats.block_evaluations;
+        self.contexts += stats.contexts;
+        self.folds += stats.folds;
+        self.max_queue_len = std::cmp::max(self.max_queue_len, stats.max_queue_len);
+        self.resumed_blocks += stats.resumed_blocks;
+        self.retired_contexts += stats.retired_contexts;
+        self.reentered_contexts += stats.reentered_contexts;
     }
 }
 
-pub fn count_reachable_blocks_and_insts(body: &FunctionBody) -> (usize, usize, FxHashSet<Block>) {
+pub fn count_reachable_blocks_and_insts(body: &FunctionBody) -> (usize, usize, HashSet<Block>) {
     let mut queue = vec![body.entry];
-    let mut visited = queue.iter().cloned().collect::<FxHashSet<_>>();
+    let mut visited = queue.iter().cloned().collect::<HashSet<_>>();
     let mut insts = 0;
     while let Some(block) = queue.pop() {
         let block_insts = body.blocks[block].insts.len();
@@ -0,0 +1,192 @@
+//! Intra-block store-to-load forwarding.
+//!
+//! Interpreter code specialized by weval is full of spills: a value
+//! is stored to some frame slot and loaded back a few instructions
+//! later. Within one block, a load from `base+K` that follows a store
+//! to `base+K` (same SSA base, same static offset, same width and
+//! type), with no intervening store that may alias and no call, can
+//! reuse the stored value directly.
+//!
+//! Any other side-effecting operator clobbers everything, except
+//! calls to functions known not to write memory (see `effects`);
+//! whether an intervening store clobbers a location is up to the
+//! alias analysis. This is most effective after the constant-offsets pass,
+//! which rewrites addresses to a common base plus static offsets.
+
+use crate::alias::{AliasAnalysis, MemLoc};
+use crate::collections::HashMap;
+use crate::effects::Effects;
+use waffle::{FunctionBody, MemoryArg, Operator, Type, Value, ValueDef};
+
+fn full_width_load(op: &Operator) -> Option<(MemoryArg, Type)> {
+    match op {
+        Operator::I32Load { memory } => Some((*memory, Type::I32)),
+        Operator::I64Load { memory } => Some((*memory, Type::I64)),
+        Operator::F32Load { memory } => Some((*memory, Type::F32)),
+        Operator::F64Load { memory } => Some((*memory, Type::F64)),
+        _ => None,
+    }
+}
+
+/// Returns the memory argument, the access size, and (if full-width
+/// and so forwardable) the stored type.
+fn store(op: &Operator) -> Option<(MemoryArg, u32, Option<Type>)> {
+    match op {
+        Operator::I32Store { memory } => Some((*memory, 4, Some(Type::I32))),
+        Operator::I64Store { memory } => Some((*memory, 8, Some(Type::I64))),
+        Operator::F32Store { memory } => Some((*memory, 4, Some(Type::F32))),
+        Operator::F64Store { memory } => Some((*memory, 8, Some(Type::F64))),
+        Operator::I32Store8 { memory } | Operator::I64Store8 { memory } => Some((*memory, 1, None)),
+        Operator::I32Store16 { memory } | Operator::I64Store16 { memory } => {
+            Some((*memory, 2, None))
+        }
+        Operator::I64Store32 { memory } => Some((*memory, 4, None)),
+        _ => None,
+    }
+}
+
+fn type_size(ty: Type) -> u32 {
+    match ty {
+        Type::I32 | Type::F32 => 4,
+        Type::I64 | Type::F64 => 8,
+        _ => unreachable!(),
+    }
+}
+
+fn is_load(op: &Operator) -> bool {
+    match op {
+        Operator::I32Load { .. }
+        | Operator::I32Load8S { .. }
+        | Operator::I32Load8U { .. }
+        | Operator::I32Load16S { .. }
+        | Operator::I32Load16U { .. }
+        | Operator::I64Load { .. }
+        | Operator::I64Load8S { .. }
+        | Operator::I64Load8U { .. }
+        | Operator::I64Load16S { .. }
+        | Operator::I64Load16U { .. }
+        | Operator::I64Load32S { .. }
+        | Operator::I64Load32U { .. }
+        | Operator::F32Load { .. }
+        | Operator::F64Load { .. } => true,
+        _ => false,
+    }
+}
+
+pub fn run(func: &mut FunctionBody, aa: &AliasAnalysis, effects: &Effects) {
+    let mut forwarded = 0;
+    // Known contents of memory locations (with the type stored),
+    // reset at each block.
+    let mut known: HashMap<MemLoc, (Type, Value)> = HashMap::default();
+
+    for block in func.blocks.iter().collect::<Vec<_>>() {
+        known.clear();
+        let insts = std::mem::take(&mut func.blocks[block].insts);
+        let mut new_insts = Vec::with_capacity(insts.len());
+
+        for inst in insts {
+            let (op, args) = match &func.values[inst] {
+                ValueDef::Operator(op, args, _) => (*op, *args),
+                _ => {
+                    new_insts.push(inst);
+                    continue;
+                }
+            };
+
+            if let Some((memarg, ty)) = full_width_load(&op) {
+                let loc = MemLoc::new(func, memarg, func.arg_pool[args][0], type_size(ty));
+                if let Some(&(stored_ty, value)) = known.get(&loc) {
+                    if stored_ty == ty {
+                        log::trace!("store_forward: forwarding {} to load {}", value, inst);
+                        func.values[inst] = ValueDef::Alias(value);
+                        forwarded += 1;
+                        continue;
+                    }
+                }
+            } else if let Some((memarg, size, ty)) = store(&op) {
+                let loc = MemLoc::new(func, memarg, func.arg_pool[args][0], size);
+                let data = func.resolve_alias(func.arg_pool[args][1]);
+                known.retain(|other, _| !aa.may_alias(other, &loc));
+                if let Some(ty) = ty {
+                    known.insert(loc, (ty, data));
+                }
+            } else if let Operator::Call { function_index } = op {
+                if effects.may_write_memory(function_index) {
+                    known.clear();
+                }
+            } else if !op.is_pure() && !is_load(&op) {
+                // Calls, bulk-memory ops, etc.: assume anything may
+                // have been written.
+                known.clear();
+            }
+
+            new_insts.push(inst);
+        }
+
+        func.blocks[block].insts = new_insts;
+    }
+
+    log::debug!("store_forward: forwarded {} loads", forwarded);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::alias::AliasPrecision;
+    use waffle::entity::EntityRef;
+    use waffle::{FrontendOptions, Func, Module};
+
+    /// Runs the pass over the first function of `wat`, and returns
+    /// what the `i32.load` in it resolves to, along with the function's
+    /// params.
+    fn forward(wat: &str) -> (Value, Vec<Value>) {
+        let bytes = wat::parse_str(wat).unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        let mut func = module.clone_and_expand_body(Func::new(0)).unwrap();
+        let load = func
+            .values
+            .entries()
+            .find(|(_, def)| matches!(def, ValueDef::Operator(Operator::I32Load { .. }, ..)))
+            .map(|(value, _)| value)
+            .unwrap();
+        let aa = AliasAnalysis::new(&func, AliasPrecision::BaseOffset, None);
+        run(&mut func, &aa, &Effects::default());
+        let params = func.blocks[func.entry]
+            .params
+            .iter()
+            .map(|&(_, param)| param)
+            .collect();
+        (func.resolve_alias(load), params)
+    }
+
+    #[test]
+    fn forwards_store_to_load() {
+        let (load, params) = forward(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32 i32) (result i32)
+                (i32.store offset=8 (local.get 0) (local.get 1))
+                (i32.load offset=8 (local.get 0))))
+            "#,
+        );
+        assert_eq!(load, params[1]);
+    }
+
+    #[test]
+    fn may_alias_store_clobbers() {
+        // The second store is off another base, so it may overwrite
+        // the first.
+        let (load, params) = forward(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32 i32 i32) (result i32)
+                (i32.store offset=8 (local.get 0) (local.get 1))
+                (i32.store (local.get 2) (i32.const 0))
+                (i32.load offset=8 (local.get 0))))
+            "#,
+        );
+        assert!(!params.contains(&load));
+    }
+}
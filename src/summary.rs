@@ -0,0 +1,257 @@
+//! Summaries of tiny callees for abstract evaluation across calls.
+//!
+//! Interpreter code is full of accessors: `get_tag(v)`, `frame_pc(f)`,
+//! `LIMIT()`. Without inlining, every call to one is an opaque
+//! runtime value, and a constant flowing through it is lost. Rather
+//! than inline, we summarize each small, side-effect-free callee as
+//! returning one of its arguments, a constant, or a load at a
+//! constant offset from one of its arguments, and the evaluator
+//! applies the summary at direct calls.
+
+use crate::collections::HashMap;
+use crate::value::WasmVal;
+use rayon::prelude::*;
+use std::collections::BTreeSet;
+use waffle::{
+    Func, FuncDecl, FunctionBody, MemoryArg, Module, Operator, Terminator, Value, ValueDef,
+};
+
+/// Largest callee, in instructions, that we summarize.
+const MAX_INSTS: usize = 16;
+
+/// The effect of a side-effect-free callee, in terms of its
+/// arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FuncSummary {
+    /// Returns the argument with this index.
+    ReturnsArg(usize),
+    /// Returns this constant.
+    ReturnsConst(WasmVal),
+    /// Returns the result of this load, whose memory argument has
+    /// any constant address offset folded in, from the argument with
+    /// this index.
+    ReturnsLoad(usize, Operator),
+}
+
+fn memory_arg_mut(op: &mut Operator) -> Option<&mut MemoryArg> {
+    match op {
+        Operator::I32Load { memory }
+        | Operator::I32Load8S { memory }
+        | Operator::I32Load8U { memory }
+        | Operator::I32Load16S { memory }
+        | Operator::I32Load16U { memory }
+        | Operator::I64Load { memory }
+        | Operator::I64Load8S { memory }
+        | Operator::I64Load8U { memory }
+        | Operator::I64Load16S { memory }
+        | Operator::I64Load16U { memory }
+        | Operator::I64Load32S { memory }
+        | Operator::I64Load32U { memory }
+        | Operator::F32Load { memory }
+        | Operator::F64Load { memory } => Some(memory),
+        _ => None,
+    }
+}
+
+fn const_val(op: &Operator) -> Option<WasmVal> {
+    match *op {
+        Operator::I32Const { value } => Some(WasmVal::I32(value)),
+        Operator::I64Const { value } => Some(WasmVal::I64(value)),
+        Operator::F32Const { value } => Some(WasmVal::F32(value)),
+        Operator::F64Const { value } => Some(WasmVal::F64(value)),
+        _ => None,
+    }
+}
+
+/// Summarize one function body, if it is small, has no side effects,
+/// and returns a single value of a summarizable form.
+fn summarize_body(body: &FunctionBody) -> Option<FuncSummary> {
+    // Follow unconditional branches from the entry to the return,
+    // renaming blockparams to the args passed to them.
+    let mut renames: HashMap<Value, Value> = HashMap::default();
+    let resolve = |renames: &HashMap<Value, Value>, mut value: Value| loop {
+        value = body.resolve_alias(value);
+        match renames.get(&value) {
+            Some(&renamed) => value = renamed,
+            None => return value,
+        }
+    };
+    let mut block = body.entry;
+    let mut insts = 0;
+    let mut loads = 0;
+    let mut visited = BTreeSet::new();
+    let ret = loop {
+        if !visited.insert(block) {
+            return None;
+        }
+        for &inst in &body.blocks[block].insts {
+            insts += 1;
+            // Only constants, address arithmetic and loads: nothing
+            // with a side effect.
+            match body.values[inst] {
+                ValueDef::Operator(op, _, _)
+                    if const_val(&op).is_some() || op == Operator::I32Add => {}
+                ValueDef::Operator(mut op, _, _) if memory_arg_mut(&mut op).is_some() => {
+                    loads += 1;
+                }
+                _ => return None,
+            }
+        }
+        match &body.blocks[block].terminator {
+            Terminator::Return { values } if values.len() == 1 => break values[0],
+            Terminator::Br { target } => {
+                let params = &body.blocks[target.block].params;
+                for (&arg, &(_, param)) in target.args.iter().zip(params.iter()) {
+                    renames.insert(param, arg);
+                }
+                block = target.block;
+            }
+            _ => return None,
+        }
+    };
+    if insts > MAX_INSTS {
+        return None;
+    }
+
+    let arg_index = |value: Value| -> Option<usize> {
+        match &body.values[value] {
+            &ValueDef::BlockParam(block, index, _) if block == body.entry => Some(index as usize),
+            _ => None,
+        }
+    };
+    // A load may trap, so may only be dropped if it is the one whose
+    // value is returned: the evaluator folds that only where it can
+    // prove the address in bounds, and otherwise keeps the call.
+    let ret = resolve(&renames, ret);
+    if loads > 1 {
+        return None;
+    }
+    if let Some(index) = arg_index(ret) {
+        return match loads {
+            0 => Some(FuncSummary::ReturnsArg(index)),
+            _ => None,
+        };
+    }
+    let (op, args) = match &body.values[ret] {
+        ValueDef::Operator(op, args, _) => (*op, &body.arg_pool[*args]),
+        _ => return None,
+    };
+    if let Some(k) = const_val(&op) {
+        return match loads {
+            0 => Some(FuncSummary::ReturnsConst(k)),
+            _ => None,
+        };
+    }
+    let mut load = op;
+    let memory = memory_arg_mut(&mut load)?;
+    let addr = resolve(&renames, args[0]);
+    let (base, offset) = match &body.values[addr] {
+        ValueDef::Operator(Operator::I32Add, add_args, _) => {
+            let add_args = &body.arg_pool[*add_args];
+            let (a, b) = (
+                resolve(&renames, add_args[0]),
+                resolve(&renames, add_args[1]),
+            );
+            let konst = |v: Value| match &body.values[v] {
+                ValueDef::Operator(Operator::I32Const { value }, _, _) => Some(*value),
+                _ => None,
+            };
+            match (konst(a), konst(b)) {
+                (None, Some(k)) => (a, k),
+                (Some(k), None) => (b, k),
+                _ => return None,
+            }
+        }
+        _ => (addr, 0),
+    };
+    memory.offset = memory.offset.checked_add(offset)?;
+    Some(FuncSummary::ReturnsLoad(arg_index(base)?, load))
+}
+
+/// Summarize the functions directly called from the given bodies.
+pub fn summarize_callees<'a>(
+    module: &Module,
+    bodies: impl Iterator<Item = &'a FunctionBody>,
+) -> HashMap<Func, FuncSummary> {
+    let mut callees = BTreeSet::new();
+    for body in bodies {
+        for (_, def) in body.values.entries() {
+            if let ValueDef::Operator(Operator::Call { function_index }, ..) = def {
+                if matches!(
+                    &module.funcs[*function_index],
+                    FuncDecl::Lazy(..) | FuncDecl::Body(..)
+                ) {
+                    callees.insert(*function_index);
+                }
+            }
+        }
+    }
+    let summaries = callees
+        .into_par_iter()
+        .filter_map(|func| {
+            let mut body = module.clone_and_expand_body(func).ok()?;
+            waffle::passes::resolve_aliases::run(&mut body);
+            let summary = summarize_body(&body)?;
+            log::debug!("summary of callee {}: {:?}", func, summary);
+            Some((func, summary))
+        })
+        .collect::<HashMap<_, _>>();
+    log::info!("Summarized {} callees", summaries.len());
+    summaries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use waffle::entity::EntityRef;
+    use waffle::FrontendOptions;
+
+    fn summaries(wat: &str) -> Vec<Option<FuncSummary>> {
+        let bytes = wat::parse_str(wat).unwrap();
+        let module = Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+        (0..module.funcs.len())
+            .map(|i| {
+                let mut body = module.clone_and_expand_body(Func::new(i)).unwrap();
+                waffle::passes::resolve_aliases::run(&mut body);
+                summarize_body(&body)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accessors_are_summarized() {
+        let summaries = summaries(
+            r#"
+            (module
+              (memory 1)
+              (func (param i32 i32) (result i32)
+                (local.get 1))
+              (func (param i32) (result i64)
+                (i64.const 42))
+              (func (param i32) (result i32)
+                (i32.load offset=4 (i32.add (local.get 0) (i32.const 8))))
+              (func (param i32) (result i32)
+                (i32.store (local.get 0) (i32.const 1))
+                (local.get 0))
+              (func (param i32) (result i32)
+                (drop (i32.load (local.get 0)))
+                (local.get 0)))
+            "#,
+        );
+        assert_eq!(summaries[0], Some(FuncSummary::ReturnsArg(1)));
+        assert_eq!(
+            summaries[1],
+            Some(FuncSummary::ReturnsConst(WasmVal::I64(42)))
+        );
+        match summaries[2] {
+            Some(FuncSummary::ReturnsLoad(0, Operator::I32Load { memory })) => {
+                assert_eq!(memory.offset, 12)
+            }
+            other => panic!("unexpected summary {:?}", other),
+        }
+        // A store is a side effect.
+        assert_eq!(summaries[3], None);
+        // A load that may trap must not be dropped.
+        assert_eq!(summaries[4], None);
+    }
+}
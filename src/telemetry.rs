@@ -0,0 +1,294 @@
+//! Specialization telemetry: metrics of a run and of each directive,
+//! for tracking weval's performance over time.
+//!
+//! `weval weval --metrics <file>` writes them as a JSON object:
+//!
+//! ```text
+//! {
+//!   "version": "0.1.0",
+//!   "total_ms": 5123.4,
+//!   "phases": [{"name": "wizen", "ms": 812.0}, ...],
+//!   "directives": 12,
+//!   ...
+//!   "per_directive": [{"id": "5d1c0e7a93b2f468", "user_id": 1, ...}, ...]
+//! }
+//! ```
+//!
+//! With the `otel` feature, `--otlp-endpoint <url>` also exports them
+//! to an OpenTelemetry collector over OTLP/HTTP, as histograms and
+//! counters named `weval.*`, with per-directive values attributed to
+//! their weval site.
+//!
+//! weval keeps no cache across runs; the one reuse within a run is a
+//! delta directive (see `PartialEvalOptions::delta_bases`) starting
+//! from its base's facts, which counts as a cache hit here.
+
+use crate::directive::{Directive, DirectiveId};
+use crate::eval::PartialEvalResult;
+use crate::stats::SpecializationStats;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Metrics of one directive's specialization.
+#[derive(Clone, Debug, Serialize)]
+pub struct DirectiveMetrics {
+    /// Stable ID of the directive; see `Directive::id`.
+    pub id: DirectiveId,
+    /// User-given ID of the directive's weval site.
+    pub user_id: u32,
+    /// Name of the generic function.
+    pub func_name: String,
+    /// Wall-clock time to evaluate and optimize the specialization,
+    /// in milliseconds. Directives are specialized in parallel, so
+    /// these may sum to more than the run's time.
+    pub eval_ms: f64,
+    /// Whether a specialization was produced.
+    pub completed: bool,
+    /// Whether the directive started from its delta base's facts.
+    pub base_hit: bool,
+    pub contexts: usize,
+    pub blocks: usize,
+    pub insts: usize,
+    pub block_evaluations: usize,
+    pub max_queue_len: usize,
+    pub folds: usize,
+    /// Blocks at which the specialization resumes the generic
+    /// function, if it timed out.
+    pub resumed_blocks: usize,
+}
+
+impl DirectiveMetrics {
+    /// Metrics of a directive, given the stats of its specialization
+    /// if it completed.
+    pub fn new(
+        directive: &Directive,
+        func_name: &str,
+        eval_time: Duration,
+        stats: Option<&SpecializationStats>,
+        base_hit: bool,
+    ) -> DirectiveMetrics {
+        let stats = stats.cloned();
+        let completed = stats.is_some();
+        let stats = stats.unwrap_or_default();
+        DirectiveMetrics {
+            id: directive.id(),
+            user_id: directive.user_id,
+            func_name: func_name.to_owned(),
+            eval_ms: millis(eval_time),
+            completed,
+            base_hit,
+            contexts: stats.contexts,
+            blocks: stats.specialized_blocks,
+            insts: stats.specialized_insts,
+            block_evaluations: stats.block_evaluations,
+            max_queue_len: stats.max_queue_len,
+            folds: stats.folds,
+            resumed_blocks: stats.resumed_blocks,
+        }
+    }
+}
+
+/// How long a phase of the run took.
+#[derive(Clone, Debug, Serialize)]
+pub struct Phase {
+    pub name: String,
+    pub ms: f64,
+}
+
+/// Times the phases of a run, one after another.
+pub struct Timer {
+    start: Instant,
+    last: Instant,
+    phases: Vec<Phase>,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        let now = Instant::now();
+        Timer {
+            start: now,
+            last: now,
+            phases: vec![],
+        }
+    }
+
+    /// End the current phase, recording it under `name`, and start
+    /// the next.
+    pub fn phase(&mut self, name: &str) {
+        let now = Instant::now();
+        self.phases.push(Phase {
+            name: name.to_owned(),
+            ms: millis(now - self.last),
+        });
+        self.last = now;
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+/// Metrics of a whole run.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunMetrics {
+    /// Version of the weval crate.
+    pub version: &'static str,
+    /// Time from the start of the run to the end of its last phase.
+    pub total_ms: f64,
+    pub phases: Vec<Phase>,
+    /// Directives evaluated, and how many of those completed.
+    pub directives: usize,
+    pub completed: usize,
+    /// Specializations in the output.
+    pub specialized: usize,
+    /// Directives skipped for the size budget, or wrapped instead.
+    pub skipped: usize,
+    pub wrapped: usize,
+    /// Specializations in the output that timed out and resume the
+    /// generic function.
+    pub partial: usize,
+    pub base_hits: usize,
+    pub contexts: usize,
+    pub blocks: usize,
+    pub block_evaluations: usize,
+    pub max_queue_len: usize,
+    pub folds: usize,
+    /// Code added by specialization, and the size of the output
+    /// module, in bytes.
+    pub added_bytes: usize,
+    pub output_bytes: usize,
+    pub per_directive: Vec<DirectiveMetrics>,
+}
+
+impl RunMetrics {
+    /// Metrics of a run, from its timer and result (with directive
+    /// metrics collected; see `PartialEvalOptions::metrics`).
+    pub fn new(timer: &Timer, result: &PartialEvalResult, output_bytes: usize) -> RunMetrics {
+        let per_directive = result.directive_metrics.clone();
+        let sum =
+            |f: fn(&DirectiveMetrics) -> usize| -> usize { per_directive.iter().map(f).sum() };
+        RunMetrics {
+            version: env!("CARGO_PKG_VERSION"),
+            total_ms: millis(timer.last - timer.start),
+            phases: timer.phases.clone(),
+            directives: per_directive.len(),
+            completed: per_directive.iter().filter(|d| d.completed).count(),
+            specialized: result.manifest.entries.len(),
+            skipped: result.skipped.len(),
+            wrapped: result.wrapped.len(),
+            partial: result.partial.len(),
+            base_hits: per_directive.iter().filter(|d| d.base_hit).count(),
+            contexts: sum(|d| d.contexts),
+            blocks: sum(|d| d.blocks),
+            block_evaluations: sum(|d| d.block_evaluations),
+            max_queue_len: per_directive
+                .iter()
+                .map(|d| d.max_queue_len)
+                .max()
+                .unwrap_or(0),
+            folds: sum(|d| d.folds),
+            added_bytes: result.added_bytes,
+            output_bytes,
+            per_directive,
+        }
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Export the metrics to an OpenTelemetry collector at `endpoint`
+    /// over OTLP/HTTP, waiting until they are sent.
+    #[cfg(feature = "otel")]
+    pub fn export_otlp(&self, endpoint: &str) -> anyhow::Result<()> {
+        use opentelemetry::metrics::MeterProvider as _;
+        use opentelemetry::KeyValue;
+        use opentelemetry_otlp::WithExportConfig;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let provider = opentelemetry_otlp::new_pipeline()
+                .metrics(opentelemetry_sdk::runtime::Tokio)
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .http()
+                        .with_endpoint(endpoint),
+                )
+                .with_resource(opentelemetry_sdk::Resource::new(vec![
+                    KeyValue::new("service.name", "weval"),
+                    KeyValue::new("service.version", self.version),
+                ]))
+                .build()?;
+            let meter = provider.meter("weval");
+
+            meter
+                .f64_histogram("weval.run.duration")
+                .with_unit(opentelemetry::metrics::Unit::new("ms"))
+                .init()
+                .record(self.total_ms, &[]);
+            let phase_ms = meter
+                .f64_histogram("weval.phase.duration")
+                .with_unit(opentelemetry::metrics::Unit::new("ms"))
+                .init();
+            for phase in &self.phases {
+                phase_ms.record(
+                    phase.ms,
+                    &[KeyValue::new("weval.phase", phase.name.clone())],
+                );
+            }
+            for (name, value) in [
+                ("weval.specialized", self.specialized),
+                ("weval.skipped", self.skipped),
+                ("weval.wrapped", self.wrapped),
+                ("weval.partial", self.partial),
+                ("weval.added_bytes", self.added_bytes),
+                ("weval.output_bytes", self.output_bytes),
+            ] {
+                meter.u64_counter(name).init().add(value as u64, &[]);
+            }
+
+            let directive_ms = meter
+                .f64_histogram("weval.directive.duration")
+                .with_unit(opentelemetry::metrics::Unit::new("ms"))
+                .init();
+            let counters = [
+                "weval.directives",
+                "weval.directive.completed",
+                "weval.directive.base_hits",
+                "weval.directive.contexts",
+                "weval.directive.blocks",
+                "weval.directive.block_evaluations",
+                "weval.directive.folds",
+            ]
+            .map(|name| meter.u64_counter(name).init());
+            let max_queue_len = meter.u64_histogram("weval.directive.max_queue_len").init();
+            for directive in &self.per_directive {
+                let attrs = [KeyValue::new("weval.site", directive.user_id as i64)];
+                directive_ms.record(directive.eval_ms, &attrs);
+                max_queue_len.record(directive.max_queue_len as u64, &attrs);
+                let values = [
+                    1,
+                    directive.completed as usize,
+                    directive.base_hit as usize,
+                    directive.contexts,
+                    directive.blocks,
+                    directive.block_evaluations,
+                    directive.folds,
+                ];
+                for (counter, value) in counters.iter().zip(values) {
+                    counter.add(value as u64, &attrs);
+                }
+            }
+
+            // Shutting down flushes the metrics to the collector.
+            provider.shutdown()?;
+            Ok(())
+        })
+    }
+}
@@ -1,6 +1,9 @@
 //! Symbolic and concrete values.
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum WasmVal {
     I32(u32),
     I64(u64),
@@ -47,12 +50,13 @@ impl std::convert::TryFrom<waffle::Operator> for WasmVal {
             waffle::Operator::I64Const { value } => Ok(WasmVal::I64(value as u64)),
             waffle::Operator::F32Const { value } => Ok(WasmVal::F32(value)),
             waffle::Operator::F64Const { value } => Ok(WasmVal::F64(value)),
+            waffle::Operator::V128Const { value } => Ok(WasmVal::V128(value)),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum AbstractValue {
     /// "top" default value; undefined.
     #[default]
@@ -64,17 +68,100 @@ pub enum AbstractValue {
     ConcreteMemory(MemoryBufferIndex, u32),
     /// Static memory pointer.
     StaticMemory(u32),
+    /// A value derived from the base of the operand stack declared
+    /// with `weval.stack.declare` by adding this constant (a byte
+    /// offset). Like any abstract value, it flows through blockparams,
+    /// so an interpreter's stack pointer, whose offset at each
+    /// bytecode is static, stays known in every block and context.
+    StackOffset(i32),
+    /// A value derived from the base of the declared operand stack
+    /// shifted left by the first constant, plus the second: a stack
+    /// pointer shifted by a constant (e.g. tagged). Unlike a stack
+    /// offset, it doesn't point into the stack, but the difference of
+    /// two with the same shift is still a constant.
+    ShiftedStackOffset(u32, i32),
     /// A value only computed at runtime. The instruction that
-    /// computed it is specified, if known.
-    Runtime(Option<waffle::Value>),
+    /// computed it is specified, if known. (The cause is not
+    /// serialized: it is only meaningful relative to one function
+    /// body.)
+    Runtime(#[serde(skip)] Option<waffle::Value>),
+}
+
+/// The kinds of fact tracked about a value: the precision tags of
+/// abstract values, and the diagnostic facts kept beside them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tag {
+    /// `AbstractValue::Concrete`.
+    Concrete,
+    /// `AbstractValue::ConcreteMemory`.
+    ConcreteMemory,
+    /// `AbstractValue::StaticMemory`.
+    StaticMemory,
+    /// `AbstractValue::StackOffset` and `ShiftedStackOffset`.
+    StackOffset,
+    /// The imported globals a runtime value is blocked on.
+    BlockedOn,
+}
+
+/// How the facts of one kind combine at a merge point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagMeet {
+    /// A fact survives only if every side carries it: it is an
+    /// assumption the specialized code relies on.
+    Intersect,
+    /// The facts of every side are collected: they are diagnostics,
+    /// which over-approximating cannot make wrong.
+    Union,
+}
+
+impl Tag {
+    /// The meet each kind of fact declares.
+    pub fn meet(self) -> TagMeet {
+        match self {
+            Tag::Concrete | Tag::ConcreteMemory | Tag::StaticMemory | Tag::StackOffset => {
+                TagMeet::Intersect
+            }
+            Tag::BlockedOn => TagMeet::Union,
+        }
+    }
+}
+
+impl TagMeet {
+    /// Meet two sets of facts into `into`. Returns whether it changed.
+    pub fn meet_sets<T: Ord + Clone>(self, into: &mut BTreeSet<T>, other: &BTreeSet<T>) -> bool {
+        let before = into.len();
+        match self {
+            TagMeet::Intersect => into.retain(|fact| other.contains(fact)),
+            TagMeet::Union => into.extend(other.iter().cloned()),
+        }
+        into.len() != before
+    }
 }
 
 /// Memory pointed to by one of the incoming arguments to a
 /// specialized function.
-#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct MemoryBufferIndex(pub u32);
 
 impl AbstractValue {
+    /// The precision tag of this value, if it has one.
+    pub fn tag(&self) -> Option<Tag> {
+        match self {
+            AbstractValue::Concrete(_) => Some(Tag::Concrete),
+            AbstractValue::ConcreteMemory(..) => Some(Tag::ConcreteMemory),
+            AbstractValue::StaticMemory(_) => Some(Tag::StaticMemory),
+            AbstractValue::StackOffset(_) | AbstractValue::ShiftedStackOffset(..) => {
+                Some(Tag::StackOffset)
+            }
+            AbstractValue::Top | AbstractValue::Runtime(_) => None,
+        }
+    }
+
+    /// The meet of two values, at every merge point: a value's
+    /// definitions across block evaluations (`def_value`), blockparam
+    /// args across edges, and block-entry states. Each tag meets as
+    /// it declares (see `Tag::meet`); a value carries at most one, so
+    /// all of them must intersect.
     pub fn meet(a: &AbstractValue, b: &AbstractValue) -> AbstractValue {
         match (a, b) {
             (AbstractValue::Top, x) | (x, AbstractValue::Top) => x.clone(),
@@ -82,6 +169,14 @@ impl AbstractValue {
             (AbstractValue::Concrete(a), AbstractValue::Concrete(b)) if a == b => {
                 AbstractValue::Concrete(*a)
             }
+            // A static-memory pointer is also a plain constant; keep
+            // the more precise tag if both sides agree on the value.
+            (AbstractValue::StaticMemory(addr), AbstractValue::Concrete(WasmVal::I32(k)))
+            | (AbstractValue::Concrete(WasmVal::I32(k)), AbstractValue::StaticMemory(addr))
+                if addr == k =>
+            {
+                AbstractValue::StaticMemory(*addr)
+            }
             (AbstractValue::Runtime(cause1), AbstractValue::Runtime(cause2)) => {
                 log::debug!(
                     "runtime({:?} meet runtime({:?}) -> runtime({:?})",
@@ -94,7 +189,15 @@ impl AbstractValue {
             (AbstractValue::Runtime(cause1), _x) | (_x, AbstractValue::Runtime(cause1)) => {
                 AbstractValue::Runtime(*cause1)
             }
-            (_av1, _av2) => AbstractValue::Runtime(None),
+            // Different facts: their intersection is empty. (No tag
+            // of an abstract value can declare a union, as a value
+            // has room for only one fact.)
+            (av1, av2) => {
+                debug_assert!([av1, av2].iter().all(|av| av
+                    .tag()
+                    .map_or(true, |tag| tag.meet() == TagMeet::Intersect)));
+                AbstractValue::Runtime(None)
+            }
         }
     }
 
@@ -126,3 +229,42 @@ impl AbstractValue {
         self.as_const_u32().map(|k| k != 0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn static_memory_meets_equal_constant() {
+        let ptr = AbstractValue::StaticMemory(0x1000);
+        let k = AbstractValue::Concrete(WasmVal::I32(0x1000));
+        assert_eq!(AbstractValue::meet(&ptr, &k), ptr);
+        assert_eq!(AbstractValue::meet(&k, &ptr), ptr);
+
+        let other = AbstractValue::Concrete(WasmVal::I32(0x1004));
+        assert_eq!(
+            AbstractValue::meet(&ptr, &other),
+            AbstractValue::Runtime(None)
+        );
+        let wide = AbstractValue::Concrete(WasmVal::I64(0x1000));
+        assert_eq!(
+            AbstractValue::meet(&ptr, &wide),
+            AbstractValue::Runtime(None)
+        );
+    }
+
+    #[test]
+    fn tags_meet_as_declared() {
+        let a = BTreeSet::from([1, 2]);
+        let b = BTreeSet::from([2, 3]);
+
+        let mut union = a.clone();
+        assert!(Tag::BlockedOn.meet().meet_sets(&mut union, &b));
+        assert_eq!(union, BTreeSet::from([1, 2, 3]));
+        assert!(!Tag::BlockedOn.meet().meet_sets(&mut union, &b));
+
+        let mut intersection = a.clone();
+        assert!(Tag::Concrete.meet().meet_sets(&mut intersection, &b));
+        assert_eq!(intersection, BTreeSet::from([2]));
+    }
+}
@@ -0,0 +1,677 @@
+//! End-to-end tests: snapshot and weval the fixture interpreter in
+//! `examples/interp`, then run the generic and specialized versions
+//! under Wasmtime and compare their results and cost, and check the
+//! specialized code itself.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use wasmtime::{Config, Engine, Instance, Linker, Module, Store};
+
+/// Fuel given to each run; enough for any of the runs below.
+const FUEL: u64 = 1 << 40;
+
+fn manifest_path(rel: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(rel)
+}
+
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("weval-end-to-end-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Assembles the fixture interpreter and wevals it with the given
+/// extra arguments, returning the generic and wevaled modules.
+fn weval_interpreter(test: &str, args: &[&str]) -> (Vec<u8>, Vec<u8>) {
+    let generic = wat::parse_file(manifest_path("examples/interp/interp.wat")).unwrap();
+    let wevaled = weval_module(test, &generic, &[&["-w"][..], args].concat());
+    (generic, wevaled)
+}
+
+/// Wevals a module with the given extra arguments, returning the
+/// wevaled module.
+fn weval_module(test: &str, generic: &[u8], args: &[&str]) -> Vec<u8> {
+    let dir = scratch_dir(test);
+    let generic_path = dir.join("interp.wasm");
+    let wevaled_path = dir.join("interp.wevaled.wasm");
+    std::fs::write(&generic_path, generic).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_weval"))
+        .arg("weval")
+        .arg("-i")
+        .arg(&generic_path)
+        .arg("-o")
+        .arg(&wevaled_path)
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "weval failed: {}", status);
+    let wevaled = std::fs::read(&wevaled_path).unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    wevaled
+}
+
+/// The custom sections of a module, in order.
+fn custom_sections(module: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut sections = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(module) {
+        if let wasmparser::Payload::CustomSection(reader) = payload.unwrap() {
+            sections.push((reader.name().to_owned(), reader.data().to_vec()));
+        }
+    }
+    sections
+}
+
+/// Runs `run(n)` in the module, returning the result and the fuel
+/// consumed (roughly, the number of Wasm instructions executed).
+fn run(engine: &Engine, module: &Module, n: i32) -> (i32, u64) {
+    run_with(engine, module, n, |_, _| {})
+}
+
+/// As `run`, letting `define` add the module's other imports.
+fn run_with(
+    engine: &Engine,
+    module: &Module,
+    n: i32,
+    define: impl FnOnce(&mut Store<()>, &mut Linker<()>),
+) -> (i32, u64) {
+    let mut store = Store::new(engine, ());
+    let mut linker = Linker::new(engine);
+    // The generic module imports the weval intrinsics; the wevaled
+    // one does not.
+    let stubs = Module::from_file(engine, manifest_path("lib/weval-stubs.wat")).unwrap();
+    let stubs = Instance::new(&mut store, &stubs, &[]).unwrap();
+    linker.instance(&mut store, "weval", stubs).unwrap();
+    define(&mut store, &mut linker);
+    let instance = linker.instantiate(&mut store, module).unwrap();
+    let run = instance
+        .get_typed_func::<i32, i32>(&mut store, "run")
+        .unwrap();
+
+    store.set_fuel(FUEL).unwrap();
+    let result = run.call(&mut store, n).unwrap();
+    let consumed = FUEL - store.get_fuel().unwrap();
+    (result, consumed)
+}
+
+#[test]
+fn interpreter_specializes() {
+    let (generic, wevaled) = weval_interpreter("specializes", &[]);
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let wevaled = Module::new(&engine, &wevaled).unwrap();
+
+    for n in [1, 2, 10, 1000] {
+        let (expected, generic_fuel) = run(&engine, &generic, n);
+        let (actual, wevaled_fuel) = run(&engine, &wevaled, n);
+        assert_eq!(expected, n * (n + 1) / 2);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+        if n >= 1000 {
+            // Specialization removes the dispatch on every bytecode;
+            // it should at least halve the work in the steady state.
+            assert!(
+                wevaled_fuel * 2 <= generic_fuel,
+                "specialized run used {} fuel, generic {}",
+                wevaled_fuel,
+                generic_fuel
+            );
+        }
+    }
+}
+
+#[test]
+fn specialized_interpreter_has_no_residue() {
+    // Without GC, functions keep their indices, and the specialized
+    // interpreter is the last function in the module.
+    let (_, wevaled) = weval_interpreter("no-residue", &["--no-gc"]);
+
+    let mut last_body = None;
+    for payload in wasmparser::Parser::new(0).parse_all(&wevaled) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+            last_body = Some(body);
+        }
+    }
+    let body = last_body.expect("no function bodies");
+
+    let mut residue = vec![];
+    let mut ops = body.get_operators_reader().unwrap();
+    while !ops.eof() {
+        match ops.read().unwrap() {
+            op @ (wasmparser::Operator::Nop
+            | wasmparser::Operator::Select
+            | wasmparser::Operator::TypedSelect { .. }) => residue.push(format!("{:?}", op)),
+            _ => {}
+        }
+    }
+    // Every `select` in the interpreter is on an operand from the
+    // (constant) bytecode, so all should fold away, as should `nop`s.
+    assert!(
+        residue.is_empty(),
+        "residue in specialized code: {:?}",
+        residue
+    );
+}
+
+#[test]
+fn unknown_custom_sections_are_preserved() {
+    use wasm_encoder::{Encode, Section};
+
+    let assorted: [(&str, &[u8]); 5] = [
+        ("producers", b"\x01\x08language\x01\x04Rust\x061.80.0"),
+        ("sourceMappingURL", b"\x0finterp.wasm.map"),
+        (
+            "component-type:interp",
+            &[0x00, 0x61, 0x73, 0x6d, 0x0d, 0x00, 0x01, 0x00],
+        ),
+        (
+            "toolchain.metadata",
+            &[0xff, 0x00, 0x80, 0x80, 0x80, 0x80, 0x10],
+        ),
+        ("empty", b""),
+    ];
+    let mut generic = wat::parse_file(manifest_path("examples/interp/interp.wat")).unwrap();
+    for (name, data) in assorted {
+        let section = wasm_encoder::CustomSection {
+            name: name.into(),
+            data: data.into(),
+        };
+        generic.push(section.id());
+        section.encode(&mut generic);
+    }
+
+    let wevaled = weval_module("custom-sections", &generic, &["-w"]);
+    let sections = custom_sections(&wevaled);
+
+    // Each comes through byte-for-byte and in order, alongside the
+    // sections weval writes itself.
+    let preserved = sections
+        .iter()
+        .filter(|(name, _)| !weval::custom_sections::is_transformed(name))
+        .map(|(name, data)| (name.as_str(), &data[..]))
+        .collect::<Vec<_>>();
+    assert_eq!(preserved, assorted);
+    assert!(
+        sections.iter().filter(|(name, _)| name == "name").count() <= 1,
+        "more than one name section"
+    );
+    Module::new(&Engine::default(), &wevaled).unwrap();
+}
+
+#[test]
+fn metrics_are_written() {
+    let path = std::env::temp_dir().join(format!(
+        "weval-end-to-end-metrics-{}.json",
+        std::process::id()
+    ));
+    weval_interpreter("metrics", &["--metrics", path.to_str().unwrap()]);
+    let metrics: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let directives = metrics["per_directive"].as_array().unwrap();
+    assert!(!directives.is_empty());
+    assert_eq!(metrics["directives"], directives.len());
+    assert_eq!(metrics["completed"], metrics["directives"]);
+    for directive in directives {
+        // The interpreter's bytecode is constant, so every
+        // specialization folds its dispatch.
+        assert!(directive["contexts"].as_u64().unwrap() > 0);
+        assert!(directive["folds"].as_u64().unwrap() > 0);
+    }
+    let phases = metrics["phases"].as_array().unwrap();
+    assert!(phases.iter().any(|phase| phase["name"] == "specialize"));
+}
+
+#[test]
+fn fixture_passes_strict_intrinsics() {
+    // Every intrinsic call in the fixture interpreter is acted on, so
+    // strict mode has nothing to flag.
+    weval_interpreter("strict", &["--strict-intrinsics", "error"]);
+}
+
+#[test]
+fn fused_interpreter_agrees() {
+    let (generic, wevaled) = weval_interpreter("fused", &["--fuse"]);
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let wevaled = Module::new(&engine, &wevaled).unwrap();
+    for n in [1, 2, 10, 1000] {
+        let (expected, _) = run(&engine, &generic, n);
+        let (actual, _) = run(&engine, &wevaled, n);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+    }
+}
+
+#[test]
+fn timed_out_specialization_resumes_generic_code() {
+    // With no time at all, the specialization stops at its first cut
+    // block and continues in the generic function from there.
+    let (generic, wevaled) = weval_interpreter("timeout", &["--timeout", "0"]);
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let wevaled = Module::new(&engine, &wevaled).unwrap();
+    for n in [1, 2, 10, 1000] {
+        let (expected, _) = run(&engine, &generic, n);
+        let (actual, _) = run(&engine, &wevaled, n);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+    }
+}
+
+#[test]
+fn side_module_specializes_against_main_memory() {
+    let dir = scratch_dir("side-module");
+    let fixture = |name| manifest_path(&format!("tests/fixtures/side-module/{}", name));
+    let main_path = dir.join("main.wasm");
+    let side_path = dir.join("side.wasm");
+    let main_out = dir.join("main.wevaled.wasm");
+    let side_out = dir.join("side.wevaled.wasm");
+    let side = wat::parse_file(fixture("side.wat")).unwrap();
+    let main = wat::parse_file(fixture("main.wat")).unwrap();
+    std::fs::write(&main_path, &main).unwrap();
+    std::fs::write(&side_path, &side).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_weval"))
+        .arg("weval")
+        .arg("-i")
+        .arg(&main_path)
+        .arg("-o")
+        .arg(&main_out)
+        .arg("-w")
+        .arg("--side-module")
+        .arg(format!(
+            "{},{},1024,2",
+            side_path.display(),
+            side_out.display()
+        ))
+        .status()
+        .unwrap();
+    assert!(status.success(), "weval failed: {}", status);
+    let wevaled_main = std::fs::read(&main_out).unwrap();
+    let wevaled_side = std::fs::read(&side_out).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // The side module keeps its imports, and records a slot for the
+    // specialized interpreter after its own.
+    let imports = wasmparser::Parser::new(0)
+        .parse_all(&wevaled_side)
+        .filter_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::ImportSection(imports) => Some(
+                imports
+                    .into_iter()
+                    .map(|import| import.unwrap().name.to_owned())
+                    .collect::<Vec<_>>(),
+            ),
+            _ => None,
+        })
+        .next()
+        .unwrap();
+    assert_eq!(
+        imports,
+        [
+            "memory",
+            "__indirect_function_table",
+            "__stack_pointer",
+            "__memory_base",
+            "__table_base"
+        ]
+    );
+    let (_, dylink) = custom_sections(&wevaled_side)
+        .into_iter()
+        .find(|(name, _)| name == "dylink.0")
+        .unwrap();
+    assert_eq!(dylink, b"\x01\x04\x04\x02\x02\x00");
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let stubs = Module::from_file(&engine, manifest_path("lib/weval-stubs.wat")).unwrap();
+    // Load the side module as the dynamic linker would, at table base
+    // 2 and memory base 1024, and run the main module's `run`.
+    let run_linked = |main: &[u8], side: &[u8], n: i32| {
+        let mut store = Store::new(&engine, ());
+        let main = Module::new(&engine, main).unwrap();
+        let main = Instance::new(&mut store, &main, &[]).unwrap();
+        let memory = main.get_memory(&mut store, "memory").unwrap();
+        let table = main
+            .get_table(&mut store, "__indirect_function_table")
+            .unwrap();
+        let global = |store: &mut Store<()>, mutability, value| {
+            let ty = wasmtime::GlobalType::new(wasmtime::ValType::I32, mutability);
+            wasmtime::Global::new(store, ty, wasmtime::Val::I32(value)).unwrap()
+        };
+        let stack_pointer = global(&mut store, wasmtime::Mutability::Var, 65536);
+        let memory_base = global(&mut store, wasmtime::Mutability::Const, 1024);
+        let table_base = global(&mut store, wasmtime::Mutability::Const, 2);
+        let mut linker = Linker::new(&engine);
+        let stubs = Instance::new(&mut store, &stubs, &[]).unwrap();
+        linker.instance(&mut store, "weval", stubs).unwrap();
+        linker.define(&store, "env", "memory", memory).unwrap();
+        linker
+            .define(&store, "env", "__indirect_function_table", table)
+            .unwrap();
+        linker
+            .define(&store, "env", "__stack_pointer", stack_pointer)
+            .unwrap();
+        linker
+            .define(&store, "env", "__memory_base", memory_base)
+            .unwrap();
+        linker
+            .define(&store, "env", "__table_base", table_base)
+            .unwrap();
+        let side = Module::new(&engine, side).unwrap();
+        linker.instantiate(&mut store, &side).unwrap();
+        let run = main.get_typed_func::<i32, i32>(&mut store, "run").unwrap();
+        store.set_fuel(FUEL).unwrap();
+        let result = run.call(&mut store, n).unwrap();
+        (result, FUEL - store.get_fuel().unwrap())
+    };
+    for n in [1, 2, 10, 1000] {
+        let (expected, generic_fuel) = run_linked(&main, &side, n);
+        let (actual, wevaled_fuel) = run_linked(&wevaled_main, &wevaled_side, n);
+        assert_eq!(expected, n * (n + 1) / 2);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+        if n >= 1000 {
+            assert!(
+                wevaled_fuel * 2 <= generic_fuel,
+                "specialized run used {} fuel, generic {}",
+                wevaled_fuel,
+                generic_fuel
+            );
+        }
+    }
+}
+
+/// Runs `run(n)` in the imported-global fixture, with `env.step` = 1.
+fn run_with_step(engine: &Engine, module: &Module, n: i32) -> (i32, u64) {
+    run_with(engine, module, n, |store, linker| {
+        let ty = wasmtime::GlobalType::new(wasmtime::ValType::I32, wasmtime::Mutability::Const);
+        let step = wasmtime::Global::new(&mut *store, ty, wasmtime::Val::I32(1)).unwrap();
+        linker.define(&*store, "env", "step", step).unwrap();
+    })
+}
+
+#[test]
+fn imported_global_keeps_indices_through_gc() {
+    // GC (on by default) removes the dead defined global, so the live
+    // one is renumbered past the imported global, which keeps its
+    // index.
+    let generic = wat::parse_file(manifest_path("tests/fixtures/imported-global.wat")).unwrap();
+    let wevaled = weval_module("imported-global-gc", &generic, &[]);
+
+    let mut globals = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(&wevaled) {
+        if let wasmparser::Payload::GlobalSection(section) = payload.unwrap() {
+            globals = section.count();
+        }
+    }
+    // `$runs`, plus weval's two globals.
+    assert_eq!(globals, 3);
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let wevaled = Module::new(&engine, &wevaled).unwrap();
+    for n in [1, 2, 10, 1000] {
+        let (expected, _) = run_with_step(&engine, &generic, n);
+        let (actual, _) = run_with_step(&engine, &wevaled, n);
+        assert_eq!(expected, n * (n + 1) / 2);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+    }
+}
+
+/// The report of `weval analyze` on a fixture in `tests/fixtures`.
+fn analyze_report(fixture: &str) -> String {
+    let generic =
+        wat::parse_file(manifest_path(&format!("tests/fixtures/{}.wat", fixture))).unwrap();
+    let dir = scratch_dir(fixture);
+    let generic_path = dir.join("generic.wasm");
+    std::fs::write(&generic_path, &generic).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_weval"))
+        .arg("analyze")
+        .arg("-i")
+        .arg(&generic_path)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "weval analyze failed: {}",
+        output.status
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn blocked_on_unions_over_predecessors() {
+    // The loop header's param comes from `env.a` on entry and `env.b`
+    // on the back edge; the branch on it is blocked on both.
+    let report = analyze_report("blocked-on-two-globals");
+    assert!(
+        report.contains("blocked on imported globals env.a, env.b"),
+        "unexpected report:\n{}",
+        report
+    );
+}
+
+#[test]
+fn blocked_on_and_constant_meet_at_one_blockparam() {
+    // The loop header's param is a constant on entry and comes from
+    // `env.a` on the back edge: the constant is lost, and the branch
+    // on the param is blocked on `env.a`.
+    let report = analyze_report("blocked-on-constant-and-global");
+    assert!(
+        report.contains("blocked on imported global env.a"),
+        "unexpected report:\n{}",
+        report
+    );
+}
+
+/// The number of operators matching `pred` in the module's last
+/// function body (the specialized function).
+fn ops_in_last_body(module: &[u8], pred: impl Fn(&wasmparser::Operator) -> bool) -> usize {
+    let mut last_body = None;
+    for payload in wasmparser::Parser::new(0).parse_all(module) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+            last_body = Some(body);
+        }
+    }
+    let mut count = 0;
+    let mut ops = last_body.unwrap().get_operators_reader().unwrap();
+    while !ops.eof() {
+        count += pred(&ops.read().unwrap()) as usize;
+    }
+    count
+}
+
+/// The number of reads of the global at `index` in the module's last
+/// function body (the specialized interpreter).
+fn global_reads_in_last_body(module: &[u8], index: u32) -> usize {
+    ops_in_last_body(
+        module,
+        |op| matches!(op, wasmparser::Operator::GlobalGet { global_index } if *global_index == index),
+    )
+}
+
+#[test]
+fn assumed_imported_global_folds() {
+    let generic = wat::parse_file(manifest_path("tests/fixtures/imported-global.wat")).unwrap();
+    let unknown = weval_module("imported-global-unknown", &generic, &[]);
+    let assumed = weval_module(
+        "imported-global-assumed",
+        &generic,
+        &["--imported-global", "env.step=1"],
+    );
+    // The import is global 0: read at runtime unless assumed.
+    assert!(global_reads_in_last_body(&unknown, 0) > 0);
+    assert_eq!(global_reads_in_last_body(&assumed, 0), 0);
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let assumed = Module::new(&engine, &assumed).unwrap();
+    for n in [1, 2, 10, 1000] {
+        let (expected, _) = run_with_step(&engine, &generic, n);
+        let (actual, _) = run_with_step(&engine, &assumed, n);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+    }
+}
+
+#[test]
+fn constant_blockparam_is_hoisted() {
+    let generic = wat::parse_file(manifest_path("tests/fixtures/hoisted-state.wat")).unwrap();
+    let hoisted = weval_module("hoisted-state", &generic, &[]);
+    let merged = weval_module(
+        "hoisted-state-disabled",
+        &generic,
+        &["--max-hoisted-blocks", "0"],
+    );
+    // Each state gets its own copy of the loop header, so the
+    // dispatch folds; without hoisting, the state merges to a runtime
+    // value and the dispatch stays.
+    let is_br_table =
+        |op: &wasmparser::Operator| matches!(op, wasmparser::Operator::BrTable { .. });
+    assert_eq!(ops_in_last_body(&hoisted, is_br_table), 0);
+    assert!(ops_in_last_body(&merged, is_br_table) > 0);
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    for wevaled in [hoisted, merged] {
+        let wevaled = Module::new(&engine, &wevaled).unwrap();
+        for n in [0, 1, 7, 1000] {
+            let (expected, _) = run(&engine, &generic, n);
+            let (actual, _) = run(&engine, &wevaled, n);
+            assert_eq!(expected, n * 3);
+            assert_eq!(actual, expected, "results differ for n = {}", n);
+        }
+    }
+}
+
+#[test]
+fn shifted_stack_pointers_keep_their_distance() {
+    let generic =
+        wat::parse_file(manifest_path("tests/fixtures/shifted-stack-pointer.wat")).unwrap();
+    let wevaled = weval_module("shifted-stack-pointer", &generic, &[]);
+    // The base is only known at runtime, but the difference of the
+    // two tagged pointers, and the depth check on it, fold.
+    let is_sub = |op: &wasmparser::Operator| matches!(op, wasmparser::Operator::I32Sub);
+    assert_eq!(ops_in_last_body(&wevaled, is_sub), 0);
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let wevaled = Module::new(&engine, &wevaled).unwrap();
+    for n in [0, 1, 7, 1000] {
+        let (expected, _) = run(&engine, &generic, n);
+        let (actual, _) = run(&engine, &wevaled, n);
+        assert_eq!(expected, 2 * n + 1);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+    }
+}
+
+#[test]
+fn widened_values_reevaluate_dependents() {
+    // Without hoisting, the counter merges to a runtime value at the
+    // loop header, and every block using it is re-evaluated (from
+    // its start) to undo the fold of the body's branch.
+    let generic = wat::parse_file(manifest_path("tests/fixtures/widening.wat")).unwrap();
+    let wevaled = weval_module("widening", &generic, &["--max-hoisted-blocks", "0"]);
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let wevaled = Module::new(&engine, &wevaled).unwrap();
+    for n in [0, 1, 2, 7, 1000] {
+        let expected = (0..n).map(|i| if i & 1 != 0 { i } else { -1 }).sum::<i32>();
+        assert_eq!(run(&engine, &generic, n).0, expected);
+        let (actual, _) = run(&engine, &wevaled, n);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+    }
+}
+
+#[test]
+fn regression_fixtures_agree() {
+    // Each fixture in `tests/fixtures/regressions` carries a pending
+    // request for its `run` function, with a runtime argument.
+    let mut fixtures = std::fs::read_dir(manifest_path("tests/fixtures/regressions"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "wat"))
+        .collect::<Vec<_>>();
+    fixtures.sort();
+    assert!(!fixtures.is_empty());
+
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    for fixture in fixtures {
+        let name = fixture.file_stem().unwrap().to_str().unwrap().to_owned();
+        let generic = wat::parse_file(&fixture).unwrap();
+        // Also with drained contexts retired, which may lose precision
+        // but must not change results.
+        let wevaled = [&[][..], &["--retire-drained-contexts"][..]]
+            .iter()
+            .enumerate()
+            .map(|(i, args)| weval_module(&format!("regression-{}-{}", name, i), &generic, args))
+            .collect::<Vec<_>>();
+        let generic = Module::new(&engine, &generic).unwrap();
+        for wevaled in wevaled {
+            let wevaled = Module::new(&engine, &wevaled)
+                .unwrap_or_else(|e| panic!("{}: invalid wevaled module: {}", name, e));
+            for n in [0, 1, 2, 7, 11, 1000] {
+                let (expected, _) = run(&engine, &generic, n);
+                let (actual, _) = run(&engine, &wevaled, n);
+                assert_eq!(actual, expected, "{}: results differ for n = {}", name, n);
+            }
+        }
+    }
+}
+
+/// The number of function bodies in a module, and of `return_call`s
+/// in them.
+fn bodies_and_return_calls(module: &[u8]) -> (usize, usize) {
+    let (mut bodies, mut return_calls) = (0, 0);
+    for payload in wasmparser::Parser::new(0).parse_all(module) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+            bodies += 1;
+            let mut ops = body.get_operators_reader().unwrap();
+            while !ops.eof() {
+                if let wasmparser::Operator::ReturnCall { .. } = ops.read().unwrap() {
+                    return_calls += 1;
+                }
+            }
+        }
+    }
+    (bodies, return_calls)
+}
+
+#[test]
+fn split_interpreter_agrees() {
+    let (_, whole) = weval_interpreter("unsplit", &[]);
+    let (generic, split) = weval_interpreter("split", &["--split-pc-range", "2"]);
+
+    // The fixture's program spans several ranges of two PCs, each in
+    // a part of its own, entered from the others by tail calls.
+    let (whole_bodies, whole_return_calls) = bodies_and_return_calls(&whole);
+    let (split_bodies, split_return_calls) = bodies_and_return_calls(&split);
+    assert!(
+        split_bodies > whole_bodies,
+        "{} bodies split, {} whole",
+        split_bodies,
+        whole_bodies
+    );
+    assert_eq!(whole_return_calls, 0);
+    assert!(split_return_calls > 0);
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    config.wasm_tail_call(true);
+    let engine = Engine::new(&config).unwrap();
+    let generic = Module::new(&engine, &generic).unwrap();
+    let split = Module::new(&engine, &split).unwrap();
+    for n in [1, 2, 10, 1000] {
+        let (expected, _) = run(&engine, &generic, n);
+        let (actual, _) = run(&engine, &split, n);
+        assert_eq!(actual, expected, "results differ for n = {}", n);
+    }
+}
@@ -0,0 +1,49 @@
+;; A loop whose exit condition is on a value that comes from the
+;; imported global `env.a` on entry and from `env.b` on the back
+;; edge, so that the first runtime branch is blocked on both. The
+;; loop runs at most twice.
+;;
+;; The request is already pending in the data segments, as in
+;; `tests/fixtures/regressions`.
+
+(module
+  (type $f_t (func (param i32) (result i32)))
+  (import "env" "a" (global $a i32))
+  (import "env" "b" (global $b i32))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $f)
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\10\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $f_t) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $f (local.get $n)))))
+
+  (func $f (type $f_t) (param $n i32) (result i32)
+    (local $x i32)
+    (local $again i32)
+    (local $was i32)
+    (local.set $x (global.get $a))
+    (local.set $again (i32.const 1))
+    (block $done
+      (loop $l
+        (br_if $done (i32.eqz (local.get $x)))
+        (local.set $x (global.get $b))
+        (local.set $n (i32.add (local.get $n) (i32.const 1)))
+        (local.set $was (local.get $again))
+        (local.set $again (i32.const 0))
+        (br_if $l (local.get $was))))
+    (local.get $n)))
@@ -0,0 +1,51 @@
+;; A three-state machine whose state is a loop-carried local, set to a
+;; different constant on each edge back to the loop header. No context
+;; is pushed, so only hoisting the state blockparam into the context
+;; lets the specialization fold the dispatch.
+;;
+;; The request (with one runtime argument) is already pending in the
+;; data segments, so this needs no snapshot.
+
+(module
+  (type $sm_t (func (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $sm)
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\10\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $sm_t) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $sm (local.get $n)))))
+
+  (func $sm (type $sm_t) (param $n i32) (result i32)
+    (local $state i32) (local $acc i32)
+    (block $done
+      (loop $loop
+        (block $s2
+          (block $s1
+            (block $s0
+              (br_table $s0 $s1 $s2 (local.get $state)))
+            ;; state 0
+            (local.set $acc (i32.add (local.get $acc) (local.get $n)))
+            (local.set $state (i32.const 1))
+            (br $loop))
+          ;; state 1
+          (local.set $acc (i32.mul (local.get $acc) (i32.const 3)))
+          (local.set $state (i32.const 2))
+          (br $loop))
+        ;; state 2
+        (br $done)))
+    (local.get $acc)))
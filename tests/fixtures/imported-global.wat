@@ -0,0 +1,123 @@
+;; The fixture interpreter of `examples/interp`, with its DEC step
+;; read from the imported global `env.step` (which the embedder sets
+;; to 1). Global 0 is the import; of the defined globals, `$unused` is
+;; dead and `$runs` is live, so removing dead globals renumbers one.
+;;
+;; The request is already pending in the data segments, so this needs
+;; no snapshot (which could not provide the import).
+
+(module
+  (type $interp_t (func (param i32 i32) (result i32)))
+  (import "weval" "push.context" (func $push_context (param i32)))
+  (import "weval" "pop.context" (func $pop_context))
+  (import "weval" "update.context" (func $update_context (param i32)))
+  (import "env" "step" (global $step i32))
+
+  (global $unused (mut i32) (i32.const 0))
+  (global $runs (mut i32) (i32.const 0))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $interp)
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\80\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\01\00\00\00\04\00\00\00\60\00\00\00\60\00\00\00"
+    "\01\00\00\00\00\00\00\00\03\00\00\00\00\00\00\00"
+    "\02\00\00\00\03\00\00\00\01\00\00\00\04\00\00\00"
+    "\00\00\00\00\05\00\00\00\01\00\00\00\03\00\00\00"
+    "\00\00\00\00\04\00\00\00\01\00\00\00\06\00\00\00"
+    "\03\00\00\00\01\00\00\00\07\00\00\00\07\00\00\00"
+    "\04\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00"
+    "\00\00\00\00\ff\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (global.set $runs (i32.add (global.get $runs) (i32.const 1)))
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $interp_t)
+          (i32.const 144) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $interp (i32.const 144) (local.get $n)))))
+
+  (func $interp (type $interp_t) (param $prog i32) (param $arg i32) (result i32)
+    (local $pc i32) (local $acc i32) (local $r0 i32) (local $r1 i32)
+    (local $op i32) (local $imm i32)
+    (call $push_context (i32.const 0))
+    (block $halt
+      (loop $loop
+        (local.set $op
+          (i32.load
+            (i32.add (local.get $prog) (i32.shl (local.get $pc) (i32.const 2)))))
+        (local.set $imm
+          (i32.load offset=4
+            (i32.add (local.get $prog) (i32.shl (local.get $pc) (i32.const 2)))))
+        (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+        (block $bad
+          (block $jnz
+            (block $dec
+              (block $add
+                (block $load
+                  (block $store
+                    (block $argop
+                      (block $loadi
+                        (br_table $halt $loadi $argop $store $load $add $dec $jnz $bad
+                          (local.get $op))
+                        ;; dead
+                        (local.set $op (i32.const 0)))
+                      ;; LOADI
+                      (local.set $acc (local.get $imm))
+                      (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                      (call $update_context (local.get $pc))
+                      (br $loop))
+                    ;; ARG
+                    (local.set $acc (local.get $arg))
+                    (call $update_context (local.get $pc))
+                    (br $loop))
+                  ;; STORE
+                  (if (local.get $imm)
+                    (then (local.set $r1 (local.get $acc)))
+                    (else (local.set $r0 (local.get $acc))))
+                  (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                  (call $update_context (local.get $pc))
+                  (br $loop))
+                ;; LOAD
+                (local.set $acc
+                  (select (local.get $r1) (local.get $r0) (local.get $imm)))
+                (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                (call $update_context (local.get $pc))
+                (br $loop))
+              ;; ADD
+              (local.set $acc
+                (i32.add (local.get $acc)
+                  (select (local.get $r1) (local.get $r0) (local.get $imm))))
+              (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+              (call $update_context (local.get $pc))
+              (br $loop))
+            ;; DEC
+            nop
+            (local.set $acc (i32.sub (local.get $acc) (global.get $step)))
+            (call $update_context (local.get $pc))
+            (br $loop))
+          ;; JNZ
+          (if (local.get $acc)
+            (then
+              (local.set $pc (local.get $imm))
+              (call $update_context (local.get $pc))
+              (br $loop)))
+          (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+          (call $update_context (local.get $pc))
+          (br $loop))
+        ;; bad opcode
+        unreachable
+        ;; dead
+        (local.set $acc (i32.const 0))
+        (br $loop)))
+    (call $pop_context)
+    (local.get $acc)))
@@ -0,0 +1,47 @@
+;; A dispatch whose arms are followed by dead code, and a trapping arm
+;; followed by more: the frontend leaves the dead code in blocks with
+;; no terminator, which specialization must not emit as-is.
+
+(module
+  (type $f_t (func (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $f)
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\10\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $f_t) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $f (local.get $n)))))
+
+  (func $f (type $f_t) (param $n i32) (result i32)
+    (local $acc i32)
+    (block $out
+      (block $bad
+        (block $odd
+          (block $even
+            (br_table $even $odd $bad (i32.and (local.get $n) (i32.const 1)))
+            ;; dead
+            (local.set $acc (i32.const 7)))
+          (local.set $acc (i32.add (local.get $n) (i32.const 1)))
+          (br $out)
+          ;; dead
+          (local.set $acc (i32.const 8)))
+        (local.set $acc (i32.mul (local.get $n) (i32.const 3)))
+        (br $out))
+      unreachable
+      ;; dead
+      (local.set $acc (i32.const 9)))
+    (local.get $acc)))
@@ -0,0 +1,44 @@
+;; Empty blocks, loops and `if` arms, and dead code after `return`s,
+;; all of which the frontend turns into empty or unterminated blocks.
+
+(module
+  (type $f_t (func (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $f)
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\10\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $f_t) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $f (local.get $n)))))
+
+  (func $f (type $f_t) (param $n i32) (result i32)
+    (block)
+    (loop)
+    (if (local.get $n) (then))
+    (block $nonzero
+      (loop $l
+        (br_if $nonzero (local.get $n))
+        (return (i32.const 5))
+        ;; dead
+        (br $l)))
+    (if (result i32) (i32.gt_u (local.get $n) (i32.const 10))
+      (then
+        (return (local.get $n))
+        ;; dead
+        (i32.const 0))
+      (else
+        (i32.const 1)))))
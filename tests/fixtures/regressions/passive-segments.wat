@@ -0,0 +1,44 @@
+;; Passive data segments, after the active ones so that their indices
+;; must survive the image being written back: a `memory.init` with
+;; constant operands (folded into stores), one with a runtime source
+;; offset, and one from a segment that is also dropped (both kept).
+
+(module
+  (type $f_t (func (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $f)
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\10\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+  (data $greeting "hello world")
+  (data $table "\01\02\03\04\05\06\07\08\09\0a\0b\0c\0d\0e\0f\10")
+  (data $once "\2a\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $f_t) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $f (local.get $n)))))
+
+  (func $f (type $f_t) (param $n i32) (result i32)
+    (memory.init $greeting (i32.const 256) (i32.const 0) (i32.const 11))
+    (memory.init $table
+      (i32.const 512) (i32.and (local.get $n) (i32.const 7)) (i32.const 4))
+    (memory.init $once (i32.const 768) (i32.const 0) (i32.const 4))
+    (data.drop $once)
+    (i32.add
+      (i32.load8_u
+        (i32.add (i32.const 256) (i32.rem_u (local.get $n) (i32.const 11))))
+      (i32.add
+        (i32.load (i32.const 512))
+        (i32.load (i32.const 768))))))
@@ -0,0 +1,60 @@
+;; An operand stack at a runtime base, with its pointers tagged by
+;; shifting them left. The distance between two tagged pointers (the
+;; depth) is still a constant, so the check on it folds away.
+;;
+;; The request (with one runtime argument) is already pending in the
+;; data segments, so this needs no snapshot.
+
+(module
+  (type $f_t (func (param i32) (result i32)))
+
+  (import "weval" "stack.declare" (func $declare (param i32 i32 i32)))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $f)
+
+  (global $stack (export "stack") (mut i32) (i32.const 1024))
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\10\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $f_t) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $f (local.get $n)))))
+
+  (func $f (type $f_t) (param $n i32) (result i32)
+    (local $base i32) (local $sp i32) (local $tagged_base i32) (local $tagged_sp i32)
+    (local.set $base (global.get $stack))
+    (call $declare (local.get $base) (i32.const 8) (i32.const 16))
+    ;; Push n and n + 1.
+    (i64.store (local.get $base) (i64.extend_i32_u (local.get $n)))
+    (i64.store offset=8 (local.get $base)
+      (i64.extend_i32_u (i32.add (local.get $n) (i32.const 1))))
+    (local.set $sp (i32.add (local.get $base) (i32.const 16)))
+    ;; Tag both pointers: shift them left and set the low bit.
+    (local.set $tagged_base
+      (i32.add (i32.shl (local.get $base) (i32.const 1)) (i32.const 1)))
+    (local.set $tagged_sp
+      (i32.add (i32.shl (local.get $sp) (i32.const 1)) (i32.const 1)))
+    ;; The depth, in slots, from the tagged pointers.
+    (if (i32.ne
+          (i32.shr_u
+            (i32.sub (local.get $tagged_sp) (local.get $tagged_base))
+            (i32.const 4))
+          (i32.const 2))
+      (then unreachable))
+    (i32.wrap_i64
+      (i64.add
+        (i64.load (local.get $base))
+        (i64.load offset=8 (local.get $base))))))
@@ -0,0 +1,40 @@
+;; The main module for `side.wat`: it owns the memory and table that
+;; the side module is linked against, and holds the fixture
+;; interpreter's request and program (as in `examples/interp`), but
+;; the request's function pointer is the side module's first table
+;; slot. The side module is loaded with `__table_base` 2 and
+;; `__memory_base` 1024.
+
+(module
+  (type $interp_t (func (param i32 i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (table (export "__indirect_function_table") 4 funcref)
+
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\02\00\00\00\80\00\00\00\80\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\01\00\00\00\04\00\00\00\60\00\00\00\60\00\00\00"
+    "\01\00\00\00\00\00\00\00\03\00\00\00\00\00\00\00"
+    "\02\00\00\00\03\00\00\00\01\00\00\00\04\00\00\00"
+    "\00\00\00\00\05\00\00\00\01\00\00\00\03\00\00\00"
+    "\00\00\00\00\04\00\00\00\01\00\00\00\06\00\00\00"
+    "\03\00\00\00\01\00\00\00\07\00\00\00\07\00\00\00"
+    "\04\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00"
+    "\00\00\00\00\ff\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "wizer.initialize")
+    (i32.store (i32.const 16) (i32.const 64)))
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $interp_t)
+          (i32.const 144) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call_indirect (type $interp_t)
+          (i32.const 144) (local.get $n) (i32.const 2))))))
@@ -0,0 +1,99 @@
+;; A side module, laid out as `wasm-ld -shared` lays one out, holding
+;; the fixture interpreter of `examples/interp` (scaling its result by a
+;; word of its own data, which is 1). `main.wat` requests its
+;; specialization through the interpreter's slot in the shared table.
+;;
+;; The `dylink.0` memory info gives 4 bytes of data and one table slot.
+
+(module
+  (@custom "dylink.0" (before first) "\01\04\04\02\01\00")
+  (type $interp_t (func (param i32 i32) (result i32)))
+  (import "env" "memory" (memory 1))
+  (import "env" "__indirect_function_table" (table 0 funcref))
+  (import "env" "__stack_pointer" (global $stack_pointer (mut i32)))
+  (import "env" "__memory_base" (global $memory_base i32))
+  (import "env" "__table_base" (global $table_base i32))
+  (import "weval" "push.context" (func $push_context (param i32)))
+  (import "weval" "pop.context" (func $pop_context))
+  (import "weval" "update.context" (func $update_context (param i32)))
+
+  (elem (global.get $table_base) func $interp)
+  (data (global.get $memory_base) "\01\00\00\00")
+
+  (export "interp" (func $interp))
+
+  (func $interp (type $interp_t) (param $prog i32) (param $arg i32) (result i32)
+    (local $pc i32) (local $acc i32) (local $r0 i32) (local $r1 i32)
+    (local $op i32) (local $imm i32)
+    (call $push_context (i32.const 0))
+    (block $halt
+      (loop $loop
+        (local.set $op
+          (i32.load
+            (i32.add (local.get $prog) (i32.shl (local.get $pc) (i32.const 2)))))
+        (local.set $imm
+          (i32.load offset=4
+            (i32.add (local.get $prog) (i32.shl (local.get $pc) (i32.const 2)))))
+        (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+        (block $bad
+          (block $jnz
+            (block $dec
+              (block $add
+                (block $load
+                  (block $store
+                    (block $argop
+                      (block $loadi
+                        (br_table $halt $loadi $argop $store $load $add $dec $jnz $bad
+                          (local.get $op))
+                        ;; dead
+                        (local.set $op (i32.const 0)))
+                      ;; LOADI
+                      (local.set $acc (local.get $imm))
+                      (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                      (call $update_context (local.get $pc))
+                      (br $loop))
+                    ;; ARG
+                    (local.set $acc (local.get $arg))
+                    (call $update_context (local.get $pc))
+                    (br $loop))
+                  ;; STORE
+                  (if (local.get $imm)
+                    (then (local.set $r1 (local.get $acc)))
+                    (else (local.set $r0 (local.get $acc))))
+                  (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                  (call $update_context (local.get $pc))
+                  (br $loop))
+                ;; LOAD
+                (local.set $acc
+                  (select (local.get $r1) (local.get $r0) (local.get $imm)))
+                (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+                (call $update_context (local.get $pc))
+                (br $loop))
+              ;; ADD
+              (local.set $acc
+                (i32.add (local.get $acc)
+                  (select (local.get $r1) (local.get $r0) (local.get $imm))))
+              (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+              (call $update_context (local.get $pc))
+              (br $loop))
+            ;; DEC
+            nop
+            (local.set $acc (i32.sub (local.get $acc) (i32.const 1)))
+            (call $update_context (local.get $pc))
+            (br $loop))
+          ;; JNZ
+          (if (local.get $acc)
+            (then
+              (local.set $pc (local.get $imm))
+              (call $update_context (local.get $pc))
+              (br $loop)))
+          (local.set $pc (i32.add (local.get $pc) (i32.const 1)))
+          (call $update_context (local.get $pc))
+          (br $loop))
+        ;; bad opcode
+        unreachable
+        ;; dead
+        (local.set $acc (i32.const 0))
+        (br $loop)))
+    (call $pop_context)
+    (i32.mul (local.get $acc) (i32.load (global.get $memory_base)))))
@@ -0,0 +1,44 @@
+;; A counting loop whose counter is the constant 0 on entry and only
+;; becomes a runtime value once the back edge is evaluated, after the
+;; loop body has first been evaluated (and its branch on the counter
+;; folded). The body must be re-evaluated when the counter changes.
+;;
+;; The request (with one runtime argument) is already pending in the
+;; data segments, so this needs no snapshot.
+
+(module
+  (type $sum_t (func (param i32) (result i32)))
+
+  (memory (export "memory") 1)
+  (table 2 funcref)
+  (elem (i32.const 1) $sum)
+
+  (data (i32.const 16) "\40\00\00\00")
+  (data (i32.const 64)
+    "\00\00\00\00\00\00\00\00\01\00\00\00\00\00\00\00"
+    "\01\00\00\00\80\00\00\00\10\00\00\00\20\00\00\00")
+  (data (i32.const 128)
+    "\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+
+  (func (export "weval.pending.head") (result i32)
+    i32.const 16)
+
+  (func (export "run") (param $n i32) (result i32)
+    (if (result i32) (i32.load (i32.const 32))
+      (then
+        (call_indirect (type $sum_t) (local.get $n) (i32.load (i32.const 32))))
+      (else
+        (call $sum (local.get $n)))))
+
+  ;; Adds each odd i below n, and subtracts one for each even i.
+  (func $sum (type $sum_t) (param $n i32) (result i32)
+    (local $i i32) (local $acc i32)
+    (block $done
+      (loop $loop
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (if (i32.and (local.get $i) (i32.const 1))
+          (then (local.set $acc (i32.add (local.get $acc) (local.get $i))))
+          (else (local.set $acc (i32.sub (local.get $acc) (i32.const 1)))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $loop)))
+    (local.get $acc)))
@@ -0,0 +1,37 @@
+//! Keeps the `weval-guest` crate's intrinsic imports in lockstep with
+//! the intrinsics weval recognizes: a module importing everything in
+//! `weval_guest::intrinsics::ABI` must resolve every intrinsic, with
+//! the signature weval expects, and nothing else.
+
+use weval::intrinsics::Intrinsics;
+
+#[test]
+fn guest_intrinsics_match_weval() {
+    let mut wat = "(module\n".to_owned();
+    for intrinsic in weval_guest::intrinsics::ABI {
+        wat += &format!(
+            "  (import \"weval\" \"{}\" (func (param {}) (result {})))\n",
+            intrinsic.name,
+            intrinsic.params.join(" "),
+            intrinsic.results.join(" ")
+        );
+    }
+    wat += ")\n";
+    let bytes = wat::parse_str(&wat).unwrap();
+    let module =
+        waffle::Module::from_wasm_bytes(&bytes[..], &waffle::FrontendOptions::default()).unwrap();
+
+    let intrinsics = Intrinsics::find(&module);
+    for (name, func) in intrinsics.by_name() {
+        assert!(
+            func.is_some(),
+            "weval-guest does not import `{}` with weval's signature",
+            name
+        );
+    }
+    assert_eq!(
+        weval_guest::intrinsics::ABI.len(),
+        intrinsics.by_name().len(),
+        "weval-guest imports intrinsics weval does not recognize"
+    );
+}